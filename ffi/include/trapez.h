@@ -0,0 +1,69 @@
+/*
+ * C interface of the trapez transaction processor, implemented by the trapez-ffi crate.
+ *
+ * Amounts are fixed-point numbers with four decimal places, i.e. 12345 is 1.2345. All calls block
+ * until the processor has handled the message. An engine must not be used from several threads at
+ * the same time.
+ */
+#ifndef TRAPEZ_H
+#define TRAPEZ_H
+
+#include <stdbool.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Return values */
+#define TRAPEZ_OK 0
+/* The processor rejected the transaction, trapez_last_error has the reason. */
+#define TRAPEZ_REJECTED 1
+/* The client has no account. */
+#define TRAPEZ_UNKNOWN 2
+/* A null pointer or an unknown transaction type. */
+#define TRAPEZ_INVALID (-1)
+/* The processor stopped. */
+#define TRAPEZ_FAILED (-2)
+
+/* Transaction types */
+#define TRAPEZ_DEPOSIT 0
+#define TRAPEZ_WITHDRAWAL 1
+#define TRAPEZ_DISPUTE 2
+#define TRAPEZ_RESOLVE 3
+#define TRAPEZ_CHARGEBACK 4
+
+typedef struct trapez_engine trapez_engine;
+
+typedef struct trapez_state {
+    uint16_t client;
+    int64_t available;
+    int64_t held;
+    int64_t total;
+    bool locked;
+} trapez_state;
+
+/* Creates an engine, or returns NULL if the runtime can't be started. */
+trapez_engine *trapez_engine_new(void);
+
+/* Stops the processor and frees the engine. */
+void trapez_engine_free(trapez_engine *engine);
+
+/* Submits a transaction. The amount is ignored for disputes, resolves and chargebacks. */
+int32_t trapez_submit(trapez_engine *engine, uint8_t kind, uint16_t client, uint32_t tx,
+                      int64_t amount);
+
+/*
+ * The error code of the last rejected transaction, e.g. "insufficient_funds", or NULL if the last
+ * transaction was accepted. The string stays valid until the next call with the engine.
+ */
+const char *trapez_last_error(const trapez_engine *engine);
+
+/* Writes the state of the client's account to out. */
+int32_t trapez_query(trapez_engine *engine, uint16_t client, trapez_state *out);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,294 @@
+/**
+ * C ABI of the processor, for services which can't link Rust code directly.
+ *
+ * An engine owns a processor with the default configuration on a single threaded runtime. Every
+ * call blocks until the processor has handled the message, so the outcome of a transaction is
+ * known when `trapez_submit` returns. Amounts are fixed-point numbers with four decimal places,
+ * i.e. `12345` is `1.2345`. `include/trapez.h` declares the functions and constants.
+ */
+use std::{ffi::CStr, os::raw::c_char, ptr};
+
+use tokio::{
+    runtime::Runtime,
+    sync::{mpsc, oneshot},
+};
+use trapez::{
+    log::Event,
+    processor::{self, Message, Notification},
+};
+
+pub const TRAPEZ_OK: i32 = 0;
+/// The processor rejected the transaction, `trapez_last_error` has the reason.
+pub const TRAPEZ_REJECTED: i32 = 1;
+/// The client has no account.
+pub const TRAPEZ_UNKNOWN: i32 = 2;
+/// A null pointer or an unknown transaction type.
+pub const TRAPEZ_INVALID: i32 = -1;
+/// The processor stopped.
+pub const TRAPEZ_FAILED: i32 = -2;
+
+pub const TRAPEZ_DEPOSIT: u8 = 0;
+pub const TRAPEZ_WITHDRAWAL: u8 = 1;
+pub const TRAPEZ_DISPUTE: u8 = 2;
+pub const TRAPEZ_RESOLVE: u8 = 3;
+pub const TRAPEZ_CHARGEBACK: u8 = 4;
+
+pub struct Engine {
+    runtime: Runtime,
+    tx_msg: mpsc::Sender<Message>,
+    rx_notify: mpsc::Receiver<Notification>,
+    /// Code of the last rejection, NUL terminated.
+    error: Option<Vec<u8>>,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct State {
+    pub client: u16,
+    pub available: i64,
+    pub held: i64,
+    pub total: i64,
+    pub locked: bool,
+}
+
+impl Engine {
+    fn new() -> std::io::Result<Engine> {
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        let (tx_msg, rx_notify) = runtime.block_on(processor::run(
+            processor::Config::default(),
+            processor::Storage::default(),
+        ));
+        Ok(Self {
+            runtime,
+            tx_msg,
+            rx_notify,
+            error: None,
+        })
+    }
+
+    // The state of all accounts once every message sent before is handled.
+    fn state(&mut self) -> Option<processor::StateView> {
+        let tx_msg = &self.tx_msg;
+        self.runtime.block_on(async {
+            let (tx, rx) = oneshot::channel();
+            tx_msg.send(Message::GetState { tx }).await.ok()?;
+            rx.await.ok()
+        })
+    }
+
+    fn submit(&mut self, msg: Message) -> i32 {
+        let tx_msg = &self.tx_msg;
+        if self.runtime.block_on(tx_msg.send(msg)).is_err() {
+            return TRAPEZ_FAILED;
+        }
+        // the notifications of the message are sent before the state is
+        if self.state().is_none() {
+            return TRAPEZ_FAILED;
+        }
+        self.error = None;
+        while let Ok(notification) = self.rx_notify.try_recv() {
+            if let Notification::Error(err, _) = notification {
+                let mut code = err.code().as_bytes().to_vec();
+                code.push(0);
+                self.error = Some(code);
+            }
+        }
+        match self.error {
+            Some(_) => TRAPEZ_REJECTED,
+            None => TRAPEZ_OK,
+        }
+    }
+}
+
+/**
+ * Creates an engine, or returns null if the runtime can't be started.
+ */
+#[no_mangle]
+pub extern "C" fn trapez_engine_new() -> *mut Engine {
+    match Engine::new() {
+        Ok(engine) => Box::into_raw(Box::new(engine)),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/**
+ * Stops the processor and frees the engine.
+ *
+ * # Safety
+ *
+ * `engine` must be null or come from `trapez_engine_new` and must not be used afterwards.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn trapez_engine_free(engine: *mut Engine) {
+    if !engine.is_null() {
+        drop(Box::from_raw(engine));
+    }
+}
+
+/**
+ * Submits a transaction of one of the `TRAPEZ_DEPOSIT` to `TRAPEZ_CHARGEBACK` types. The amount is
+ * ignored for disputes, resolves and chargebacks.
+ *
+ * # Safety
+ *
+ * `engine` must be null or a live engine from `trapez_engine_new`.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn trapez_submit(
+    engine: *mut Engine,
+    kind: u8,
+    client: u16,
+    tx: u32,
+    amount: i64,
+) -> i32 {
+    let engine = match engine.as_mut() {
+        Some(engine) => engine,
+        None => return TRAPEZ_INVALID,
+    };
+    let msg = match kind {
+        TRAPEZ_DEPOSIT => Message::Deposit {
+            client,
+            tx,
+            amount,
+            trade: None,
+        },
+        TRAPEZ_WITHDRAWAL => Message::Withdrawal {
+            client,
+            tx,
+            amount,
+            trade: None,
+        },
+        TRAPEZ_DISPUTE => Message::Dispute {
+            client,
+            tx,
+            reason: None,
+        },
+        TRAPEZ_RESOLVE => Message::Resolve {
+            client,
+            tx,
+            reason: None,
+        },
+        TRAPEZ_CHARGEBACK => Message::Chargeback {
+            client,
+            tx,
+            reason: None,
+        },
+        _ => return TRAPEZ_INVALID,
+    };
+    engine.submit(msg)
+}
+
+/**
+ * The error code of the last rejected transaction, e.g. `insufficient_funds`, or null if the last
+ * transaction was accepted. The string stays valid until the next call with the engine.
+ *
+ * # Safety
+ *
+ * `engine` must be null or a live engine from `trapez_engine_new`.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn trapez_last_error(engine: *const Engine) -> *const c_char {
+    match engine.as_ref().and_then(|engine| engine.error.as_deref()) {
+        Some(code) => CStr::from_bytes_with_nul_unchecked(code).as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/**
+ * Writes the state of the client's account to `out`.
+ *
+ * # Safety
+ *
+ * `engine` must be null or a live engine from `trapez_engine_new`, `out` null or valid for writes.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn trapez_query(engine: *mut Engine, client: u16, out: *mut State) -> i32 {
+    let (engine, out) = match (engine.as_mut(), out.as_mut()) {
+        (Some(engine), Some(out)) => (engine, out),
+        _ => return TRAPEZ_INVALID,
+    };
+    let state = match engine.state() {
+        Some(state) => state,
+        None => return TRAPEZ_FAILED,
+    };
+    match state.get(client) {
+        Some(s) => {
+            *out = State {
+                client: s.client,
+                available: s.available,
+                held: s.held,
+                total: s.total,
+                locked: s.locked,
+            };
+            TRAPEZ_OK
+        }
+        None => TRAPEZ_UNKNOWN,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+
+    use super::*;
+
+    #[test]
+    fn engine() {
+        unsafe {
+            let engine = trapez_engine_new();
+            assert!(!engine.is_null());
+            assert_eq!(
+                trapez_submit(engine, TRAPEZ_DEPOSIT, 1, 1, 20000),
+                TRAPEZ_OK
+            );
+            assert!(trapez_last_error(engine).is_null());
+            assert_eq!(
+                trapez_submit(engine, TRAPEZ_WITHDRAWAL, 1, 2, 30000),
+                TRAPEZ_REJECTED
+            );
+            assert_eq!(
+                CStr::from_ptr(trapez_last_error(engine)).to_str(),
+                Ok("insufficient_funds")
+            );
+            assert_eq!(trapez_submit(engine, TRAPEZ_DISPUTE, 1, 1, 0), TRAPEZ_OK);
+            assert_eq!(trapez_submit(engine, 9, 1, 1, 0), TRAPEZ_INVALID);
+
+            let mut state = State::default();
+            assert_eq!(trapez_query(engine, 1, &mut state), TRAPEZ_OK);
+            assert_eq!(
+                state,
+                State {
+                    client: 1,
+                    available: 0,
+                    held: 20000,
+                    total: 20000,
+                    locked: false
+                }
+            );
+            assert_eq!(trapez_query(engine, 2, &mut state), TRAPEZ_UNKNOWN);
+            assert_eq!(trapez_query(engine, 1, ptr::null_mut()), TRAPEZ_INVALID);
+            trapez_engine_free(engine);
+        }
+        assert_eq!(
+            unsafe { trapez_submit(ptr::null_mut(), TRAPEZ_DEPOSIT, 1, 1, 1) },
+            TRAPEZ_INVALID
+        );
+    }
+
+    #[test]
+    fn header() {
+        // the header is written by hand, it has to declare everything exported here
+        let header = include_str!("../include/trapez.h");
+        let source = include_str!("lib.rs");
+        for line in source.lines() {
+            let name = line
+                .strip_prefix("pub const ")
+                .or_else(|| line.strip_prefix("pub extern \"C\" fn "))
+                .or_else(|| line.strip_prefix("pub unsafe extern \"C\" fn "))
+                .and_then(|rest| rest.split([':', '(']).next());
+            if let Some(name) = name {
+                assert!(header.contains(name), "{name} is missing in trapez.h");
+            }
+        }
+    }
+}
@@ -1,8 +1,11 @@
-use std::collections::{
-    btree_map::{BTreeMap, Entry},
-    BTreeSet,
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fmt,
+    sync::Arc,
 };
 
+use crate::spill;
+
 #[derive(Debug, thiserror::Error, PartialEq, Eq)]
 pub enum Error {
     #[error("Transaction {0} already exists.")]
@@ -13,82 +16,627 @@ pub enum Error {
     TransactionUndisputed(u32),
     #[error("Transaction {0} is already disputed.")]
     TransactionAlreadyDisputed(u32),
+    #[error("Transaction {0} was reversed by a chargeback.")]
+    TransactionReversed(u32),
+    #[error("The dispute of transaction {0} was already resolved.")]
+    DisputeAlreadyResolved(u32),
+    #[error("The dispute of transaction {0} was already charged back.")]
+    DisputeAlreadyChargedBack(u32),
     #[error("Insufficient funds (requested: {requested}, available: {available}).")]
     InsufficientFunds { requested: i64, available: i64 },
     #[error("Negative amount.")]
     NegativeAmount(i64),
+    #[error("Amount {amount} is below the minimum of {min}.")]
+    AmountBelowMinimum { amount: i64, min: i64 },
+    #[error("Amount {amount} is above the maximum of {max}.")]
+    AmountAboveMaximum { amount: i64, max: i64 },
+    #[error("Withdrawal {0} is not pending approval.")]
+    NotPending(u32),
+    #[error("Transaction {0} is no open authorization.")]
+    NotAuthorized(u32),
     #[error("The account is currently locked.")]
     Locked,
+    #[error("Storage error: `{0}`.")]
+    Storage(String),
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::TransactionAlreadyExists(_) => "transaction_already_exists",
+            Error::TransactionUnknown(_) => "transaction_unknown",
+            Error::TransactionUndisputed(_) => "transaction_undisputed",
+            Error::TransactionAlreadyDisputed(_) => "transaction_already_disputed",
+            Error::TransactionReversed(_) => "transaction_reversed",
+            Error::DisputeAlreadyResolved(_) => "dispute_already_resolved",
+            Error::DisputeAlreadyChargedBack(_) => "dispute_already_charged_back",
+            Error::InsufficientFunds { .. } => "insufficient_funds",
+            Error::NegativeAmount(_) => "negative_amount",
+            Error::AmountBelowMinimum { .. } => "amount_below_minimum",
+            Error::AmountAboveMaximum { .. } => "amount_above_maximum",
+            Error::NotPending(_) => "withdrawal_not_pending",
+            Error::NotAuthorized(_) => "not_authorized",
+            Error::Locked => "locked",
+            Error::Storage(_) => "storage",
+        }
+    }
 }
 
 pub type Result = std::result::Result<(), Error>;
 
-#[derive(Debug, PartialEq, Eq)]
-pub struct Account {
+/**
+ * Inclusive bounds of an amount. Unset bounds are not checked.
+ */
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Bounds {
+    pub min: Option<i64>,
+    pub max: Option<i64>,
+}
+
+impl Bounds {
+    pub fn check(&self, amount: i64) -> Result {
+        match (self.min, self.max) {
+            (Some(min), _) if amount < min => Err(Error::AmountBelowMinimum { amount, min }),
+            (_, Some(max)) if amount > max => Err(Error::AmountAboveMaximum { amount, max }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/**
+ * Bounds of the amounts of deposits and withdrawals.
+ */
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub deposit: Bounds,
+    pub withdrawal: Bounds,
+}
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        *self == Limits::default()
+    }
+}
+
+/**
+ * Transaction log stored as two parallel vectors sorted by transaction id.
+ *
+ * Takes 12 bytes per entry instead of the per-node overhead of a BTreeMap. Transaction ids mostly
+ * arrive in increasing order, so inserts usually append.
+ *
+ * With a spill store only the most recent entries are kept in memory; older ones get moved to
+ * sorted segments on disk.
+ */
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Log {
+    txs: Vec<u32>,
+    amounts: Vec<i64>,
+    spilled: Option<Spilled>,
+}
+
+struct Spilled {
+    store: Arc<spill::Store>,
+    segments: Vec<spill::Segment>,
+}
+
+impl fmt::Debug for Spilled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(&self.segments).finish()
+    }
+}
+
+impl PartialEq for Spilled {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.store, &other.store) && self.segments == other.segments
+    }
+}
+
+impl Eq for Spilled {}
+
+impl Spilled {
+    fn get(&self, tx: u32) -> std::result::Result<Option<i64>, Error> {
+        for segment in self.segments.iter().rev() {
+            if let Some(amount) = self
+                .store
+                .get(segment, tx)
+                .map_err(|err| Error::Storage(err.to_string()))?
+            {
+                return Ok(Some(amount));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Log {
+    fn get(&self, tx: u32) -> std::result::Result<Option<i64>, Error> {
+        match (self.txs.binary_search(&tx), &self.spilled) {
+            (Ok(i), _) => Ok(Some(self.amounts[i])),
+            (Err(_), Some(spilled)) => spilled.get(tx),
+            (Err(_), None) => Ok(None),
+        }
+    }
+
     /**
-     * The total funds that are available for trading, staking, withdrawal, etc.
-     *
-     * Could be calculated on-demand from the log but stored here for efficient retrieval.
+     * Returns false if the transaction already exists.
      */
-    pub available: i64,
+    fn insert(&mut self, tx: u32, amount: i64) -> std::result::Result<bool, Error> {
+        if self.get(tx)?.is_some() {
+            return Ok(false);
+        }
+        self.spill()?;
+        let pos = match self.txs.last() {
+            Some(last) if *last > tx => self.txs.partition_point(|t| *t < tx),
+            _ => self.txs.len(),
+        };
+        self.txs.insert(pos, tx);
+        self.amounts.insert(pos, amount);
+        Ok(true)
+    }
+
+    // Moves the oldest entries to disk once the window is full, keeping half of the window in
+    // memory.
+    fn spill(&mut self) -> std::result::Result<(), Error> {
+        let spilled = match &mut self.spilled {
+            Some(spilled) if self.txs.len() >= spilled.store.window.max(1) => spilled,
+            _ => return Ok(()),
+        };
+        let n = self.txs.len() - spilled.store.window / 2;
+        let segment = spilled
+            .store
+            .write(&self.txs[..n], &self.amounts[..n])
+            .map_err(|err| Error::Storage(err.to_string()))?;
+        spilled.segments.push(segment);
+        self.txs.drain(..n);
+        self.amounts.drain(..n);
+        Ok(())
+    }
+
     /**
-     * The total funds that are held for dispute.
-     *
-     * Could be calculated on-demand from the log but stored here for efficient retrieval.
+     * All entries including the spilled ones, sorted by transaction id.
      */
+    fn entries(&self) -> std::result::Result<Vec<(u32, i64)>, Error> {
+        let mut entries = Vec::new();
+        if let Some(spilled) = &self.spilled {
+            for segment in &spilled.segments {
+                entries.extend(
+                    spilled
+                        .store
+                        .read(segment)
+                        .map_err(|err| Error::Storage(err.to_string()))?,
+                );
+            }
+        }
+        entries.extend(self.txs.iter().copied().zip(self.amounts.iter().copied()));
+        entries.sort_unstable_by_key(|(tx, _)| *tx);
+        Ok(entries)
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.txs.len()
+    }
+}
+
+impl FromIterator<(u32, i64)> for Log {
+    fn from_iter<I: IntoIterator<Item = (u32, i64)>>(iter: I) -> Self {
+        let mut log = Log::default();
+        for (tx, amount) in iter {
+            // can't fail without spill store
+            let _ = log.insert(tx, amount);
+        }
+        log
+    }
+}
+
+/**
+ * How the last dispute of a transaction ended.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Closed {
+    Resolved,
+    ChargedBack,
+}
+
+/// Number of closed disputes an account remembers the outcome of.
+pub const CLOSED: usize = 16;
+
+/**
+ * The complete contents of an account, used for snapshots.
+ */
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Parts {
+    pub available: i64,
     pub held: i64,
+    pub locked: bool,
+    /// Transaction ids and signed amounts, sorted by transaction id.
+    pub log: Vec<(u32, i64)>,
+    pub disputes: Vec<u32>,
+    pub reversed: Vec<u32>,
+    /// Withdrawals pending approval with their amounts, sorted by transaction id.
+    pub pending: Vec<(u32, i64)>,
+    /// Open authorizations with their amounts and expiry times, sorted by transaction id.
+    pub authorized: Vec<(u32, i64, u64)>,
+    /// The most recently closed disputes, oldest first, and whether they were charged back.
+    pub closed: Vec<(u32, bool)>,
+}
+
+mod funds {
+    use super::Error;
+
     /**
-     * Whether the account is locked.
+     * The balances and the lock of an account. They can only change through `Unlocked`, which a
+     * locked account doesn't hand out, so no operation can forget to check the lock.
      */
-    pub locked: bool,
+    #[derive(Debug, Default, PartialEq, Eq)]
+    pub struct Funds {
+        available: i64,
+        held: i64,
+        locked: bool,
+    }
+
+    impl Funds {
+        pub fn restore(available: i64, held: i64, locked: bool) -> Funds {
+            Self {
+                available,
+                held,
+                locked,
+            }
+        }
+
+        pub fn available(&self) -> i64 {
+            self.available
+        }
+
+        pub fn held(&self) -> i64 {
+            self.held
+        }
+
+        pub fn locked(&self) -> bool {
+            self.locked
+        }
+
+        pub fn unlocked(&mut self) -> Result<Unlocked<'_>, Error> {
+            if self.locked {
+                Err(Error::Locked)
+            } else {
+                Ok(Unlocked(self))
+            }
+        }
+    }
+
+    /**
+     * Access to the balances of an account which isn't locked.
+     */
+    pub struct Unlocked<'a>(&'a mut Funds);
+
+    impl Unlocked<'_> {
+        pub fn available(&self) -> i64 {
+            self.0.available
+        }
+
+        /// Adds a signed amount to the available funds.
+        pub fn credit(&mut self, amount: i64) {
+            self.0.available += amount;
+        }
+
+        /// Moves a signed amount from the available to the held funds.
+        pub fn hold(&mut self, amount: i64) {
+            self.0.available -= amount;
+            self.0.held += amount;
+        }
+
+        /// Moves a signed amount from the held back to the available funds.
+        pub fn release(&mut self, amount: i64) {
+            self.0.held -= amount;
+            self.0.available += amount;
+        }
+
+        /// Withdraws a signed amount from the held funds and locks the account, which ends the
+        /// access.
+        pub fn charge_back(self, amount: i64) {
+            self.0.held -= amount;
+            self.0.locked = true;
+        }
+    }
+}
+
+use funds::{Funds, Unlocked};
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Account {
+    funds: Funds,
     /**
      * The log of deposits and withdrawels. We use i64 throughout in order to avoid conversions.
      *
      * This should eventually become bound either by size or some kind of age threshold.
      */
-    log: BTreeMap<u32, i64>,
+    log: Log,
     /**
      * Set of disputed transactions. Could also be an attribute inside the transaction log but as
      * the number of disputes should stay small we don't waste space on every log entry.
      */
     disputes: BTreeSet<u32>,
+    /**
+     * Charged back transactions which were reversed, so they can't be disputed again.
+     */
+    reversed: BTreeSet<u32>,
+    /**
+     * Outcomes of the last `CLOSED` closed disputes, oldest first, so a late resolve or chargeback
+     * of a dispute which the other one already closed gets a specific error.
+     */
+    closed: VecDeque<(u32, Closed)>,
+    /**
+     * Withdrawals waiting for an approval. Their amounts are still available but earmarked, so
+     * other withdrawals can't use them.
+     */
+    pending: BTreeMap<u32, i64>,
+    /**
+     * Open authorizations with their amounts and expiry times. Like pending withdrawals, their
+     * amounts are still available but earmarked until captured or expired.
+     */
+    authorized: BTreeMap<u32, (i64, u64)>,
+    /**
+     * Bounds of the amounts of deposits and withdrawals, shared by all accounts.
+     */
+    limits: Option<Arc<Limits>>,
+}
+
+// Remembers the outcome of a closed dispute, forgetting the oldest one.
+fn close(closed: &mut VecDeque<(u32, Closed)>, tx: u32, outcome: Closed) {
+    if closed.len() == CLOSED {
+        closed.pop_front();
+    }
+    closed.push_back((tx, outcome));
+}
+
+// Logs a transaction and credits its signed amount.
+fn record(log: &mut Log, mut funds: Unlocked, tx: u32, amount: i64) -> Result {
+    if log.insert(tx, amount)? {
+        funds.credit(amount);
+        Ok(())
+    } else {
+        Err(Error::TransactionAlreadyExists(tx))
+    }
 }
 
 impl Account {
     pub fn new() -> Account {
-        Account {
-            available: 0,
-            held: 0,
-            locked: false,
-            log: BTreeMap::new(),
-            disputes: BTreeSet::new(),
+        Account::default()
+    }
+
+    /**
+     * Creates an account which keeps only the most recent transactions in memory and spills the
+     * others to the given store.
+     */
+    pub fn with_store(store: Arc<spill::Store>) -> Account {
+        let mut account = Account::new();
+        account.log.spilled = Some(Spilled {
+            store,
+            segments: Vec::new(),
+        });
+        account
+    }
+
+    /**
+     * Rejects deposits and withdrawals with amounts out of the given bounds.
+     */
+    pub fn limited(mut self, limits: Arc<Limits>) -> Account {
+        self.limits = Some(limits);
+        self
+    }
+
+    pub fn to_parts(&self) -> std::result::Result<Parts, Error> {
+        Ok(Parts {
+            available: self.available(),
+            held: self.held(),
+            locked: self.locked(),
+            log: self.log.entries()?,
+            disputes: self.disputes.iter().copied().collect(),
+            reversed: self.reversed.iter().copied().collect(),
+            pending: self
+                .pending
+                .iter()
+                .map(|(tx, amount)| (*tx, *amount))
+                .collect(),
+            authorized: self
+                .authorized
+                .iter()
+                .map(|(tx, (amount, expires))| (*tx, *amount, *expires))
+                .collect(),
+            closed: self
+                .closed
+                .iter()
+                .map(|(tx, closed)| (*tx, *closed == Closed::ChargedBack))
+                .collect(),
+        })
+    }
+
+    /**
+     * A copy of the account to try a message about the given transaction on. Its log only holds
+     * that transaction, so no other entries are read from the spill store.
+     */
+    pub fn fork(&self, tx: u32) -> std::result::Result<Account, Error> {
+        let mut log = Log::default();
+        if let Some(amount) = self.log.get(tx)? {
+            log.insert(tx, amount)?;
         }
+        Ok(Account {
+            funds: Funds::restore(self.available(), self.held(), self.locked()),
+            log,
+            disputes: self.disputes.clone(),
+            reversed: self.reversed.clone(),
+            closed: self.closed.clone(),
+            pending: self.pending.clone(),
+            authorized: self.authorized.clone(),
+            limits: self.limits.clone(),
+        })
     }
 
-    fn chk_lock(&self) -> Result {
-        if self.locked {
-            Err(Error::Locked)
+    /**
+     * Restores an account from its parts. With a store, older log entries get spilled again.
+     */
+    pub fn from_parts(
+        parts: Parts,
+        store: Option<Arc<spill::Store>>,
+    ) -> std::result::Result<Account, Error> {
+        let mut account = match store {
+            Some(store) => Account::with_store(store),
+            None => Account::new(),
+        };
+        for (tx, amount) in parts.log {
+            account.log.insert(tx, amount)?;
+        }
+        account.funds = Funds::restore(parts.available, parts.held, parts.locked);
+        account.disputes = parts.disputes.into_iter().collect();
+        account.reversed = parts.reversed.into_iter().collect();
+        account.pending = parts.pending.into_iter().collect();
+        account.authorized = parts
+            .authorized
+            .into_iter()
+            .map(|(tx, amount, expires)| (tx, (amount, expires)))
+            .collect();
+        account.closed = parts
+            .closed
+            .into_iter()
+            .map(|(tx, charged_back)| {
+                if charged_back {
+                    (tx, Closed::ChargedBack)
+                } else {
+                    (tx, Closed::Resolved)
+                }
+            })
+            .collect();
+        Ok(account)
+    }
+
+    /**
+     * Overwrites the balances, to test the checks which detect inconsistent ones.
+     */
+    #[cfg(test)]
+    pub fn tamper(&mut self, available: i64, held: i64) {
+        self.funds = Funds::restore(available, held, self.locked());
+    }
+
+    fn chk_reversed(&self, tx: u32) -> Result {
+        if self.reversed.contains(&tx) {
+            Err(Error::TransactionReversed(tx))
         } else {
             Ok(())
         }
     }
 
+    // Whichever of a resolve and a chargeback arrives first closes the dispute, the other one is
+    // rejected. Repeating the winner and losers older than the remembered outcomes get the
+    // generic errors.
+    fn chk_closed(&self, tx: u32, winner: Closed) -> Result {
+        match self.closed.iter().find(|(closed, _)| *closed == tx) {
+            _ if self.disputes.contains(&tx) => Ok(()),
+            Some((_, Closed::Resolved)) if winner == Closed::Resolved => {
+                Err(Error::DisputeAlreadyResolved(tx))
+            }
+            Some((_, Closed::ChargedBack)) if winner == Closed::ChargedBack => {
+                Err(Error::DisputeAlreadyChargedBack(tx))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /**
+     * The total funds that are available for trading, staking, withdrawal, etc.
+     *
+     * Could be calculated on-demand from the log but stored for efficient retrieval.
+     */
+    pub fn available(&self) -> i64 {
+        self.funds.available()
+    }
+
+    /**
+     * The total funds that are held for dispute.
+     *
+     * Could be calculated on-demand from the log but stored for efficient retrieval.
+     */
+    pub fn held(&self) -> i64 {
+        self.funds.held()
+    }
+
+    /**
+     * Whether the account is locked.
+     */
+    pub fn locked(&self) -> bool {
+        self.funds.locked()
+    }
+
     /**
      * The total funds that are available or held.
      */
     pub fn total(&self) -> i64 {
-        self.available + self.held
+        self.available() + self.held()
     }
 
-    fn tx(&mut self, tx: u32, amount: i64) -> Result {
-        match self.log.entry(tx) {
-            Entry::Occupied(_) => Err(Error::TransactionAlreadyExists(tx)),
-            Entry::Vacant(entry) => {
-                entry.insert(amount);
-                self.available += amount;
-                Ok(())
-            }
+    /**
+     * The earmarked funds of the withdrawals pending approval, part of the available funds.
+     */
+    pub fn pending(&self) -> i64 {
+        self.pending.values().sum()
+    }
+
+    /**
+     * The amount of a withdrawal pending approval.
+     */
+    pub fn pending_amount(&self, tx: u32) -> Option<i64> {
+        self.pending.get(&tx).copied()
+    }
+
+    /**
+     * The earmarked funds of the open authorizations, part of the available funds.
+     */
+    pub fn authorized(&self) -> i64 {
+        self.authorized.values().map(|(amount, _)| amount).sum()
+    }
+
+    /**
+     * The amount and the expiry time of an open authorization.
+     */
+    pub fn authorization(&self, tx: u32) -> Option<(i64, u64)> {
+        self.authorized.get(&tx).copied()
+    }
+
+    /**
+     * The disputed transactions, in order of their ids.
+     */
+    pub fn disputes(&self) -> impl Iterator<Item = u32> + '_ {
+        self.disputes.iter().copied()
+    }
+
+    /**
+     * Whether the account has no funds, no open disputes and no lock, so nothing but its log.
+     */
+    pub fn is_empty(&self) -> bool {
+        self.available() == 0
+            && self.held() == 0
+            && !self.locked()
+            && self.disputes.is_empty()
+            && self.pending.is_empty()
+            && self.authorized.is_empty()
+    }
+
+    /**
+     * The signed amount of a logged transaction.
+     */
+    pub fn amount(&self, tx: u32) -> Option<i64> {
+        self.log.get(tx).ok().flatten()
+    }
+
+    /**
+     * The sum of the amounts of all disputed transactions, which should equal the held funds.
+     */
+    pub fn disputed(&self) -> std::result::Result<i64, Error> {
+        let mut sum = 0;
+        for tx in &self.disputes {
+            sum += self.log.get(*tx)?.ok_or(Error::TransactionUnknown(*tx))?;
         }
+        Ok(sum)
     }
 
     /**
@@ -98,11 +646,17 @@ impl Account {
      * Although the amount type is signed we only allow positive values.
      */
     pub fn deposit(&mut self, tx: u32, amount: i64) -> Result {
-        self.chk_lock()?;
+        let funds = self.funds.unlocked()?;
         if amount < 0 {
             return Err(Error::NegativeAmount(amount));
         }
-        self.tx(tx, amount)
+        if self.pending.contains_key(&tx) || self.authorized.contains_key(&tx) {
+            return Err(Error::TransactionAlreadyExists(tx));
+        }
+        if let Some(limits) = &self.limits {
+            limits.deposit.check(amount)?;
+        }
+        record(&mut self.log, funds, tx, amount)
     }
 
     /**
@@ -112,19 +666,122 @@ impl Account {
      * Although the amount type is signed we only allow positive values.
      */
     pub fn withdraw(&mut self, tx: u32, amount: i64) -> Result {
-        self.chk_lock()?;
+        self.chk_withdrawal(tx, amount)?;
+        let funds = self.funds.unlocked()?;
+        record(&mut self.log, funds, tx, -amount)
+    }
+
+    fn chk_withdrawal(&self, tx: u32, amount: i64) -> Result {
+        if self.locked() {
+            return Err(Error::Locked);
+        }
         if amount < 0 {
             return Err(Error::NegativeAmount(amount));
         }
-        if self.available < amount {
+        if let Some(limits) = &self.limits {
+            limits.withdrawal.check(amount)?;
+        }
+        if self.pending.contains_key(&tx) || self.authorized.contains_key(&tx) {
+            return Err(Error::TransactionAlreadyExists(tx));
+        }
+        // earmarked funds can't be withdrawn
+        let available = self.available() - self.pending() - self.authorized();
+        if available < amount {
             // If a client does not have sufficient available funds the withdrawal should fail and
             // the total amount of funds should not change
             return Err(Error::InsufficientFunds {
-                available: self.available,
+                available,
                 requested: amount,
             });
         }
-        self.tx(tx, -amount)
+        Ok(())
+    }
+
+    /**
+     * Requests a withdrawal which needs an approval. Its amount stays available but is earmarked
+     * until the withdrawal is approved or rejected.
+     */
+    pub fn request(&mut self, tx: u32, amount: i64) -> Result {
+        self.chk_withdrawal(tx, amount)?;
+        if self.log.get(tx)?.is_some() {
+            return Err(Error::TransactionAlreadyExists(tx));
+        }
+        self.pending.insert(tx, amount);
+        Ok(())
+    }
+
+    /**
+     * Approves a pending withdrawal, which debits its amount now.
+     */
+    pub fn approve(&mut self, tx: u32) -> Result {
+        let amount = self.pending_amount(tx).ok_or(Error::NotPending(tx))?;
+        let funds = self.funds.unlocked()?;
+        // a dispute may have taken the earmarked funds
+        if funds.available() < amount {
+            return Err(Error::InsufficientFunds {
+                available: funds.available(),
+                requested: amount,
+            });
+        }
+        record(&mut self.log, funds, tx, -amount)?;
+        self.pending.remove(&tx);
+        Ok(())
+    }
+
+    /**
+     * Rejects a pending withdrawal, which releases its earmarked funds.
+     */
+    pub fn reject(&mut self, tx: u32) -> Result {
+        self.pending
+            .remove(&tx)
+            .map(|_| ())
+            .ok_or(Error::NotPending(tx))
+    }
+
+    /**
+     * Authorizes a withdrawal without debiting it, like a card authorization. Its amount stays
+     * available but is earmarked until the authorization is captured or expires at `expires`.
+     */
+    pub fn authorize(&mut self, tx: u32, amount: i64, expires: u64) -> Result {
+        self.chk_withdrawal(tx, amount)?;
+        if self.log.get(tx)?.is_some() {
+            return Err(Error::TransactionAlreadyExists(tx));
+        }
+        self.authorized.insert(tx, (amount, expires));
+        Ok(())
+    }
+
+    /**
+     * Captures an open authorization, which debits its amount now.
+     */
+    pub fn capture(&mut self, tx: u32) -> Result {
+        let (amount, _) = self.authorization(tx).ok_or(Error::NotAuthorized(tx))?;
+        let funds = self.funds.unlocked()?;
+        // a dispute may have taken the earmarked funds
+        if funds.available() < amount {
+            return Err(Error::InsufficientFunds {
+                available: funds.available(),
+                requested: amount,
+            });
+        }
+        record(&mut self.log, funds, tx, -amount)?;
+        self.authorized.remove(&tx);
+        Ok(())
+    }
+
+    /**
+     * Removes an expired authorization, which releases its earmarked funds, and returns its
+     * amount. The expiry time must match, so an authorization of a reused id isn't expired early.
+     */
+    pub fn expire(&mut self, tx: u32, expires: u64) -> Option<i64> {
+        match self.authorized.get(&tx) {
+            Some((amount, at)) if *at == expires => {
+                let amount = *amount;
+                self.authorized.remove(&tx);
+                Some(amount)
+            }
+            _ => None,
+        }
     }
 
     /**
@@ -132,19 +789,19 @@ impl Account {
      * reversed. The transaction shouldn't be reversed yet but the associated funds should be held.
      */
     pub fn dispute(&mut self, tx: u32) -> Result {
-        self.chk_lock()?;
-        match self.log.entry(tx) {
-            Entry::Vacant(_) => Err(Error::TransactionUnknown(tx)),
-            Entry::Occupied(entry) => {
+        self.chk_reversed(tx)?;
+        let mut funds = self.funds.unlocked()?;
+        match self.log.get(tx)? {
+            None => Err(Error::TransactionUnknown(tx)),
+            Some(amount) => {
                 if self.disputes.contains(&tx) {
                     Err(Error::TransactionAlreadyDisputed(tx))
                 } else {
                     self.disputes.insert(tx);
-                    let amount = entry.get();
-                    // available funds should decrease by the amount disputed
-                    self.available -= amount;
-                    // held funds should increase by the amount disputed
-                    self.held += amount;
+                    self.closed.retain(|(closed, _)| *closed != tx);
+                    // available funds should decrease and held funds increase by the amount
+                    // disputed
+                    funds.hold(amount);
                     Ok(())
                 }
             }
@@ -155,38 +812,48 @@ impl Account {
      * A resolve represents a resolution to a dispute, releasing the associated held funds.
      */
     pub fn resolve(&mut self, tx: u32) -> Result {
-        self.chk_lock()?;
-        match self.log.entry(tx) {
-            Entry::Vacant(_) => Err(Error::TransactionUnknown(tx)),
-            Entry::Occupied(entry) => {
+        self.chk_reversed(tx)?;
+        self.chk_closed(tx, Closed::ChargedBack)?;
+        let mut funds = self.funds.unlocked()?;
+        match self.log.get(tx)? {
+            None => Err(Error::TransactionUnknown(tx)),
+            Some(amount) => {
                 if !self.disputes.contains(&tx) {
                     Err(Error::TransactionUndisputed(tx))
                 } else {
                     // Funds that were previously disputed are no longer disputed.
                     self.disputes.remove(&tx);
-                    let amount = entry.get();
-                    // available funds should increase by the amount no longer disputed
-                    self.available += amount;
-                    // held funds should decrease by the amount no longer disputed
-                    self.held -= amount;
+                    close(&mut self.closed, tx, Closed::Resolved);
+                    // available funds should increase and held funds decrease by the amount no
+                    // longer disputed
+                    funds.release(amount);
                     Ok(())
                 }
             }
         }
     }
 
-    pub fn chargeback(&mut self, tx: u32) -> Result {
-        self.chk_lock()?;
-        match self.log.entry(tx) {
-            Entry::Vacant(_) => Err(Error::TransactionUnknown(tx)),
-            Entry::Occupied(entry) => {
+    /**
+     * A chargeback is the final state of a dispute: the held funds are withdrawn and the account
+     * gets locked. With `reverse` the transaction is also marked as reversed, which excludes it
+     * from any further dispute.
+     */
+    pub fn chargeback(&mut self, tx: u32, reverse: bool) -> Result {
+        self.chk_reversed(tx)?;
+        self.chk_closed(tx, Closed::Resolved)?;
+        let funds = self.funds.unlocked()?;
+        match self.log.get(tx)? {
+            None => Err(Error::TransactionUnknown(tx)),
+            Some(amount) => {
                 if !self.disputes.contains(&tx) {
                     Err(Error::TransactionUndisputed(tx))
                 } else {
                     self.disputes.remove(&tx);
-                    let amount = entry.get();
-                    self.held -= amount;
-                    self.locked = true;
+                    close(&mut self.closed, tx, Closed::ChargedBack);
+                    funds.charge_back(amount);
+                    if reverse {
+                        self.reversed.insert(tx);
+                    }
                     Ok(())
                 }
             }
@@ -204,16 +871,99 @@ mod tests {
         assert_eq!(
             account,
             Account {
-                available: 0,
-                held: 0,
-                locked: false,
-                log: BTreeMap::new(),
-                disputes: BTreeSet::new()
+                funds: Funds::restore(0, 0, false),
+                log: Log::default(),
+                disputes: BTreeSet::new(),
+                reversed: BTreeSet::new(),
+                closed: VecDeque::new(),
+                pending: BTreeMap::new(),
+                authorized: BTreeMap::new(),
+                limits: None
             }
         );
         assert_eq!(account.total(), 0)
     }
 
+    #[test]
+    fn log() {
+        let mut log = Log::default();
+        assert_eq!(log.insert(2, 20), Ok(true));
+        assert_eq!(log.insert(5, 50), Ok(true));
+        assert_eq!(log.insert(1, 10), Ok(true));
+        assert_eq!(log.insert(3, 30), Ok(true));
+        assert_eq!(log.insert(3, 31), Ok(false));
+        assert_eq!(log.insert(5, 51), Ok(false));
+        assert_eq!(log.txs, vec![1, 2, 3, 5]);
+        assert_eq!(log.amounts, vec![10, 20, 30, 50]);
+        assert_eq!(log.get(3), Ok(Some(30)));
+        assert_eq!(log.get(4), Ok(None));
+    }
+
+    #[test]
+    fn spill() {
+        let store = spill::Store::create(std::env::temp_dir(), 4).unwrap();
+        let mut account = Account::with_store(store);
+
+        for tx in 1..=10 {
+            account.deposit(tx, i64::from(tx)).unwrap();
+        }
+        assert!(account.log.len() <= 4);
+        assert_eq!(account.total(), 55);
+        assert_eq!(
+            account.deposit(1, 1).unwrap_err(),
+            Error::TransactionAlreadyExists(1)
+        );
+        // below the spilled ranges
+        account.deposit(0, 100).unwrap();
+        // forks only copy the entry of their transaction
+        let mut fork = account.fork(2).unwrap();
+        assert_eq!((fork.log.len(), fork.total()), (1, 155));
+        assert_eq!(
+            fork.deposit(2, 1).unwrap_err(),
+            Error::TransactionAlreadyExists(2)
+        );
+        fork.dispute(2).unwrap();
+        assert_eq!((fork.held(), account.held()), (2, 0));
+
+        account.dispute(1).unwrap();
+        account.dispute(0).unwrap();
+        assert_eq!(account.held(), 101);
+        assert_eq!(account.disputed(), Ok(101));
+        account.resolve(1).unwrap();
+        account.chargeback(0, false).unwrap();
+        assert_eq!(account.total(), 55);
+        assert_eq!(account.amount(7), Some(7));
+        assert_eq!(account.amount(11), None);
+    }
+
+    #[test]
+    fn parts() {
+        let store = spill::Store::create(std::env::temp_dir(), 4).unwrap();
+        let mut account = Account::with_store(store.clone());
+        for tx in (1..=10).rev() {
+            account.deposit(tx, i64::from(tx)).unwrap();
+        }
+        account.dispute(3).unwrap();
+
+        let parts = account.to_parts().unwrap();
+        assert_eq!(
+            parts.log,
+            (1..=10).map(|tx| (tx, i64::from(tx))).collect::<Vec<_>>()
+        );
+        assert_eq!(parts.disputes, [3]);
+        assert_eq!(parts.held, 3);
+
+        let restored = Account::from_parts(parts.clone(), Some(store)).unwrap();
+        assert_eq!(restored.to_parts().unwrap(), parts);
+        assert_eq!(
+            Account::from_parts(parts.clone(), None)
+                .unwrap()
+                .to_parts()
+                .unwrap(),
+            parts
+        );
+    }
+
     #[test]
     fn deposit() {
         let mut account = Account::new();
@@ -222,11 +972,14 @@ mod tests {
         assert_eq!(
             &account,
             &Account {
-                available: 5,
-                held: 0,
-                locked: false,
+                funds: Funds::restore(5, 0, false),
                 log: [(0, 5)].into_iter().collect(),
-                disputes: BTreeSet::new()
+                disputes: BTreeSet::new(),
+                reversed: BTreeSet::new(),
+                closed: VecDeque::new(),
+                pending: BTreeMap::new(),
+                authorized: BTreeMap::new(),
+                limits: None
             }
         );
         assert_eq!(account.total(), 5);
@@ -235,11 +988,14 @@ mod tests {
         assert_eq!(
             &account,
             &Account {
-                available: 8,
-                held: 0,
-                locked: false,
+                funds: Funds::restore(8, 0, false),
                 log: [(0, 5), (1, 3)].into_iter().collect(),
-                disputes: BTreeSet::new()
+                disputes: BTreeSet::new(),
+                reversed: BTreeSet::new(),
+                closed: VecDeque::new(),
+                pending: BTreeMap::new(),
+                authorized: BTreeMap::new(),
+                limits: None
             }
         );
         assert_eq!(account.total(), 8);
@@ -266,11 +1022,14 @@ mod tests {
         assert_eq!(
             &account,
             &Account {
-                available: 2,
-                held: 0,
-                locked: false,
+                funds: Funds::restore(2, 0, false),
                 log: [(0, 5), (1, -3)].into_iter().collect(),
-                disputes: BTreeSet::new()
+                disputes: BTreeSet::new(),
+                reversed: BTreeSet::new(),
+                closed: VecDeque::new(),
+                pending: BTreeMap::new(),
+                authorized: BTreeMap::new(),
+                limits: None
             }
         );
         assert_eq!(account.total(), 2);
@@ -295,6 +1054,109 @@ mod tests {
         assert_eq!(account.total(), 2)
     }
 
+    #[test]
+    fn limits() {
+        let limits = Limits {
+            deposit: Bounds {
+                min: Some(2),
+                max: Some(10),
+            },
+            withdrawal: Bounds {
+                min: None,
+                max: Some(3),
+            },
+        };
+        let mut account = Account::new().limited(Arc::new(limits));
+
+        assert_eq!(
+            account.deposit(0, 1).unwrap_err(),
+            Error::AmountBelowMinimum { amount: 1, min: 2 }
+        );
+        assert_eq!(
+            account.deposit(0, 11).unwrap_err(),
+            Error::AmountAboveMaximum {
+                amount: 11,
+                max: 10
+            }
+        );
+        account.deposit(0, 10).unwrap();
+        // checked before the funds
+        assert_eq!(
+            account.withdraw(1, 20).unwrap_err(),
+            Error::AmountAboveMaximum { amount: 20, max: 3 }
+        );
+        account.withdraw(1, 1).unwrap();
+        assert_eq!(account.total(), 9);
+        assert!(!limits.is_empty() && Limits::default().is_empty());
+    }
+
+    #[test]
+    fn approval() {
+        let mut account = Account::new();
+
+        account.deposit(0, 10).unwrap();
+        account.request(1, 6).unwrap();
+        account.request(2, 3).unwrap();
+        // earmarked, not debited
+        assert_eq!((account.available(), account.pending()), (10, 9));
+        assert_eq!(
+            account.withdraw(3, 2).unwrap_err(),
+            Error::InsufficientFunds {
+                available: 1,
+                requested: 2
+            }
+        );
+        assert_eq!(
+            account.deposit(1, 1).unwrap_err(),
+            Error::TransactionAlreadyExists(1)
+        );
+        account.approve(1).unwrap();
+        account.reject(2).unwrap();
+        assert_eq!((account.available(), account.pending()), (4, 0));
+        assert_eq!(account.amount(1), Some(-6));
+        assert_eq!(account.approve(2).unwrap_err(), Error::NotPending(2));
+        assert_eq!(account.reject(1).unwrap_err(), Error::NotPending(1));
+
+        // survives a snapshot
+        account.request(3, 4).unwrap();
+        let account = Account::from_parts(account.to_parts().unwrap(), None).unwrap();
+        assert_eq!(account.pending_amount(3), Some(4));
+    }
+
+    #[test]
+    fn authorization() {
+        let mut account = Account::new();
+
+        account.deposit(0, 10).unwrap();
+        account.authorize(1, 6, 100).unwrap();
+        account.authorize(2, 3, 200).unwrap();
+        // earmarked, not debited
+        assert_eq!((account.available(), account.authorized()), (10, 9));
+        assert_eq!(
+            account.withdraw(3, 2).unwrap_err(),
+            Error::InsufficientFunds {
+                available: 1,
+                requested: 2
+            }
+        );
+        assert_eq!(
+            account.authorize(2, 1, 300).unwrap_err(),
+            Error::TransactionAlreadyExists(2)
+        );
+        account.capture(1).unwrap();
+        assert_eq!(account.amount(1), Some(-6));
+        assert_eq!(account.expire(2, 100), None);
+        assert_eq!(account.expire(2, 200), Some(3));
+        assert_eq!((account.available(), account.authorized()), (4, 0));
+        assert_eq!(account.capture(2).unwrap_err(), Error::NotAuthorized(2));
+        assert_eq!(account.capture(1).unwrap_err(), Error::NotAuthorized(1));
+
+        // survives a snapshot
+        account.authorize(3, 4, 400).unwrap();
+        let account = Account::from_parts(account.to_parts().unwrap(), None).unwrap();
+        assert_eq!(account.authorization(3), Some((4, 400)));
+    }
+
     #[test]
     fn dispute() {
         let mut account = Account::new();
@@ -308,11 +1170,14 @@ mod tests {
         assert_eq!(
             &account,
             &Account {
-                available: -3,
-                held: 5,
-                locked: false,
+                funds: Funds::restore(-3, 5, false),
                 log: [(0, 5), (1, -3)].into_iter().collect(),
-                disputes: [0].into_iter().collect()
+                disputes: [0].into_iter().collect(),
+                reversed: BTreeSet::new(),
+                closed: VecDeque::new(),
+                pending: BTreeMap::new(),
+                authorized: BTreeMap::new(),
+                limits: None
             }
         );
         assert_eq!(account.total(), 2);
@@ -321,11 +1186,14 @@ mod tests {
         assert_eq!(
             &account,
             &Account {
-                available: 0,
-                held: 2,
-                locked: false,
+                funds: Funds::restore(0, 2, false),
                 log: [(0, 5), (1, -3)].into_iter().collect(),
-                disputes: [0, 1].into_iter().collect()
+                disputes: [0, 1].into_iter().collect(),
+                reversed: BTreeSet::new(),
+                closed: VecDeque::new(),
+                pending: BTreeMap::new(),
+                authorized: BTreeMap::new(),
+                limits: None
             }
         );
         assert_eq!(account.total(), 2);
@@ -352,11 +1220,14 @@ mod tests {
         assert_eq!(
             &account,
             &Account {
-                available: 5,
-                held: 0,
-                locked: false,
+                funds: Funds::restore(5, 0, false),
                 log: [(0, 5)].into_iter().collect(),
-                disputes: BTreeSet::new()
+                disputes: BTreeSet::new(),
+                reversed: BTreeSet::new(),
+                closed: [(0, Closed::Resolved)].into_iter().collect(),
+                pending: BTreeMap::new(),
+                authorized: BTreeMap::new(),
+                limits: None
             }
         );
         assert_eq!(account.total(), 5);
@@ -369,7 +1240,12 @@ mod tests {
             account.resolve(0).unwrap_err(),
             Error::TransactionUndisputed(0)
         );
-        assert_eq!(account.total(), 5);
+        account.deposit(1, 1).unwrap();
+        assert_eq!(
+            account.resolve(1).unwrap_err(),
+            Error::TransactionUndisputed(1)
+        );
+        assert_eq!(account.total(), 6);
     }
 
     #[test]
@@ -377,28 +1253,31 @@ mod tests {
         let mut account = Account::new();
 
         assert_eq!(
-            account.chargeback(0).unwrap_err(),
+            account.chargeback(0, false).unwrap_err(),
             Error::TransactionUnknown(0)
         );
 
         account.deposit(0, 5).unwrap();
 
         assert_eq!(
-            account.chargeback(0).unwrap_err(),
+            account.chargeback(0, false).unwrap_err(),
             Error::TransactionUndisputed(0)
         );
 
         account.dispute(0).unwrap();
 
-        assert!(account.chargeback(0).is_ok());
+        assert!(account.chargeback(0, false).is_ok());
         assert_eq!(
             &account,
             &Account {
-                available: 0,
-                held: 0,
-                locked: true,
+                funds: Funds::restore(0, 0, true),
                 log: [(0, 5)].into_iter().collect(),
-                disputes: BTreeSet::new()
+                disputes: BTreeSet::new(),
+                reversed: BTreeSet::new(),
+                closed: [(0, Closed::ChargedBack)].into_iter().collect(),
+                pending: BTreeMap::new(),
+                authorized: BTreeMap::new(),
+                limits: None
             }
         );
         assert_eq!(account.total(), 0);
@@ -407,7 +1286,74 @@ mod tests {
         assert_eq!(account.withdraw(1, 1).unwrap_err(), Error::Locked);
         assert_eq!(account.dispute(1).unwrap_err(), Error::Locked);
         assert_eq!(account.resolve(1).unwrap_err(), Error::Locked);
-        assert_eq!(account.chargeback(1).unwrap_err(), Error::Locked);
+        assert_eq!(account.chargeback(1, false).unwrap_err(), Error::Locked);
         assert_eq!(account.total(), 0);
     }
+
+    #[test]
+    fn race() {
+        // resolve first
+        let mut account = Account::new();
+        account.deposit(0, 5).unwrap();
+        account.dispute(0).unwrap();
+        account.resolve(0).unwrap();
+        assert_eq!(
+            account.chargeback(0, false).unwrap_err(),
+            Error::DisputeAlreadyResolved(0)
+        );
+        assert_eq!((account.available(), account.locked()), (5, false));
+        // only the most recent outcomes are remembered
+        for tx in 1..=CLOSED as u32 {
+            account.deposit(tx, 1).unwrap();
+            account.dispute(tx).unwrap();
+            account.resolve(tx).unwrap();
+        }
+        assert_eq!(
+            account.chargeback(1, false).unwrap_err(),
+            Error::DisputeAlreadyResolved(1)
+        );
+        assert_eq!(
+            account.chargeback(0, false).unwrap_err(),
+            Error::TransactionUndisputed(0)
+        );
+        // a new dispute can be closed again
+        account.dispute(0).unwrap();
+        account.chargeback(0, false).unwrap();
+
+        // chargeback first
+        let mut account = Account::new();
+        account.deposit(0, 5).unwrap();
+        account.dispute(0).unwrap();
+        account.chargeback(0, false).unwrap();
+        assert_eq!(
+            account.resolve(0).unwrap_err(),
+            Error::DisputeAlreadyChargedBack(0)
+        );
+        assert_eq!(account.chargeback(0, false).unwrap_err(), Error::Locked);
+        assert_eq!((account.total(), account.locked()), (0, true));
+        // survives a snapshot
+        let account = Account::from_parts(account.to_parts().unwrap(), None).unwrap();
+        assert_eq!(account.to_parts().unwrap().closed, [(0, true)]);
+    }
+
+    #[test]
+    fn reverse() {
+        let mut account = Account::new();
+        account.deposit(0, 5).unwrap();
+        account.deposit(1, 3).unwrap();
+        account.dispute(0).unwrap();
+        account.chargeback(0, true).unwrap();
+        assert_eq!(account.total(), 3);
+        assert_eq!(account.to_parts().unwrap().reversed, [0]);
+
+        assert_eq!(
+            account.dispute(0).unwrap_err(),
+            Error::TransactionReversed(0)
+        );
+        assert_eq!(
+            account.chargeback(0, true).unwrap_err(),
+            Error::TransactionReversed(0)
+        );
+        assert_eq!(account.dispute(1).unwrap_err(), Error::Locked);
+    }
 }
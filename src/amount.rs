@@ -1,134 +1,210 @@
-use serde::{Deserialize, Deserializer, Serializer};
+use std::fmt;
 
 const NUM_DIGITS: usize = 4;
+pub(crate) const UNIT: u64 = 10_u64.pow(NUM_DIGITS as u32);
 
-pub fn serialize<S>(amount: &i64, s: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    let mut str = amount.to_string();
-    if str.len() <= NUM_DIGITS {
-        let pad = NUM_DIGITS + 1 - str.len();
-        str.insert_str(0, "0".repeat(pad).as_str());
+/**
+ * Displays 1/10000th currency units as decimal with four decimal places, without allocating.
+ */
+pub struct Decimal(pub i64);
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        write!(f, "{sign}{}.{:04}", abs / UNIT, abs % UNIT)
     }
-    str.insert(str.len() - NUM_DIGITS, '.');
-    s.serialize_str(str.as_str())
 }
 
-pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let s: Option<&str> = Deserialize::deserialize(deserializer)?;
+/**
+ * Renders 1/10000th currency units as decimal string with four decimal places.
+ */
+pub fn format(amount: i64) -> String {
+    Decimal(amount).to_string()
+}
 
-    if s.is_none() {
-        return Ok(None);
-    }
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum Error {
+    #[error("cannot parse amount from empty string")]
+    Empty,
+    #[error("invalid digit found in string")]
+    InvalidDigit,
+    #[error("amount too large")]
+    Overflow,
+}
 
-    let mut s = s.unwrap().to_string();
-    let pad_digits = if let Some(dec_pos) = s.rfind('.') {
-        // remove '.'
-        s.replace_range(dec_pos..=dec_pos, "");
-        s.len() - dec_pos
-    } else {
-        0
+/**
+ * Parses a decimal string into 1/10000th currency units. Additional decimal places get truncated.
+ */
+pub fn parse(s: &str) -> Result<i64, Error> {
+    parse_bytes(s.as_bytes())
+}
+
+/**
+ * Like `parse` but operates on raw bytes so CSV fields can be parsed without any allocation.
+ *
+ * Digit runs are converted eight at a time (SWAR), so the loop has one iteration per eight digits
+ * instead of a multiply and a branch per digit.
+ */
+pub fn parse_bytes(s: &[u8]) -> Result<i64, Error> {
+    let (negative, s) = match s.split_first() {
+        None => return Err(Error::Empty),
+        Some((b'-', rest)) => (true, rest),
+        Some((b'+', rest)) => (false, rest),
+        Some(_) => (false, s),
+    };
+    let (int, frac) = match s.iter().position(|b| *b == b'.') {
+        Some(pos) => (&s[..pos], &s[pos + 1..]),
+        None => (s, &s[s.len()..]),
     };
-    if pad_digits > NUM_DIGITS {
-        // trim
-        s.replace_range(s.len() - (pad_digits - NUM_DIGITS)..s.len(), "")
-    } else {
-        // pad
-        let pad = "0".repeat(NUM_DIGITS - pad_digits);
-        s.push_str(pad.as_str());
+    if int.is_empty() && frac.is_empty() {
+        return Err(Error::InvalidDigit);
+    }
+
+    // The first chunk takes the odd digits so all following chunks are full.
+    let mut value: i64 = 0;
+    let mut rest = int;
+    while !rest.is_empty() {
+        let len = match rest.len() % 8 {
+            0 => 8,
+            n => n,
+        };
+        let (chunk, tail) = rest.split_at(len);
+        let chunk = digits(chunk).ok_or(Error::InvalidDigit)?;
+        value = value
+            .checked_mul(10_i64.pow(len as u32))
+            .and_then(|v| v.checked_add(chunk as i64))
+            .ok_or(Error::Overflow)?;
+        rest = tail;
+    }
+
+    let (kept, truncated) = frac.split_at(frac.len().min(NUM_DIGITS));
+    let mut padded = [b'0'; NUM_DIGITS];
+    padded[..kept.len()].copy_from_slice(kept);
+    let frac = digits(&padded).ok_or(Error::InvalidDigit)?;
+    // truncated digits still have to be valid
+    if truncated.chunks(8).any(|chunk| digits(chunk).is_none()) {
+        return Err(Error::InvalidDigit);
+    }
+
+    let value = value
+        .checked_mul(UNIT as i64)
+        .and_then(|v| v.checked_add(frac as i64))
+        .ok_or(Error::Overflow)?;
+    Ok(if negative { -value } else { value })
+}
+
+/**
+ * Converts up to eight ASCII digits at once, or returns `None` if any byte isn't a digit.
+ */
+fn digits(run: &[u8]) -> Option<u64> {
+    const ONES: u64 = 0x0101_0101_0101_0101;
+
+    // Left-pad with zeros, the first digit ends up in the lowest byte.
+    let mut buf = [b'0'; 8];
+    buf[8 - run.len()..].copy_from_slice(run);
+    let v = u64::from_le_bytes(buf);
+    // Digits are 0x30..=0x39, so the high nibble is 3 before and after adding 6.
+    if v & (0xf0 * ONES) != 0x30 * ONES
+        || v.wrapping_add(0x06 * ONES) & (0xf0 * ONES) != 0x30 * ONES
+    {
+        return None;
     }
-    s.parse::<i64>().map(Some).map_err(serde::de::Error::custom)
+    // Combine neighbouring bytes, then 16 bit lanes, then 32 bit lanes.
+    let v = (v & (0x0f * ONES)).wrapping_mul(10 << 8 | 1) >> 8;
+    let v = (v & 0x00ff_00ff_00ff_00ff).wrapping_mul(100 << 16 | 1) >> 16;
+    Some((v & 0x0000_ffff_0000_ffff).wrapping_mul(10_000 << 32 | 1) >> 32)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testkit;
 
-    use serde::Serialize;
-    use serde_test::{assert_de_tokens, assert_de_tokens_error, assert_ser_tokens, Token};
-
-    #[derive(Debug, Deserialize, PartialEq, Eq)]
-    struct In {
-        #[serde(with = "super")]
-        value: Option<i64>,
-    }
+    #[test]
+    fn de() {
+        assert_eq!(parse("1"), Ok(10000));
+        assert_eq!(parse("1.0"), Ok(10000));
+        assert_eq!(parse("1.1234"), Ok(11234));
+        assert_eq!(parse("1.12345"), Ok(11234));
+        assert_eq!(parse("-1.12345"), Ok(-11234));
+        assert_eq!(parse("+.5"), Ok(5000));
+        assert_eq!(parse("2."), Ok(20000));
 
-    #[derive(Debug, Serialize, PartialEq, Eq)]
-    struct Out {
-        #[serde(with = "super")]
-        value: i64,
+        assert_eq!(parse(""), Err(Error::Empty));
+        assert_eq!(parse("x"), Err(Error::InvalidDigit));
+        assert_eq!(parse("."), Err(Error::InvalidDigit));
+        assert_eq!(parse("1.-5"), Err(Error::InvalidDigit));
+        assert_eq!(parse("1.2.3"), Err(Error::InvalidDigit));
+        assert_eq!(parse("1.12345x"), Err(Error::InvalidDigit));
+        assert_eq!(parse("922337203685477.5807"), Ok(i64::MAX));
+        assert_eq!(parse("922337203685478"), Err(Error::Overflow));
+        assert_eq!(parse("123456789.87654321"), Ok(1234567898765));
+        assert_eq!(parse("000000000000000001"), Ok(10000));
+        assert_eq!(parse("12345678x"), Err(Error::InvalidDigit));
+        assert_eq!(parse("1:"), Err(Error::InvalidDigit));
+        assert_eq!(parse("1.1/"), Err(Error::InvalidDigit));
+        assert_eq!(parse("1.1234567890:"), Err(Error::InvalidDigit));
     }
 
-    fn assert_de(s: &'static str, value: Option<i64>) {
-        if value.is_some() {
-            assert_de_tokens(
-                &In { value },
-                &[
-                    Token::Struct { name: "In", len: 1 },
-                    Token::Str("value"),
-                    Token::Some,
-                    Token::BorrowedStr(s),
-                    Token::StructEnd,
-                ],
-            );
-        } else {
-            assert_de_tokens(
-                &In { value },
-                &[
-                    Token::Struct { name: "In", len: 1 },
-                    Token::Str("value"),
-                    Token::None,
-                    Token::StructEnd,
-                ],
-            );
+    // Straightforward parser to check the SWAR one against.
+    fn reference(s: &[u8]) -> Option<i64> {
+        let s = std::str::from_utf8(s).ok()?;
+        let (negative, s) = match s.strip_prefix('-') {
+            Some(s) => (true, s),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
         };
+        let (int, frac) = s.split_once('.').unwrap_or((s, ""));
+        if int.is_empty() && frac.is_empty()
+            || !int.chars().chain(frac.chars()).all(|c| c.is_ascii_digit())
+        {
+            return None;
+        }
+        let mut value: i128 = 0;
+        for d in int
+            .bytes()
+            .chain(format!("{frac:0<4}").bytes().take(NUM_DIGITS))
+        {
+            value = value * 10 + i128::from(d - b'0');
+            if value > i128::from(i64::MAX) {
+                return None;
+            }
+        }
+        let value = i64::try_from(value).ok()?;
+        Some(if negative { -value } else { value })
     }
 
     #[test]
-    fn de() {
-        assert_de("1", Some(10000));
-        assert_de("1.0", Some(10000));
-        assert_de("1.1234", Some(11234));
-        assert_de("1.12345", Some(11234));
-        assert_de("-1.12345", Some(-11234));
-        assert_de("", None);
-
-        assert_de_tokens_error::<In>(
-            &[
-                Token::Struct { name: "In", len: 1 },
-                Token::Str("value"),
-                Token::Some,
-                Token::BorrowedStr("x"),
-                Token::StructEnd,
-            ],
-            "invalid digit found in string",
-        );
+    fn fuzz() {
+        let mut rng = testkit::Rng::new(1);
+        for _ in 0..100_000 {
+            let s = testkit::bytes(&mut rng, b"0123456789999.-+", 24);
+            assert_eq!(
+                parse_bytes(&s).ok(),
+                reference(&s),
+                "{:?}",
+                String::from_utf8_lossy(&s)
+            );
+        }
     }
 
-    fn assert_ser(value: i64, s: &'static str) {
-        assert_ser_tokens(
-            &Out { value },
-            &[
-                Token::Struct {
-                    name: "Out",
-                    len: 1,
-                },
-                Token::Str("value"),
-                Token::BorrowedStr(s),
-                Token::StructEnd,
-            ],
-        )
+    #[test]
+    fn swar() {
+        assert_eq!(digits(b"12345678"), Some(12345678));
+        assert_eq!(digits(b"9"), Some(9));
+        assert_eq!(digits(b""), Some(0));
+        assert_eq!(digits(b"99999999"), Some(99999999));
+        assert_eq!(digits(b"1234 678"), None);
+        assert_eq!(digits(&[b'1', 0xff]), None);
     }
 
     #[test]
     fn ser() {
-        assert_ser(0, "0.0000");
-        assert_ser(10, "0.0010");
-        assert_ser(10000, "1.0000");
-        assert_ser(-10000, "-1.0000");
+        assert_eq!(format(0), "0.0000");
+        assert_eq!(format(10), "0.0010");
+        assert_eq!(format(10000), "1.0000");
+        assert_eq!(format(-10000), "-1.0000");
+        assert_eq!(format(-10), "-0.0010");
+        assert_eq!(format(i64::MIN), "-922337203685477.5808");
     }
 }
@@ -0,0 +1,730 @@
+/**
+ * Append-only, hash-chained audit log of accepted state-changing operations.
+ *
+ * Every line has the form `<seq> <hash> <record>` where `hash` is the hex encoded SHA-256 digest of
+ * `<seq> <previous hash> <record>`. The first entry (sequence number 1) chains to a digest of all
+ * zeros. Modifying, removing or reordering entries breaks the chain, which `verify` detects.
+ *
+ * `compact` folds older entries into per-account opening balances. A compacted log starts with
+ * `checkpoint <seq> <hash> <openings hash>` naming the last folded entry, followed by lines
+ * `opening,<client>,<available>,<held>,<locked>` whose SHA-256 digest is the openings hash. The
+ * remaining entries keep their sequence numbers and hashes and chain to the checkpoint.
+ *
+ * `settle` nets the deposits, withdrawals and chargebacks of every client within a range of entries
+ * into settlement instructions.
+ *
+ * Admin commands changing a snapshot outside of a run append records of their own, see `admin`.
+ * They move no funds, so `compact` and `settle` pass over them.
+ */
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use crate::{
+    amount, processor,
+    sha256::{self, Digest},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Audit log IO error: `{0}`.")]
+    Io(#[from] io::Error),
+    #[error("Malformed audit log entry in line {0}.")]
+    Malformed(u64),
+    #[error("Unexpected sequence number {found} in audit log (expected {expected}).")]
+    Sequence { expected: u64, found: u64 },
+    #[error("Hash mismatch for audit log entry {0}.")]
+    Hash(u64),
+    #[error("Malformed or modified audit log checkpoint.")]
+    Checkpoint,
+    #[error("Audit log entry {0} can't be folded into opening balances.")]
+    Fold(u64),
+    #[error("Audit log entries up to {0} are compacted into opening balances.")]
+    Compacted(u64),
+}
+
+pub struct Log {
+    writer: Box<dyn Write + Send>,
+    seq: u64,
+    prev: Digest,
+}
+
+impl Log {
+    /**
+     * Starts a new chain on the given writer.
+     */
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Log {
+        Self {
+            writer: Box::new(writer),
+            seq: 0,
+            prev: [0; 32],
+        }
+    }
+
+    /**
+     * Opens the audit log at the given path for appending. Existing entries are verified and the
+     * chain is continued from the last one.
+     */
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Log, Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let (seq, prev) = chain(BufReader::new(&file))?;
+        Ok(Self {
+            seq,
+            prev,
+            ..Log::new(file)
+        })
+    }
+
+    pub fn append(&mut self, record: &str) -> io::Result<()> {
+        let seq = self.seq + 1;
+        let hash = hash(seq, &self.prev, record);
+        let line = format!("{seq} {} {record}\n", sha256::hex(&hash));
+        self.writer.write_all(line.as_bytes())?;
+        self.writer.flush()?;
+        self.seq = seq;
+        self.prev = hash;
+        Ok(())
+    }
+}
+
+fn hash(seq: u64, prev: &Digest, record: &str) -> Digest {
+    sha256::digest(format!("{seq} {} {record}", sha256::hex(prev)).as_bytes())
+}
+
+/**
+ * The balances of an account at a checkpoint.
+ */
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Opening {
+    available: i64,
+    held: i64,
+    locked: bool,
+}
+
+impl Opening {
+    fn line(&self, client: u16) -> String {
+        format!(
+            "opening,{client},{},{},{}\n",
+            amount::Decimal(self.available),
+            amount::Decimal(self.held),
+            self.locked
+        )
+    }
+
+    // Applies an accepted record. Disputes, resolves and chargebacks carry the disputed amount,
+    // approvals, rejections and captures the amount of the withdrawal. Withdrawals awaiting
+    // approval are booked when requested, so only a rejection books them back. Authorizations are
+    // only booked when captured, expired ones leave no record.
+    fn apply(&mut self, kind: &str, amount: i64) -> Option<()> {
+        match kind {
+            "deposit" => self.available += amount,
+            "withdrawal" => self.available -= amount,
+            "approve" => (),
+            "reject" => self.available += amount,
+            "authorize" => (),
+            "capture" => self.available -= amount,
+            "dispute" => {
+                self.available -= amount;
+                self.held += amount;
+            }
+            "resolve" => {
+                self.held -= amount;
+                self.available += amount;
+            }
+            "chargeback" => {
+                self.held -= amount;
+                self.locked = true;
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+/**
+ * A parsed audit log: the checkpoint (if compacted) and the remaining entries.
+ */
+#[derive(Debug, Default)]
+struct Entries {
+    base: (u64, Digest),
+    openings: BTreeMap<u16, Opening>,
+    /// Sequence number, hash and record.
+    entries: Vec<(u64, Digest, String)>,
+}
+
+fn parse_opening(line: &str) -> Option<(u16, Opening)> {
+    let mut fields = line.strip_prefix("opening,")?.split(',');
+    let client = fields.next()?.parse().ok()?;
+    let opening = Opening {
+        available: amount::parse(fields.next()?).ok()?,
+        held: amount::parse(fields.next()?).ok()?,
+        locked: fields.next()?.parse().ok()?,
+    };
+    fields.next().is_none().then_some((client, opening))
+}
+
+fn parse_checkpoint(line: &str) -> Option<(u64, String, String)> {
+    let mut parts = line.strip_prefix("checkpoint ")?.split(' ');
+    let seq = parts.next()?.parse().ok()?;
+    let hash = parts.next()?.to_string();
+    let openings = parts.next()?.to_string();
+    parts.next().is_none().then_some((seq, hash, openings))
+}
+
+// Verifies all entries. With `keep` false only the last sequence number and hash are retained.
+fn read<R: BufRead>(reader: R, keep: bool) -> Result<Entries, Error> {
+    let mut log = Entries::default();
+    let mut lines = reader.lines().peekable();
+    if let Some(Ok(line)) = lines.peek() {
+        if let Some((seq, hash, openings)) = parse_checkpoint(line) {
+            lines.next();
+            let mut digest = sha256::Sha256::new();
+            while let Some(Ok(line)) = lines.peek() {
+                if !line.starts_with("opening,") {
+                    break;
+                }
+                let (client, opening) = parse_opening(line).ok_or(Error::Checkpoint)?;
+                digest.update(opening.line(client).as_bytes());
+                log.openings.insert(client, opening);
+                lines.next();
+            }
+            if sha256::hex(&digest.finish()) != openings {
+                return Err(Error::Checkpoint);
+            }
+            let mut prev = [0; 32];
+            for (i, byte) in prev.iter_mut().enumerate() {
+                *byte = hash
+                    .get(i * 2..i * 2 + 2)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or(Error::Checkpoint)?;
+            }
+            log.base = (seq, prev);
+        }
+    }
+
+    let (mut seq, mut prev) = log.base;
+    for line in lines {
+        let line = line?;
+        let expected = seq + 1;
+        let mut parts = line.splitn(3, ' ');
+        let (found, hex, record) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(found), Some(hex), Some(record)) => (
+                found.parse().map_err(|_| Error::Malformed(expected))?,
+                hex,
+                record,
+            ),
+            _ => return Err(Error::Malformed(expected)),
+        };
+        if found != expected {
+            return Err(Error::Sequence { expected, found });
+        }
+        let hash = hash(found, &prev, record);
+        if sha256::hex(&hash) != hex {
+            return Err(Error::Hash(found));
+        }
+        if keep {
+            log.entries.push((found, hash, record.to_string()));
+        }
+        seq = found;
+        prev = hash;
+    }
+    if !keep {
+        log.base = (seq, prev);
+    }
+    Ok(log)
+}
+
+// Verifies all entries and returns the last sequence number and hash.
+fn chain<R: BufRead>(reader: R) -> Result<(u64, Digest), Error> {
+    read(reader, false).map(|log| log.base)
+}
+
+/**
+ * Folds all but the last `keep` entries into opening balances and returns the number of folded
+ * entries. The log must not be open for appending at the same time.
+ */
+pub fn compact<P: AsRef<Path>>(path: P, keep: usize) -> Result<usize, Error> {
+    let path = path.as_ref();
+    let mut log = read(BufReader::new(File::open(path)?), true)?;
+    let fold = log.entries.len().saturating_sub(keep);
+    if fold == 0 {
+        return Ok(0);
+    }
+    for (seq, hash, record) in log.entries.drain(..fold) {
+        let folded = if is_admin(&record) {
+            Some(())
+        } else {
+            parse_record(&record).and_then(|(kind, client, amount)| {
+                log.openings.entry(client).or_default().apply(kind, amount)
+            })
+        };
+        folded.ok_or(Error::Fold(seq))?;
+        log.base = (seq, hash);
+    }
+
+    let openings: String = log
+        .openings
+        .iter()
+        .map(|(client, opening)| opening.line(*client))
+        .collect();
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut w = BufWriter::new(File::create(&tmp)?);
+    writeln!(
+        w,
+        "checkpoint {} {} {}",
+        log.base.0,
+        sha256::hex(&log.base.1),
+        sha256::hex(&sha256::digest(openings.as_bytes()))
+    )?;
+    w.write_all(openings.as_bytes())?;
+    for (seq, hash, record) in &log.entries {
+        writeln!(w, "{seq} {} {record}", sha256::hex(hash))?;
+    }
+    w.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(fold)
+}
+
+/**
+ * Folds all entries up to the last one of a client into opening balances, so none of its records
+ * remain, and returns the number of folded entries. The chain stays verifiable as after `compact`.
+ */
+pub fn erase<P: AsRef<Path>>(path: P, client: u16) -> Result<usize, Error> {
+    let path = path.as_ref();
+    let log = read(BufReader::new(File::open(path)?), true)?;
+    let of_client = |record: &str| record.split(',').nth(1) == Some(client.to_string().as_str());
+    match log
+        .entries
+        .iter()
+        .rposition(|(_, _, record)| of_client(record))
+    {
+        Some(last) => compact(path, log.entries.len() - last - 1),
+        None => Ok(0),
+    }
+}
+
+// The kind, client and amount of a record.
+fn parse_record(record: &str) -> Option<(&str, u16, i64)> {
+    let mut fields = record.split(',');
+    let kind = fields.next()?;
+    let client = fields.next()?.parse().ok()?;
+    let _tx = fields.next()?;
+    Some((kind, client, amount::parse(fields.next()?).ok()?))
+}
+
+/**
+ * The movements of a client's funds from and to the outside world within a settlement period.
+ */
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Settlement {
+    pub client: u16,
+    pub deposits: i64,
+    pub withdrawals: i64,
+    pub chargebacks: i64,
+}
+
+impl Settlement {
+    /**
+     * Positive if the client was credited funds which have yet to be collected, negative if funds
+     * have to be paid out to the client.
+     */
+    pub fn net(&self) -> i64 {
+        self.deposits - self.withdrawals - self.chargebacks
+    }
+
+    pub fn instruction(&self) -> &'static str {
+        match self.net() {
+            net if net > 0 => "collect",
+            net if net < 0 => "pay",
+            _ => "none",
+        }
+    }
+}
+
+/**
+ * Nets the entries after sequence number `after` up to and including `through` (the last entry by
+ * default) per client and writes a settlement instruction file: the net amount to `collect` from or
+ * to `pay` to every client with movements in the period. The whole chain is verified first.
+ */
+pub fn settle<P: AsRef<Path>, W: Write>(
+    path: P,
+    after: u64,
+    through: Option<u64>,
+    writer: W,
+) -> Result<Vec<Settlement>, Error> {
+    let log = read(BufReader::new(File::open(path)?), true)?;
+    if after < log.base.0 {
+        return Err(Error::Compacted(log.base.0));
+    }
+    let mut settlements = BTreeMap::<u16, Settlement>::new();
+    let period = log
+        .entries
+        .iter()
+        .filter(|(seq, ..)| *seq > after && through.is_none_or(|through| *seq <= through));
+    for (seq, _, record) in period.filter(|(_, _, record)| !is_admin(record)) {
+        let (kind, client, amount) = parse_record(record).ok_or(Error::Malformed(*seq))?;
+        let settlement = settlements.entry(client).or_insert_with(|| Settlement {
+            client,
+            ..Settlement::default()
+        });
+        match kind {
+            "deposit" => settlement.deposits += amount,
+            "withdrawal" | "capture" => settlement.withdrawals += amount,
+            "reject" => settlement.withdrawals -= amount,
+            "chargeback" => settlement.chargebacks += amount,
+            _ => (),
+        }
+    }
+    // disputes and resolves only move funds within an account
+    settlements.retain(|_, s| s.deposits != 0 || s.withdrawals != 0 || s.chargebacks != 0);
+
+    let mut w = BufWriter::new(writer);
+    writeln!(w, "client,deposits,withdrawals,chargebacks,net,instruction")?;
+    for s in settlements.values() {
+        writeln!(
+            w,
+            "{},{},{},{},{},{}",
+            s.client,
+            amount::Decimal(s.deposits),
+            amount::Decimal(s.withdrawals),
+            amount::Decimal(s.chargebacks),
+            amount::Decimal(s.net().abs()),
+            s.instruction()
+        )?;
+    }
+    w.flush()?;
+    Ok(settlements.into_values().collect())
+}
+
+/**
+ * Checks the hash chain of the given audit log and returns the number of entries.
+ */
+pub fn verify<P: AsRef<Path>>(path: P) -> Result<u64, Error> {
+    chain(BufReader::new(File::open(path)?)).map(|(seq, _)| seq)
+}
+
+/**
+ * The audit record of a state-changing message, in the format of the CSV input.
+ *
+ * Disputes, resolves and chargebacks carry the amount of the disputed transaction if known,
+ * approvals, rejections and captures the amount of the withdrawal, so records can be folded into
+ * balances without looking up earlier transactions. The CSV parser ignores it. The reference and
+ * the timestamp of the input record, the reason code of the message and the traded asset and its
+ * price follow as optional fields `reference,reason,timestamp,asset,price`.
+ */
+pub fn record(
+    msg: &processor::Message,
+    disputed: Option<i64>,
+    origin: Option<&processor::Origin>,
+) -> Option<String> {
+    let mut record = fields(msg, disputed)?;
+    let optional = [
+        origin.and_then(|o| o.reference.as_deref()).map(quote),
+        msg.reason().map(|reason| reason.to_string()),
+        origin.and_then(|o| o.timestamp).map(|t| t.to_string()),
+        msg.trade().map(|trade| trade.asset.to_string()),
+        msg.trade().map(|trade| amount::format(trade.price)),
+    ];
+    // trailing empty fields are left out
+    let len = optional
+        .iter()
+        .rposition(Option::is_some)
+        .map_or(0, |i| i + 1);
+    for field in &optional[..len] {
+        record.push(',');
+        record.push_str(field.as_deref().unwrap_or_default());
+    }
+    Some(record)
+}
+
+// Quotes a CSV field if needed. Line breaks are replaced, since every record is a single line.
+fn quote(field: &str) -> String {
+    let field = field.replace(['\r', '\n'], " ");
+    if field.contains([',', '"']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+fn fields(msg: &processor::Message, disputed: Option<i64>) -> Option<String> {
+    use processor::Message::*;
+
+    match msg {
+        Deposit {
+            client, tx, amount, ..
+        }
+        | Withdrawal {
+            client, tx, amount, ..
+        }
+        | Authorize { client, tx, amount } => Some(format!(
+            "{},{client},{tx},{}",
+            msg.kind()?,
+            amount::format(*amount)
+        )),
+        Dispute { client, tx, .. }
+        | Resolve { client, tx, .. }
+        | Chargeback { client, tx, .. }
+        | Approve { client, tx }
+        | Reject { client, tx }
+        | Capture { client, tx } => Some(match disputed {
+            Some(amount) => {
+                format!("{},{client},{tx},{}", msg.kind()?, amount::Decimal(amount))
+            }
+            None => format!("{},{client},{tx},", msg.kind()?),
+        }),
+        // admin messages aren't part of the input format
+        GetState { .. }
+        | GetStats { .. }
+        | GetDisputes { .. }
+        | GetPositions { .. }
+        | SubscribeDisputes { .. }
+        | SaveSnapshot { .. }
+        | Simulate { .. }
+        | Batch(_) => None,
+    }
+}
+
+/// Admin commands with audit records, see `admin`.
+const ADMIN: [&str; 4] = ["annotate", "release-hold", "link", "unlink"];
+
+fn is_admin(record: &str) -> bool {
+    record
+        .split(',')
+        .next()
+        .is_some_and(|kind| ADMIN.contains(&kind))
+}
+
+/**
+ * The audit record of an admin command changing the account of a client in a snapshot, e.g.
+ * `release-hold,7`, `annotate,7,set tier=high` or `link,2,1` (linking client 2 to client 1). The client is the second field like in the
+ * records of messages, so `erase` folds these records as well.
+ */
+pub fn admin(command: &str, client: u16, args: &[&str]) -> String {
+    debug_assert!(ADMIN.contains(&command));
+    let mut record = format!("{command},{client}");
+    for arg in args {
+        record.push(',');
+        record.push_str(&quote(arg));
+    }
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use crate::metadata;
+
+    #[derive(Clone, Default)]
+    struct Buf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn log(records: &[&str]) -> String {
+        let buf = Buf::default();
+        let mut log = Log::new(buf.clone());
+        for record in records {
+            log.append(record).unwrap();
+        }
+        let bytes = buf.0.lock().unwrap().clone();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn append() {
+        let log = log(&["deposit,1,1,1.0000", "dispute,1,1,"]);
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("1 ") && lines[0].ends_with(" deposit,1,1,1.0000"));
+        assert!(lines[1].starts_with("2 ") && lines[1].ends_with(" dispute,1,1,"));
+        assert_eq!(chain(log.as_bytes()).unwrap().0, 2);
+    }
+
+    #[test]
+    fn tamper() {
+        let log = log(&[
+            "deposit,1,1,1.0000",
+            "withdrawal,1,2,0.5000",
+            "dispute,1,1,",
+        ]);
+
+        let modified = log.replace("0.5000", "0.0500");
+        assert!(matches!(chain(modified.as_bytes()), Err(Error::Hash(2))));
+
+        let lines: Vec<_> = log.lines().collect();
+        let removed = [lines[0], lines[2]].join("\n");
+        assert!(matches!(
+            chain(removed.as_bytes()),
+            Err(Error::Sequence {
+                expected: 2,
+                found: 3
+            })
+        ));
+
+        assert!(matches!(chain("x".as_bytes()), Err(Error::Malformed(1))));
+    }
+
+    #[test]
+    fn compact() {
+        let path = std::env::temp_dir().join(format!("trapez-{}.audit", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut log = Log::open(&path).unwrap();
+        for record in [
+            "deposit,1,1,5.0000",
+            "deposit,2,2,1.0000",
+            "dispute,1,1,5.0000",
+            "withdrawal,2,3,0.5000",
+        ] {
+            log.append(record).unwrap();
+        }
+        drop(log);
+        let (seq, hash) = chain(BufReader::new(File::open(&path).unwrap())).unwrap();
+
+        assert_eq!(super::compact(&path, 10).unwrap(), 0);
+        assert_eq!(super::compact(&path, 1).unwrap(), 3);
+        let content = fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = content.lines().collect();
+        assert!(lines[0].starts_with("checkpoint 3 "));
+        assert_eq!(lines[1], "opening,1,0.0000,5.0000,false");
+        assert_eq!(lines[2], "opening,2,1.0000,0.0000,false");
+        assert!(lines[3].starts_with("4 "));
+        assert_eq!(verify(&path).unwrap(), 4);
+
+        // appending continues the original chain
+        let mut log = Log::open(&path).unwrap();
+        log.append("resolve,1,1,5.0000").unwrap();
+        drop(log);
+        assert_eq!(super::compact(&path, 0).unwrap(), 2);
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.contains("opening,1,5.0000,0.0000,false"));
+        assert!(content.contains("opening,2,0.5000,0.0000,false"));
+        assert_eq!(verify(&path).unwrap(), 5);
+        assert_ne!(
+            (seq, hash),
+            chain(BufReader::new(File::open(&path).unwrap())).unwrap()
+        );
+
+        let tampered = content.replace("opening,2,0.5000", "opening,2,9.5000");
+        assert!(matches!(chain(tampered.as_bytes()), Err(Error::Checkpoint)));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn admin() {
+        let path = std::env::temp_dir().join(format!("trapez-{}-admin.audit", std::process::id()));
+        let tier = metadata::Change::Set {
+            key: "tier".into(),
+            value: "high, manual".into(),
+        };
+        fs::write(
+            &path,
+            log(&[
+                "deposit,1,1,9.0000",
+                &super::admin("annotate", 1, &[&tier.to_string()]),
+                &super::admin("release-hold", 2, &[]),
+                &super::admin("link", 3, &["1"]),
+                "deposit,2,2,1.0000",
+            ]),
+        )
+        .unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.contains(" annotate,1,\"set tier=high, manual\"\n"));
+        assert!(content.contains(" release-hold,2\n"));
+        assert!(content.contains(" link,3,1\n"));
+
+        // admin records move no funds
+        let settlements = super::settle(&path, 0, None, io::sink()).unwrap();
+        assert_eq!(settlements.len(), 2);
+        assert_eq!(super::compact(&path, 0).unwrap(), 5);
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.contains("opening,1,9.0000,0.0000,false"));
+        assert!(content.contains("opening,2,1.0000,0.0000,false"));
+        assert_eq!(verify(&path).unwrap(), 5);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn erase() {
+        let path = std::env::temp_dir().join(format!("trapez-{}-erase.audit", std::process::id()));
+        fs::write(
+            &path,
+            log(&[
+                "deposit,1,1,9.0000,\"Jane Doe\"",
+                "deposit,12,2,1.0000",
+                "withdrawal,1,3,4.0000",
+                "deposit,2,4,5.0000",
+            ]),
+        )
+        .unwrap();
+        assert_eq!(super::erase(&path, 3).unwrap(), 0);
+        assert_eq!(super::erase(&path, 1).unwrap(), 3);
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(!content.contains("Jane Doe"));
+        assert!(content.contains("opening,1,5.0000,0.0000,false"));
+        assert!(content.contains("opening,12,1.0000,0.0000,false"));
+        assert!(content.contains("deposit,2,4,5.0000"));
+        assert_eq!(verify(&path).unwrap(), 4);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn settle() {
+        let path = std::env::temp_dir().join(format!("trapez-{}-settle.audit", std::process::id()));
+        fs::write(
+            &path,
+            log(&[
+                "deposit,1,1,9.0000",
+                "deposit,1,2,10.0000",
+                "withdrawal,1,3,4.0000",
+                "deposit,2,4,5.0000",
+                "dispute,2,4,5.0000",
+                "chargeback,2,4,5.0000",
+                "withdrawal,3,5,2.0000",
+                "dispute,1,2,10.0000,,10.4",
+                &super::admin("release-hold", 1, &[]),
+            ]),
+        )
+        .unwrap();
+
+        let mut output = Vec::new();
+        let settlements = super::settle(&path, 1, None, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,deposits,withdrawals,chargebacks,net,instruction\n\
+             1,10.0000,4.0000,0.0000,6.0000,collect\n\
+             2,5.0000,0.0000,5.0000,0.0000,none\n\
+             3,0.0000,2.0000,0.0000,2.0000,pay\n"
+        );
+        assert_eq!(settlements[2].net(), -20000);
+
+        let settlements = super::settle(&path, 0, Some(1), io::sink()).unwrap();
+        assert_eq!(settlements.len(), 1);
+        assert_eq!(settlements[0].deposits, 90000);
+
+        super::compact(&path, 5).unwrap();
+        assert!(matches!(
+            super::settle(&path, 1, None, io::sink()),
+            Err(Error::Compacted(4))
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+/**
+ * Synthetic workloads for measuring the performance of the pipeline.
+ *
+ * The workload is generated in memory from a seed, so runs are repeatable and don't depend on disk
+ * performance.
+ */
+use std::{
+    fmt::Write,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::oneshot;
+
+use crate::{amount, cli, processor};
+
+// xorshift64*, good enough for generating test data
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Self(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/**
+ * Generates a CSV input with the given number of rows.
+ *
+ * Most rows are deposits and withdrawals, with disputes, resolves and a few chargebacks referring
+ * to earlier deposits of the same client.
+ */
+pub fn workload(rows: usize, clients: u16, seed: u64) -> Vec<u8> {
+    let clients = u64::from(clients.max(1));
+    let mut rng = Rng::new(seed);
+    let mut deposits: Vec<Vec<u32>> = vec![Vec::new(); clients as usize];
+    let mut csv = String::from("type,client,tx,amount\n");
+    for tx in 1..=rows as u32 {
+        let client = rng.below(clients) as u16 + 1;
+        let txs = &mut deposits[usize::from(client) - 1];
+        let amount = amount::format(rng.below(10_000_000) as i64 + 1);
+        let _ = match (rng.below(100), txs.last()) {
+            (0..=59, _) | (_, None) => {
+                txs.push(tx);
+                writeln!(csv, "deposit,{client},{tx},{amount}")
+            }
+            (60..=84, _) => writeln!(csv, "withdrawal,{client},{tx},{amount}"),
+            (85..=92, Some(_)) => {
+                let disputed = txs[rng.below(txs.len() as u64) as usize];
+                writeln!(csv, "dispute,{client},{disputed},")
+            }
+            (93..=98, Some(_)) => {
+                let disputed = txs[rng.below(txs.len() as u64) as usize];
+                writeln!(csv, "resolve,{client},{disputed},")
+            }
+            (_, Some(_)) => {
+                let disputed = txs[rng.below(txs.len() as u64) as usize];
+                writeln!(csv, "chargeback,{client},{disputed},")
+            }
+        };
+    }
+    csv.into_bytes()
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub rows: usize,
+    pub bytes: usize,
+    pub duration: Duration,
+    /// Latency percentiles (50, 90, 99, 100) of handing a batch over to the processor.
+    pub send_latency: [Duration; 4],
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let secs = self.duration.as_secs_f64();
+        writeln!(
+            f,
+            "{} rows ({} bytes) in {:?}: {:.0} rows/s, {:.1} MB/s",
+            self.rows,
+            self.bytes,
+            self.duration,
+            self.rows as f64 / secs,
+            self.bytes as f64 / secs / 1e6
+        )?;
+        let [p50, p90, p99, max] = self.send_latency;
+        writeln!(
+            f,
+            "batch send latency: p50 {p50:?}, p90 {p90:?}, p99 {p99:?}, max {max:?}"
+        )
+    }
+}
+
+/**
+ * Runs the workload through CSV parsing and the processor until the final state is available.
+ */
+pub async fn run(input: &[u8], config: processor::Config) -> Result<Report, cli::Error> {
+    let storage = processor::Storage::default();
+    let batches = storage.batches.clone();
+    let (tx_msg, mut rx_notify) = processor::run(config, storage).await;
+    tokio::spawn(async move { while rx_notify.recv().await.is_some() {} });
+
+    let start = Instant::now();
+    let mut rows = 0;
+    let mut latencies = Vec::new();
+    let mut msgs = cli::read_csv(input).flatten().peekable();
+    while msgs.peek().is_some() {
+        let mut batch = batches.take();
+        batch.msgs.extend(msgs.by_ref().take(processor::BATCH_SIZE));
+        rows += batch.len();
+        let sent = Instant::now();
+        tx_msg
+            .send(processor::Message::Batch(batch))
+            .await
+            .map_err(cli::Error::Send)?;
+        latencies.push(sent.elapsed());
+    }
+    let (tx, rx) = oneshot::channel();
+    tx_msg
+        .send(processor::Message::GetState { tx })
+        .await
+        .map_err(cli::Error::Send)?;
+    rx.await.map_err(cli::Error::RecvState)?;
+    let duration = start.elapsed();
+
+    latencies.sort_unstable();
+    let percentile = |p: usize| {
+        latencies
+            .get((latencies.len() * p / 100).min(latencies.len().saturating_sub(1)))
+            .copied()
+            .unwrap_or_default()
+    };
+    Ok(Report {
+        rows,
+        bytes: input.len(),
+        duration,
+        send_latency: [
+            percentile(50),
+            percentile(90),
+            percentile(99),
+            percentile(100),
+        ],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deterministic() {
+        let csv = workload(1000, 10, 42);
+        assert_eq!(csv, workload(1000, 10, 42));
+        assert_ne!(csv, workload(1000, 10, 43));
+        assert_eq!(csv.iter().filter(|b| **b == b'\n').count(), 1001);
+        assert!(cli::read_csv(&csv[..]).all(|res| res.is_ok()));
+    }
+
+    #[tokio::test]
+    async fn report() {
+        let report = run(&workload(100, 3, 1), processor::Config::default())
+            .await
+            .unwrap();
+        assert_eq!(report.rows, 100);
+        assert!(report.send_latency[0] <= report.send_latency[3]);
+    }
+}
@@ -0,0 +1,200 @@
+/**
+ * Fault injection between the CSV reader and the processor, enabled with the `chaos` feature.
+ *
+ * Every message is dropped, duplicated or delayed with the configured probabilities. Delayed
+ * messages are held back and sent after up to two batches of later messages, so they arrive out of
+ * order. All decisions derive from the seed, so a run can be repeated exactly.
+ */
+use std::fmt;
+
+use crate::{
+    log,
+    processor::{self, Batch, Message, Origin},
+    rng::Rng,
+};
+
+/**
+ * Probabilities of the faults in percent.
+ */
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Config {
+    pub seed: u64,
+    pub drop: u8,
+    pub duplicate: u8,
+    pub delay: u8,
+}
+
+/**
+ * Counts of the injected faults, logged at the end of the input.
+ */
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub delayed: u64,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Injected faults: {} dropped, {} duplicated, {} delayed.",
+            self.dropped, self.duplicated, self.delayed
+        )
+    }
+}
+
+impl log::Event for Report {
+    fn code(&self) -> &'static str {
+        "chaos"
+    }
+}
+
+pub struct Chaos {
+    config: Config,
+    rng: Rng,
+    /// Delayed messages and the number of messages still to pass before them.
+    delayed: Vec<(usize, Message, Origin)>,
+    report: Report,
+}
+
+impl Chaos {
+    pub fn new(config: Config) -> Chaos {
+        Self {
+            config,
+            rng: Rng::new(config.seed),
+            delayed: Vec::new(),
+            report: Report::default(),
+        }
+    }
+
+    fn roll(&mut self, percent: u8) -> bool {
+        self.rng.below(100) < u64::from(percent)
+    }
+
+    /**
+     * Injects faults into a batch before it's sent. Delayed messages which are due get added.
+     */
+    pub fn apply(&mut self, batch: &mut Batch) {
+        let msgs = std::mem::take(&mut batch.msgs);
+        let origins = std::mem::take(&mut batch.origins);
+        for (msg, origin) in msgs.into_iter().zip(origins) {
+            self.release(batch);
+            if self.roll(self.config.drop) {
+                self.report.dropped += 1;
+            } else if self.roll(self.config.delay) {
+                self.report.delayed += 1;
+                let after = self.rng.below(2 * processor::BATCH_SIZE as u64) as usize + 1;
+                self.delayed.push((after, msg, origin));
+            } else {
+                if self.roll(self.config.duplicate) {
+                    if let Some(copy) = msg.copy() {
+                        self.report.duplicated += 1;
+                        batch.push(copy, origin.clone());
+                    }
+                }
+                batch.push(msg, origin);
+            }
+        }
+    }
+
+    // Counts down the delayed messages and adds the due ones to the batch.
+    fn release(&mut self, batch: &mut Batch) {
+        let mut i = 0;
+        while i < self.delayed.len() {
+            self.delayed[i].0 -= 1;
+            if self.delayed[i].0 == 0 {
+                let (_, msg, origin) = self.delayed.swap_remove(i);
+                batch.push(msg, origin);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /**
+     * Adds all messages still delayed to the last batch and returns the counts of the faults.
+     */
+    pub fn finish(mut self, batch: &mut Batch) -> Report {
+        self.delayed.sort_by_key(|(after, ..)| *after);
+        for (_, msg, origin) in self.delayed.drain(..) {
+            batch.push(msg, origin);
+        }
+        self.report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli;
+
+    #[test]
+    fn delay() {
+        let mut chaos = Chaos::new(Config {
+            seed: 1,
+            delay: 50,
+            ..Config::default()
+        });
+        let mut batch = Batch::from(Vec::new());
+        let mut sent = Vec::new();
+        for tx in 0..1000 {
+            batch.push(
+                Message::Dispute {
+                    client: 1,
+                    tx,
+                    reason: None,
+                },
+                Origin {
+                    source: "-".into(),
+                    line: u64::from(tx),
+                    offset: 0,
+                    reference: None,
+                    timestamp: None,
+                },
+            );
+            if batch.len() >= processor::BATCH_SIZE {
+                chaos.apply(&mut batch);
+                sent.append(&mut batch.msgs);
+                batch.origins.clear();
+            }
+        }
+        chaos.apply(&mut batch);
+        let report = chaos.finish(&mut batch);
+        sent.append(&mut batch.msgs);
+
+        assert!(report.delayed > 0);
+        let mut txs: Vec<_> = sent
+            .iter()
+            .map(|msg| match msg {
+                Message::Dispute { tx, .. } => *tx,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert!(txs.windows(2).any(|w| w[0] > w[1]));
+        // nothing is lost
+        txs.sort_unstable();
+        assert_eq!(txs, (0..1000).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn duplicates() {
+        // transaction ids make every message idempotent, duplicates must not change the outcome
+        let input = std::fs::read("data/cases/sample/in.csv").unwrap();
+        let expected = std::fs::read_to_string("data/cases/sample/out.csv").unwrap();
+        for seed in 0..10 {
+            let options = cli::Options {
+                logger: log::Logger::capture(log::Format::Text),
+                chaos: Some(Config {
+                    seed,
+                    duplicate: 50,
+                    ..Config::default()
+                }),
+                ..cli::Options::default()
+            };
+            let mut buf = Vec::new();
+            let _ = cli::run(&input[..], &mut buf, options).await;
+            assert_eq!(String::from_utf8(buf).unwrap(), expected, "seed {seed}");
+        }
+    }
+}
@@ -6,29 +6,85 @@
  * Reading the CSV file continues despite any deserialization errors. The only fatal errors are when the
  * input file can't be read or forwarding messages to processor fails.
  */
-use serde::{self, Deserialize, Serialize};
-use std::fmt;
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fmt,
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    sync::Arc,
+};
 use tokio::sync::{
+    broadcast,
     mpsc::error::SendError,
     oneshot::{self, error::RecvError},
 };
 
-use crate::{amount, processor};
+use crate::{
+    amount, audit, hierarchy, log, metadata, position, processor, reconcile, reorder, sha256,
+    snapshot, spill,
+    throughput::{CountingReader, Meter},
+    wal, webhook,
+};
+
+#[cfg(feature = "chaos")]
+use crate::chaos;
 
 #[derive(thiserror::Error)]
 pub enum Error {
     #[error("Deserialization error: `{0}`.")]
     De(csv::Error),
-    #[error("Serialization error: `{0}`.")]
-    Ser(csv::Error),
-    #[error("Input error: `{0}`.")]
-    Input(String),
+    #[error("Invalid field `{field}` in line {line}: `{reason}`.")]
+    Parse {
+        line: u64,
+        field: &'static str,
+        reason: Cow<'static, str>,
+    },
+    #[error("Input error: `{reason}`.")]
+    Input {
+        client: u16,
+        tx: u32,
+        reason: Cow<'static, str>,
+    },
+    #[error("Unknown transaction type `{name}` in line {line}.")]
+    UnknownType {
+        line: u64,
+        client: u16,
+        tx: u32,
+        name: String,
+    },
     #[error("Send error: `{0}`.")]
     Send(SendError<processor::Message>),
     #[error("Receive state error: `{0}`.")]
     RecvState(RecvError),
     #[error("IO error: `{0}`.")]
     Io(std::io::Error),
+    #[error("{0}")]
+    Audit(audit::Error),
+    #[error("{0}")]
+    Snapshot(snapshot::Error),
+    #[error("{0}")]
+    Reconcile(reconcile::Error),
+    #[error("Reconciliation found {0} mismatches.")]
+    Mismatches(usize),
+    #[error(
+        "Control totals in line {line} don't match: {rows} rows summing to {} instead of \
+         {expected_rows} rows summing to {}.",
+        amount::Decimal(*sum),
+        amount::Decimal(*expected_sum)
+    )]
+    Control {
+        line: u64,
+        rows: u64,
+        sum: i64,
+        expected_rows: u64,
+        expected_sum: i64,
+    },
+    #[error("The input ends with {0} rows not covered by a trailer.")]
+    MissingTrailer(u64),
+    #[error("Control total verification failed for {0} blocks of the input.")]
+    Controls(usize),
 }
 
 // Used by default when the main function returns Err.
@@ -39,14 +95,290 @@ impl fmt::Debug for Error {
     }
 }
 
-// CSV structure of the input file
-#[derive(Debug, Deserialize)]
+impl log::Event for Error {
+    fn code(&self) -> &'static str {
+        match self {
+            Error::De(_) => "deserialization",
+            Error::Parse { .. } => "parse",
+            Error::Input { .. } => "input",
+            Error::UnknownType { .. } => "unknown_type",
+            Error::Send(_) => "send",
+            Error::RecvState(_) => "receive_state",
+            Error::Io(_) => "io",
+            Error::Audit(_) => "audit",
+            Error::Snapshot(_) => "snapshot",
+            Error::Reconcile(_) => "reconcile",
+            Error::Mismatches(_) => "reconcile_mismatches",
+            Error::Control { .. } => "control_mismatch",
+            Error::MissingTrailer(_) => "missing_trailer",
+            Error::Controls(_) => "control_failed",
+        }
+    }
+
+    fn client(&self) -> Option<u16> {
+        match self {
+            Error::Input { client, .. } | Error::UnknownType { client, .. } => Some(*client),
+            _ => None,
+        }
+    }
+
+    fn tx(&self) -> Option<u32> {
+        match self {
+            Error::Input { tx, .. } | Error::UnknownType { tx, .. } => Some(*tx),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Warning about an input which is already part of the loaded snapshot.
+ */
+#[derive(Debug)]
+struct DuplicateInput {
+    source: Arc<str>,
+}
+
+impl fmt::Display for DuplicateInput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Input `{}` was already processed, skipping it.",
+            self.source
+        )
+    }
+}
+
+impl log::Event for DuplicateInput {
+    fn code(&self) -> &'static str {
+        "duplicate_input"
+    }
+}
+
+/**
+ * What happens to input rows with an unknown transaction type.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum UnknownTypes {
+    /// Log the row and continue.
+    Skip,
+    /// Abort the run.
+    Fail,
+    /// Log the row and copy it to the dead-letter file.
+    DeadLetter,
+}
+
+// Transaction types of the input file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TxType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Approve,
+    Reject,
+    Authorize,
+    Capture,
+}
+
+impl TxType {
+    const NAMES: [(&'static [u8], TxType); 9] = [
+        (b"deposit", TxType::Deposit),
+        (b"withdrawal", TxType::Withdrawal),
+        (b"dispute", TxType::Dispute),
+        (b"resolve", TxType::Resolve),
+        (b"chargeback", TxType::Chargeback),
+        (b"approve", TxType::Approve),
+        (b"reject", TxType::Reject),
+        (b"authorize", TxType::Authorize),
+        (b"capture", TxType::Capture),
+    ];
+
+    // Case-insensitive and without any allocation.
+    fn parse(name: &[u8]) -> Option<TxType> {
+        Self::NAMES
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, tx_type)| *tx_type)
+    }
+}
+
+/**
+ * Type of the control records closing a block of input rows. The `tx` column holds the number of
+ * rows in the block and the `amount` column the sum of their amounts.
+ */
+pub const TRAILER: &str = "trailer";
+
+/**
+ * The names of the transaction types in the input, matched case-insensitively.
+ */
+pub fn type_names() -> impl Iterator<Item = &'static str> {
+    TxType::NAMES
+        .iter()
+        .filter_map(|(name, _)| std::str::from_utf8(name).ok())
+}
+
+/**
+ * An alternative name of a transaction type, e.g. `credit` for deposits.
+ */
+#[derive(Debug, Clone)]
+pub struct Alias {
+    name: Vec<u8>,
+    tx_type: TxType,
+}
+
+/**
+ * Parses an alias in the form `<alias>=<type>`.
+ */
+pub fn parse_alias(s: &str) -> Result<Alias, String> {
+    let (name, tx_type) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected `<alias>=<type>`, got `{s}`"))?;
+    Ok(Alias {
+        name: name.trim().as_bytes().to_vec(),
+        tx_type: TxType::parse(tx_type.trim().as_bytes())
+            .ok_or_else(|| format!("unknown transaction type `{tx_type}`"))?,
+    })
+}
+
+// CSV structure of the input file.
+#[derive(Debug)]
 struct Input {
-    r#type: String,
+    r#type: TxType,
     client: u16,
     tx: u32,
-    #[serde(with = "amount")]
     amount: Option<i64>,
+    reason: Option<processor::Reason>,
+    trade: Option<position::Trade>,
+}
+
+// Column positions of the input fields, resolved from the CSV header.
+#[derive(Debug, Default)]
+struct Columns {
+    r#type: Option<usize>,
+    client: Option<usize>,
+    tx: Option<usize>,
+    amount: Option<usize>,
+    reference: Option<usize>,
+    reason: Option<usize>,
+    timestamp: Option<usize>,
+    asset: Option<usize>,
+    price: Option<usize>,
+}
+
+impl Columns {
+    fn new(headers: &csv::ByteRecord) -> Columns {
+        let position = |name: &[u8]| headers.iter().position(|header| header == name);
+        Self {
+            r#type: position(b"type"),
+            client: position(b"client"),
+            tx: position(b"tx"),
+            amount: position(b"amount"),
+            reference: position(b"reference").or_else(|| position(b"memo")),
+            reason: position(b"reason"),
+            timestamp: position(b"timestamp"),
+            asset: position(b"asset"),
+            price: position(b"price"),
+        }
+    }
+}
+
+impl Input {
+    // Extracts the fields manually instead of going through serde in order to avoid any
+    // allocations per record.
+    fn parse(
+        record: &csv::ByteRecord,
+        columns: &Columns,
+        aliases: &[Alias],
+    ) -> Result<Input, Error> {
+        let line = record.position().map_or(0, |pos| pos.line());
+        let err = |field: &'static str, reason: Cow<'static, str>| Error::Parse {
+            line,
+            field,
+            reason,
+        };
+        let get = |field: &'static str, column: Option<usize>| {
+            column
+                .and_then(|column| record.get(column))
+                .ok_or_else(|| err(field, "missing field".into()))
+        };
+        let str = |field: &'static str, column: Option<usize>| {
+            std::str::from_utf8(get(field, column)?).map_err(|e| err(field, e.to_string().into()))
+        };
+
+        let client = str("client", columns.client)?
+            .parse()
+            .map_err(|e: std::num::ParseIntError| err("client", e.to_string().into()))?;
+        let tx = str("tx", columns.tx)?
+            .parse()
+            .map_err(|e: std::num::ParseIntError| err("tx", e.to_string().into()))?;
+        let r#type = get("type", columns.r#type)?;
+        let alias = || {
+            aliases
+                .iter()
+                .find(|alias| alias.name.eq_ignore_ascii_case(r#type))
+                .map(|alias| alias.tx_type)
+        };
+        // only checked here, the processor gets the timestamp along with the origin
+        match columns.timestamp.and_then(|column| record.get(column)) {
+            None | Some(b"") => (),
+            Some(_) => {
+                str("timestamp", columns.timestamp)?
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|e| err("timestamp", e.to_string().into()))?;
+            }
+        }
+        Ok(Input {
+            r#type: TxType::parse(r#type)
+                .or_else(alias)
+                .ok_or_else(|| Error::UnknownType {
+                    line,
+                    client,
+                    tx,
+                    name: String::from_utf8_lossy(r#type).into_owned(),
+                })?,
+            client,
+            tx,
+            amount: match columns.amount.and_then(|column| record.get(column)) {
+                None | Some(b"") => None,
+                Some(amount) => Some(
+                    amount::parse_bytes(amount).map_err(|e| err("amount", e.to_string().into()))?,
+                ),
+            },
+            reason: match columns.reason.and_then(|column| record.get(column)) {
+                None | Some(b"") => None,
+                Some(_) => Some(
+                    processor::Reason::parse(str("reason", columns.reason)?)
+                        .map_err(|e| err("reason", e.into()))?,
+                ),
+            },
+            trade: {
+                let asset = columns
+                    .asset
+                    .and_then(|column| record.get(column))
+                    .filter(|asset| !asset.is_empty());
+                let price = columns
+                    .price
+                    .and_then(|column| record.get(column))
+                    .filter(|price| !price.is_empty());
+                match (asset, price) {
+                    (None, None) => None,
+                    (Some(_), None) => return Err(err("price", "missing price of asset".into())),
+                    (None, Some(_)) => return Err(err("asset", "missing asset of price".into())),
+                    (Some(_), Some(price)) => Some(position::Trade {
+                        asset: position::Asset::parse(str("asset", columns.asset)?)
+                            .map_err(|e| err("asset", e.into()))?,
+                        price: match amount::parse_bytes(price) {
+                            Ok(price) if price > 0 => price,
+                            Ok(_) => return Err(err("price", "price must be positive".into())),
+                            Err(e) => return Err(err("price", e.to_string().into())),
+                        },
+                    }),
+                }
+            },
+        })
+    }
 }
 
 // The csv crate doesn't support internally tagged unions :( (https://github.com/BurntSushi/rust-csv/issues/211)
@@ -54,81 +386,779 @@ impl TryFrom<Input> for processor::Message {
     type Error = Error;
 
     fn try_from(i: Input) -> std::result::Result<Self, Self::Error> {
-        match i.r#type.as_str() {
-            "deposit" => Ok(processor::Message::Deposit {
+        let input_err = |reason: &'static str| Error::Input {
+            client: i.client,
+            tx: i.tx,
+            reason: reason.into(),
+        };
+        match i.r#type {
+            TxType::Deposit => Ok(processor::Message::Deposit {
                 client: i.client,
                 tx: i.tx,
                 amount: i
                     .amount
-                    .ok_or_else(|| Error::Input("missing amount for deposit".to_string()))?,
+                    .ok_or_else(|| input_err("missing amount for deposit"))?,
+                trade: i.trade,
             }),
-            "withdrawal" => Ok(processor::Message::Withdrawal {
+            TxType::Withdrawal => Ok(processor::Message::Withdrawal {
                 client: i.client,
                 tx: i.tx,
                 amount: i
                     .amount
-                    .ok_or_else(|| Error::Input("missing amount for deposit".to_string()))?,
+                    .ok_or_else(|| input_err("missing amount for deposit"))?,
+                trade: i.trade,
+            }),
+            TxType::Dispute => Ok(processor::Message::Dispute {
+                client: i.client,
+                tx: i.tx,
+                reason: i.reason,
+            }),
+            TxType::Resolve => Ok(processor::Message::Resolve {
+                client: i.client,
+                tx: i.tx,
+                reason: i.reason,
+            }),
+            TxType::Chargeback => Ok(processor::Message::Chargeback {
+                client: i.client,
+                tx: i.tx,
+                reason: i.reason,
             }),
-            "dispute" => Ok(processor::Message::Dispute {
+            TxType::Approve => Ok(processor::Message::Approve {
                 client: i.client,
                 tx: i.tx,
             }),
-            "resolve" => Ok(processor::Message::Resolve {
+            TxType::Reject => Ok(processor::Message::Reject {
                 client: i.client,
                 tx: i.tx,
             }),
-            "chargeback" => Ok(processor::Message::Chargeback {
+            TxType::Authorize => Ok(processor::Message::Authorize {
+                client: i.client,
+                tx: i.tx,
+                amount: i
+                    .amount
+                    .ok_or_else(|| input_err("missing amount for authorization"))?,
+            }),
+            TxType::Capture => Ok(processor::Message::Capture {
                 client: i.client,
                 tx: i.tx,
             }),
-            unknown => Err(Error::Input(format!("invalid input type: '{unknown}'"))),
         }
     }
 }
 
-// CSV structure of the output file
-#[derive(Debug, Serialize)]
-struct Output {
-    client: u16,
-    #[serde(with = "amount")]
-    available: i64,
-    #[serde(with = "amount")]
-    held: i64,
-    #[serde(with = "amount")]
-    total: i64,
-    locked: bool,
+// Size of the output buffer, so large reports are written in few syscalls
+const OUTPUT_BUFFER_SIZE: usize = 1 << 20;
+
+/**
+ * Writes the CSV report straight from the account states.
+ *
+ * None of the fields need quoting, so the rows are formatted directly into the buffer instead of
+ * going through a CSV serializer.
+ */
+pub fn write_report<'a, W: Write>(
+    writer: W,
+    states: impl IntoIterator<Item = &'a processor::State>,
+) -> std::io::Result<()> {
+    report(writer, states, false)
+}
+
+// With `pending`, the funds earmarked by withdrawals awaiting approval follow as another column.
+fn report<'a, W: Write>(
+    writer: W,
+    states: impl IntoIterator<Item = &'a processor::State>,
+    pending: bool,
+) -> std::io::Result<()> {
+    let mut wtr = BufWriter::with_capacity(OUTPUT_BUFFER_SIZE, writer);
+    if pending {
+        writeln!(wtr, "client,available,held,total,locked,pending")?;
+    } else {
+        writeln!(wtr, "client,available,held,total,locked")?;
+    }
+    for s in states {
+        write!(
+            wtr,
+            "{},{},{},{},{}",
+            s.client,
+            amount::Decimal(s.available),
+            amount::Decimal(s.held),
+            amount::Decimal(s.total),
+            s.locked
+        )?;
+        if pending {
+            writeln!(wtr, ",{}", amount::Decimal(s.pending))?;
+        } else {
+            writeln!(wtr)?;
+        }
+    }
+    wtr.flush()
+}
+
+/**
+ * Writes the balances of the parent accounts including their descendants, with the number of
+ * accounts added up and how many of them are locked.
+ */
+pub fn write_roll_up<'a, W: Write>(
+    writer: W,
+    roll_ups: impl IntoIterator<Item = &'a hierarchy::RollUp>,
+) -> std::io::Result<()> {
+    let mut wtr = BufWriter::new(writer);
+    writeln!(wtr, "client,available,held,total,accounts,locked")?;
+    for r in roll_ups {
+        writeln!(
+            wtr,
+            "{},{},{},{},{},{}",
+            r.client,
+            amount::Decimal(r.available),
+            amount::Decimal(r.held),
+            amount::Decimal(r.total),
+            r.accounts,
+            r.locked
+        )?;
+    }
+    wtr.flush()
+}
+
+/**
+ * Writes the positions in traded assets with the units held, their average and total cost and the
+ * profit or loss realized so far.
+ */
+pub fn write_positions<'a, W: Write>(
+    writer: W,
+    positions: impl IntoIterator<Item = &'a (u16, position::Asset, position::Position)>,
+) -> std::io::Result<()> {
+    let mut wtr = BufWriter::new(writer);
+    writeln!(wtr, "client,asset,units,average_cost,cost,realized")?;
+    for (client, asset, p) in positions {
+        writeln!(
+            wtr,
+            "{client},{asset},{},{},{},{}",
+            amount::Decimal(p.units),
+            amount::Decimal(p.average_cost()),
+            amount::Decimal(p.cost),
+            amount::Decimal(p.realized)
+        )?;
+    }
+    wtr.flush()
+}
+
+/**
+ * Writes the open disputes making up the held funds, the amounts of each client add up to its
+ * `held` column in the report. Ages and reason codes are empty where unknown.
+ */
+pub fn write_disputes<'a, W: Write>(
+    writer: W,
+    disputes: impl IntoIterator<Item = &'a processor::OpenDispute>,
+) -> std::io::Result<()> {
+    let mut wtr = BufWriter::new(writer);
+    writeln!(wtr, "client,tx,amount,age,reason")?;
+    for d in disputes {
+        writeln!(
+            wtr,
+            "{},{},{},{},{}",
+            d.client,
+            d.tx,
+            amount::Decimal(d.amount),
+            d.age.map(|age| age.to_string()).unwrap_or_default(),
+            d.reason
+                .map(|reason| reason.to_string())
+                .unwrap_or_default()
+        )?;
+    }
+    wtr.flush()
+}
+
+/**
+ * Writes messages converted from other feeds in the input format, with the reference of each
+ * source record in the `reference` column.
+ */
+pub struct InputWriter<W: Write> {
+    writer: csv::Writer<W>,
+}
+
+impl<W: Write> InputWriter<W> {
+    pub fn new(writer: W) -> csv::Result<InputWriter<W>> {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record(["type", "client", "tx", "amount", "reference"])?;
+        Ok(Self { writer })
+    }
+
+    pub fn write(&mut self, msg: &processor::Message, reference: &str) -> csv::Result<()> {
+        let (client, tx) = msg.target().unwrap_or_default();
+        let amount = match msg {
+            processor::Message::Deposit { amount, .. }
+            | processor::Message::Withdrawal { amount, .. } => amount::format(*amount),
+            _ => String::new(),
+        };
+        self.writer.write_record([
+            msg.kind().unwrap_or_default(),
+            &client.to_string(),
+            &tx.to_string(),
+            &amount,
+            reference,
+        ])
+    }
+
+    pub fn finish(mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
 }
 
-fn read_csv<R: std::io::Read>(reader: R) -> impl Iterator<Item = processor::Message> {
-    let reader = csv::ReaderBuilder::new()
+/**
+ * Options controlling a single run.
+ */
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub logger: log::Logger,
+    /// Name of the input in the origins attached to errors.
+    pub source: Arc<str>,
+    pub processor: processor::Config,
+    /// Append accepted state-changing operations to the audit log at this path.
+    pub audit: Option<PathBuf>,
+    /// Spill transaction log entries beyond the in-memory window (second field) to a file in
+    /// the directory (first field).
+    pub spill: Option<(PathBuf, usize)>,
+    /// Recover from and append to the write-ahead log at this path.
+    pub wal: Option<(PathBuf, wal::Fsync)>,
+    /// Write snapshots to this path, at least at the end of the input.
+    pub snapshot: Option<PathBuf>,
+    /// Start from the state in the snapshot at this path.
+    pub load_snapshot: Option<PathBuf>,
+    /// Digest of the input. Inputs already contained in the loaded snapshot are skipped, and the
+    /// digest is recorded next to the written snapshot.
+    pub digest: Option<sha256::Digest>,
+    /// Log dispute lifecycle events (opened, resolved, charged back) to stderr.
+    pub log_disputes: bool,
+    /// Post chargebacks, account locks, large disputes and low balances to a webhook.
+    pub webhook: Option<webhook::Config>,
+    /// Print the processed and rejected message counts to stderr after the report.
+    pub summary: bool,
+    /// Print duration, rates and peak channel depth to stderr after the report.
+    pub throughput: bool,
+    /// Alternative names of transaction types in the input.
+    pub aliases: Vec<Alias>,
+    pub unknown_types: UnknownTypes,
+    /// Copy input rows with unknown types (see `unknown_types`) and with amounts out of the limits
+    /// of the processor to this CSV file.
+    pub dead_letter: Option<PathBuf>,
+    /// Compare the final state to the expected balances in this CSV file.
+    pub reconcile: Option<PathBuf>,
+    /// Amounts may differ from the expected balances by up to this amount.
+    pub tolerance: i64,
+    /// Fail the run unless the input ends with a trailer covering all rows after the previous one.
+    pub require_trailer: bool,
+    /// Only report accounts whose metadata in the loaded snapshot matches all of these filters.
+    pub filters: Vec<metadata::Filter>,
+    /// Write the open disputes making up the held funds to this CSV file.
+    pub held_report: Option<PathBuf>,
+    /// Write the balances of the parent accounts rolled up with their descendants to this CSV file.
+    pub roll_up: Option<(hierarchy::Hierarchy, PathBuf)>,
+    /// Write the positions in traded assets to this CSV file.
+    pub positions_report: Option<PathBuf>,
+    /// Hold back timestamped messages until the input is this far past their timestamp and apply
+    /// them in timestamp order, see the `reorder` module.
+    pub reorder_window: Option<u64>,
+    /// Inject faults between the reader and the processor.
+    #[cfg(feature = "chaos")]
+    pub chaos: Option<chaos::Config>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            logger: log::Logger::new(log::Format::Text),
+            source: "-".into(),
+            processor: processor::Config::default(),
+            audit: None,
+            spill: None,
+            wal: None,
+            snapshot: None,
+            load_snapshot: None,
+            digest: None,
+            log_disputes: false,
+            webhook: None,
+            summary: false,
+            throughput: false,
+            aliases: Vec::new(),
+            unknown_types: UnknownTypes::Skip,
+            dead_letter: None,
+            reconcile: None,
+            tolerance: 0,
+            require_trailer: false,
+            filters: Vec::new(),
+            held_report: None,
+            roll_up: None,
+            positions_report: None,
+            reorder_window: None,
+            #[cfg(feature = "chaos")]
+            chaos: None,
+        }
+    }
+}
+
+// Rejected input rows, appended to a CSV file with the header of the input.
+struct DeadLetter {
+    writer: csv::Writer<File>,
+    empty: bool,
+}
+
+impl DeadLetter {
+    fn open(path: &PathBuf) -> std::io::Result<DeadLetter> {
+        let file = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)?;
+        Ok(Self {
+            empty: file.metadata()?.len() == 0,
+            writer: csv::WriterBuilder::new().flexible(true).from_writer(file),
+        })
+    }
+
+    fn write(&mut self, headers: &csv::ByteRecord, record: &csv::ByteRecord) -> csv::Result<()> {
+        if self.empty {
+            self.writer.write_byte_record(headers)?;
+            self.empty = false;
+        }
+        self.writer.write_byte_record(record)?;
+        Ok(self.writer.flush()?)
+    }
+}
+
+// Row count and amount sum of the rows since the last trailer.
+#[derive(Debug, Default)]
+struct Block {
+    rows: u64,
+    sum: i64,
+}
+
+// Reads all records into the same buffer.
+pub(crate) struct CsvMessages<R> {
+    reader: csv::Reader<R>,
+    record: csv::ByteRecord,
+    columns: Columns,
+    headers: csv::ByteRecord,
+    aliases: Vec<Alias>,
+    block: Block,
+    trailers: u64,
+    require_trailer: bool,
+    /// Number of trailers which didn't match their block, including a missing last one.
+    controls_failed: usize,
+    done: bool,
+}
+
+impl<R> CsvMessages<R> {
+    fn aliases(mut self, aliases: Vec<Alias>) -> Self {
+        self.aliases = aliases;
+        self
+    }
+
+    fn require_trailer(mut self, require_trailer: bool) -> Self {
+        self.require_trailer = require_trailer;
+        self
+    }
+
+    // Checks the trailer in the current record against the block it closes and starts a new one.
+    fn trailer(&mut self) -> Result<(), Error> {
+        let block = std::mem::take(&mut self.block);
+        self.trailers += 1;
+        let line = self.record.position().map_or(0, |pos| pos.line());
+        let field = |column: Option<usize>| column.and_then(|column| self.record.get(column));
+        let err = |field: &'static str, reason: String| Error::Parse {
+            line,
+            field,
+            reason: reason.into(),
+        };
+        let expected_rows = std::str::from_utf8(field(self.columns.tx).unwrap_or_default())
+            .map_err(|e| err("tx", e.to_string()))?
+            .parse()
+            .map_err(|e: std::num::ParseIntError| err("tx", e.to_string()))?;
+        let expected_sum = amount::parse_bytes(field(self.columns.amount).unwrap_or_default())
+            .map_err(|e| err("amount", e.to_string()))?;
+        if (block.rows, block.sum) != (expected_rows, expected_sum) {
+            return Err(Error::Control {
+                line,
+                rows: block.rows,
+                sum: block.sum,
+                expected_rows,
+                expected_sum,
+            });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn controls_failed(&self) -> usize {
+        self.controls_failed
+    }
+
+    // The origin of the record returned last.
+    pub(crate) fn origin(&self, source: &Arc<str>) -> processor::Origin {
+        let pos = self.record.position();
+        let reference = self
+            .columns
+            .reference
+            .and_then(|column| self.record.get(column))
+            .filter(|reference| !reference.is_empty());
+        // validated when parsing the record
+        let timestamp = self
+            .columns
+            .timestamp
+            .and_then(|column| self.record.get(column))
+            .and_then(|timestamp| std::str::from_utf8(timestamp).ok())
+            .and_then(|timestamp| timestamp.trim().parse().ok());
+        processor::Origin {
+            source: source.clone(),
+            line: pos.map_or(0, |pos| pos.line()),
+            offset: pos.map_or(0, |pos| pos.byte()),
+            reference: reference.map(|reference| String::from_utf8_lossy(reference).into()),
+            timestamp,
+        }
+    }
+}
+
+impl<R: std::io::Read> Iterator for CsvMessages<R> {
+    type Item = Result<processor::Message, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.reader.read_byte_record(&mut self.record) {
+                Ok(false) if self.done => return None,
+                Ok(false) => {
+                    self.done = true;
+                    if self.require_trailer && (self.block.rows > 0 || self.trailers == 0) {
+                        self.controls_failed += 1;
+                        return Some(Err(Error::MissingTrailer(self.block.rows)));
+                    }
+                    return None;
+                }
+                Ok(true) => {
+                    let r#type = self.columns.r#type.and_then(|c| self.record.get(c));
+                    if r#type.is_some_and(|t| t.eq_ignore_ascii_case(TRAILER.as_bytes())) {
+                        match self.trailer() {
+                            Ok(()) => continue,
+                            Err(err) => {
+                                self.controls_failed += 1;
+                                return Some(Err(err));
+                            }
+                        }
+                    }
+                    // rejected rows are counted as well, the totals are about the file content
+                    self.block.rows += 1;
+                    let amount = self.columns.amount.and_then(|c| self.record.get(c));
+                    if let Some(Ok(amount)) = amount.map(amount::parse_bytes) {
+                        self.block.sum = self.block.sum.saturating_add(amount);
+                    }
+                    return Some(
+                        Input::parse(&self.record, &self.columns, &self.aliases)
+                            .and_then(TryInto::try_into),
+                    );
+                }
+                Err(err) => return Some(Err(Error::De(err))),
+            }
+        }
+    }
+}
+
+// Moves the rows of a verified block to the batch to send.
+fn release(held: &mut processor::Batch, batch: &mut processor::Batch) {
+    batch.msgs.append(&mut held.msgs);
+    batch.origins.append(&mut held.origins);
+}
+
+pub(crate) fn read_csv<R: std::io::Read>(reader: R) -> CsvMessages<R> {
+    // log records only have a reference column if they carry a reference
+    let mut reader = csv::ReaderBuilder::new()
         .trim(csv::Trim::All)
+        .flexible(true)
         .from_reader(reader);
-    reader
-        .into_deserialize::<Input>()
-        .map(|res_input| res_input.map_err(Error::De).and_then(TryInto::try_into))
-        .filter_map(|res_msg| res_msg.map_err(|err| eprintln!("{err}")).ok())
+    let headers = reader.byte_headers().cloned().unwrap_or_default();
+    CsvMessages {
+        reader,
+        record: csv::ByteRecord::new(),
+        columns: Columns::new(&headers),
+        headers,
+        aliases: Vec::new(),
+        block: Block::default(),
+        trailers: 0,
+        require_trailer: false,
+        controls_failed: 0,
+        done: false,
+    }
 }
 
-pub async fn run<R: std::io::Read, W: std::io::Write>(reader: R, writer: W) -> Result<(), Error> {
+pub async fn run<R: std::io::Read, W: std::io::Write>(
+    reader: R,
+    writer: W,
+    options: Options,
+) -> Result<(), Error> {
+    let logger = options.logger;
+    let reader = CountingReader::new(reader);
+    let mut meter = Meter::start(reader.counter());
+    // Rows in the write-ahead log, the audit log or intermediate snapshots outlive a run failing
+    // its control totals, so such runs hold each block back until its trailer verifies.
+    let durable = options.wal.is_some()
+        || options.audit.is_some()
+        || options.processor.snapshot_every.is_some();
+
     // Create the processor and the get send and receive handles for transaction messages
     // and errors.
-    let (tx_msg, mut rx_err) = processor::run().await;
+    let audit = options
+        .audit
+        .map(audit::Log::open)
+        .transpose()
+        .map_err(Error::Audit)?;
+    let spill = options
+        .spill
+        .map(|(dir, window)| spill::Store::create(dir, window))
+        .transpose()
+        .map_err(Error::Io)?;
+    let (wal, recovered) = match options.wal {
+        Some((path, fsync)) => {
+            let (log, recovered) = wal::Log::open(path, fsync).map_err(Error::Io)?;
+            (Some(log), recovered)
+        }
+        None => (None, Vec::new()),
+    };
+    let expected = options
+        .reconcile
+        .as_ref()
+        .map(|path| {
+            let file = File::open(path).map_err(Error::Io)?;
+            reconcile::read(file).map_err(Error::Reconcile)
+        })
+        .transpose()?;
+    let mut inputs = match &options.load_snapshot {
+        Some(path) => snapshot::Inputs::read(path).map_err(Error::Io)?,
+        None => snapshot::Inputs::default(),
+    };
+    let restored = options
+        .load_snapshot
+        .map(snapshot::read)
+        .transpose()
+        .map_err(Error::Snapshot)?;
+    // Metadata only changes through `annotate`, so the loaded one is still current at the end.
+    let metadata = match (&restored, options.filters.is_empty()) {
+        (Some(contents), false) => contents.metadata.clone(),
+        _ => BTreeMap::new(),
+    };
+    let limits = options.processor.limits;
+    let approvals = options.processor.approve_above.is_some();
+    let batches = processor::BatchPool::default();
+    let (tx_msg, mut rx_notify) = processor::run(
+        options.processor,
+        processor::Storage {
+            audit,
+            spill,
+            wal,
+            recovered,
+            snapshot: options.snapshot.clone(),
+            restored,
+            batches: batches.clone(),
+        },
+    )
+    .await;
 
-    tokio::spawn(async move {
-        while let Some(res) = rx_err.recv().await {
-            eprintln!("{res}"); // log transaction errors to stderr
+    let webhook = options.webhook.clone().map(|config| {
+        let threshold = config.dispute_threshold;
+        (webhook::Sender::start(config), threshold)
+    });
+    let notifications = tokio::spawn({
+        let logger = logger.clone();
+        let hooks = webhook.as_ref().map(|(sender, _)| sender.handle());
+        async move {
+            // log transaction errors and warnings to stderr
+            while let Some(notification) = rx_notify.recv().await {
+                match notification {
+                    processor::Notification::Error(err, origin) => {
+                        logger.error(&err, origin.as_ref())
+                    }
+                    processor::Notification::Warning(warning, origin) => {
+                        logger.warning(&warning, origin.as_ref());
+                        let hook = webhook::Hook::from_warning(&warning);
+                        if let (Some(hooks), Some(hook)) = (&hooks, hook) {
+                            let _ = hooks.send(hook);
+                        }
+                    }
+                }
+            }
         }
     });
 
+    let mut disputes = None;
+    if options.log_disputes || webhook.is_some() {
+        let (tx_sub, rx_sub) = oneshot::channel();
+        tx_msg
+            .send(processor::Message::SubscribeDisputes { tx: tx_sub })
+            .await
+            .map_err(Error::Send)?;
+        let mut rx_disputes = rx_sub.await.map_err(Error::RecvState)?;
+        let logger = logger.clone();
+        let log_disputes = options.log_disputes;
+        disputes = Some(tokio::spawn(async move {
+            loop {
+                match rx_disputes.recv().await {
+                    Ok(event) => {
+                        if log_disputes {
+                            logger.info(&event, event.origin.as_ref());
+                        }
+                        if let Some((sender, threshold)) = &webhook {
+                            for hook in webhook::Hook::from_event(&event, *threshold) {
+                                sender.send(hook);
+                            }
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+            // wait for outstanding deliveries without blocking the runtime
+            if let Some((sender, _)) = webhook {
+                let failed = tokio::task::spawn_blocking(move || sender.finish())
+                    .await
+                    .unwrap_or_default();
+                for failed in failed {
+                    logger.error(&failed, None);
+                }
+            }
+        }));
+    }
+
     // Send transaction messages extracted from the CSV file to the transaction processor.
     // Additional sources can by added by replicating this pattern and running the message
     // producers in dedicated threads.
     let tx_csv = tx_msg.clone();
-    for csv_msg in read_csv(reader) {
-        tx_csv.send(csv_msg).await.map_err(Error::Send)?;
+    let mut batch = batches.take();
+    let mut dead_letter = options
+        .dead_letter
+        .as_ref()
+        .map(DeadLetter::open)
+        .transpose()
+        .map_err(Error::Io)?;
+    let mut msgs = read_csv(reader)
+        .aliases(options.aliases)
+        .require_trailer(options.require_trailer);
+    #[cfg(feature = "chaos")]
+    let mut chaos = options.chaos.map(chaos::Chaos::new);
+    let mut reorder = options.reorder_window.map(reorder::Reorder::new);
+    let mut held = durable.then(processor::Batch::default);
+    let (mut trailers, mut controls_failed) = (0, 0);
+    let duplicate = options
+        .digest
+        .is_some_and(|digest| inputs.contains(&digest));
+    if duplicate {
+        logger.warning(
+            &DuplicateInput {
+                source: options.source.clone(),
+            },
+            None,
+        );
+    } else {
+        while let Some(res_msg) = msgs.next() {
+            meter.row();
+            if let Some(held) = &mut held {
+                // a trailer was read up to this record
+                if msgs.trailers != trailers {
+                    trailers = msgs.trailers;
+                    if msgs.controls_failed() == controls_failed {
+                        release(held, &mut batch);
+                    } else {
+                        controls_failed = msgs.controls_failed();
+                        *held = processor::Batch::default();
+                    }
+                }
+            }
+            let origin = msgs.origin(&options.source);
+            match res_msg {
+                Ok(csv_msg) => match (&mut dead_letter, csv_msg.check_limits(&limits)) {
+                    // the processor would reject it anyway
+                    (Some(dead_letter), Err(err)) => {
+                        logger.error(&err, Some(&origin));
+                        dead_letter
+                            .write(&msgs.headers, &msgs.record)
+                            .map_err(Error::De)?;
+                    }
+                    _ => held.as_mut().unwrap_or(&mut batch).push(csv_msg, origin),
+                },
+                Err(err @ Error::UnknownType { .. }) => match options.unknown_types {
+                    UnknownTypes::Skip => logger.error(&err, Some(&origin)),
+                    UnknownTypes::Fail => return Err(err),
+                    UnknownTypes::DeadLetter => {
+                        logger.error(&err, Some(&origin));
+                        if let Some(dead_letter) = &mut dead_letter {
+                            dead_letter
+                                .write(&msgs.headers, &msgs.record)
+                                .map_err(Error::De)?;
+                        }
+                    }
+                },
+                Err(err) => logger.error(&err, Some(&origin)),
+            }
+            // faults may add messages, so batches can be a bit larger
+            if batch.len() >= processor::BATCH_SIZE {
+                #[cfg(feature = "chaos")]
+                if let Some(chaos) = &mut chaos {
+                    chaos.apply(&mut batch);
+                }
+                if let Some(reorder) = &mut reorder {
+                    reorder.apply(&mut batch);
+                    // everything held back
+                    if batch.is_empty() {
+                        continue;
+                    }
+                }
+                let full = std::mem::replace(&mut batch, batches.take());
+                tx_csv
+                    .send(processor::Message::Batch(full))
+                    .await
+                    .map_err(Error::Send)?;
+                meter.depth(processor::CHANNEL_SIZE - tx_csv.capacity());
+            }
+        }
+    }
+    if let Some(held) = &mut held {
+        if msgs.controls_failed() == 0 {
+            release(held, &mut batch);
+        }
+    }
+    #[cfg(feature = "chaos")]
+    if let Some(mut chaos) = chaos {
+        chaos.apply(&mut batch);
+        logger.info(&chaos.finish(&mut batch), None);
+    }
+    if let Some(mut reorder) = reorder {
+        reorder.apply(&mut batch);
+        logger.info(&reorder.finish(&mut batch), None);
+    }
+    if !batch.is_empty() {
+        tx_csv
+            .send(processor::Message::Batch(batch))
+            .await
+            .map_err(Error::Send)?;
     }
     drop(tx_csv);
 
+    // Neither snapshot nor report of an input failing its control totals.
+    if msgs.controls_failed() > 0 {
+        drop(tx_msg);
+        let _ = notifications.await;
+        if let Some(disputes) = disputes {
+            let _ = disputes.await;
+        }
+        logger.flush();
+        return Err(Error::Controls(msgs.controls_failed()));
+    }
+
+    if options.snapshot.is_some() {
+        let (tx_saved, rx_saved) = oneshot::channel();
+        tx_msg
+            .send(processor::Message::SaveSnapshot { tx: tx_saved })
+            .await
+            .map_err(Error::Send)?;
+        rx_saved.await.map_err(Error::RecvState)?;
+    }
+    if let (Some(path), Some(digest)) = (&options.snapshot, &options.digest) {
+        inputs.insert(digest);
+        inputs.write(path).map_err(Error::Io)?;
+    }
+
     // Finally request the state of the transaction processor.
     let (tx_state, rx_state) = oneshot::channel();
     tx_msg
@@ -136,34 +1166,478 @@ pub async fn run<R: std::io::Read, W: std::io::Write>(reader: R, writer: W) -> R
         .await
         .map_err(Error::Send)?;
     let state = rx_state.await.map_err(Error::RecvState)?;
-    let mut wtr = csv::Writer::from_writer(writer);
-    for s in state {
-        if let Err(err) = wtr
-            .serialize(Output {
-                client: s.client,
-                available: s.available,
-                held: s.held,
-                total: s.total,
-                locked: s.locked,
-            })
-            .map_err(Error::Ser)
-        {
-            eprintln!("{err}");
+    let included = |client| {
+        let metadata = metadata.get(&client);
+        options.filters.iter().all(|f| f.matches(metadata))
+    };
+    let filtered = state.iter().filter(|s| included(s.client));
+    report(writer, filtered, approvals).map_err(Error::Io)?;
+    if let Some(path) = &options.held_report {
+        let (tx_disputes, rx_disputes) = oneshot::channel();
+        tx_msg
+            .send(processor::Message::GetDisputes { tx: tx_disputes })
+            .await
+            .map_err(Error::Send)?;
+        let disputes = rx_disputes.await.map_err(Error::RecvState)?;
+        let file = std::fs::File::create(path).map_err(Error::Io)?;
+        write_disputes(file, disputes.iter().filter(|d| included(d.client))).map_err(Error::Io)?;
+    }
+    if let Some((hierarchy, path)) = &options.roll_up {
+        // descendants count in full, the filters only select the parents to report
+        let roll_ups = hierarchy.roll_up(state.iter());
+        let file = std::fs::File::create(path).map_err(Error::Io)?;
+        write_roll_up(file, roll_ups.iter().filter(|r| included(r.client))).map_err(Error::Io)?;
+    }
+    if let Some(path) = &options.positions_report {
+        let (tx_positions, rx_positions) = oneshot::channel();
+        tx_msg
+            .send(processor::Message::GetPositions { tx: tx_positions })
+            .await
+            .map_err(Error::Send)?;
+        let positions = rx_positions.await.map_err(Error::RecvState)?;
+        let file = std::fs::File::create(path).map_err(Error::Io)?;
+        write_positions(file, positions.iter().filter(|(c, ..)| included(*c)))
+            .map_err(Error::Io)?;
+    }
+    let stats = if options.summary {
+        let (tx_stats, rx_stats) = oneshot::channel();
+        tx_msg
+            .send(processor::Message::GetStats { reply: tx_stats })
+            .await
+            .map_err(Error::Send)?;
+        Some(rx_stats.await.map_err(Error::RecvState)?)
+    } else {
+        None
+    };
+
+    // Stop the processor and wait until everything it reported is logged.
+    drop(tx_msg);
+    let _ = notifications.await;
+    if let Some(disputes) = disputes {
+        let _ = disputes.await;
+    }
+    let mismatches = match &expected {
+        Some(expected) => reconcile::compare(expected, &state, options.tolerance),
+        None => Vec::new(),
+    };
+    for mismatch in &mismatches {
+        logger.warning(mismatch, None);
+    }
+    logger.flush();
+
+    if let Some(stats) = stats {
+        write!(std::io::stderr(), "{stats}").map_err(Error::Io)?;
+    }
+    if options.throughput {
+        let throughput = meter.finish();
+        match logger.format() {
+            log::Format::Text => eprintln!("{throughput}"),
+            log::Format::Json => eprintln!("{}", throughput.json()),
         }
     }
-    wtr.flush().map_err(Error::Io)
+    match mismatches.len() {
+        0 => Ok(()),
+        n => Err(Error::Mismatches(n)),
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::testkit;
 
+    // Runs every `data/cases/<name>/in.csv` and compares the report to `out.csv` and the log to
+    // `err.txt`, if present.
     #[tokio::test]
-    async fn samples() {
-        let file = std::fs::File::open("data/in.csv").unwrap();
-        let expected = std::fs::read_to_string("data/out.csv").unwrap();
-        let mut buf = Vec::new();
-        let _ = super::run(file, &mut buf).await;
-        let actual = String::from_utf8(buf).unwrap();
-        assert_eq!(actual, expected)
+    async fn cases() {
+        let mut dirs: Vec<_> = std::fs::read_dir("data/cases")
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        dirs.sort();
+        assert!(!dirs.is_empty());
+        for dir in dirs {
+            let file = std::fs::File::open(dir.join("in.csv")).unwrap();
+            let expected = std::fs::read_to_string(dir.join("out.csv")).unwrap();
+            let expected_log = std::fs::read_to_string(dir.join("err.txt")).unwrap_or_default();
+            let logger = log::Logger::capture(log::Format::Text);
+            let options = Options {
+                logger: logger.clone(),
+                source: "in.csv".into(),
+                ..Options::default()
+            };
+            let mut buf = Vec::new();
+            let _ = run(file, &mut buf, options).await;
+            let actual = String::from_utf8(buf).unwrap();
+            assert_eq!(actual, expected, "{}", dir.display());
+            let log: String = logger.take().iter().map(|e| format!("{e}\n")).collect();
+            assert_eq!(log, expected_log, "{}", dir.display());
+        }
+    }
+
+    #[test]
+    fn fuzz() {
+        const FIELDS: &[&[u8]] = &[
+            b"deposit",
+            b"withdrawal",
+            b"dispute",
+            b"resolve",
+            b"chargeback",
+            b"1",
+            b"65536",
+            b"4294967296",
+            b"-1",
+            b"1.5",
+            b"",
+            b" ",
+            b"\"",
+            b"\xff",
+        ];
+        let mut rng = testkit::Rng::new(1);
+        for _ in 0..2_000 {
+            let mut input = b"type,client,tx,amount\n".to_vec();
+            for _ in 0..rng.below(20) {
+                let fields: Vec<_> = (0..rng.below(6))
+                    .map(|_| match rng.below(8) {
+                        0 => testkit::bytes(&mut rng, b"0123456789.,\"\n", 12),
+                        _ => FIELDS[rng.below(FIELDS.len() as u64) as usize].to_vec(),
+                    })
+                    .collect();
+                input.extend(fields.join(&b',').into_iter().chain([b'\n']));
+            }
+            let lines = input.iter().filter(|b| **b == b'\n').count();
+            let mut msgs = read_csv(&input[..]);
+            let mut count = 0;
+            while msgs.next().is_some() {
+                count += 1;
+                // every item consumes input, an error must not repeat forever
+                assert!(count <= lines, "{:?}", String::from_utf8_lossy(&input));
+            }
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let input = "amount,type,tx,client\n1.5,deposit,1,2\n,dispute,1,2\nx,deposit,2,2\n\
+                     1,deposit,3,x\n";
+        let res: Vec<_> = read_csv(input.as_bytes()).collect();
+        assert!(matches!(
+            res[0],
+            Ok(processor::Message::Deposit {
+                client: 2,
+                tx: 1,
+                amount: 15000,
+                trade: None,
+            })
+        ));
+        assert!(matches!(
+            res[1],
+            Ok(processor::Message::Dispute {
+                client: 2,
+                tx: 1,
+                reason: None
+            })
+        ));
+        assert!(matches!(
+            res[2],
+            Err(Error::Parse {
+                line: 4,
+                field: "amount",
+                ..
+            })
+        ));
+        assert!(matches!(
+            res[3],
+            Err(Error::Parse {
+                line: 5,
+                field: "client",
+                ..
+            })
+        ));
+        assert_eq!(res.len(), 4);
+
+        let res: Vec<_> =
+            read_csv("type,client,tx\nDeposit,1,1\nCHARGEBACK,1,1\nrefund,1,1\n".as_bytes())
+                .collect();
+        assert!(matches!(
+            res[0],
+            Err(Error::Input {
+                client: 1,
+                tx: 1,
+                ..
+            })
+        ));
+        assert!(matches!(
+            res[1],
+            Ok(processor::Message::Chargeback {
+                client: 1,
+                tx: 1,
+                reason: None
+            })
+        ));
+        assert!(
+            matches!(&res[2], Err(Error::UnknownType { line: 4, name, .. }) if name == "refund")
+        );
+
+        let aliases = vec![parse_alias("credit=deposit").unwrap()];
+        let res: Vec<_> = read_csv("type,client,tx,amount\nCredit,1,1,1\n".as_bytes())
+            .aliases(aliases)
+            .collect();
+        assert!(matches!(
+            res[0],
+            Ok(processor::Message::Deposit {
+                client: 1,
+                tx: 1,
+                amount: 10000,
+                trade: None,
+            })
+        ));
+        assert!(parse_alias("credit").is_err());
+        assert!(parse_alias("credit=refund").is_err());
+
+        let res: Vec<_> = read_csv("type,client\ndeposit,1\n".as_bytes()).collect();
+        assert!(matches!(res[0], Err(Error::Parse { field: "tx", .. })));
+    }
+
+    #[tokio::test]
+    async fn unknown_types() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1\nrefund,1,1,1\n";
+        let path = std::env::temp_dir().join(format!("trapez-{}-dead.csv", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let options = Options {
+            unknown_types: UnknownTypes::DeadLetter,
+            dead_letter: Some(path.clone()),
+            ..Options::default()
+        };
+        run(input.as_bytes(), Vec::new(), options).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "type,client,tx,amount\nrefund,1,1,1\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        // deposits and withdrawals out of bounds are dead-lettered as well
+        let mut options = Options {
+            dead_letter: Some(path.clone()),
+            ..Options::default()
+        };
+        options.processor.limits.withdrawal.max = Some(5000);
+        let bounded =
+            "type,client,tx,amount\ndeposit,1,1,1\nwithdrawal,1,2,0.6\nwithdrawal,1,3,0.5\n";
+        let mut output = Vec::new();
+        run(bounded.as_bytes(), &mut output, options).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "type,client,tx,amount\nwithdrawal,1,2,0.6\n"
+        );
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .ends_with("\n1,0.5000,0.0000,0.5000,false\n"));
+        std::fs::remove_file(&path).unwrap();
+
+        let options = Options {
+            unknown_types: UnknownTypes::Fail,
+            ..Options::default()
+        };
+        let mut output = Vec::new();
+        assert!(matches!(
+            run(input.as_bytes(), &mut output, options).await,
+            Err(Error::UnknownType { line: 3, .. })
+        ));
+        assert!(output.is_empty());
+    }
+
+    #[tokio::test]
+    async fn trailers() {
+        let run_trailers = |input: &'static str, require_trailer| async move {
+            let logger = log::Logger::capture(log::Format::Text);
+            let options = Options {
+                logger: logger.clone(),
+                require_trailer,
+                ..Options::default()
+            };
+            let mut output = Vec::new();
+            let res = run(input.as_bytes(), &mut output, options).await;
+            (res, output, logger.take())
+        };
+
+        let input = "type,client,tx,amount\ndeposit,1,1,5\ntrailer,,2,5\ndeposit,1,2,1\n";
+        let (res, output, log) = run_trailers(input, false).await;
+        assert!(matches!(res, Err(Error::Controls(1))));
+        assert!(output.is_empty());
+        assert_eq!(log.len(), 1);
+        assert!(log[0].starts_with("Control totals in line 3 don't match: 1 rows summing to"));
+
+        let input = "type,client,tx,amount\ndeposit,1,1,5\ntrailer,,1,5\ndeposit,1,2,1\n";
+        let (res, output, _) = run_trailers(input, false).await;
+        assert!(res.is_ok() && !output.is_empty());
+        let (res, output, log) = run_trailers(input, true).await;
+        assert!(matches!(res, Err(Error::Controls(1))));
+        assert!(output.is_empty());
+        assert!(
+            log[0].starts_with("The input ends with 1 rows"),
+            "{}",
+            log[0]
+        );
+        let (res, ..) = run_trailers("type,client,tx,amount\n", true).await;
+        assert!(matches!(res, Err(Error::Controls(1))));
+    }
+
+    #[tokio::test]
+    async fn trailers_wal() {
+        let dir = std::env::temp_dir();
+        let wal = dir.join(format!("trapez-{}-trailers-wal.csv", std::process::id()));
+        let audit = dir.join(format!("trapez-{}-trailers-audit.log", std::process::id()));
+        let _ = std::fs::remove_file(&wal);
+        let _ = std::fs::remove_file(&audit);
+        let options = || Options {
+            logger: log::Logger::capture(log::Format::Text),
+            wal: Some((wal.clone(), wal::Fsync::Never)),
+            audit: Some(audit.clone()),
+            ..Options::default()
+        };
+
+        // only the verified block reaches the logs
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1\n\
+                     trailer,,1,1\n\
+                     deposit,2,2,10\n\
+                     deposit,2,3,5\n\
+                     trailer,,3,99\n";
+        let res = run(input.as_bytes(), Vec::new(), options()).await;
+        assert!(matches!(res, Err(Error::Controls(1))));
+        let log = std::fs::read_to_string(&wal).unwrap();
+        assert!(log.ends_with("\ndeposit,1,1,1.0000\n"), "{}", log);
+        let log = std::fs::read_to_string(&audit).unwrap();
+        assert!(!log.contains("deposit,2,"), "{}", log);
+
+        // and nothing of the rejected block is recovered
+        let mut output = Vec::new();
+        run("type,client,tx,amount\n".as_bytes(), &mut output, options())
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n"
+        );
+        std::fs::remove_file(&wal).unwrap();
+        std::fs::remove_file(&audit).unwrap();
+    }
+
+    #[tokio::test]
+    async fn held_report() {
+        let input = "type,client,tx,amount,reason\n\
+                     deposit,1,1,2\n\
+                     deposit,1,2,3\n\
+                     deposit,2,3,4\n\
+                     dispute,1,2,,10.4\n\
+                     dispute,1,1,,\n\
+                     deposit,2,4,1\n\
+                     dispute,2,3,,\n\
+                     resolve,2,3,,\n";
+        let path = std::env::temp_dir().join(format!("trapez-{}-held.csv", std::process::id()));
+        let options = Options {
+            held_report: Some(path.clone()),
+            ..Options::default()
+        };
+        let mut output = Vec::new();
+        run(input.as_bytes(), &mut output, options).await.unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n\
+             1,0.0000,5.0000,5.0000,false\n\
+             2,5.0000,0.0000,5.0000,false\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "client,tx,amount,age,reason\n\
+             1,1,2.0000,3,\n\
+             1,2,3.0000,4,10.4\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn positions_report() {
+        let input = "type,client,tx,amount,asset,price\n\
+                     deposit,1,1,1000,AAPL,100\n\
+                     deposit,1,2,2000,AAPL,200\n\
+                     withdrawal,1,3,900,AAPL,180\n\
+                     deposit,2,4,50,,\n\
+                     deposit,2,5,50,BTC-USD,\n\
+                     deposit,2,6,50,BTC-USD,-1\n";
+        let path =
+            std::env::temp_dir().join(format!("trapez-{}-positions.csv", std::process::id()));
+        let options = Options {
+            positions_report: Some(path.clone()),
+            ..Options::default()
+        };
+        let mut output = Vec::new();
+        run(input.as_bytes(), &mut output, options).await.unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n\
+             1,2100.0000,0.0000,2100.0000,false\n\
+             2,50.0000,0.0000,50.0000,false\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "client,asset,units,average_cost,cost,realized\n\
+             1,AAPL,15.0000,150.0000,2250.0000,150.0000\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn references() {
+        let input = "type,client,tx,amount,memo,reason,timestamp\n\
+                     deposit,1,1,2,\"P-1, first\"\n\
+                     withdrawal,1,2,3,P-2\n\
+                     dispute,1,1,,,10.4,1700000000\n";
+        let dir = std::env::temp_dir();
+        let wal = dir.join(format!("trapez-{}-references-wal.csv", std::process::id()));
+        let audit = dir.join(format!(
+            "trapez-{}-references-audit.log",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&wal);
+        let _ = std::fs::remove_file(&audit);
+        let logger = log::Logger::capture(log::Format::Json);
+        let options = Options {
+            logger: logger.clone(),
+            wal: Some((wal.clone(), wal::Fsync::Never)),
+            audit: Some(audit.clone()),
+            ..Options::default()
+        };
+        run(input.as_bytes(), Vec::new(), options).await.unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&wal).unwrap(),
+            "type,client,tx,amount,reference,reason,timestamp,asset,price\n\
+             deposit,1,1,2.0000,\"P-1, first\"\n\
+             withdrawal,1,2,3.0000,P-2\n\
+             dispute,1,1,2.0000,,10.4,1700000000\n"
+        );
+        // replaying the log yields the references and reason codes again
+        let mut msgs = read_csv(std::fs::File::open(&wal).unwrap());
+        msgs.next();
+        assert_eq!(
+            msgs.origin(&"wal".into()).reference.as_deref(),
+            Some("P-1, first")
+        );
+        let reason = msgs.nth(1).and_then(|msg| msg.ok()?.reason());
+        assert_eq!(
+            reason.map(|reason| reason.to_string()).as_deref(),
+            Some("10.4")
+        );
+        assert_eq!(msgs.origin(&"wal".into()).timestamp, Some(1700000000));
+        assert!(std::fs::read_to_string(&audit)
+            .unwrap()
+            .contains(" deposit,1,1,2.0000,\"P-1, first\"\n"));
+        let events = logger.take();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].contains("\"reference\":\"P-2\""), "{}", events[0]);
+        std::fs::remove_file(&wal).unwrap();
+        std::fs::remove_file(&audit).unwrap();
     }
 }
@@ -0,0 +1,201 @@
+/**
+ * Erasure of a client's personal data on request, e.g. under the GDPR, without changing balances.
+ *
+ * The `erase` admin command works on a snapshot. The metadata of the account is replaced by an
+ * `erased` tag, its transaction log is collapsed into a single opening entry over the total funds
+ * and its count of opened disputes and the outcomes of its closed disputes are dropped. Balances,
+ * the lock, holds, links and positions stay as they are. The audit log is erased separately by
+ * `audit::erase`, which keeps its hash chain verifiable.
+ */
+use std::fmt;
+
+use crate::{amount, metadata::Metadata, snapshot};
+
+/// Tag of erased accounts, so reports can be filtered on them.
+pub const TAG: &str = "erased";
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum Error {
+    #[error("There is no account of client {0} in the snapshot.")]
+    UnknownAccount(u16),
+    #[error("Client {0} has open disputes, which have to be closed first.")]
+    OpenDisputes(u16),
+    #[error("Client {0} has withdrawals pending approval or open authorizations.")]
+    Pending(u16),
+}
+
+/**
+ * What an erasure removed from a snapshot and the audit log.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub client: u16,
+    /// Number of log entries collapsed into the opening entry.
+    pub transactions: usize,
+    /// Transaction id and amount of the opening entry, none for an account without transactions.
+    pub opening: Option<(u32, i64)>,
+    /// Number of reversed transactions dropped with the log.
+    pub reversed: usize,
+    /// Number of metadata values removed.
+    pub values: usize,
+    /// Number of tags removed.
+    pub tags: usize,
+    /// The dropped count of disputes opened by the client.
+    pub disputes: u32,
+    /// Number of audit log entries folded into opening balances, if the audit log was erased.
+    pub audit: Option<usize>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Erased client {}:", self.client)?;
+        match self.opening {
+            Some((tx, amount)) => writeln!(
+                f,
+                "- {} transactions collapsed into the opening entry {tx} over {}",
+                self.transactions,
+                amount::Decimal(amount)
+            )?,
+            None => writeln!(f, "- no transactions")?,
+        }
+        writeln!(f, "- {} reversed transactions", self.reversed)?;
+        writeln!(
+            f,
+            "- {} metadata values and {} tags",
+            self.values, self.tags
+        )?;
+        writeln!(f, "- the count of {} opened disputes", self.disputes)?;
+        if let Some(audit) = self.audit {
+            writeln!(
+                f,
+                "- {audit} audit log entries folded into opening balances"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Erases the personal data of a client's account. Accounts with open disputes, pending withdrawals
+ * or open authorizations are refused, since those still refer to single transactions.
+ *
+ * The opening entry keeps the id of the first transaction and is marked as reversed, so it can't be
+ * disputed. The ids of the other transactions can be used again.
+ */
+pub fn erase(contents: &mut snapshot::Contents, client: u16) -> Result<Report, Error> {
+    let parts = contents
+        .accounts
+        .iter_mut()
+        .find(|(c, _)| *c == client)
+        .map(|(_, parts)| parts)
+        .ok_or(Error::UnknownAccount(client))?;
+    if !parts.disputes.is_empty() {
+        return Err(Error::OpenDisputes(client));
+    }
+    if !parts.pending.is_empty() || !parts.authorized.is_empty() {
+        return Err(Error::Pending(client));
+    }
+    let mut report = Report {
+        client,
+        transactions: parts.log.len(),
+        reversed: parts.reversed.len(),
+        ..Report::default()
+    };
+    parts.reversed.clear();
+    parts.closed.clear();
+    if let Some((tx, _)) = parts.log.first() {
+        let opening = (*tx, parts.available + parts.held);
+        parts.log = vec![opening];
+        parts.reversed.push(opening.0);
+        report.opening = Some(opening);
+    }
+    let metadata = contents.metadata.remove(&client).unwrap_or_default();
+    report.values = metadata.values.len();
+    report.tags = metadata.tags.len();
+    contents.metadata.insert(
+        client,
+        Metadata {
+            tags: [TAG.to_string()].into(),
+            ..Metadata::default()
+        },
+    );
+    report.disputes = contents.disputes.remove(&client).unwrap_or_default();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::Parts;
+
+    #[test]
+    fn erase() {
+        let mut contents = snapshot::Contents {
+            accounts: vec![
+                (
+                    1,
+                    Parts {
+                        available: 70_000,
+                        locked: true,
+                        log: vec![(3, 50_000), (4, 30_000), (5, -10_000)],
+                        reversed: vec![4],
+                        ..Parts::default()
+                    },
+                ),
+                (
+                    2,
+                    Parts {
+                        held: 10,
+                        log: vec![(6, 10)],
+                        disputes: vec![6],
+                        ..Parts::default()
+                    },
+                ),
+                (3, Parts::default()),
+            ],
+            disputes: [(1, 2), (2, 1)].into(),
+            metadata: [(
+                1,
+                Metadata {
+                    values: [("name".to_string(), "Jane Doe".to_string())].into(),
+                    tags: ["vip".to_string()].into(),
+                },
+            )]
+            .into(),
+            ..snapshot::Contents::default()
+        };
+
+        let report = super::erase(&mut contents, 1).unwrap();
+        assert_eq!(
+            report,
+            Report {
+                client: 1,
+                transactions: 3,
+                opening: Some((3, 70_000)),
+                reversed: 1,
+                values: 1,
+                tags: 1,
+                disputes: 2,
+                audit: None,
+            }
+        );
+        let (_, parts) = &contents.accounts[0];
+        assert_eq!((parts.available, parts.locked), (70_000, true));
+        assert_eq!(
+            (&parts.log[..], &parts.reversed[..]),
+            (&[(3, 70_000)][..], &[3][..])
+        );
+        assert_eq!(contents.metadata[&1].tags, [TAG.to_string()].into());
+        assert!(contents.metadata[&1].values.is_empty());
+        assert_eq!(contents.disputes.get(&1), None);
+        assert!(report.to_string().contains("3 transactions collapsed"));
+
+        assert_eq!(super::erase(&mut contents, 2), Err(Error::OpenDisputes(2)));
+        assert_eq!(
+            super::erase(&mut contents, 4),
+            Err(Error::UnknownAccount(4))
+        );
+        let report = super::erase(&mut contents, 3).unwrap();
+        assert_eq!((report.transactions, report.opening), (0, None));
+    }
+}
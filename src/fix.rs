@@ -0,0 +1,434 @@
+/**
+ * Conversion of FIX drop-copy logs into deposits and withdrawals.
+ *
+ * Brokerages often only provide a FIX drop copy of their executions and allocations. Each line of
+ * such a log holds one message, optionally prefixed with a timestamp or session information, with
+ * fields separated by SOH or `|`. Fills of execution reports, or the allocations of allocation
+ * instructions and reports, are mapped to cash movements of the account: buys are withdrawals and
+ * sells are deposits. The `Account` (or `AllocAccount`) field holds the client id.
+ *
+ * FIX identifiers aren't numeric, so transaction ids are assigned consecutively and the `ExecID`
+ * (or `AllocID/AllocAccount`) is kept as reference. Resent messages (`PossDupFlag=Y`) and repeated
+ * references are skipped, so the conversion of a log with retransmissions stays the same.
+ */
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    io::{self, BufRead},
+    sync::Arc,
+};
+
+use crate::{amount, log, processor};
+
+const SOH: u8 = 0x01;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error: `{0}`.")]
+    Io(#[from] io::Error),
+    #[error("Invalid FIX message in line {line}: `{reason}`.")]
+    Invalid {
+        line: u64,
+        reason: Cow<'static, str>,
+    },
+    #[error("Transaction ids are exhausted in line {0}.")]
+    Overflow(u64),
+}
+
+impl log::Event for Error {
+    fn code(&self) -> &'static str {
+        match self {
+            Error::Io(_) => "io",
+            Error::Invalid { .. } => "fix_invalid",
+            Error::Overflow(_) => "fix_overflow",
+        }
+    }
+}
+
+/**
+ * The messages to take cash movements from. A drop copy usually contains both for the same trades,
+ * so only one kind is converted.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Source {
+    /// Fills of execution reports (`35=8`).
+    Executions,
+    /// Allocation instructions and reports (`35=J`, `35=AS`).
+    Allocations,
+}
+
+/**
+ * A converted cash movement.
+ */
+#[derive(Debug)]
+pub struct Entry {
+    /// A deposit or withdrawal.
+    pub message: processor::Message,
+    pub reference: String,
+    pub line: u64,
+}
+
+/**
+ * Converts the messages of a FIX log line by line.
+ */
+pub struct Reader<R> {
+    reader: R,
+    source: Source,
+    next_tx: Option<u32>,
+    line: u64,
+    buf: Vec<u8>,
+    seen: HashSet<String>,
+    pending: std::vec::IntoIter<(u16, i64, String)>,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(reader: R, source: Source, first_tx: u32) -> Reader<R> {
+        Self {
+            reader,
+            source,
+            next_tx: Some(first_tx),
+            line: 0,
+            buf: Vec::new(),
+            seen: HashSet::new(),
+            pending: Vec::new().into_iter(),
+        }
+    }
+
+    /**
+     * The origin of the line read last.
+     */
+    pub fn origin(&self, source: &Arc<str>) -> processor::Origin {
+        processor::Origin {
+            source: source.clone(),
+            line: self.line,
+            offset: 0,
+            reference: None,
+            timestamp: None,
+        }
+    }
+
+    fn entry(&mut self, client: u16, amount: i64, reference: String) -> Result<Entry, Error> {
+        let tx = self.next_tx.ok_or(Error::Overflow(self.line))?;
+        self.next_tx = tx.checked_add(1);
+        let message = if amount < 0 {
+            processor::Message::Withdrawal {
+                client,
+                tx,
+                amount: -amount,
+                trade: None,
+            }
+        } else {
+            processor::Message::Deposit {
+                client,
+                tx,
+                amount,
+                trade: None,
+            }
+        };
+        Ok(Entry {
+            message,
+            reference,
+            line: self.line,
+        })
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = Result<Entry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((client, amount, reference)) = self.pending.next() {
+                return Some(self.entry(client, amount, reference));
+            }
+            self.buf.clear();
+            match self.reader.read_until(b'\n', &mut self.buf) {
+                Ok(0) => return None,
+                Ok(_) => self.line += 1,
+                Err(err) => return Some(Err(err.into())),
+            }
+            let movements = match Message::parse(&self.buf) {
+                Ok(Some(msg)) => msg.movements(self.source),
+                Ok(None) => continue,
+                Err(reason) => Err(reason),
+            };
+            match movements {
+                Ok(movements) => {
+                    let movements: Vec<_> = movements
+                        .into_iter()
+                        .filter(|(_, _, reference)| self.seen.insert(reference.clone()))
+                        .collect();
+                    self.pending = movements.into_iter();
+                }
+                Err(reason) => {
+                    return Some(Err(Error::Invalid {
+                        line: self.line,
+                        reason,
+                    }))
+                }
+            }
+        }
+    }
+}
+
+// The fields of a message in their original order, repeating groups included.
+struct Message<'a> {
+    fields: Vec<(u32, &'a str)>,
+}
+
+impl<'a> Message<'a> {
+    // Returns `None` for lines without a FIX message.
+    fn parse(line: &'a [u8]) -> Result<Option<Message<'a>>, Cow<'static, str>> {
+        let start = match line.windows(5).position(|w| w == b"8=FIX") {
+            Some(start) => start,
+            None => return Ok(None),
+        };
+        let raw = std::str::from_utf8(&line[start..])
+            .map_err(|_| "not UTF-8")?
+            .trim_end();
+        let delimiter = raw
+            .bytes()
+            .find(|b| *b == SOH || *b == b'|')
+            .ok_or("no field delimiter")?;
+        let raw = raw.trim_end_matches(delimiter as char);
+
+        let mut fields = Vec::new();
+        let mut checksum: u32 = 0;
+        for field in raw.split(delimiter as char) {
+            let (tag, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("field `{field}` without a tag"))?;
+            let tag = tag.parse().map_err(|_| format!("invalid tag `{tag}`"))?;
+            if tag == 10 {
+                // the checksum is taken over the original bytes, where every delimiter was a SOH
+                let expected: u32 = value
+                    .parse()
+                    .map_err(|_| format!("invalid checksum `{value}`"))?;
+                if checksum % 256 != expected {
+                    return Err(format!(
+                        "checksum {expected:03} doesn't match the message ({:03})",
+                        checksum % 256
+                    )
+                    .into());
+                }
+                break;
+            }
+            checksum += field.bytes().map(u32::from).sum::<u32>() + u32::from(SOH);
+            fields.push((tag, value));
+        }
+        Ok(Some(Self { fields }))
+    }
+
+    fn get(&self, tag: u32) -> Option<&'a str> {
+        self.fields
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, value)| *value)
+    }
+
+    fn require(&self, tag: u32, name: &str) -> Result<&'a str, Cow<'static, str>> {
+        self.get(tag)
+            .filter(|value| !value.is_empty())
+            .ok_or_else(|| format!("missing {name} ({tag})").into())
+    }
+
+    // The signed cash movements as (client, amount, reference), buys being negative.
+    fn movements(&self, source: Source) -> Result<Vec<(u16, i64, String)>, Cow<'static, str>> {
+        if self.get(43) == Some("Y") {
+            return Ok(Vec::new());
+        }
+        match (self.get(35), source) {
+            (Some("8"), Source::Executions) => self.execution(),
+            (Some("J" | "AS"), Source::Allocations) => self.allocations(),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    fn execution(&self) -> Result<Vec<(u16, i64, String)>, Cow<'static, str>> {
+        // `F` is a trade since FIX 4.3, `1` and `2` are partial and full fills before
+        if !matches!(self.get(150), Some("F" | "1" | "2")) {
+            return Ok(Vec::new());
+        }
+        let sign = self.sign()?;
+        let client = client(self.require(1, "Account")?)?;
+        let amount = match self.get(381) {
+            Some(gross) => decimal(gross, "GrossTradeAmt")?,
+            None => product(self.require(32, "LastQty")?, self.require(31, "LastPx")?)?,
+        };
+        Ok(vec![(
+            client,
+            sign * amount,
+            self.require(17, "ExecID")?.to_string(),
+        )])
+    }
+
+    fn allocations(&self) -> Result<Vec<(u16, i64, String)>, Cow<'static, str>> {
+        match self.get(71) {
+            None | Some("0") => (),
+            Some(kind) => return Err(format!("unsupported AllocTransType `{kind}`").into()),
+        }
+        let sign = self.sign()?;
+        let id = self.require(70, "AllocID")?;
+        let avg_px = self.get(6);
+
+        // every AllocAccount starts an entry of the repeating group
+        let mut groups: Vec<Vec<(u32, &str)>> = Vec::new();
+        for &(tag, value) in &self.fields {
+            match (tag, groups.last_mut()) {
+                (79, _) => groups.push(vec![(tag, value)]),
+                (_, Some(group)) => group.push((tag, value)),
+                (_, None) => (),
+            }
+        }
+        if groups.is_empty() {
+            return Err("no allocations".into());
+        }
+        groups
+            .into_iter()
+            .map(|group| {
+                let get = |tag| group.iter().find(|(t, _)| *t == tag).map(|(_, v)| *v);
+                let account = get(79).unwrap_or_default();
+                let amount = match (get(154), get(80), avg_px) {
+                    (Some(net), _, _) => decimal(net, "AllocNetMoney")?,
+                    (None, Some(qty), Some(px)) => product(qty, px)?,
+                    _ => {
+                        return Err(
+                            "missing AllocNetMoney (154) or AllocQty (80) and AvgPx (6)".into()
+                        )
+                    }
+                };
+                Ok((client(account)?, sign * amount, format!("{id}/{account}")))
+            })
+            .collect()
+    }
+
+    fn sign(&self) -> Result<i64, Cow<'static, str>> {
+        match self.require(54, "Side")? {
+            // buy, buy minus
+            "1" | "3" => Ok(-1),
+            // sell, sell plus, sell short, sell short exempt
+            "2" | "4" | "5" | "6" => Ok(1),
+            side => Err(format!("unsupported Side `{side}`").into()),
+        }
+    }
+}
+
+fn client(account: &str) -> Result<u16, Cow<'static, str>> {
+    account
+        .parse()
+        .map_err(|_| format!("account `{account}` is no client id").into())
+}
+
+fn decimal(value: &str, name: &str) -> Result<i64, Cow<'static, str>> {
+    match amount::parse(value) {
+        Ok(amount) if amount > 0 => Ok(amount),
+        Ok(_) => Err(format!("{name} `{value}` isn't positive").into()),
+        Err(err) => Err(format!("{name} `{value}`: {err}").into()),
+    }
+}
+
+// Quantity times price, truncated to four decimal places like parsed amounts.
+fn product(qty: &str, px: &str) -> Result<i64, Cow<'static, str>> {
+    let qty = i128::from(decimal(qty, "quantity")?);
+    let px = i128::from(decimal(px, "price")?);
+    i64::try_from(qty * px / 10_000).map_err(|_| "amount too large".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Joins fields with SOH and appends the checksum.
+    fn message(fields: &str) -> String {
+        let body: String = fields
+            .split('|')
+            .map(|field| format!("{field}\x01"))
+            .collect();
+        let checksum = body.bytes().map(u32::from).sum::<u32>() % 256;
+        format!("{body}10={checksum:03}\x01")
+    }
+
+    fn convert(log: &str, source: Source) -> Vec<Result<(String, String), String>> {
+        Reader::new(log.as_bytes(), source, 100)
+            .map(|res| {
+                res.map(|entry| (format!("{:?}", entry.message), entry.reference))
+                    .map_err(|err| err.to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn executions() {
+        let log = [
+            // a fill with quantity and price
+            format!(
+                "20260101-10:00:00 {}",
+                message("8=FIX.4.4|35=8|49=BROKER|1=7|17=E1|150=F|54=1|32=10|31=2.5")
+            ),
+            // not a fill
+            message("8=FIX.4.4|35=8|1=7|17=E2|150=0|54=1"),
+            // a resend and a repeated report of the first fill
+            message("8=FIX.4.4|35=8|43=Y|1=7|17=E1|150=F|54=1|32=10|31=2.5"),
+            message("8=FIX.4.4|35=8|1=7|17=E1|150=F|54=1|32=10|31=2.5"),
+            // a pipe-delimited fill with the gross amount, before FIX 4.3
+            "8=FIX.4.2|35=8|1=8|17=E3|150=2|54=2|381=100.125|10=000|".to_string(),
+            // a heartbeat and a log line
+            message("8=FIX.4.4|35=0"),
+            "session logon".to_string(),
+            message("8=FIX.4.4|35=8|1=ACC|17=E4|150=F|54=1|32=1|31=1"),
+        ]
+        .join("\n");
+        let mut entries = convert(&log, Source::Executions);
+        // the checksum of the pipe-delimited message is wrong
+        assert_eq!(
+            entries.remove(1),
+            Err(
+                "Invalid FIX message in line 5: `checksum 000 doesn't match the message (210)`."
+                    .into()
+            )
+        );
+        assert_eq!(
+            entries,
+            [
+                Ok((
+                    "Withdrawal { client: 7, tx: 100, amount: 250000, trade: None }".into(),
+                    "E1".into()
+                )),
+                Err("Invalid FIX message in line 8: `account `ACC` is no client id`.".into())
+            ]
+        );
+
+        let log = log.replace("10=000|", "10=210|");
+        assert_eq!(
+            convert(&log, Source::Executions)[1],
+            Ok((
+                "Deposit { client: 8, tx: 101, amount: 1001250, trade: None }".into(),
+                "E3".into()
+            ))
+        );
+        assert_eq!(convert(&log, Source::Allocations), []);
+    }
+
+    #[test]
+    fn allocations() {
+        let log = [
+            message("8=FIX.4.4|35=J|70=A1|71=0|54=2|6=4|78=2|79=1|80=5|79=2|154=12.5"),
+            message("8=FIX.4.4|35=AS|70=A2|71=2|54=2|78=1|79=1|80=5"),
+        ]
+        .join("\n");
+        assert_eq!(
+            convert(&log, Source::Allocations),
+            [
+                Ok((
+                    "Deposit { client: 1, tx: 100, amount: 200000, trade: None }".into(),
+                    "A1/1".into()
+                )),
+                Ok((
+                    "Deposit { client: 2, tx: 101, amount: 125000, trade: None }".into(),
+                    "A1/2".into()
+                )),
+                Err("Invalid FIX message in line 2: `unsupported AllocTransType `2``.".into())
+            ]
+        );
+    }
+}
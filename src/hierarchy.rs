@@ -0,0 +1,194 @@
+/**
+ * Parent/child relationships between accounts, like an omnibus account with its sub-ledgers.
+ *
+ * The relationships are read from a file at startup with one `<child>,<parent>` pair per line.
+ * Accounts keep their own balances, the roll-up report adds up the balances of every parent and
+ * all its descendants.
+ */
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::processor;
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum Error {
+    #[error("Invalid relationship `{1}` in line {0}, expected `<child>,<parent>`.")]
+    Line(usize, String),
+    #[error("Client {child} has the parents {first} and {second}.")]
+    Parents { child: u16, first: u16, second: u16 },
+    #[error("Client {0} is its own ancestor.")]
+    Cycle(u16),
+}
+
+/**
+ * The parent of every sub-account.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Hierarchy {
+    parents: BTreeMap<u16, u16>,
+}
+
+/**
+ * The balances of a parent account including all its descendants.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RollUp {
+    pub client: u16,
+    pub available: i64,
+    pub held: i64,
+    pub total: i64,
+    /// Number of accounts added up, the parent's own one included if it exists.
+    pub accounts: u32,
+    /// Number of locked accounts among them.
+    pub locked: u32,
+}
+
+impl Hierarchy {
+    /**
+     * Parses the relationship file. Empty lines and lines starting with `#` are skipped, a repeated
+     * pair is accepted.
+     */
+    pub fn parse(s: &str) -> Result<Hierarchy, Error> {
+        let mut hierarchy = Hierarchy::default();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (child, parent) = line
+                .split_once(',')
+                .and_then(|(child, parent)| {
+                    Some((child.trim().parse().ok()?, parent.trim().parse().ok()?))
+                })
+                .ok_or_else(|| Error::Line(i + 1, line.to_string()))?;
+            match hierarchy.parents.insert(child, parent) {
+                Some(first) if first != parent => {
+                    return Err(Error::Parents {
+                        child,
+                        first,
+                        second: parent,
+                    })
+                }
+                _ => (),
+            }
+        }
+        for child in hierarchy.parents.keys() {
+            hierarchy.ancestors(*child)?;
+        }
+        Ok(hierarchy)
+    }
+
+    pub fn parent(&self, child: u16) -> Option<u16> {
+        self.parents.get(&child).copied()
+    }
+
+    // The parent, grandparent and so on of a client.
+    fn ancestors(&self, client: u16) -> Result<Vec<u16>, Error> {
+        let mut ancestors = Vec::new();
+        let mut current = client;
+        while let Some(parent) = self.parent(current) {
+            if parent == client || ancestors.contains(&parent) {
+                return Err(Error::Cycle(parent));
+            }
+            ancestors.push(parent);
+            current = parent;
+        }
+        Ok(ancestors)
+    }
+
+    /**
+     * Adds up the balances of every parent and its descendants, ordered by the parent's client id.
+     * Parents are listed even without accounts of their own or of their descendants.
+     */
+    pub fn roll_up<'a>(
+        &self,
+        states: impl IntoIterator<Item = &'a processor::State>,
+    ) -> Vec<RollUp> {
+        let parents: BTreeSet<u16> = self.parents.values().copied().collect();
+        let mut roll_ups: BTreeMap<u16, RollUp> = parents
+            .into_iter()
+            .map(|client| {
+                let roll_up = RollUp {
+                    client,
+                    ..RollUp::default()
+                };
+                (client, roll_up)
+            })
+            .collect();
+        for s in states {
+            // cycles are rejected when parsing
+            let ancestors = self.ancestors(s.client).unwrap_or_default();
+            for client in std::iter::once(s.client).chain(ancestors) {
+                if let Some(roll_up) = roll_ups.get_mut(&client) {
+                    roll_up.available += s.available;
+                    roll_up.held += s.held;
+                    roll_up.total += s.total;
+                    roll_up.accounts += 1;
+                    roll_up.locked += u32::from(s.locked);
+                }
+            }
+        }
+        roll_ups.into_values().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roll_up() {
+        let hierarchy = Hierarchy::parse("# omnibus\n2,1\n3, 1\n\n4,3\n4,3\n6,5\n").unwrap();
+        assert_eq!(hierarchy.parent(4), Some(3));
+        let state = |client, available, locked| processor::State {
+            client,
+            available,
+            held: 1,
+            total: available + 1,
+            locked,
+            pending: 0,
+        };
+        let states = [
+            state(1, 10, false),
+            state(2, 20, false),
+            state(4, 40, true),
+            state(7, 70, false),
+        ];
+        assert_eq!(
+            hierarchy.roll_up(&states),
+            [
+                RollUp {
+                    client: 1,
+                    available: 70,
+                    held: 3,
+                    total: 73,
+                    accounts: 3,
+                    locked: 1,
+                },
+                RollUp {
+                    client: 3,
+                    available: 40,
+                    held: 1,
+                    total: 41,
+                    accounts: 1,
+                    locked: 1,
+                },
+                RollUp {
+                    client: 5,
+                    ..RollUp::default()
+                },
+            ]
+        );
+
+        assert_eq!(
+            Hierarchy::parse("2,1\n2,3\n"),
+            Err(Error::Parents {
+                child: 2,
+                first: 1,
+                second: 3
+            })
+        );
+        assert_eq!(Hierarchy::parse("1,2\n2,3\n3,1\n"), Err(Error::Cycle(1)));
+        assert_eq!(Hierarchy::parse("1,1\n"), Err(Error::Cycle(1)));
+        assert_eq!(Hierarchy::parse("2 1\n"), Err(Error::Line(1, "2 1".into())));
+    }
+}
@@ -0,0 +1,311 @@
+/**
+ * Read-only queries over the state in a snapshot, answered by the interactive `inspect` command.
+ *
+ * Every input line is one query, like `account 42`, `tx 100`, `top 10 held` or `locked`. Lists of
+ * accounts are printed in the report format. Invalid queries only print an error, they don't end
+ * the session.
+ */
+use std::io::{self, BufRead, Write};
+
+use crate::{amount, cli, processor, snapshot};
+
+const HELP: &str = "\
+account <client>              balances, disputes, hold and metadata of an account
+tx <id>                       the transactions with this id
+top <n> available|held|total  the accounts with the most funds
+locked                        the locked accounts
+holds                         the accounts on hold
+quit                          end the session";
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum Error {
+    #[error("Unknown query `{0}`, try `help`.")]
+    Unknown(String),
+    #[error("Invalid argument `{0}`, try `help`.")]
+    Argument(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    Available,
+    Held,
+    Total,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Query {
+    Account(u16),
+    Tx(u32),
+    Top { n: usize, column: Column },
+    Locked,
+    OnHold,
+    Help,
+    Quit,
+}
+
+impl Query {
+    /**
+     * Parses a query, `None` for an empty line.
+     */
+    pub fn parse(line: &str) -> Result<Option<Query>, Error> {
+        fn arg<T: std::str::FromStr>(arg: Option<&str>) -> Result<T, Error> {
+            let arg = arg.unwrap_or_default();
+            arg.parse().map_err(|_| Error::Argument(arg.to_string()))
+        }
+
+        let mut words = line.split_whitespace();
+        let query = match words.next() {
+            None => return Ok(None),
+            Some("account") => Query::Account(arg(words.next())?),
+            Some("tx") => Query::Tx(arg(words.next())?),
+            Some("top") => Query::Top {
+                n: arg(words.next())?,
+                column: match words.next() {
+                    Some("available") => Column::Available,
+                    Some("held") => Column::Held,
+                    Some("total") => Column::Total,
+                    column => return Err(Error::Argument(column.unwrap_or_default().into())),
+                },
+            },
+            Some("locked") => Query::Locked,
+            Some("holds") => Query::OnHold,
+            Some("help") => Query::Help,
+            Some("quit" | "exit") => Query::Quit,
+            Some(query) => return Err(Error::Unknown(query.to_string())),
+        };
+        match words.next() {
+            Some(extra) => Err(Error::Argument(extra.to_string())),
+            None => Ok(Some(query)),
+        }
+    }
+}
+
+fn state(client: u16, parts: &crate::account::Parts) -> processor::State {
+    processor::State {
+        client,
+        available: parts.available,
+        held: parts.held,
+        total: parts.available + parts.held,
+        locked: parts.locked,
+        pending: parts.pending.iter().map(|(_, amount)| amount).sum(),
+    }
+}
+
+/**
+ * Writes the answer to a query.
+ */
+pub fn answer<W: Write>(contents: &snapshot::Contents, query: Query, mut w: W) -> io::Result<()> {
+    let states = contents
+        .accounts
+        .iter()
+        .map(|(client, parts)| state(*client, parts));
+    match query {
+        Query::Account(client) => {
+            let parts = match contents.accounts.iter().find(|(c, _)| *c == client) {
+                Some((_, parts)) => parts,
+                None => match contents.links.primary(client) {
+                    Some(primary) => {
+                        return writeln!(w, "Client {client} is linked to client {primary}.")
+                    }
+                    None => return writeln!(w, "No account of client {client}."),
+                },
+            };
+            cli::write_report(&mut w, [&state(client, parts)])?;
+            writeln!(
+                w,
+                "transactions: {}, disputes opened: {}",
+                parts.log.len(),
+                contents.disputes.get(&client).copied().unwrap_or_default()
+            )?;
+            if contents.holds.contains(&client) {
+                writeln!(w, "on hold")?;
+            }
+            let members: Vec<_> = contents
+                .links
+                .members(client)
+                .map(|c| c.to_string())
+                .collect();
+            if !members.is_empty() {
+                writeln!(w, "linked clients: {}", members.join(", "))?;
+            }
+            if let Some(metadata) = contents.metadata.get(&client) {
+                let values = metadata
+                    .values
+                    .iter()
+                    .map(|(key, value)| format!("{key}={value}"));
+                let tags = metadata.tags.iter().map(|tag| format!("tag={tag}"));
+                writeln!(
+                    w,
+                    "metadata: {}",
+                    values.chain(tags).collect::<Vec<_>>().join(", ")
+                )?;
+            }
+            for tx in &parts.disputes {
+                let amount = parts
+                    .log
+                    .binary_search_by_key(tx, |(tx, _)| *tx)
+                    .map_or(0, |i| parts.log[i].1);
+                write!(w, "open dispute of tx {tx}: {}", amount::Decimal(amount))?;
+                match contents.reasons.get(&(client, *tx)) {
+                    Some(reason) => writeln!(w, ", reason {reason}")?,
+                    None => writeln!(w)?,
+                }
+            }
+            Ok(())
+        }
+        Query::Tx(tx) => {
+            let mut found = false;
+            for (client, parts) in &contents.accounts {
+                let amount = match parts.log.binary_search_by_key(&tx, |(tx, _)| *tx) {
+                    Ok(i) => parts.log[i].1,
+                    Err(_) => continue,
+                };
+                let kind = if amount < 0 { "withdrawal" } else { "deposit" };
+                let status = if parts.disputes.contains(&tx) {
+                    "disputed"
+                } else if parts.reversed.contains(&tx) {
+                    "reversed"
+                } else {
+                    "settled"
+                };
+                writeln!(
+                    w,
+                    "client {client}: {kind} of {}, {status}",
+                    amount::Decimal(amount.abs())
+                )?;
+                found = true;
+            }
+            if !found {
+                writeln!(w, "No transaction {tx}.")?;
+            }
+            Ok(())
+        }
+        Query::Top { n, column } => {
+            let mut states: Vec<_> = states.collect();
+            let key = |s: &processor::State| match column {
+                Column::Available => s.available,
+                Column::Held => s.held,
+                Column::Total => s.total,
+            };
+            // ties in client order
+            states.sort_by_key(|s| (std::cmp::Reverse(key(s)), s.client));
+            cli::write_report(w, states.iter().take(n))
+        }
+        Query::Locked => {
+            let locked: Vec<_> = states.filter(|s| s.locked).collect();
+            cli::write_report(w, &locked)
+        }
+        Query::OnHold => {
+            let held: Vec<_> = states
+                .filter(|s| contents.holds.contains(&s.client))
+                .collect();
+            cli::write_report(w, &held)
+        }
+        Query::Help => writeln!(w, "{HELP}"),
+        Query::Quit => Ok(()),
+    }
+}
+
+/**
+ * Answers the queries read from the input until it ends or a `quit`. With `prompt`, a prompt is
+ * written before every query.
+ */
+pub fn run<R: BufRead, W: Write>(
+    contents: &snapshot::Contents,
+    input: R,
+    mut output: W,
+    prompt: bool,
+) -> io::Result<()> {
+    let mut lines = input.lines();
+    loop {
+        if prompt {
+            write!(output, "> ")?;
+            output.flush()?;
+        }
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => return Ok(()),
+        };
+        match Query::parse(&line) {
+            Ok(Some(Query::Quit)) => return Ok(()),
+            Ok(Some(query)) => answer(contents, query, &mut output)?,
+            Ok(None) => (),
+            Err(err) => writeln!(output, "{err}")?,
+        }
+        output.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{account::Parts, metadata::Metadata};
+
+    #[test]
+    fn queries() {
+        let mut contents = snapshot::Contents {
+            accounts: vec![
+                (
+                    1,
+                    Parts {
+                        available: 10000,
+                        held: 50000,
+                        log: vec![(1, 50000), (2, 20000), (3, -10000)],
+                        disputes: vec![1],
+                        ..Parts::default()
+                    },
+                ),
+                (
+                    2,
+                    Parts {
+                        available: 30000,
+                        locked: true,
+                        log: vec![(1, 30000)],
+                        ..Parts::default()
+                    },
+                ),
+            ],
+            ..Default::default()
+        };
+        contents.disputes.insert(1, 2);
+        contents.reasons.insert((1, 1), "10.4".into());
+        contents.holds.insert(1);
+        contents.links.insert(3, 1);
+        contents.metadata.insert(
+            1,
+            Metadata {
+                values: [("tier".into(), "high".into())].into_iter().collect(),
+                tags: ["vip".into()].into_iter().collect(),
+            },
+        );
+
+        let input =
+            "account 1\naccount 3\n\ntx 1\ntx 4\ntop 1 available\nlocked\nbalance\ntop x held\nquit\nlocked\n";
+        let mut output = Vec::new();
+        run(&contents, input.as_bytes(), &mut output, false).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n\
+             1,1.0000,5.0000,6.0000,false\n\
+             transactions: 3, disputes opened: 2\n\
+             on hold\n\
+             linked clients: 3\n\
+             metadata: tier=high, tag=vip\n\
+             open dispute of tx 1: 5.0000, reason 10.4\n\
+             Client 3 is linked to client 1.\n\
+             client 1: deposit of 5.0000, disputed\n\
+             client 2: deposit of 3.0000, settled\n\
+             No transaction 4.\n\
+             client,available,held,total,locked\n\
+             2,3.0000,0.0000,3.0000,true\n\
+             client,available,held,total,locked\n\
+             2,3.0000,0.0000,3.0000,true\n\
+             Unknown query `balance`, try `help`.\n\
+             Invalid argument `x`, try `help`.\n"
+        );
+        assert_eq!(
+            Query::parse("account 1 2"),
+            Err(Error::Argument("2".into()))
+        );
+    }
+}
@@ -0,0 +1,361 @@
+/**
+ * Minimal JSON reader for state dumps, and writer for generated documents like schemas.
+ *
+ * Numbers are limited to integers, which is all the dumps contain. Amounts are written as decimal
+ * strings so they keep their exact value.
+ */
+use std::{
+    fmt::{self, Write},
+    str,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(i64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum Error {
+    #[error("Invalid JSON at byte {0}.")]
+    Syntax(usize),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Writes the value with an indentation of two spaces per level, or on a single line with `{:#}`.
+ */
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+impl Value {
+    fn write(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        let (open, close, len) = match self {
+            Value::Null => return write!(f, "null"),
+            Value::Bool(b) => return write!(f, "{b}"),
+            Value::Number(n) => return write!(f, "{n}"),
+            Value::String(s) => return write!(f, "\"{}\"", escape(s)),
+            Value::Array(values) => ('[', ']', values.len()),
+            Value::Object(fields) => ('{', '}', fields.len()),
+        };
+        f.write_char(open)?;
+        for i in 0..len {
+            if i > 0 {
+                f.write_char(',')?;
+            }
+            if f.alternate() {
+                if i > 0 {
+                    f.write_char(' ')?;
+                }
+            } else {
+                write!(f, "\n{:indent$}", "", indent = 2 * (depth + 1))?;
+            }
+            match self {
+                Value::Array(values) => values[i].write(f, depth + 1)?,
+                Value::Object(fields) => {
+                    write!(f, "\"{}\": ", escape(&fields[i].0))?;
+                    fields[i].1.write(f, depth + 1)?;
+                }
+                _ => unreachable!(),
+            }
+        }
+        if len > 0 && !f.alternate() {
+            write!(f, "\n{:indent$}", "", indent = 2 * depth)?;
+        }
+        f.write_char(close)
+    }
+}
+
+/**
+ * Escapes a string for use within JSON quotes.
+ */
+pub fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn parse(s: &str) -> Result<Value, Error> {
+    let mut parser = Parser {
+        input: s.as_bytes(),
+        pos: 0,
+    };
+    let value = parser.value()?;
+    parser.whitespace();
+    if parser.pos != parser.input.len() {
+        return Err(Error::Syntax(parser.pos));
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn err<T>(&self) -> Result<T, Error> {
+        Err(Error::Syntax(self.pos))
+    }
+
+    fn whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.input.get(self.pos) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.whitespace();
+        self.input.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, token: &[u8]) -> Result<(), Error> {
+        if self.input[self.pos..].starts_with(token) {
+            self.pos += token.len();
+            Ok(())
+        } else {
+            self.err()
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, Error> {
+        match self.peek() {
+            Some(b'n') => self.expect(b"null").map(|()| Value::Null),
+            Some(b't') => self.expect(b"true").map(|()| Value::Bool(true)),
+            Some(b'f') => self.expect(b"false").map(|()| Value::Bool(false)),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'[') => self.array(),
+            Some(b'{') => self.object(),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            _ => self.err(),
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, Error> {
+        let start = self.pos;
+        if self.input[self.pos] == b'-' {
+            self.pos += 1;
+        }
+        while let Some(b'0'..=b'9') = self.input.get(self.pos) {
+            self.pos += 1;
+        }
+        str::from_utf8(&self.input[start..self.pos])
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .map(Value::Number)
+            .ok_or(Error::Syntax(start))
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        self.expect(b"\"")?;
+        let mut s = String::new();
+        loop {
+            let start = self.pos;
+            while let Some(b) = self.input.get(self.pos) {
+                if *b == b'"' || *b == b'\\' || *b < 0x20 {
+                    break;
+                }
+                self.pos += 1;
+            }
+            // the input is a str and the run ends at an ASCII byte
+            s.push_str(str::from_utf8(&self.input[start..self.pos]).unwrap());
+            match self.input.get(self.pos) {
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(s);
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let c = match self.input.get(self.pos) {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            let hex = self
+                                .input
+                                .get(self.pos + 1..self.pos + 5)
+                                .and_then(|hex| str::from_utf8(hex).ok())
+                                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                                .and_then(char::from_u32);
+                            match hex {
+                                Some(c) => {
+                                    self.pos += 4;
+                                    c
+                                }
+                                // surrogate pairs aren't needed for dumps
+                                None => return self.err(),
+                            }
+                        }
+                        _ => return self.err(),
+                    };
+                    self.pos += 1;
+                    s.push(c);
+                }
+                _ => return self.err(),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, Error> {
+        self.expect(b"[")?;
+        let mut values = Vec::new();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Value::Array(values));
+        }
+        loop {
+            values.push(self.value()?);
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Value::Array(values));
+                }
+                _ => return self.err(),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, Error> {
+        self.expect(b"{")?;
+        let mut fields = Vec::new();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Value::Object(fields));
+        }
+        loop {
+            if self.peek() != Some(b'"') {
+                return self.err();
+            }
+            let key = self.string()?;
+            if self.peek() != Some(b':') {
+                return self.err();
+            }
+            self.pos += 1;
+            fields.push((key, self.value()?));
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Value::Object(fields));
+                }
+                _ => return self.err(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values() {
+        let value =
+            parse(r#" {"a": [1, -2, true, null], "b": {"c": "x\"é\n"}, "d": []} "#).unwrap();
+        assert_eq!(
+            value.get("a").and_then(Value::as_array),
+            Some(
+                &[
+                    Value::Number(1),
+                    Value::Number(-2),
+                    Value::Bool(true),
+                    Value::Null
+                ][..]
+            )
+        );
+        assert_eq!(
+            value
+                .get("b")
+                .and_then(|b| b.get("c"))
+                .and_then(Value::as_str),
+            Some("x\"é\n")
+        );
+        assert_eq!(value.get("d"), Some(&Value::Array(Vec::new())));
+        assert_eq!(value.get("e"), None);
+    }
+
+    #[test]
+    fn write() {
+        let value = parse(r#"{"a": [1, "x\"\n"], "b": {}, "c": null}"#).unwrap();
+        assert_eq!(
+            value.to_string(),
+            "{\n  \"a\": [\n    1,\n    \"x\\\"\\n\"\n  ],\n  \"b\": {},\n  \"c\": null\n}"
+        );
+        assert_eq!(
+            format!("{value:#}"),
+            r#"{"a": [1, "x\"\n"], "b": {}, "c": null}"#
+        );
+        assert_eq!(parse(&value.to_string()).unwrap(), value);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse(""), Err(Error::Syntax(0)));
+        assert_eq!(parse("[1,]"), Err(Error::Syntax(3)));
+        assert_eq!(parse("{\"a\" 1}"), Err(Error::Syntax(5)));
+        assert_eq!(parse("1 2"), Err(Error::Syntax(2)));
+        assert_eq!(parse("\"abc"), Err(Error::Syntax(4)));
+        assert_eq!(parse("99999999999999999999"), Err(Error::Syntax(0)));
+    }
+}
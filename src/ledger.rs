@@ -0,0 +1,704 @@
+/**
+ * Independent double-entry bookkeeping of a write-ahead log, to cross-check the processor.
+ *
+ * Every accepted message posts an entry moving an amount between two books: the available or held
+ * funds of a client, or the outside world which deposits come from and withdrawals, captures and
+ * chargebacks go to. All books together therefore always sum up to zero. Authorizations and
+ * withdrawals waiting for an approval post no entry, they only earmark available funds until they
+ * are captured, approved, rejected or expired. Whether a withdrawal waits and when an authorization
+ * expires depends on the configuration of the run, which the log doesn't record, so `verify` and
+ * `export` take it. `verify` replays a log through both the ledger and the processor and compares
+ * the resulting balances, so a bug in either implementation shows up as drift between them.
+ *
+ * `export` writes the entries as journal for the accounting team, with the books mapped to accounts
+ * of their chart of accounts.
+ */
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+    fs::File,
+    io::{self, Write},
+    path::Path,
+};
+
+use tokio::sync::oneshot;
+
+use crate::{
+    amount, cli,
+    processor::{self, Message},
+    reconcile,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error: `{0}`.")]
+    Io(#[from] std::io::Error),
+    #[error("Could not communicate with the processor.")]
+    Processor,
+    #[error("The books of the ledger sum up to {0} instead of zero.")]
+    Unbalanced(i64),
+    #[error("The ledger and the processor disagree in {0} cases.")]
+    Drift(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Book {
+    World,
+    Available(u16),
+    Held(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Settled,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+#[derive(Debug, Default)]
+pub struct Ledger {
+    books: BTreeMap<Book, i64>,
+    /// Signed amount and dispute status of every transaction by client and id.
+    txs: HashMap<(u16, u32), (i64, Status)>,
+    /// Clients with an account, opened by their first deposit even if it's rejected.
+    clients: BTreeSet<u16>,
+    locked: BTreeSet<u16>,
+    /// Amount and expiry time of the open authorizations by client and id. They earmark available
+    /// funds but post no entry until captured.
+    authorized: HashMap<(u16, u32), (i64, u64)>,
+    /// Amount of the withdrawals waiting for an approval by client and id, earmarked like
+    /// authorizations.
+    pending: HashMap<(u16, u32), i64>,
+    approve_above: Option<i64>,
+    authorization_ttl: Option<u64>,
+    entries: u64,
+}
+
+impl Ledger {
+    /**
+     * An empty ledger holding withdrawals for approval and expiring authorizations like a processor
+     * with the given configuration.
+     */
+    pub fn new(config: &processor::Config) -> Ledger {
+        Ledger {
+            approve_above: config.approve_above,
+            authorization_ttl: config.authorization_ttl,
+            ..Ledger::default()
+        }
+    }
+
+    fn balance(&self, book: Book) -> i64 {
+        self.books.get(&book).copied().unwrap_or_default()
+    }
+
+    // Moves an amount from one book to another.
+    fn post(&mut self, from: Book, to: Book, amount: i64) {
+        *self.books.entry(from).or_default() -= amount;
+        *self.books.entry(to).or_default() += amount;
+        self.entries += 1;
+    }
+
+    // Funds which can be withdrawn or authorized.
+    fn unearmarked(&self, client: u16) -> i64 {
+        let authorized: i64 = self
+            .authorized
+            .iter()
+            .filter(|((c, _), _)| *c == client)
+            .map(|(_, (amount, _))| amount)
+            .sum();
+        let pending: i64 = self
+            .pending
+            .iter()
+            .filter(|((c, _), _)| *c == client)
+            .map(|(_, amount)| amount)
+            .sum();
+        self.balance(Book::Available(client)) - authorized - pending
+    }
+
+    /**
+     * Books a message with the timestamp of its row and returns whether it posted an entry.
+     */
+    pub fn apply(&mut self, msg: &Message, timestamp: Option<u64>) -> bool {
+        self.book(msg, timestamp).is_some()
+    }
+
+    // Books a message and returns the entry, if it was accepted and moves funds.
+    fn book(&mut self, msg: &Message, timestamp: Option<u64>) -> Option<(Book, Book, i64)> {
+        // like the processor, expire by event time before the message
+        if let Some(now) = timestamp {
+            self.authorized.retain(|_, (_, expires)| *expires > now);
+        }
+        let (client, tx) = msg.target()?;
+        if let Message::Deposit { .. } = msg {
+            self.clients.insert(client);
+        }
+        if !self.clients.contains(&client) || self.locked.contains(&client) {
+            return None;
+        }
+        let status = self.txs.get(&(client, tx)).copied();
+        let authorization = self.authorized.get(&(client, tx)).copied();
+        let pending = self.pending.get(&(client, tx)).copied();
+        let earmarked = authorization.is_some() || pending.is_some();
+        let (from, to, amount) = match (msg, status) {
+            (Message::Deposit { amount, .. }, None) if *amount >= 0 && !earmarked => {
+                self.txs.insert((client, tx), (*amount, Status::Settled));
+                (Book::World, Book::Available(client), *amount)
+            }
+            (Message::Withdrawal { amount, .. }, None)
+                if *amount >= 0 && !earmarked && self.unearmarked(client) >= *amount =>
+            {
+                if self.approve_above.is_some_and(|limit| *amount > limit) {
+                    self.pending.insert((client, tx), *amount);
+                    return None;
+                }
+                self.txs.insert((client, tx), (-amount, Status::Settled));
+                (Book::Available(client), Book::World, *amount)
+            }
+            // a dispute may have taken the earmarked funds
+            (Message::Approve { .. }, None) => match pending {
+                Some(amount) if self.balance(Book::Available(client)) >= amount => {
+                    self.pending.remove(&(client, tx));
+                    self.txs.insert((client, tx), (-amount, Status::Settled));
+                    (Book::Available(client), Book::World, amount)
+                }
+                _ => return None,
+            },
+            (Message::Reject { .. }, None) => {
+                self.pending.remove(&(client, tx));
+                return None;
+            }
+            (Message::Authorize { amount, .. }, None)
+                if *amount >= 0 && !earmarked && self.unearmarked(client) >= *amount =>
+            {
+                let expires = match (self.authorization_ttl, timestamp) {
+                    (Some(ttl), Some(timestamp)) => timestamp.saturating_add(ttl),
+                    _ => u64::MAX,
+                };
+                self.authorized.insert((client, tx), (*amount, expires));
+                return None;
+            }
+            (Message::Capture { .. }, None) => match authorization {
+                Some((amount, _)) if self.balance(Book::Available(client)) >= amount => {
+                    self.authorized.remove(&(client, tx));
+                    self.txs.insert((client, tx), (-amount, Status::Settled));
+                    (Book::Available(client), Book::World, amount)
+                }
+                _ => return None,
+            },
+            (Message::Dispute { .. }, Some((amount, Status::Settled | Status::Resolved))) => {
+                self.txs.insert((client, tx), (amount, Status::Disputed));
+                (Book::Available(client), Book::Held(client), amount)
+            }
+            (Message::Resolve { .. }, Some((amount, Status::Disputed))) => {
+                self.txs.insert((client, tx), (amount, Status::Resolved));
+                (Book::Held(client), Book::Available(client), amount)
+            }
+            (Message::Chargeback { .. }, Some((amount, Status::Disputed))) => {
+                self.txs.insert((client, tx), (amount, Status::ChargedBack));
+                self.locked.insert(client);
+                (Book::Held(client), Book::World, amount)
+            }
+            _ => return None,
+        };
+        self.post(from, to, amount);
+        Some((from, to, amount))
+    }
+
+    /**
+     * The sum of all books, zero unless an entry got lost.
+     */
+    pub fn imbalance(&self) -> i64 {
+        self.books.values().sum()
+    }
+
+    /**
+     * The balances of all accounts, to compare them with `reconcile::compare`.
+     */
+    pub fn expected(&self) -> Vec<reconcile::Expected> {
+        self.clients
+            .iter()
+            .map(|client| {
+                let available = self.balance(Book::Available(*client));
+                let held = self.balance(Book::Held(*client));
+                reconcile::Expected {
+                    client: *client,
+                    available: Some(available),
+                    held: Some(held),
+                    total: Some(available + held),
+                    locked: Some(self.locked.contains(client)),
+                }
+            })
+            .collect()
+    }
+}
+
+/**
+ * Outcome of a verification.
+ */
+#[derive(Debug)]
+pub struct Report {
+    pub messages: u64,
+    pub entries: u64,
+    /// Differences between the processor (actual) and the ledger (expected).
+    pub mismatches: Vec<reconcile::Mismatch>,
+}
+
+/**
+ * Replays the write-ahead log at the given path through the ledger and a processor with the given
+ * configuration, the one of the run writing the log, and compares their balances.
+ */
+pub async fn verify<P: AsRef<Path>>(path: P, config: processor::Config) -> Result<Report, Error> {
+    let mut ledger = Ledger::new(&config);
+    // rejections aren't reported, they only matter if the ledger disagrees
+    let (tx_msg, _) = processor::run(config, processor::Storage::default()).await;
+
+    let mut messages = 0;
+    let mut batch = processor::Batch::default();
+    let source = path.as_ref().to_string_lossy().into();
+    let mut msgs = cli::read_csv(File::open(&path)?);
+    while let Some(res) = msgs.next() {
+        // like on recovery, a torn last line is skipped
+        let msg = match res {
+            Ok(msg) => msg,
+            Err(_) => continue,
+        };
+        // the processor expires authorizations by the timestamps of the origins
+        let origin = msgs.origin(&source);
+        ledger.apply(&msg, origin.timestamp);
+        messages += 1;
+        batch.push(msg, origin);
+        if batch.len() == processor::BATCH_SIZE {
+            let full = std::mem::take(&mut batch);
+            tx_msg
+                .send(Message::Batch(full))
+                .await
+                .map_err(|_| Error::Processor)?;
+        }
+    }
+    tx_msg
+        .send(Message::Batch(batch))
+        .await
+        .map_err(|_| Error::Processor)?;
+    let (tx, rx) = oneshot::channel();
+    tx_msg
+        .send(Message::GetState { tx })
+        .await
+        .map_err(|_| Error::Processor)?;
+    let state = rx.await.map_err(|_| Error::Processor)?;
+
+    match ledger.imbalance() {
+        0 => Ok(Report {
+            messages,
+            entries: ledger.entries,
+            mismatches: reconcile::compare(&ledger.expected(), &state, 0),
+        }),
+        sum => Err(Error::Unbalanced(sum)),
+    }
+}
+
+/**
+ * Output format of `export`.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Plain text journal of ledger-cli, hledger and beancount's importers.
+    Ledger,
+    /// Intuit Interchange Format, imported by QuickBooks.
+    Iif,
+}
+
+/**
+ * Names of the accounts the books are posted to. `{client}` in the client account names is replaced
+ * with the client id.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chart {
+    /// Where deposits come from and withdrawals go to.
+    pub cash: String,
+    /// Where chargebacks go to.
+    pub chargebacks: String,
+    pub available: String,
+    pub held: String,
+}
+
+impl Default for Chart {
+    fn default() -> Self {
+        Self {
+            cash: "Assets:Cash".to_string(),
+            chargebacks: "Assets:Cash".to_string(),
+            available: "Liabilities:Clients:{client}:Available".to_string(),
+            held: "Liabilities:Clients:{client}:Held".to_string(),
+        }
+    }
+}
+
+impl Chart {
+    fn account(&self, book: Book, msg: &Message) -> String {
+        match book {
+            Book::World if matches!(msg, Message::Chargeback { .. }) => self.chargebacks.clone(),
+            Book::World => self.cash.clone(),
+            Book::Available(client) => self.available.replace("{client}", &client.to_string()),
+            Book::Held(client) => self.held.replace("{client}", &client.to_string()),
+        }
+    }
+}
+
+/**
+ * The date all journal entries are booked on, since the log doesn't record when messages arrived.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Date {
+    year: u16,
+    month: u8,
+    day: u8,
+}
+
+impl Date {
+    /**
+     * Parses an ISO 8601 date like `2023-01-31`.
+     */
+    pub fn parse(s: &str) -> Result<Date, String> {
+        let err = || format!("expected a date like `2023-01-31`, got `{s}`");
+        let mut parts = s.splitn(3, '-').map(str::parse::<u16>);
+        let (year, month, day) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(year)), Some(Ok(month)), Some(Ok(day))) => (year, month, day),
+            _ => return Err(err()),
+        };
+        if s.len() != 10 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return Err(err());
+        }
+        Ok(Self {
+            year,
+            month: month as u8,
+            day: day as u8,
+        })
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+/**
+ * Writes a journal entry for every message of the write-ahead log at the given path which the ledger
+ * accepts under the configuration of the run writing the log, and returns the number of entries. In the journal, the book funds leave is debited and
+ * the book they go to is credited, so a deposit debits the cash account and credits the client's
+ * available funds. References of the messages are added as comment or to the memo.
+ */
+pub fn export<P: AsRef<Path>, W: Write>(
+    path: P,
+    config: &processor::Config,
+    chart: &Chart,
+    format: Format,
+    date: Date,
+    writer: W,
+) -> Result<u64, Error> {
+    let mut writer = io::BufWriter::new(writer);
+    if format == Format::Iif {
+        writeln!(writer, "!TRNS\tTRNSTYPE\tDATE\tACCNT\tAMOUNT\tDOCNUM\tMEMO")?;
+        writeln!(writer, "!SPL\tTRNSTYPE\tDATE\tACCNT\tAMOUNT\tDOCNUM\tMEMO")?;
+        writeln!(writer, "!ENDTRNS")?;
+    }
+    let mut ledger = Ledger::new(config);
+    let source = path.as_ref().to_string_lossy().into();
+    let mut msgs = cli::read_csv(File::open(&path)?);
+    while let Some(res) = msgs.next() {
+        let origin = msgs.origin(&source);
+        let (msg, (from, to, amount)) =
+            match res.map(|msg| (ledger.book(&msg, origin.timestamp), msg)) {
+                Ok((Some(entry), msg)) => (msg, entry),
+                _ => continue,
+            };
+        let reference = origin.reference;
+        let (client, tx) = msg.target().unwrap_or_default();
+        let mut memo = format!("{} of client {client}", msg.kind().unwrap_or_default());
+        let (debit, credit) = (chart.account(from, &msg), chart.account(to, &msg));
+        match format {
+            Format::Ledger => {
+                writeln!(writer, "{date} ({tx}) {memo}")?;
+                if let Some(reference) = reference {
+                    writeln!(writer, "    ; reference: {reference}")?;
+                }
+                writeln!(writer, "    {debit}  {}", amount::Decimal(amount))?;
+                writeln!(writer, "    {credit}  {}", amount::Decimal(-amount))?;
+                writeln!(writer)?;
+            }
+            Format::Iif => {
+                if let Some(reference) = reference {
+                    // tabs and line breaks would break the row
+                    memo = format!(
+                        "{memo}, reference {}",
+                        reference.replace(char::is_whitespace, " ")
+                    );
+                }
+                let date = format!("{:02}/{:02}/{:04}", date.month, date.day, date.year);
+                let rows = [("TRNS", debit, amount), ("SPL", credit, -amount)];
+                for (kind, account, amount) in rows {
+                    writeln!(
+                        writer,
+                        "{kind}\tGENERAL JOURNAL\t{date}\t{account}\t{}\t{tx}\t{memo}",
+                        amount::Decimal(amount)
+                    )?;
+                }
+                writeln!(writer, "ENDTRNS")?;
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(ledger.entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn verify_log() {
+        // the input format is the log format
+        let report = verify("data/cases/sample/in.csv", processor::Config::default())
+            .await
+            .unwrap();
+        assert_eq!(report.messages, 18);
+        assert_eq!(report.entries, 12);
+        assert!(report.mismatches.is_empty(), "{:?}", report.mismatches);
+    }
+
+    #[test]
+    fn journal() {
+        let path = std::env::temp_dir().join(format!("trapez-{}-journal.csv", std::process::id()));
+        let input = std::fs::read_to_string("data/cases/disputes/in.csv").unwrap();
+        std::fs::write(
+            &path,
+            input.replacen("amount", "amount,reference", 1).replacen(
+                "deposit,1,1,10",
+                "deposit,1,1,10,P-1",
+                1,
+            ),
+        )
+        .unwrap();
+        let chart = Chart {
+            held: "Liabilities:Held:{client}".to_string(),
+            ..Chart::default()
+        };
+        let date = Date::parse("2023-01-31").unwrap();
+
+        let mut journal = Vec::new();
+        let entries = export(
+            &path,
+            &processor::Config::default(),
+            &chart,
+            Format::Ledger,
+            date,
+            &mut journal,
+        )
+        .unwrap();
+        let journal = String::from_utf8(journal).unwrap();
+        assert_eq!(journal.matches("\n\n").count() as u64, entries);
+        assert!(journal.starts_with(
+            "2023-01-31 (1) deposit of client 1\n    \
+             ; reference: P-1\n    \
+             Assets:Cash  10.0000\n    \
+             Liabilities:Clients:1:Available  -10.0000\n\n"
+        ));
+        assert!(journal.contains("Liabilities:Held:"));
+
+        let mut iif = Vec::new();
+        export(
+            &path,
+            &processor::Config::default(),
+            &chart,
+            Format::Iif,
+            date,
+            &mut iif,
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let iif = String::from_utf8(iif).unwrap();
+        assert_eq!(iif.matches("\nENDTRNS\n").count() as u64, entries);
+        assert!(iif.contains(
+            "\nTRNS\tGENERAL JOURNAL\t01/31/2023\tAssets:Cash\t10.0000\t1\tdeposit of client 1, reference P-1\n"
+        ));
+
+        assert!(Date::parse("2023-1-31").is_err());
+        assert!(Date::parse("2023-13-01").is_err());
+    }
+
+    #[tokio::test]
+    async fn authorizations() {
+        let path =
+            std::env::temp_dir().join(format!("trapez-{}-authorizations.csv", std::process::id()));
+        std::fs::write(
+            &path,
+            "type,client,tx,amount\ndeposit,1,1,10\nauthorize,1,2,4\ncapture,1,2,\n",
+        )
+        .unwrap();
+        let report = verify(&path, processor::Config::default()).await.unwrap();
+        assert_eq!(report.entries, 2);
+        assert!(report.mismatches.is_empty(), "{:?}", report.mismatches);
+        let mut journal = Vec::new();
+        let date = Date::parse("2023-01-31").unwrap();
+        export(
+            &path,
+            &processor::Config::default(),
+            &Chart::default(),
+            Format::Ledger,
+            date,
+            &mut journal,
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(String::from_utf8(journal).unwrap().ends_with(
+            "2023-01-31 (2) capture of client 1\n    \
+             Liabilities:Clients:1:Available  4.0000\n    \
+             Assets:Cash  -4.0000\n\n"
+        ));
+
+        let config = processor::Config {
+            authorization_ttl: Some(500),
+            ..processor::Config::default()
+        };
+        let mut ledger = Ledger::new(&config);
+        let deposit = Message::Deposit {
+            client: 1,
+            tx: 1,
+            amount: 10,
+            trade: None,
+        };
+        assert!(ledger.apply(&deposit, None));
+        let authorize = |tx, amount| Message::Authorize {
+            client: 1,
+            tx,
+            amount,
+        };
+        let withdrawal = |tx, amount| Message::Withdrawal {
+            client: 1,
+            tx,
+            amount,
+            trade: None,
+        };
+        let capture = |tx| Message::Capture { client: 1, tx };
+        assert!(!ledger.apply(&authorize(2, 4), Some(1_000)));
+        // earmarked
+        assert!(!ledger.apply(&withdrawal(3, 7), Some(1_100)));
+        // expired at 1_500
+        assert!(!ledger.apply(&capture(2), Some(1_500)));
+        assert!(ledger.apply(&withdrawal(4, 7), Some(1_500)));
+        assert!(!ledger.apply(&authorize(5, 2), Some(1_600)));
+        assert!(ledger.apply(&capture(5), Some(1_700)));
+        assert!(!ledger.apply(&capture(5), Some(1_700)));
+        assert_eq!(ledger.balance(Book::Available(1)), 1);
+        assert_eq!(ledger.imbalance(), 0);
+    }
+
+    #[tokio::test]
+    async fn approvals() {
+        let path =
+            std::env::temp_dir().join(format!("trapez-{}-approvals.csv", std::process::id()));
+        std::fs::write(
+            &path,
+            "type,client,tx,amount\n\
+             deposit,1,1,10\n\
+             withdrawal,1,2,8\n\
+             reject,1,2,\n\
+             withdrawal,1,3,6\n\
+             withdrawal,1,4,5\n\
+             approve,1,3,\n",
+        )
+        .unwrap();
+        let config = processor::Config {
+            approve_above: Some(5),
+            ..processor::Config::default()
+        };
+        let report = verify(&path, config.clone()).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(report.mismatches.is_empty(), "{:?}", report.mismatches);
+        // the rejected withdrawal isn't booked, the one of 5 finds 6 earmarked
+        assert_eq!(report.entries, 2);
+
+        let mut ledger = Ledger::new(&config);
+        let withdrawal = |tx, amount| Message::Withdrawal {
+            client: 1,
+            tx,
+            amount,
+            trade: None,
+        };
+        let deposit = Message::Deposit {
+            client: 1,
+            tx: 1,
+            amount: 10,
+            trade: None,
+        };
+        assert!(ledger.apply(&deposit, None));
+        assert!(!ledger.apply(&withdrawal(2, 8), None));
+        assert!(!ledger.apply(&withdrawal(3, 3), None));
+        assert!(!ledger.apply(&Message::Reject { client: 1, tx: 2 }, None));
+        assert!(ledger.apply(&withdrawal(3, 3), None));
+        assert_eq!(ledger.expected()[0].available, Some(7));
+    }
+
+    #[test]
+    fn drift() {
+        let mut ledger = Ledger::default();
+        for msg in [
+            Message::Deposit {
+                client: 1,
+                tx: 1,
+                amount: 10,
+                trade: None,
+            },
+            Message::Withdrawal {
+                client: 1,
+                tx: 2,
+                amount: 4,
+                trade: None,
+            },
+            Message::Dispute {
+                client: 1,
+                tx: 2,
+                reason: None,
+            },
+            Message::Chargeback {
+                client: 1,
+                tx: 2,
+                reason: None,
+            },
+            Message::Deposit {
+                client: 1,
+                tx: 3,
+                amount: 1,
+                trade: None,
+            },
+            Message::Withdrawal {
+                client: 2,
+                tx: 4,
+                amount: 0,
+                trade: None,
+            },
+        ] {
+            ledger.apply(&msg, None);
+        }
+        assert_eq!(ledger.imbalance(), 0);
+        assert_eq!(ledger.balance(Book::World), -10);
+
+        let mut state = processor::StateView::default();
+        state.set(processor::State {
+            client: 1,
+            available: 10,
+            held: 0,
+            total: 10,
+            locked: false,
+            pending: 0,
+        });
+        assert_eq!(
+            reconcile::compare(&ledger.expected(), &state, 0),
+            [reconcile::Mismatch::Locked {
+                client: 1,
+                expected: true,
+                actual: false
+            }]
+        );
+    }
+}
@@ -0,0 +1,35 @@
+pub mod account;
+pub mod amount;
+pub mod audit;
+pub mod bench;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod cli;
+pub mod erasure;
+pub mod fix;
+pub mod hierarchy;
+pub mod inspect;
+pub mod json;
+pub mod ledger;
+pub mod links;
+pub mod log;
+pub mod metadata;
+#[cfg(unix)]
+pub mod mmap;
+pub mod mt940;
+pub mod position;
+pub mod processor;
+pub mod reconcile;
+pub mod reorder;
+pub mod replay;
+#[cfg(any(test, feature = "chaos", feature = "testkit"))]
+pub mod rng;
+pub mod schema;
+pub mod sha256;
+pub mod snapshot;
+pub mod spill;
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
+pub mod throughput;
+pub mod wal;
+pub mod webhook;
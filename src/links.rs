@@ -0,0 +1,170 @@
+/**
+ * Joint accounts: client ids linked to the account of a primary client, like the members of a
+ * household modeled as several clients.
+ *
+ * The transactions of a linked client are booked on the primary's account and share its balance.
+ * They keep their own client id in the audit log, the write-ahead log and the error log, so the
+ * activity of every client can still be told apart. Links are only set by the `link` and `unlink`
+ * admin commands on snapshots, never while messages are processed. The commands refuse to run while
+ * the write-ahead log holds entries a run would recover, so replaying the log on top of a snapshot
+ * always sees the links the entries were booked with.
+ */
+use std::collections::BTreeMap;
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum Error {
+    #[error("Client {0} can't be linked to itself.")]
+    Itself(u16),
+    #[error("Client {client} is already linked to client {primary}.")]
+    Linked { client: u16, primary: u16 },
+    #[error("Client {0} is linked itself and can't be a primary client.")]
+    Chain(u16),
+    #[error("Client {0} has linked clients and can't be linked itself.")]
+    HasMembers(u16),
+    #[error("Client {0} has no account to link to.")]
+    NoAccount(u16),
+    #[error("Client {0} has an account of its own.")]
+    OwnAccount(u16),
+    #[error("Client {0} is not linked.")]
+    NotLinked(u16),
+    #[error("The write-ahead log holds {0} entries a run would recover on top of the snapshot.")]
+    Recovery(usize),
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Itself(_) => "link_itself",
+            Error::Linked { .. } => "already_linked",
+            Error::Chain(_) => "link_chain",
+            Error::HasMembers(_) => "link_has_members",
+            Error::NoAccount(_) => "link_no_account",
+            Error::OwnAccount(_) => "link_own_account",
+            Error::NotLinked(_) => "not_linked",
+            Error::Recovery(_) => "link_recovery_pending",
+        }
+    }
+}
+
+/**
+ * The primary client of every linked client.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Links {
+    primaries: BTreeMap<u16, u16>,
+}
+
+impl Links {
+    pub fn is_empty(&self) -> bool {
+        self.primaries.is_empty()
+    }
+
+    /**
+     * The primary client whose account a linked client shares.
+     */
+    pub fn primary(&self, client: u16) -> Option<u16> {
+        self.primaries.get(&client).copied()
+    }
+
+    /**
+     * The clients linked to a primary client.
+     */
+    pub fn members(&self, primary: u16) -> impl Iterator<Item = u16> + '_ {
+        self.primaries
+            .iter()
+            .filter(move |(_, p)| **p == primary)
+            .map(|(client, _)| *client)
+    }
+
+    /**
+     * Pairs of linked and primary client, ordered by the linked client.
+     */
+    pub fn iter(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        self.primaries
+            .iter()
+            .map(|(client, primary)| (*client, *primary))
+    }
+
+    /**
+     * Links a client to the account of a primary client. The client must not have an account of
+     * its own, `has_account` tells which clients do. Links don't chain.
+     */
+    pub fn link(
+        &mut self,
+        client: u16,
+        primary: u16,
+        has_account: impl Fn(u16) -> bool,
+    ) -> Result<(), Error> {
+        if client == primary {
+            return Err(Error::Itself(client));
+        }
+        if let Some(primary) = self.primary(client) {
+            return Err(Error::Linked { client, primary });
+        }
+        if self.primaries.contains_key(&primary) {
+            return Err(Error::Chain(primary));
+        }
+        if self.members(client).next().is_some() {
+            return Err(Error::HasMembers(client));
+        }
+        if !has_account(primary) {
+            return Err(Error::NoAccount(primary));
+        }
+        if has_account(client) {
+            return Err(Error::OwnAccount(client));
+        }
+        self.primaries.insert(client, primary);
+        Ok(())
+    }
+
+    /**
+     * Removes the link of a client and returns its primary client. The transactions booked while
+     * linked stay on the primary's account, the client starts over with a new account.
+     */
+    pub fn unlink(&mut self, client: u16) -> Result<u16, Error> {
+        self.primaries
+            .remove(&client)
+            .ok_or(Error::NotLinked(client))
+    }
+
+    /**
+     * Adds a link read from a snapshot, which was checked when it was made.
+     */
+    pub fn insert(&mut self, client: u16, primary: u16) {
+        self.primaries.insert(client, primary);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link() {
+        let mut links = Links::default();
+        let accounts = [1, 5];
+        let has_account = |client| accounts.contains(&client);
+
+        links.link(2, 1, has_account).unwrap();
+        links.link(3, 1, has_account).unwrap();
+        assert_eq!(links.primary(2), Some(1));
+        assert_eq!(links.members(1).collect::<Vec<_>>(), [2, 3]);
+
+        assert_eq!(links.link(4, 4, has_account), Err(Error::Itself(4)));
+        assert_eq!(
+            links.link(2, 5, has_account),
+            Err(Error::Linked {
+                client: 2,
+                primary: 1
+            })
+        );
+        assert_eq!(links.link(4, 2, has_account), Err(Error::Chain(2)));
+        assert_eq!(links.link(1, 5, has_account), Err(Error::HasMembers(1)));
+        assert_eq!(links.link(4, 6, has_account), Err(Error::NoAccount(6)));
+        assert_eq!(links.link(5, 1, has_account), Err(Error::OwnAccount(5)));
+
+        assert_eq!(links.unlink(2), Ok(1));
+        assert_eq!(links.unlink(2), Err(Error::NotLinked(2)));
+        assert_eq!(links.iter().collect::<Vec<_>>(), [(3, 1)]);
+    }
+}
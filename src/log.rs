@@ -0,0 +1,360 @@
+/**
+ * Logging of runtime errors and rejected input rows.
+ *
+ * Events are written to stderr either as plain text (the error message only) or as one JSON
+ * object per line for consumption by log aggregators.
+ */
+use std::{
+    fmt::{self, Write},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{json, processor::Origin};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Info,
+    Warning,
+    Error,
+}
+
+impl Level {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Level::Info => "info",
+            Level::Warning => "warning",
+            Level::Error => "error",
+        }
+    }
+}
+
+/**
+ * Errors and notifications which can be logged as structured events.
+ */
+pub trait Event: fmt::Display {
+    /**
+     * A stable, machine readable identifier of the event variant.
+     */
+    fn code(&self) -> &'static str;
+
+    fn client(&self) -> Option<u16> {
+        None
+    }
+
+    fn tx(&self) -> Option<u32> {
+        None
+    }
+}
+
+// Events held back by a deterministic logger: input line, rank of the level and rendered event.
+type Buffer = Arc<Mutex<Vec<(u64, u8, String)>>>;
+
+#[derive(Debug, Clone)]
+pub struct Logger {
+    format: Format,
+    buffer: Option<Buffer>,
+    /// Keep the events on `flush`, so tests can take them.
+    capture: bool,
+}
+
+impl Logger {
+    pub fn new(format: Format) -> Logger {
+        Self {
+            format,
+            buffer: None,
+            capture: false,
+        }
+    }
+
+    /**
+     * A logger which leaves out timestamps and holds events back until `flush`, which writes them
+     * ordered by the input line causing them. The log of a run then doesn't depend on the
+     * scheduling of the tasks reporting the events.
+     */
+    pub fn deterministic(format: Format) -> Logger {
+        Self {
+            format,
+            buffer: Some(Buffer::default()),
+            capture: false,
+        }
+    }
+
+    /**
+     * A deterministic logger whose events are taken by the test instead of written to stderr.
+     */
+    #[cfg(test)]
+    pub fn capture(format: Format) -> Logger {
+        Self {
+            capture: true,
+            ..Self::deterministic(format)
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn info<E: Event>(&self, event: &E, origin: Option<&Origin>) {
+        self.log(Level::Info, event, origin);
+    }
+
+    pub fn warning<E: Event>(&self, event: &E, origin: Option<&Origin>) {
+        self.log(Level::Warning, event, origin);
+    }
+
+    /**
+     * Logs an error, with the origin of the input causing it if known.
+     */
+    pub fn error<E: Event>(&self, event: &E, origin: Option<&Origin>) {
+        self.log(Level::Error, event, origin);
+    }
+
+    fn log<E: Event>(&self, level: Level, event: &E, origin: Option<&Origin>) {
+        match &self.buffer {
+            None => eprintln!(
+                "{}",
+                self.render(level, event, origin, Some(SystemTime::now()))
+            ),
+            Some(buffer) => {
+                let line = origin.map_or(u64::MAX, |origin| origin.line);
+                // errors first, they are reported before the warnings and events they cause
+                let rank = match level {
+                    Level::Error => 0,
+                    Level::Warning => 1,
+                    Level::Info => 2,
+                };
+                let event = self.render(level, event, origin, None);
+                buffer.lock().unwrap().push((line, rank, event));
+            }
+        }
+    }
+
+    /**
+     * Writes the events held back by a deterministic logger.
+     */
+    pub fn flush(&self) {
+        if self.capture {
+            return;
+        }
+        for event in self.take() {
+            eprintln!("{event}");
+        }
+    }
+
+    /**
+     * Removes the events held back and returns them in the order `flush` writes them.
+     */
+    pub fn take(&self) -> Vec<String> {
+        let mut events = match &self.buffer {
+            Some(buffer) => std::mem::take(&mut *buffer.lock().unwrap()),
+            None => return Vec::new(),
+        };
+        // stable, so events of the same line and level keep the order of their task
+        events.sort_by_key(|(line, rank, _)| (*line, *rank));
+        events.into_iter().map(|(_, _, event)| event).collect()
+    }
+
+    fn render<E: Event>(
+        &self,
+        level: Level,
+        event: &E,
+        origin: Option<&Origin>,
+        now: Option<SystemTime>,
+    ) -> String {
+        match self.format {
+            Format::Text => match origin {
+                Some(origin) => format!("{event} ({origin})"),
+                None => event.to_string(),
+            },
+            Format::Json => {
+                let mut s = String::new();
+                let _ = write!(s, "{{\"level\":\"{}\"", level.as_str());
+                if let Some(now) = now {
+                    let _ = write!(
+                        s,
+                        ",\"timestamp\":\"{}\"",
+                        rfc3339(now.duration_since(UNIX_EPOCH).unwrap_or_default())
+                    );
+                }
+                if let Some(client) = event.client() {
+                    let _ = write!(s, ",\"client\":{client}");
+                }
+                if let Some(tx) = event.tx() {
+                    let _ = write!(s, ",\"tx\":{tx}");
+                }
+                if let Some(origin) = origin {
+                    let _ = write!(
+                        s,
+                        ",\"source\":\"{}\",\"line\":{},\"offset\":{}",
+                        json::escape(&origin.source),
+                        origin.line,
+                        origin.offset
+                    );
+                    if let Some(reference) = &origin.reference {
+                        let _ = write!(s, ",\"reference\":\"{}\"", json::escape(reference));
+                    }
+                }
+                let _ = write!(
+                    s,
+                    ",\"code\":\"{}\",\"message\":\"{}\"}}",
+                    event.code(),
+                    json::escape(&event.to_string())
+                );
+                s
+            }
+        }
+    }
+}
+
+/**
+ * The current UTC date as `YYYY-MM-DD`.
+ */
+pub fn today() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    rfc3339(now)[..10].to_string()
+}
+
+// Formats the time since the epoch as UTC timestamp with millisecond precision. The date
+// conversion follows http://howardhinnant.github.io/date_algorithms.html#civil_from_days.
+fn rfc3339(since_epoch: Duration) -> String {
+    let secs = since_epoch.as_secs();
+    let days = (secs / 86400) as i64;
+    let secs_of_day = secs % 86400;
+
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct TestEvent;
+
+    impl fmt::Display for TestEvent {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "Something \"bad\" happened.")
+        }
+    }
+
+    impl Event for TestEvent {
+        fn code(&self) -> &'static str {
+            "test"
+        }
+
+        fn client(&self) -> Option<u16> {
+            Some(1)
+        }
+    }
+
+    #[test]
+    fn timestamp() {
+        assert_eq!(rfc3339(Duration::ZERO), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            rfc3339(Duration::from_millis(951_827_696_789)),
+            "2000-02-29T12:34:56.789Z"
+        );
+        assert_eq!(
+            rfc3339(Duration::from_secs(1_767_225_599)),
+            "2025-12-31T23:59:59.000Z"
+        );
+    }
+
+    #[test]
+    fn render() {
+        let now = UNIX_EPOCH + Duration::from_secs(86400);
+        assert_eq!(
+            Logger::new(Format::Text).render(Level::Error, &TestEvent, None, Some(now)),
+            "Something \"bad\" happened."
+        );
+        assert_eq!(
+            Logger::new(Format::Json).render(Level::Error, &TestEvent, None, Some(now)),
+            "{\"level\":\"error\",\"timestamp\":\"1970-01-02T00:00:00.000Z\",\"client\":1,\
+             \"code\":\"test\",\"message\":\"Something \\\"bad\\\" happened.\"}"
+        );
+
+        let origin = Origin {
+            source: "data/in.csv".into(),
+            line: 3,
+            offset: 40,
+            reference: Some("P-17".into()),
+            timestamp: None,
+        };
+        assert_eq!(
+            Logger::new(Format::Text).render(Level::Error, &TestEvent, Some(&origin), Some(now)),
+            "Something \"bad\" happened. (data/in.csv:3)"
+        );
+        assert_eq!(
+            Logger::new(Format::Json).render(Level::Error, &TestEvent, Some(&origin), Some(now)),
+            "{\"level\":\"error\",\"timestamp\":\"1970-01-02T00:00:00.000Z\",\"client\":1,\
+             \"source\":\"data/in.csv\",\"line\":3,\"offset\":40,\"reference\":\"P-17\",\
+             \"code\":\"test\",\"message\":\"Something \\\"bad\\\" happened.\"}"
+        );
+    }
+
+    #[test]
+    fn deterministic() {
+        let logger = Logger::deterministic(Format::Json);
+        let origin = |line| Origin {
+            source: "in.csv".into(),
+            line,
+            offset: 0,
+            reference: None,
+            timestamp: None,
+        };
+        logger.info(&TestEvent, Some(&origin(2)));
+        logger.error(&TestEvent, None);
+        logger.warning(&TestEvent, Some(&origin(2)));
+        logger.error(&TestEvent, Some(&origin(3)));
+        logger.error(&TestEvent, Some(&origin(2)));
+
+        let levels: Vec<_> = logger
+            .take()
+            .iter()
+            .map(|event| {
+                assert!(!event.contains("timestamp"));
+                let level = event.split('"').nth(3).unwrap().to_string();
+                let line = event
+                    .contains("\"line\"")
+                    .then(|| event.split("\"line\":").nth(1));
+                (level, line.flatten().map(|s| s.as_bytes()[0]))
+            })
+            .collect();
+        assert_eq!(
+            levels,
+            [
+                ("error".to_string(), Some(b'2')),
+                ("warning".to_string(), Some(b'2')),
+                ("info".to_string(), Some(b'2')),
+                ("error".to_string(), Some(b'3')),
+                ("error".to_string(), None),
+            ]
+        );
+        assert!(logger.take().is_empty());
+    }
+}
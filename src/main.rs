@@ -1,22 +1,905 @@
-mod account;
-mod amount;
-mod cli;
-mod processor;
+use std::{
+    fs::File,
+    io::{stdin, stdout, BufReader, IsTerminal},
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
-use std::{fs::File, io::stdout};
-
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+#[cfg(feature = "chaos")]
+use trapez::chaos;
+#[cfg(unix)]
+use trapez::mmap;
+use trapez::{
+    account, amount, audit, bench, cli, erasure, fix, hierarchy, inspect, ledger, links, log,
+    metadata, mt940, processor, replay, schema, sha256, snapshot, wal, webhook,
+};
 
 #[derive(Parser)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    #[clap(value_parser)]
-    file_path: String,
+    #[clap(subcommand)]
+    command: Option<Command>,
+    #[clap(value_parser, required = true)]
+    file_path: Option<String>,
+    /// Format of the error log written to stderr.
+    #[clap(long, value_enum, default_value_t = log::Format::Text)]
+    log_format: log::Format,
+    /// Print processed and rejected message counts to stderr at the end of the run.
+    #[clap(long)]
+    summary: bool,
+    /// Print duration, rows/s, bytes/s and peak channel depth to stderr at the end of the run.
+    #[clap(long)]
+    throughput: bool,
+    /// Make the log reproducible: leave out timestamps and write all events at the end of the
+    /// run, ordered by input line.
+    #[clap(long, conflicts_with = "throughput")]
+    deterministic: bool,
+    /// Warn about deposits above this amount.
+    #[clap(long, value_parser = amount::parse)]
+    warn_deposit_above: Option<i64>,
+    /// Warn when the total funds of an account rise above this amount.
+    #[clap(long, value_parser = amount::parse)]
+    warn_total_above: Option<i64>,
+    /// Reject deposits below this amount.
+    #[clap(long, value_parser = amount::parse)]
+    min_deposit: Option<i64>,
+    /// Reject deposits above this amount.
+    #[clap(long, value_parser = amount::parse)]
+    max_deposit: Option<i64>,
+    /// Reject withdrawals below this amount.
+    #[clap(long, value_parser = amount::parse)]
+    min_withdrawal: Option<i64>,
+    /// Reject withdrawals above this amount.
+    #[clap(long, value_parser = amount::parse)]
+    max_withdrawal: Option<i64>,
+    /// Hold withdrawals above this amount for approval: their funds are earmarked until an
+    /// `approve` row debits them or a `reject` row releases them. Adds a `pending` column to the
+    /// report.
+    #[clap(long, value_parser = amount::parse)]
+    approve_withdrawals_above: Option<i64>,
+    /// Expire `authorize` rows not captured by a `capture` row within this many milliseconds of
+    /// their timestamp, which releases their earmarked funds. Without it, or without a timestamp,
+    /// authorizations stay open.
+    #[clap(long)]
+    authorization_ttl: Option<u64>,
+    /// Warn when the available funds of an account fall below this amount, e.g. for margin calls.
+    /// Also posted to the webhook.
+    #[clap(long, value_parser = amount::parse)]
+    low_balance: Option<i64>,
+    /// The low balance threshold of a client, e.g. `7=100.00`, overriding --low-balance. Can be
+    /// repeated.
+    #[clap(long, value_parser = processor::LowBalance::parse_client)]
+    client_low_balance: Vec<(u16, i64)>,
+    /// Warn about transactions processed more than this many milliseconds after the time in their
+    /// `timestamp` column, read as Unix epoch milliseconds. The summary shows the distribution of
+    /// the lags whenever the input has timestamps.
+    #[clap(long)]
+    sla: Option<u64>,
+    /// The SLA of a client in milliseconds, e.g. `7=60000`, overriding --sla. Can be repeated.
+    #[clap(long, value_parser = processor::Sla::parse_client)]
+    client_sla: Vec<(u16, u64)>,
+    /// Warn when a client opens more than this number of disputes.
+    #[clap(long)]
+    warn_disputes_above: Option<u32>,
+    /// Append accepted state-changing operations to this hash-chained audit log.
+    #[clap(long, value_parser)]
+    audit_log: Option<PathBuf>,
+    /// Keep at most this many transactions per account in memory and spill older ones to disk. The
+    /// client of every transaction id is still kept in memory.
+    #[clap(long, requires = "spill-dir")]
+    log_window: Option<usize>,
+    /// Directory of the spill file used with --log-window.
+    #[clap(long, value_parser, requires = "log-window")]
+    spill_dir: Option<PathBuf>,
+    /// Append state-changing messages to this write-ahead log before applying them, and replay
+    /// the log on start to recover the state of a crashed run.
+    #[clap(long, value_parser)]
+    wal: Option<PathBuf>,
+    /// When to force write-ahead log entries to disk.
+    #[clap(long, value_enum, default_value_t = wal::Fsync::Batch)]
+    wal_fsync: wal::Fsync,
+    /// Write a snapshot of the complete state to this path at the end of the input.
+    #[clap(long, value_parser)]
+    snapshot: Option<PathBuf>,
+    /// Also write a snapshot after every this many transactions.
+    #[clap(long, requires = "snapshot")]
+    snapshot_every: Option<u64>,
+    /// Start from the state of an earlier snapshot.
+    #[clap(long, value_parser)]
+    load_snapshot: Option<PathBuf>,
+    /// Process the input on top of the --load-snapshot state without writing any state: no audit
+    /// log, write-ahead log, snapshot, webhook or dead letters. Prints the report and errors the
+    /// input would lead to, and implies --summary.
+    #[clap(
+        long,
+        requires = "load-snapshot",
+        conflicts_with_all = &["audit-log", "wal", "snapshot", "webhook-url", "dead-letter"]
+    )]
+    dry_run: bool,
+    /// Memory-map the input file instead of reading it.
+    #[cfg(unix)]
+    #[clap(long)]
+    mmap: bool,
+    /// Log dispute lifecycle events to stderr.
+    #[clap(long)]
+    log_disputes: bool,
+    /// Post chargebacks and account locks as JSON to this `http://` URL.
+    #[clap(long, value_parser = webhook::Url::parse)]
+    webhook_url: Option<webhook::Url>,
+    /// Additional header for webhook requests, e.g. `Authorization: Bearer <token>`.
+    #[clap(long, value_parser = webhook::parse_header, requires = "webhook-url")]
+    webhook_header: Option<String>,
+    /// Number of delivery attempts per webhook.
+    #[clap(long, requires = "webhook-url", default_value = "3")]
+    webhook_attempts: u32,
+    /// Delay in milliseconds before the first webhook retry, doubled for every further one.
+    #[clap(long, requires = "webhook-url", default_value = "500")]
+    webhook_backoff_ms: u64,
+    /// Also post disputes of transactions above this amount to the webhook.
+    #[clap(long, value_parser = amount::parse, requires = "webhook-url")]
+    webhook_dispute_above: Option<i64>,
+    /// Accept an alternative name for a transaction type, e.g. `credit=deposit`. Can be repeated.
+    #[clap(long, value_parser = cli::parse_alias)]
+    type_alias: Vec<cli::Alias>,
+    /// How to handle rows with an unknown transaction type.
+    #[clap(long, value_enum, default_value_t = cli::UnknownTypes::Skip)]
+    unknown_types: cli::UnknownTypes,
+    /// CSV file receiving the rows routed to it by --unknown-types dead-letter, and the deposits
+    /// and withdrawals with amounts out of the bounds of --min-deposit and friends.
+    #[clap(long, value_parser, required_if_eq("unknown-types", "dead-letter"))]
+    dead_letter: Option<PathBuf>,
+    /// Write the open disputes making up the held funds of each account, with their amounts, ages
+    /// and reason codes, to this CSV file.
+    #[clap(long, value_parser)]
+    held_report: Option<PathBuf>,
+    /// CSV file of `<child>,<parent>` client pairs relating sub-accounts to their parent account,
+    /// e.g. the sub-ledgers of an omnibus account.
+    #[clap(long, value_parser, requires = "roll-up-report")]
+    hierarchy: Option<PathBuf>,
+    /// Write the balances of every parent account rolled up with those of its descendants to this
+    /// CSV file.
+    #[clap(long, value_parser, requires = "hierarchy")]
+    roll_up_report: Option<PathBuf>,
+    /// Write the positions in the assets traded by deposits and withdrawals with `asset` and
+    /// `price` columns, with their average cost and realized profit or loss, to this CSV file.
+    #[clap(long, value_parser)]
+    positions_report: Option<PathBuf>,
+    /// Only accept transactions of these clients, e.g. `1-1000,2000`.
+    #[clap(long, value_parser = processor::Clients::parse, conflicts_with = "clients-file")]
+    clients: Option<processor::Clients>,
+    /// Only accept transactions of the clients listed in this file (ids and ranges like --clients,
+    /// separated by commas or newlines).
+    #[clap(long, value_parser)]
+    clients_file: Option<PathBuf>,
+    /// Only accept disputes, resolves and chargebacks without reason code or with one of these
+    /// codes, e.g. `10.4,13.1,4837`.
+    #[clap(
+        long,
+        value_parser = processor::Reason::parse,
+        value_delimiter = ',',
+        conflicts_with = "reason-codes-file"
+    )]
+    reason_codes: Option<Vec<processor::Reason>>,
+    /// Only accept the reason codes listed in this file, one per line and optionally followed by
+    /// a description. Lines starting with `#` are comments.
+    #[clap(long, value_parser)]
+    reason_codes_file: Option<PathBuf>,
+    /// Hold back transactions until the input has reached a `timestamp` this far past theirs, in
+    /// the unit of the timestamps, and apply them in timestamp order. Evens out slightly
+    /// out-of-order feeds merged from several sources.
+    #[clap(long)]
+    reorder_window: Option<u64>,
+    /// Put accounts on hold whose available funds go negative by a dispute, and reject their
+    /// withdrawals until the hold is released with release-hold.
+    #[clap(long)]
+    hold_negative: bool,
+    /// Fold transactions into the history of their account by the `timestamp` column, replaying
+    /// the account when a late one arrives. The history covers the current run only.
+    #[clap(long, conflicts_with = "spill-dir")]
+    backfill: bool,
+    /// Remove empty accounts from memory after this many transactional messages without activity
+    /// on them. Their old transactions can't be disputed anymore afterwards.
+    #[clap(long)]
+    evict_idle: Option<u64>,
+    /// Mark charged back transactions as reversed, so they can't be disputed again.
+    #[clap(long)]
+    reverse_chargebacks: bool,
+    /// Check the account invariants after every transaction and report violations as errors.
+    #[clap(long)]
+    check_invariants: bool,
+    /// Compare the final state to the expected balances in this CSV file (columns as in the report,
+    /// only client is required) and fail on mismatches.
+    #[clap(long, value_parser)]
+    reconcile: Option<PathBuf>,
+    /// Allowed difference between actual and expected amounts with --reconcile.
+    #[clap(long, value_parser = amount::parse, requires = "reconcile", default_value = "0")]
+    reconcile_tolerance: i64,
+    /// Fail the run unless the input ends with a `trailer` row whose control totals cover all rows
+    /// after the previous trailer. Trailers in the input are verified either way.
+    #[clap(long)]
+    require_trailer: bool,
+    /// Only report accounts whose metadata in the --load-snapshot snapshot matches, e.g.
+    /// `tag=vip` or `region=eu`. Can be repeated, all filters have to match.
+    #[clap(long, value_parser = metadata::Filter::parse)]
+    filter: Vec<metadata::Filter>,
+    /// Inject faults between the reader and the processor, seeded with this value.
+    #[cfg(feature = "chaos")]
+    #[clap(long)]
+    chaos_seed: Option<u64>,
+    /// Percentage of messages dropped with --chaos-seed.
+    #[cfg(feature = "chaos")]
+    #[clap(long, value_parser = clap::value_parser!(u8).range(0..=100), default_value_t = 0, requires = "chaos-seed")]
+    chaos_drop: u8,
+    /// Percentage of messages duplicated with --chaos-seed.
+    #[cfg(feature = "chaos")]
+    #[clap(long, value_parser = clap::value_parser!(u8).range(0..=100), default_value_t = 0, requires = "chaos-seed")]
+    chaos_duplicate: u8,
+    /// Percentage of messages delayed, i.e. sent out of order, with --chaos-seed.
+    #[cfg(feature = "chaos")]
+    #[clap(long, value_parser = clap::value_parser!(u8).range(0..=100), default_value_t = 0, requires = "chaos-seed")]
+    chaos_delay: u8,
+    /// The tokio runtime driving the pipeline.
+    #[clap(long, value_enum, global = true, default_value_t = Runtime::MultiThread)]
+    runtime: Runtime,
+    /// Number of worker threads of the multi-thread runtime (default: number of CPUs).
+    #[clap(long, value_parser, global = true)]
+    worker_threads: Option<NonZeroUsize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Runtime {
+    /// Everything runs on the main thread, which avoids cross-thread wakeups.
+    CurrentThread,
+    MultiThread,
+}
+
+/**
+ * The configuration of the run writing a write-ahead log, as far as it decides what the messages
+ * book. The log doesn't record which withdrawals waited for an approval or when authorizations
+ * expired, so the commands replaying it need it again.
+ */
+#[derive(clap::Args)]
+struct Replay {
+    /// The --approve-withdrawals-above of the run writing the log.
+    #[clap(long, value_parser = amount::parse)]
+    approve_withdrawals_above: Option<i64>,
+    /// The --authorization-ttl of the run writing the log.
+    #[clap(long)]
+    authorization_ttl: Option<u64>,
+}
+
+impl Replay {
+    fn config(&self) -> processor::Config {
+        processor::Config {
+            approve_above: self.approve_withdrawals_above,
+            authorization_ttl: self.authorization_ttl,
+            ..processor::Config::default()
+        }
+    }
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+#[derive(Subcommand)]
+enum Command {
+    /// Measure throughput and latency with a generated in-memory workload.
+    Bench {
+        /// Number of generated rows.
+        #[clap(long, default_value_t = 1_000_000)]
+        rows: usize,
+        /// Number of distinct clients.
+        #[clap(long, default_value_t = 1000)]
+        clients: u16,
+        /// Seed of the workload generator.
+        #[clap(long, default_value_t = 1)]
+        seed: u64,
+    },
+    /// Check the hash chain of an audit log.
+    VerifyAudit {
+        #[clap(value_parser)]
+        path: PathBuf,
+    },
+    /// Replay a write-ahead log through the processor and an independent double-entry ledger and
+    /// compare the resulting balances.
+    Verify {
+        #[clap(value_parser)]
+        wal: PathBuf,
+        #[clap(flatten)]
+        replay: Replay,
+    },
+    /// Write the entries the ledger books for a write-ahead log as journal for accounting.
+    ExportJournal {
+        #[clap(value_parser)]
+        wal: PathBuf,
+        #[clap(flatten)]
+        replay: Replay,
+        #[clap(long, value_enum, default_value = "ledger")]
+        format: ledger::Format,
+        /// Date of the entries, today (UTC) by default.
+        #[clap(long, value_parser = ledger::Date::parse)]
+        date: Option<ledger::Date>,
+        /// Account deposits come from and withdrawals go to.
+        #[clap(long, default_value = "Assets:Cash")]
+        cash_account: String,
+        /// Account chargebacks go to.
+        #[clap(long, default_value = "Assets:Cash")]
+        chargeback_account: String,
+        /// Account of a client's available funds, `{client}` is replaced with the client id.
+        #[clap(long, default_value = "Liabilities:Clients:{client}:Available")]
+        available_account: String,
+        /// Account of a client's held funds, `{client}` is replaced with the client id.
+        #[clap(long, default_value = "Liabilities:Clients:{client}:Held")]
+        held_account: String,
+    },
+    /// Fold older audit log entries into per-account opening balances.
+    CompactAudit {
+        #[clap(value_parser)]
+        path: PathBuf,
+        /// Number of most recent entries to keep.
+        #[clap(long)]
+        keep: usize,
+    },
+    /// Net the movements of every client in a period of an audit log into settlement
+    /// instructions.
+    Settle {
+        #[clap(value_parser)]
+        path: PathBuf,
+        /// Sequence number of the last entry before the period.
+        #[clap(long, default_value_t = 0)]
+        after: u64,
+        /// Sequence number of the last entry of the period, the end of the log by default.
+        #[clap(long)]
+        through: Option<u64>,
+    },
+    /// Query the state in a snapshot interactively, e.g. `account 42`, `tx 100`, `top 10 held` or
+    /// `locked`. `help` lists all queries.
+    Inspect {
+        #[clap(value_parser)]
+        snapshot: PathBuf,
+    },
+    /// Print the state in a snapshot as JSON.
+    ExportState {
+        #[clap(value_parser)]
+        snapshot: PathBuf,
+    },
+    /// Write a snapshot from a JSON state dump as printed by export-state.
+    ImportState {
+        #[clap(value_parser)]
+        json: PathBuf,
+        #[clap(value_parser)]
+        snapshot: PathBuf,
+    },
+    /// Change the metadata and tags of an account in a snapshot.
+    Annotate {
+        #[clap(value_parser)]
+        snapshot: PathBuf,
+        #[clap(long)]
+        client: u16,
+        /// Set a metadata value, e.g. `tier=high`. Can be repeated.
+        #[clap(long, value_parser = metadata::parse_set)]
+        set: Vec<metadata::Change>,
+        /// Remove a metadata value. Can be repeated.
+        #[clap(long, value_parser = metadata::name)]
+        unset: Vec<String>,
+        /// Add a tag, e.g. `vip`. Can be repeated.
+        #[clap(long, value_parser = metadata::name)]
+        tag: Vec<String>,
+        /// Remove a tag. Can be repeated.
+        #[clap(long, value_parser = metadata::name)]
+        untag: Vec<String>,
+        /// Append the changes to this audit log.
+        #[clap(long, value_parser)]
+        audit_log: Option<PathBuf>,
+    },
+    /// Release the hold of an account in a snapshot after review.
+    ReleaseHold {
+        #[clap(value_parser)]
+        snapshot: PathBuf,
+        #[clap(long)]
+        client: u16,
+        /// Append the release to this audit log.
+        #[clap(long, value_parser)]
+        audit_log: Option<PathBuf>,
+    },
+    /// Book the transactions of a client without an account on the account of a primary client in
+    /// a snapshot, e.g. for the members of a household.
+    Link {
+        #[clap(value_parser)]
+        snapshot: PathBuf,
+        #[clap(long)]
+        client: u16,
+        #[clap(long)]
+        primary: u16,
+        /// The write-ahead log of the runs on the snapshot. Refuses to link while it holds entries
+        /// a run would recover, since they would be replayed under the new link.
+        #[clap(long, value_parser)]
+        wal: Option<PathBuf>,
+        /// Append the link to this audit log.
+        #[clap(long, value_parser)]
+        audit_log: Option<PathBuf>,
+    },
+    /// Give a linked client in a snapshot an account of its own again.
+    Unlink {
+        #[clap(value_parser)]
+        snapshot: PathBuf,
+        #[clap(long)]
+        client: u16,
+        /// The write-ahead log of the runs on the snapshot. Refuses to unlink while it holds
+        /// entries a run would recover, since they would be replayed without the link.
+        #[clap(long, value_parser)]
+        wal: Option<PathBuf>,
+        /// Append the unlink to this audit log.
+        #[clap(long, value_parser)]
+        audit_log: Option<PathBuf>,
+    },
+    /// Erase the personal data of a client in a snapshot and print what was removed. The balances
+    /// stay, the transaction log is collapsed into a single opening entry.
+    Erase {
+        #[clap(value_parser)]
+        snapshot: PathBuf,
+        #[clap(long)]
+        client: u16,
+        /// Also fold the audit log up to the client's last entry into opening balances.
+        #[clap(long, value_parser)]
+        audit_log: Option<PathBuf>,
+    },
+    /// Replay a write-ahead log up to a message and print the state of its client right before it.
+    StateAt {
+        #[clap(value_parser)]
+        wal: PathBuf,
+        /// Stop at the first message with this transaction id.
+        #[clap(
+            long,
+            value_parser,
+            required_unless_present = "line",
+            conflicts_with = "line"
+        )]
+        tx: Option<u32>,
+        /// Stop at the message in this line of the log.
+        #[clap(long, value_parser)]
+        line: Option<u64>,
+        #[clap(flatten)]
+        replay: Replay,
+    },
+    /// Print the JSON Schema of input records, processor messages or report rows.
+    Schema {
+        #[clap(value_enum)]
+        kind: schema::Kind,
+    },
+    /// Convert the fills or allocations in a FIX drop-copy log to deposits and withdrawals in the
+    /// input format.
+    FromFix {
+        #[clap(value_parser)]
+        log: PathBuf,
+        /// The messages to convert.
+        #[clap(long, value_enum, default_value = "executions")]
+        source: fix::Source,
+        /// Transaction id of the first converted message, the following ones are consecutive.
+        #[clap(long, default_value_t = 1)]
+        first_tx: u32,
+    },
+    /// Convert the statement lines of SWIFT MT940 statements or MT942 interim reports to deposits
+    /// and withdrawals in the input format.
+    FromMt940 {
+        #[clap(value_parser)]
+        statement: PathBuf,
+        /// Map a statement account identification to a client id, e.g. `DE89370400440532013000=7`.
+        /// Can be repeated. Numeric identifications are client ids by default.
+        #[clap(long, value_parser = mt940::parse_account)]
+        account: Vec<(String, u16)>,
+        /// Transaction id of the first converted line, the following ones are consecutive.
+        #[clap(long, default_value_t = 1)]
+        first_tx: u32,
+    },
+    /// Compare two states, each given as a snapshot or a JSON state dump, and list their
+    /// differences.
+    Diff {
+        #[clap(value_parser)]
+        left: PathBuf,
+        #[clap(value_parser)]
+        right: PathBuf,
+    },
+}
+
+// Links must not change under the entries of a write-ahead log a run would recover.
+fn chk_recovered(wal: Option<PathBuf>) -> anyhow::Result<()> {
+    match wal.map(wal::entries).transpose()? {
+        Some(entries) if entries > 0 => Err(links::Error::Recovery(entries).into()),
+        _ => Ok(()),
+    }
+}
+
+fn main() -> anyhow::Result<()> {
     let args = Args::try_parse()?;
-    let file = File::open(args.file_path)?;
-    cli::run(file, stdout()).await?;
+    let runtime = match args.runtime {
+        Runtime::CurrentThread => tokio::runtime::Builder::new_current_thread().build()?,
+        Runtime::MultiThread => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            if let Some(threads) = args.worker_threads {
+                builder.worker_threads(threads.get());
+            }
+            builder.build()?
+        }
+    };
+    runtime.block_on(run(args))
+}
+
+async fn run(args: Args) -> anyhow::Result<()> {
+    match args.command {
+        Some(Command::VerifyAudit { path }) => {
+            let entries = audit::verify(path)?;
+            println!("Audit log verified ({entries} entries).");
+            return Ok(());
+        }
+        Some(Command::Verify { wal, replay }) => {
+            let report = ledger::verify(wal, replay.config()).await?;
+            let logger = log::Logger::new(args.log_format);
+            for mismatch in &report.mismatches {
+                logger.warning(mismatch, None);
+            }
+            if !report.mismatches.is_empty() {
+                return Err(ledger::Error::Drift(report.mismatches.len()).into());
+            }
+            println!(
+                "Ledger verified ({} messages, {} entries).",
+                report.messages, report.entries
+            );
+            return Ok(());
+        }
+        Some(Command::ExportJournal {
+            wal,
+            replay,
+            format,
+            date,
+            cash_account,
+            chargeback_account,
+            available_account,
+            held_account,
+        }) => {
+            let chart = ledger::Chart {
+                cash: cash_account,
+                chargebacks: chargeback_account,
+                available: available_account,
+                held: held_account,
+            };
+            let date = match date {
+                Some(date) => date,
+                None => ledger::Date::parse(&log::today()).map_err(anyhow::Error::msg)?,
+            };
+            ledger::export(wal, &replay.config(), &chart, format, date, stdout())?;
+            return Ok(());
+        }
+        Some(Command::CompactAudit { path, keep }) => {
+            let folded = audit::compact(path, keep)?;
+            println!("Folded {folded} audit log entries.");
+            return Ok(());
+        }
+        Some(Command::Settle {
+            path,
+            after,
+            through,
+        }) => {
+            audit::settle(path, after, through, stdout())?;
+            return Ok(());
+        }
+        Some(Command::Inspect { snapshot }) => {
+            let contents = snapshot::read(snapshot)?;
+            let prompt = stdin().is_terminal();
+            inspect::run(&contents, stdin().lock(), stdout(), prompt)?;
+            return Ok(());
+        }
+        Some(Command::ExportState { snapshot }) => {
+            print!("{}", snapshot::to_json(&snapshot::read(snapshot)?));
+            return Ok(());
+        }
+        Some(Command::ImportState { json, snapshot }) => {
+            let contents = snapshot::from_json(&std::fs::read_to_string(json)?)?;
+            snapshot::write(snapshot, &contents)?;
+            println!("Imported {} accounts.", contents.accounts.len());
+            return Ok(());
+        }
+        Some(Command::Annotate {
+            snapshot,
+            client,
+            set,
+            unset,
+            tag,
+            untag,
+            audit_log,
+        }) => {
+            // opened first, so a broken chain fails the command before it changes anything
+            let mut audit = audit_log.map(audit::Log::open).transpose()?;
+            let mut contents = snapshot::read(&snapshot)?;
+            let changes: Vec<_> = set
+                .into_iter()
+                .chain(unset.into_iter().map(metadata::Change::Unset))
+                .chain(tag.into_iter().map(metadata::Change::Tag))
+                .chain(untag.into_iter().map(metadata::Change::Untag))
+                .collect();
+            metadata::annotate(&mut contents, client, changes.iter().cloned())?;
+            snapshot::write(snapshot, &contents)?;
+            if let Some(audit) = &mut audit {
+                for change in &changes {
+                    audit.append(&audit::admin("annotate", client, &[&change.to_string()]))?;
+                }
+            }
+            return Ok(());
+        }
+        Some(Command::ReleaseHold {
+            snapshot,
+            client,
+            audit_log,
+        }) => {
+            let mut audit = audit_log.map(audit::Log::open).transpose()?;
+            let mut contents = snapshot::read(&snapshot)?;
+            if contents.holds.remove(&client) {
+                snapshot::write(snapshot, &contents)?;
+                if let Some(audit) = &mut audit {
+                    audit.append(&audit::admin("release-hold", client, &[]))?;
+                }
+                println!("Released the hold of client {client}.");
+            } else {
+                println!("Client {client} is not on hold.");
+            }
+            return Ok(());
+        }
+        Some(Command::Link {
+            snapshot,
+            client,
+            primary,
+            wal,
+            audit_log,
+        }) => {
+            chk_recovered(wal)?;
+            let mut audit = audit_log.map(audit::Log::open).transpose()?;
+            let mut contents = snapshot::read(&snapshot)?;
+            let accounts = &contents.accounts;
+            let has_account = |client| accounts.iter().any(|(c, _)| *c == client);
+            contents.links.link(client, primary, has_account)?;
+            snapshot::write(snapshot, &contents)?;
+            if let Some(audit) = &mut audit {
+                audit.append(&audit::admin("link", client, &[&primary.to_string()]))?;
+            }
+            println!("Linked client {client} to the account of client {primary}.");
+            return Ok(());
+        }
+        Some(Command::Unlink {
+            snapshot,
+            client,
+            wal,
+            audit_log,
+        }) => {
+            chk_recovered(wal)?;
+            let mut audit = audit_log.map(audit::Log::open).transpose()?;
+            let mut contents = snapshot::read(&snapshot)?;
+            let primary = contents.links.unlink(client)?;
+            snapshot::write(snapshot, &contents)?;
+            if let Some(audit) = &mut audit {
+                audit.append(&audit::admin("unlink", client, &[&primary.to_string()]))?;
+            }
+            println!("Unlinked client {client} from the account of client {primary}.");
+            return Ok(());
+        }
+        Some(Command::Erase {
+            snapshot,
+            client,
+            audit_log,
+        }) => {
+            let mut contents = snapshot::read(&snapshot)?;
+            let mut report = erasure::erase(&mut contents, client)?;
+            snapshot::write(snapshot, &contents)?;
+            if let Some(path) = audit_log {
+                report.audit = Some(audit::erase(path, client)?);
+            }
+            print!("{report}");
+            return Ok(());
+        }
+        Some(Command::StateAt {
+            wal,
+            tx,
+            line,
+            replay,
+        }) => {
+            let point = match (tx, line) {
+                (Some(tx), _) => replay::Point::Tx(tx),
+                (None, Some(line)) => replay::Point::Line(line),
+                (None, None) => unreachable!("either --tx or --line is required"),
+            };
+            let report = replay::state_at(wal, replay.config(), point).await?;
+            println!("{report}");
+            if report.state.iter().next().is_some() {
+                cli::write_report(stdout(), report.state.iter())?;
+            }
+            return Ok(());
+        }
+        Some(Command::Schema { kind }) => {
+            println!("{}", schema::schema(kind));
+            return Ok(());
+        }
+        Some(Command::FromFix {
+            log,
+            source,
+            first_tx,
+        }) => {
+            let name: Arc<str> = log.to_string_lossy().into();
+            let logger = log::Logger::new(args.log_format);
+            let mut reader = fix::Reader::new(BufReader::new(File::open(&log)?), source, first_tx);
+            let mut writer = cli::InputWriter::new(stdout())?;
+            while let Some(res) = reader.next() {
+                match res {
+                    Ok(entry) => writer.write(&entry.message, &entry.reference)?,
+                    Err(err @ fix::Error::Invalid { .. }) => {
+                        logger.error(&err, Some(&reader.origin(&name)))
+                    }
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            writer.finish()?;
+            return Ok(());
+        }
+        Some(Command::FromMt940 {
+            statement,
+            account,
+            first_tx,
+        }) => {
+            let source: Arc<str> = statement.to_string_lossy().into();
+            let logger = log::Logger::new(args.log_format);
+            let results =
+                mt940::convert(BufReader::new(File::open(statement)?), &account, first_tx)?;
+            let mut writer = cli::InputWriter::new(stdout())?;
+            for res in results {
+                match res {
+                    Ok(entry) => writer.write(&entry.message, &entry.reference)?,
+                    Err(err) => {
+                        let origin = processor::Origin {
+                            source: source.clone(),
+                            line: err.line(),
+                            offset: 0,
+                            reference: None,
+                            timestamp: None,
+                        };
+                        logger.error(&err, Some(&origin))
+                    }
+                }
+            }
+            writer.finish()?;
+            return Ok(());
+        }
+        Some(Command::Diff { left, right }) => {
+            let differences = snapshot::diff(&snapshot::load(left)?, &snapshot::load(right)?);
+            let logger = log::Logger::new(args.log_format);
+            for difference in &differences {
+                logger.warning(difference, None);
+            }
+            if !differences.is_empty() {
+                return Err(snapshot::Error::Differ(differences.len()).into());
+            }
+            println!("The states are identical.");
+            return Ok(());
+        }
+        Some(Command::Bench {
+            rows,
+            clients,
+            seed,
+        }) => {
+            let input = bench::workload(rows, clients, seed);
+            print!(
+                "{}",
+                bench::run(&input, processor::Config::default()).await?
+            );
+            return Ok(());
+        }
+        None => (),
+    }
+    let path = args.file_path.unwrap_or_default();
+    let file = File::open(&path)?;
+    // only needed to recognize inputs which are already part of a snapshot
+    let digest = match (&args.snapshot, &args.load_snapshot) {
+        (None, None) => None,
+        _ => Some(sha256::read(File::open(&path)?)?),
+    };
+    let clients = match args.clients_file {
+        Some(path) => Some(
+            processor::Clients::parse(&std::fs::read_to_string(path)?)
+                .map_err(anyhow::Error::msg)?,
+        ),
+        None => args.clients,
+    };
+    let hierarchy = match args.hierarchy {
+        Some(path) => Some(hierarchy::Hierarchy::parse(&std::fs::read_to_string(
+            path,
+        )?)?),
+        None => None,
+    };
+    let reason_codes = match args.reason_codes_file {
+        Some(path) => Some(
+            processor::Reason::parse_file(&std::fs::read_to_string(path)?)
+                .map_err(anyhow::Error::msg)?,
+        ),
+        None => args.reason_codes,
+    };
+    let options = cli::Options {
+        logger: if args.deterministic {
+            log::Logger::deterministic(args.log_format)
+        } else {
+            log::Logger::new(args.log_format)
+        },
+        source: path.into(),
+        processor: processor::Config {
+            large_deposit: args.warn_deposit_above,
+            large_total: args.warn_total_above,
+            max_disputes: args.warn_disputes_above,
+            snapshot_every: args.snapshot_every,
+            check_invariants: args.check_invariants,
+            reverse_chargebacks: args.reverse_chargebacks,
+            hold_negative: args.hold_negative,
+            backfill: args.backfill,
+            evict_idle: args.evict_idle,
+            clients,
+            reason_codes,
+            limits: account::Limits {
+                deposit: account::Bounds {
+                    min: args.min_deposit,
+                    max: args.max_deposit,
+                },
+                withdrawal: account::Bounds {
+                    min: args.min_withdrawal,
+                    max: args.max_withdrawal,
+                },
+            },
+            approve_above: args.approve_withdrawals_above,
+            sla: processor::Sla {
+                lag: args.sla,
+                clients: args.client_sla.into_iter().collect(),
+            },
+            authorization_ttl: args.authorization_ttl,
+            low_balance: processor::LowBalance {
+                threshold: args.low_balance,
+                clients: args.client_low_balance.into_iter().collect(),
+            },
+        },
+        audit: args.audit_log,
+        spill: args.spill_dir.zip(args.log_window),
+        wal: args.wal.map(|path| (path, args.wal_fsync)),
+        snapshot: args.snapshot,
+        load_snapshot: args.load_snapshot,
+        digest,
+        log_disputes: args.log_disputes,
+        webhook: args.webhook_url.map(|url| webhook::Config {
+            url,
+            header: args.webhook_header,
+            attempts: args.webhook_attempts,
+            backoff: Duration::from_millis(args.webhook_backoff_ms),
+            dispute_threshold: args.webhook_dispute_above,
+        }),
+        summary: args.summary || args.dry_run,
+        throughput: args.throughput,
+        aliases: args.type_alias,
+        unknown_types: args.unknown_types,
+        dead_letter: args.dead_letter,
+        held_report: args.held_report,
+        roll_up: hierarchy.zip(args.roll_up_report),
+        positions_report: args.positions_report,
+        reorder_window: args.reorder_window,
+        reconcile: args.reconcile,
+        tolerance: args.reconcile_tolerance,
+        require_trailer: args.require_trailer,
+        filters: args.filter,
+        #[cfg(feature = "chaos")]
+        chaos: args.chaos_seed.map(|seed| chaos::Config {
+            seed,
+            drop: args.chaos_drop,
+            duplicate: args.chaos_duplicate,
+            delay: args.chaos_delay,
+        }),
+    };
+    #[cfg(unix)]
+    if args.mmap {
+        let map = mmap::Mmap::open(&file)?;
+        cli::run(&map[..], stdout(), options).await?;
+        return Ok(());
+    }
+    cli::run(file, stdout(), options).await?;
     Ok(())
 }
@@ -0,0 +1,205 @@
+/**
+ * Key/value metadata and tags of accounts, like a risk tier, a region or a `vip` tag.
+ *
+ * Metadata is set by the `annotate` admin command on a snapshot and carried along with the state
+ * by the processor, so it survives further runs on top of the snapshot. Reports can be restricted
+ * to the accounts matching a set of filters.
+ */
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use crate::snapshot;
+
+/// Reserved for filters on tags, `tag=vip` matches accounts tagged `vip`.
+const TAG: &str = "tag";
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum Error {
+    #[error("Invalid name `{0}`, expected letters, digits, `_`, `.` and `-`.")]
+    Name(String),
+    #[error("Invalid value `{0}`, expected printable characters.")]
+    Value(String),
+    #[error("Expected `<key>=<value>`, got `{0}`.")]
+    Pair(String),
+    #[error("The key `tag` is reserved for filtering on tags.")]
+    Reserved,
+    #[error("There is no account of client {0} in the snapshot.")]
+    UnknownAccount(u16),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub values: BTreeMap<String, String>,
+    pub tags: BTreeSet<String>,
+}
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty() && self.tags.is_empty()
+    }
+
+    pub fn apply(&mut self, change: Change) {
+        match change {
+            Change::Set { key, value } => {
+                self.values.insert(key, value);
+            }
+            Change::Unset(key) => {
+                self.values.remove(&key);
+            }
+            Change::Tag(tag) => {
+                self.tags.insert(tag);
+            }
+            Change::Untag(tag) => {
+                self.tags.remove(&tag);
+            }
+        }
+    }
+}
+
+/**
+ * An admin operation on the metadata of an account.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Set { key: String, value: String },
+    Unset(String),
+    Tag(String),
+    Untag(String),
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::Set { key, value } => write!(f, "set {key}={value}"),
+            Change::Unset(key) => write!(f, "unset {key}"),
+            Change::Tag(tag) => write!(f, "tag {tag}"),
+            Change::Untag(tag) => write!(f, "untag {tag}"),
+        }
+    }
+}
+
+/**
+ * Applies the changes to the metadata of an account in the snapshot contents.
+ */
+pub fn annotate(
+    contents: &mut snapshot::Contents,
+    client: u16,
+    changes: impl IntoIterator<Item = Change>,
+) -> Result<(), Error> {
+    if !contents.accounts.iter().any(|(c, _)| *c == client) {
+        return Err(Error::UnknownAccount(client));
+    }
+    let metadata = contents.metadata.entry(client).or_default();
+    for change in changes {
+        metadata.apply(change);
+    }
+    if metadata.is_empty() {
+        contents.metadata.remove(&client);
+    }
+    Ok(())
+}
+
+/**
+ * Checks a key or tag: letters, digits, `_`, `.` and `-`.
+ */
+pub fn name(s: &str) -> Result<String, Error> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-');
+    if s.is_empty() || s.len() > usize::from(u16::MAX) || !s.chars().all(valid) {
+        return Err(Error::Name(s.to_string()));
+    }
+    Ok(s.to_string())
+}
+
+fn value(s: &str) -> Result<String, Error> {
+    if s.is_empty() || s.len() > usize::from(u16::MAX) || s.chars().any(char::is_control) {
+        return Err(Error::Value(s.to_string()));
+    }
+    Ok(s.to_string())
+}
+
+fn pair(s: &str) -> Result<(String, String), Error> {
+    let (key, val) = s
+        .split_once('=')
+        .ok_or_else(|| Error::Pair(s.to_string()))?;
+    Ok((name(key.trim())?, value(val.trim())?))
+}
+
+/**
+ * Parses the `key=value` argument of a `Change::Set`.
+ */
+pub fn parse_set(s: &str) -> Result<Change, Error> {
+    match pair(s)? {
+        (key, _) if key == TAG => Err(Error::Reserved),
+        (key, value) => Ok(Change::Set { key, value }),
+    }
+}
+
+/**
+ * Restricts a report to accounts with a tag (`tag=vip`) or a metadata value (`region=eu`).
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filter {
+    Tag(String),
+    Value { key: String, value: String },
+}
+
+impl Filter {
+    pub fn parse(s: &str) -> Result<Filter, Error> {
+        Ok(match pair(s)? {
+            (key, tag) if key == TAG => Filter::Tag(name(&tag)?),
+            (key, value) => Filter::Value { key, value },
+        })
+    }
+
+    pub fn matches(&self, metadata: Option<&Metadata>) -> bool {
+        match (self, metadata) {
+            (Filter::Tag(tag), Some(metadata)) => metadata.tags.contains(tag),
+            (Filter::Value { key, value }, Some(metadata)) => {
+                metadata.values.get(key) == Some(value)
+            }
+            (_, None) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters() {
+        let mut metadata = Metadata::default();
+        metadata.apply(parse_set("tier = high").unwrap());
+        metadata.apply(parse_set("region=eu-west").unwrap());
+        metadata.apply(Change::Tag(name("vip").unwrap()));
+        metadata.apply(Change::Unset("region".into()));
+        assert_eq!(metadata.values.len(), 1);
+
+        let matches = |filter: &str| Filter::parse(filter).unwrap().matches(Some(&metadata));
+        assert!(matches("tag=vip"));
+        assert!(matches("tier=high"));
+        assert!(!matches("tier=low"));
+        assert!(!matches("region=eu-west"));
+        assert!(!Filter::parse("tag=vip").unwrap().matches(None));
+
+        assert_eq!(parse_set("tag=vip"), Err(Error::Reserved));
+        assert_eq!(parse_set("tier"), Err(Error::Pair("tier".into())));
+        assert_eq!(parse_set("a b=c"), Err(Error::Name("a b".into())));
+        assert!(Filter::parse("tag=a b").is_err());
+
+        let mut contents = snapshot::Contents {
+            accounts: vec![(1, Default::default())],
+            ..Default::default()
+        };
+        annotate(&mut contents, 1, [Change::Tag("vip".into())]).unwrap();
+        assert!(contents.metadata[&1].tags.contains("vip"));
+        annotate(&mut contents, 1, [Change::Untag("vip".into())]).unwrap();
+        assert!(contents.metadata.is_empty());
+        assert_eq!(
+            annotate(&mut contents, 2, []),
+            Err(Error::UnknownAccount(2))
+        );
+    }
+}
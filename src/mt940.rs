@@ -0,0 +1,430 @@
+/**
+ * Conversion of SWIFT MT940 statements and MT942 interim reports into deposits and withdrawals.
+ *
+ * Every statement line (`:61:`) becomes a deposit for credits and a withdrawal for debits (the other
+ * way around for reversals) of the client the statement's account (`:25:`) is mapped to. Numeric
+ * account identifications are taken as client ids as they are. Transaction ids are assigned
+ * consecutively and the bank reference of the line, or the customer reference if there is none, is
+ * kept as reference. Lines whose bank reference was seen before, e.g. in an interim report covering
+ * the same day, are skipped.
+ *
+ * Statements with an opening balance (`:60F:`/`:60M:`) are checked against their closing balance
+ * (`:62F:`/`:62M:`), so truncated or otherwise incomplete files are noticed.
+ */
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    io::{self, BufRead},
+};
+
+use crate::{amount, log, processor};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Invalid statement field in line {line}: `{reason}`.")]
+    Invalid {
+        line: u64,
+        reason: Cow<'static, str>,
+    },
+    #[error("No client for account `{account}` in line {line}, map it with --account.")]
+    UnknownAccount { line: u64, account: String },
+    #[error(
+        "Closing balance {} in line {line} doesn't match the opening balance and the statement \
+         lines ({}).",
+        amount::Decimal(*closing),
+        amount::Decimal(*calculated)
+    )]
+    Balance {
+        line: u64,
+        closing: i64,
+        calculated: i64,
+    },
+    #[error("Transaction ids are exhausted in line {0}.")]
+    Overflow(u64),
+}
+
+impl Error {
+    /**
+     * The line of the statement file the error refers to.
+     */
+    pub fn line(&self) -> u64 {
+        match self {
+            Error::Invalid { line, .. }
+            | Error::UnknownAccount { line, .. }
+            | Error::Balance { line, .. }
+            | Error::Overflow(line) => *line,
+        }
+    }
+}
+
+impl log::Event for Error {
+    fn code(&self) -> &'static str {
+        match self {
+            Error::Invalid { .. } => "mt940_invalid",
+            Error::UnknownAccount { .. } => "mt940_unknown_account",
+            Error::Balance { .. } => "mt940_balance",
+            Error::Overflow(_) => "mt940_overflow",
+        }
+    }
+}
+
+/**
+ * Parses an account mapping like `DE89370400440532013000=7`.
+ */
+pub fn parse_account(s: &str) -> Result<(String, u16), String> {
+    let (account, client) = s
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected `<account>=<client>`, got `{s}`"))?;
+    let client = client
+        .parse()
+        .map_err(|_| format!("invalid client id `{client}`"))?;
+    Ok((account.trim().to_string(), client))
+}
+
+/**
+ * A converted statement line.
+ */
+#[derive(Debug)]
+pub struct Entry {
+    /// A deposit or withdrawal.
+    pub message: processor::Message,
+    pub reference: String,
+    pub line: u64,
+}
+
+// A tagged field, with continuation lines joined by newlines.
+#[derive(Debug)]
+struct Field {
+    tag: String,
+    value: String,
+    line: u64,
+}
+
+// A statement line: its signed amount and its customer and bank reference.
+#[derive(Debug, PartialEq, Eq)]
+struct StatementLine<'a> {
+    amount: i64,
+    customer: &'a str,
+    bank: Option<&'a str>,
+}
+
+/**
+ * Converts all statement lines of a file. Invalid fields don't stop the conversion, their errors
+ * are returned in place of the entries.
+ */
+pub fn convert<R: BufRead>(
+    reader: R,
+    accounts: &[(String, u16)],
+    first_tx: u32,
+) -> Result<Vec<Result<Entry, Error>>, io::Error> {
+    let mut results = Vec::new();
+    let mut next_tx = Some(first_tx);
+    let mut seen = HashSet::new();
+    // the client, opening balance plus the lines so far, and whether a line was invalid
+    let mut client: Result<u16, String> = Err(String::new());
+    let mut balance = None;
+    let mut complete = true;
+
+    for field in fields(reader)? {
+        let line = field.line;
+        let invalid = |reason: Cow<'static, str>| Error::Invalid { line, reason };
+        match field.tag.as_str() {
+            "25" => {
+                let account = field.value.trim();
+                client = accounts
+                    .iter()
+                    .find(|(a, _)| a == account)
+                    .map(|(_, client)| *client)
+                    .or_else(|| account.parse().ok())
+                    .ok_or_else(|| account.to_string());
+                balance = None;
+                complete = true;
+            }
+            "60F" | "60M" => match parse_balance(&field.value) {
+                Ok(amount) => balance = Some(amount),
+                Err(reason) => results.push(Err(invalid(reason))),
+            },
+            "61" => {
+                let parsed = parse_line(&field.value)
+                    .map_err(invalid)
+                    .and_then(|parsed| {
+                        let client = client
+                            .clone()
+                            .map_err(|account| Error::UnknownAccount { line, account })?;
+                        Ok((client, parsed))
+                    });
+                let (client, parsed) = match parsed {
+                    Ok(res) => res,
+                    Err(err) => {
+                        complete = false;
+                        results.push(Err(err));
+                        continue;
+                    }
+                };
+                balance = balance.map(|balance: i64| balance.saturating_add(parsed.amount));
+                if let Some(bank) = parsed.bank.filter(|bank| *bank != "NONREF") {
+                    if !seen.insert(bank.to_string()) {
+                        continue;
+                    }
+                }
+                let tx = match next_tx {
+                    Some(tx) => tx,
+                    None => {
+                        results.push(Err(Error::Overflow(line)));
+                        continue;
+                    }
+                };
+                next_tx = tx.checked_add(1);
+                let message = match parsed.amount {
+                    amount if amount < 0 => processor::Message::Withdrawal {
+                        client,
+                        tx,
+                        amount: -amount,
+                        trade: None,
+                    },
+                    amount => processor::Message::Deposit {
+                        client,
+                        tx,
+                        amount,
+                        trade: None,
+                    },
+                };
+                results.push(Ok(Entry {
+                    message,
+                    reference: parsed.bank.unwrap_or(parsed.customer).to_string(),
+                    line,
+                }));
+            }
+            "62F" | "62M" => match (parse_balance(&field.value), balance.take()) {
+                (Err(reason), _) => results.push(Err(invalid(reason))),
+                (Ok(closing), Some(calculated)) if complete && closing != calculated => results
+                    .push(Err(Error::Balance {
+                        line,
+                        closing,
+                        calculated,
+                    })),
+                (Ok(_), _) => (),
+            },
+            _ => (),
+        }
+    }
+    Ok(results)
+}
+
+// Splits the file into tagged fields, skipping the SWIFT block headers and message trailers.
+fn fields<R: BufRead>(reader: R) -> Result<Vec<Field>, io::Error> {
+    let mut fields: Vec<Field> = Vec::new();
+    let mut open = false;
+    for (i, text) in reader.lines().enumerate() {
+        let text = text?;
+        let mut text = text.trim_end();
+        if let Some(start) = text.find("{4:") {
+            text = &text[start + 3..];
+        }
+        if text.starts_with('{') || text.starts_with('-') {
+            open = false;
+            continue;
+        }
+        let tag = text
+            .strip_prefix(':')
+            .and_then(|rest| rest.split_once(':'))
+            .filter(|(tag, _)| (2..=3).contains(&tag.len()))
+            .filter(|(tag, _)| tag.bytes().all(|b| b.is_ascii_alphanumeric()));
+        match tag {
+            Some((tag, value)) => {
+                fields.push(Field {
+                    tag: tag.to_string(),
+                    value: value.to_string(),
+                    line: i as u64 + 1,
+                });
+                open = true;
+            }
+            None => match fields.last_mut() {
+                Some(field) if open && !text.is_empty() => {
+                    field.value.push('\n');
+                    field.value.push_str(text);
+                }
+                _ => (),
+            },
+        }
+    }
+    Ok(fields)
+}
+
+// SWIFT amounts use a decimal comma and no sign.
+fn parse_amount(s: &str) -> Result<i64, Cow<'static, str>> {
+    amount::parse(&s.replace(',', ".")).map_err(|err| format!("amount `{s}`: {err}").into())
+}
+
+// A balance like `C230131EUR1000,00`: mark, date, currency and amount.
+fn parse_balance(value: &str) -> Result<i64, Cow<'static, str>> {
+    let sign = match value.get(..1) {
+        Some("C") => 1,
+        Some("D") => -1,
+        _ => return Err(format!("balance `{value}` without debit/credit mark").into()),
+    };
+    let amount = value
+        .get(10..)
+        .ok_or_else(|| format!("balance `{value}` is too short"))?;
+    Ok(sign * parse_amount(amount)?)
+}
+
+/*
+ * A statement line like `2301310131CR500,00NTRFCUSTREF//BANKREF`: value date, optional entry date,
+ * debit/credit mark with an optional funds code, amount, transaction type, customer reference and
+ * optional bank reference, with supplementary details on a second line.
+ */
+fn parse_line(value: &str) -> Result<StatementLine<'_>, Cow<'static, str>> {
+    let first = value.lines().next().unwrap_or_default();
+    let err = |what: &str| format!("{what} in statement line `{first}`");
+    let is_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+
+    let mut rest = match first.get(..6) {
+        Some(date) if is_digits(date) => &first[6..],
+        _ => return Err(err("no value date").into()),
+    };
+    if rest.get(..4).is_some_and(is_digits) {
+        rest = &rest[4..];
+    }
+    let (sign, len) = match rest.get(..2) {
+        Some("RC") => (-1, 2),
+        Some("RD") => (1, 2),
+        _ if rest.starts_with('C') => (1, 1),
+        _ if rest.starts_with('D') => (-1, 1),
+        _ => return Err(err("no debit/credit mark").into()),
+    };
+    rest = &rest[len..];
+    // the third letter of the currency code, only given to tell funds apart
+    if rest.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        rest = &rest[1..];
+    }
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit() && c != ',')
+        .unwrap_or(rest.len());
+    let amount = parse_amount(&rest[..end])?;
+    if amount <= 0 {
+        return Err(err("non-positive amount").into());
+    }
+    let references = rest[end..]
+        .get(4..)
+        .ok_or_else(|| err("no transaction type"))?;
+    let (customer, bank) = match references.split_once("//") {
+        Some((customer, bank)) => (customer, Some(bank).filter(|bank| !bank.is_empty())),
+        None => (references, None),
+    };
+    if customer.is_empty() {
+        return Err(err("no customer reference").into());
+    }
+    Ok(StatementLine {
+        amount: sign * amount,
+        customer,
+        bank,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statement_lines() {
+        assert_eq!(
+            parse_line("2301310131C500,25NTRFCUST1//BANK1\nSupplementary"),
+            Ok(StatementLine {
+                amount: 5002500,
+                customer: "CUST1",
+                bank: Some("BANK1")
+            })
+        );
+        assert_eq!(
+            parse_line("230131RDR12,NMSCNONREF"),
+            Ok(StatementLine {
+                amount: 120000,
+                customer: "NONREF",
+                bank: None
+            })
+        );
+        assert_eq!(parse_line("230131D1,5NCHGX").unwrap().amount, -15000);
+        assert!(parse_line("230131X1,5NCHGX").is_err());
+        assert!(parse_line("230131C0,NCHGX").is_err());
+        assert!(parse_line("230131C1,").is_err());
+        assert_eq!(parse_account("DE89 3704=7"), Ok(("DE89 3704".into(), 7)));
+    }
+
+    #[test]
+    fn convert() {
+        let file = "\
+{1:F01BANKDEFFXXXX0000000000}{2:O9400000230131BANKDEFFXXXX00000000002301310000N}{4:
+:20:STATEMENT1
+:25:DE89370400440532013000
+:28C:1/1
+:60F:C230130EUR100,00
+:61:2301310131C500,00NTRFCUST1//BANK1
+:86:Deposit
+from client
+:61:230131D50,NTRFCUST2//BANK2
+:62F:C230131EUR550,00
+-}
+:20:INTERIM
+:25:12
+:13D:2301311200+0100
+:61:230131C5,NTRFNONREF
+:61:2301310131C500,00NTRFCUST1//BANK1
+:61:230131C1,NTRF
+:20:STATEMENT2
+:25:12
+:60F:C230130EUR0,
+:61:230131D1,NTRFNONREF//NONREF
+:62F:C230131EUR1,
+";
+        let results: Vec<_> =
+            super::convert(file.as_bytes(), &[("DE89370400440532013000".into(), 7)], 10)
+                .unwrap()
+                .into_iter()
+                .map(|res| match res {
+                    Ok(entry) => Ok((format!("{:?}", entry.message), entry.reference, entry.line)),
+                    Err(err) => Err(err.to_string()),
+                })
+                .collect();
+        assert_eq!(
+            results,
+            [
+                Ok((
+                    "Deposit { client: 7, tx: 10, amount: 5000000, trade: None }".into(),
+                    "BANK1".into(),
+                    6
+                )),
+                Ok((
+                    "Withdrawal { client: 7, tx: 11, amount: 500000, trade: None }".into(),
+                    "BANK2".into(),
+                    9
+                )),
+                // the repeated BANK1 is skipped
+                Ok((
+                    "Deposit { client: 12, tx: 12, amount: 50000, trade: None }".into(),
+                    "NONREF".into(),
+                    15
+                )),
+                Err("Invalid statement field in line 17: \
+                     `no customer reference in statement line `230131C1,NTRF``."
+                    .into()),
+                Ok((
+                    "Withdrawal { client: 12, tx: 13, amount: 10000, trade: None }".into(),
+                    "NONREF".into(),
+                    21
+                )),
+                Err(
+                    "Closing balance 1.0000 in line 22 doesn't match the opening balance and the \
+                     statement lines (-1.0000)."
+                        .into()
+                ),
+            ]
+        );
+
+        let results = super::convert(":25:ACC\n:61:230131C1,NTRFX\n".as_bytes(), &[], 1).unwrap();
+        assert!(matches!(
+            &results[..],
+            [Err(Error::UnknownAccount { line: 2, account })] if account == "ACC"
+        ));
+    }
+}
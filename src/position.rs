@@ -0,0 +1,189 @@
+/**
+ * Basic position accounting next to the cash balances, for brokerage clients.
+ *
+ * Deposits and withdrawals may name the asset bought or sold with them and its unit price. The
+ * amount stays the cash value of the trade, so the balances are not affected; it buys or sells
+ * `amount / price` units of the asset. Every client's position in an asset tracks the units held,
+ * their cost basis and the profit or loss realized by sales at the average cost.
+ */
+use std::fmt;
+
+use crate::amount;
+
+/**
+ * An asset code like `AAPL` or `BTC-USD`, stored inline like the reason codes.
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Asset {
+    len: u8,
+    code: [u8; Asset::MAX_LEN],
+}
+
+impl Asset {
+    pub const MAX_LEN: usize = 12;
+
+    /**
+     * Parses a code of up to twelve ASCII letters, digits, dots and dashes.
+     */
+    pub fn parse(s: &str) -> Result<Asset, String> {
+        let valid = |b: u8| b.is_ascii_alphanumeric() || b == b'.' || b == b'-';
+        if s.is_empty() || s.len() > Self::MAX_LEN || !s.bytes().all(valid) {
+            return Err(format!(
+                "invalid asset `{s}`, expected up to {} letters, digits, dots and dashes",
+                Self::MAX_LEN
+            ));
+        }
+        let mut code = [0; Self::MAX_LEN];
+        code[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(Self {
+            len: s.len() as u8,
+            code,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only ASCII gets parsed
+        std::str::from_utf8(&self.code[..usize::from(self.len)]).unwrap_or_default()
+    }
+}
+
+impl PartialOrd for Asset {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Asset {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl fmt::Display for Asset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Asset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+/**
+ * The asset bought by a deposit or sold by a withdrawal and its unit price, in 1/10000th currency
+ * units.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trade {
+    pub asset: Asset,
+    pub price: i64,
+}
+
+impl Trade {
+    // The units traded for a cash amount, in 1/10000th units.
+    fn units(&self, amount: i64) -> i64 {
+        scale(amount, amount::UNIT as i64, self.price)
+    }
+}
+
+// `value * mul / div` without overflowing the intermediate product.
+fn scale(value: i64, mul: i64, div: i64) -> i64 {
+    match div {
+        0 => 0,
+        _ => (i128::from(value) * i128::from(mul) / i128::from(div)) as i64,
+    }
+}
+
+/**
+ * A client's position in one asset. Units are counted in 1/10000th, amounts in 1/10000th currency
+ * units.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Position {
+    pub units: i64,
+    /// Cost basis of the units held.
+    pub cost: i64,
+    /// Profit (or loss if negative) realized by the sales so far.
+    pub realized: i64,
+}
+
+impl Position {
+    pub fn buy(&mut self, amount: i64, trade: &Trade) {
+        self.units += trade.units(amount);
+        self.cost += amount;
+    }
+
+    /**
+     * Sells at most the units held. The cost basis of the sold units is their share of the cost
+     * of all units held, i.e. the average cost.
+     */
+    pub fn sell(&mut self, amount: i64, trade: &Trade) {
+        let sold = trade.units(amount).min(self.units);
+        if sold <= 0 {
+            return;
+        }
+        let basis = scale(self.cost, sold, self.units);
+        let proceeds = scale(sold, trade.price, amount::UNIT as i64);
+        self.units -= sold;
+        self.cost -= basis;
+        self.realized += proceeds - basis;
+    }
+
+    /**
+     * The average cost per unit of the units held, zero without units.
+     */
+    pub fn average_cost(&self) -> i64 {
+        scale(self.cost, amount::UNIT as i64, self.units)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn average_cost() {
+        let trade = |price| Trade {
+            asset: Asset::parse("AAPL").unwrap(),
+            price,
+        };
+        let mut position = Position::default();
+
+        // 10 units at 100, 10 units at 200
+        position.buy(10_000_000, &trade(1_000_000));
+        position.buy(20_000_000, &trade(2_000_000));
+        assert_eq!(position.units, 200_000);
+        assert_eq!(position.average_cost(), 1_500_000);
+
+        // 5 units at 180
+        position.sell(9_000_000, &trade(1_800_000));
+        assert_eq!(
+            position,
+            Position {
+                units: 150_000,
+                cost: 22_500_000,
+                realized: 1_500_000,
+            }
+        );
+        assert_eq!(position.average_cost(), 1_500_000);
+
+        // only the units held are sold
+        position.sell(20_000_000, &trade(1_000_000));
+        assert_eq!(
+            position,
+            Position {
+                units: 0,
+                cost: 0,
+                realized: -6_000_000,
+            }
+        );
+        assert_eq!(position.average_cost(), 0);
+
+        assert_eq!(Asset::parse("BTC-USD").unwrap().to_string(), "BTC-USD");
+        assert!(Asset::parse("").is_err());
+        assert!(Asset::parse("TOOLONGASSETCODE").is_err());
+        assert!(Asset::parse("A B").is_err());
+    }
+}
@@ -1,25 +1,763 @@
-use std::collections::btree_map::{BTreeMap, Entry};
+use std::{
+    collections::{
+        hash_map::{Entry, HashMap},
+        BTreeMap, BTreeSet,
+    },
+    fmt, io, mem,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    account::{self, Account},
+    amount, audit, links,
+    log::{self, Event},
+    metadata::Metadata,
+    position::{Asset, Position, Trade},
+    snapshot, spill, wal,
+};
+use tokio::sync::{broadcast, mpsc, oneshot};
 
-use crate::account::{self, Account};
-use tokio::sync::{mpsc, oneshot};
+/**
+ * The capacity of the message and notification channels.
+ */
+pub const CHANNEL_SIZE: usize = 100;
+
+/**
+ * The number of transactional messages producers should combine into one `Batch` message.
+ */
+pub const BATCH_SIZE: usize = 64;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Transaction error for client {client}: `{err}`.")]
-    Transaction { client: u16, err: account::Error },
-    #[error("Client '{0}' not found.")]
-    UnknownClient(u16),
+    Transaction {
+        client: u16,
+        tx: u32,
+        err: account::Error,
+    },
+    #[error("Client '{client}' not found.")]
+    UnknownClient { client: u16, tx: u32 },
+    #[error("Client '{client}' is not allowed.")]
+    ClientNotAllowed { client: u16, tx: u32 },
+    #[error("Transaction {tx} belongs to client {owner}, not to client {client}.")]
+    ForeignTransaction { client: u16, tx: u32, owner: u16 },
+    #[error("Unknown reason code `{reason}` for transaction {tx} of client {client}.")]
+    UnknownReason {
+        client: u16,
+        tx: u32,
+        reason: Reason,
+    },
+    #[error("Client {client} is on hold for review, withdrawal {tx} is rejected.")]
+    OnHold { client: u16, tx: u32 },
+    #[error("Only transactional messages can be simulated.")]
+    NotTransactional,
     #[error("Error sending state result.")]
     Send(),
+    #[error("Error writing audit log entry `{record}`: `{err}`.")]
+    Audit { record: String, err: io::Error },
+    #[error("Error writing write-ahead log: `{0}`.")]
+    Wal(io::Error),
+    #[error("{0}")]
+    Snapshot(snapshot::Error),
+    #[error("Invariant violated for client {client} after transaction {tx}: {violation}.")]
+    Invariant {
+        client: u16,
+        tx: u32,
+        violation: Violation,
+    },
+}
+
+impl Error {
+    // The error with another client, e.g. the linked client whose message was booked on the
+    // account of its primary client.
+    fn for_client(mut self, member: u16) -> Error {
+        match &mut self {
+            Error::Transaction { client, .. }
+            | Error::UnknownClient { client, .. }
+            | Error::ClientNotAllowed { client, .. }
+            | Error::ForeignTransaction { client, .. }
+            | Error::UnknownReason { client, .. }
+            | Error::OnHold { client, .. }
+            | Error::Invariant { client, .. } => *client = member,
+            Error::NotTransactional
+            | Error::Send()
+            | Error::Audit { .. }
+            | Error::Wal(_)
+            | Error::Snapshot(_) => (),
+        }
+        self
+    }
+}
+
+impl log::Event for Error {
+    fn code(&self) -> &'static str {
+        match self {
+            Error::Transaction { err, .. } => err.code(),
+            Error::UnknownClient { .. } => "unknown_client",
+            Error::ClientNotAllowed { .. } => "client_not_allowed",
+            Error::ForeignTransaction { .. } => "foreign_transaction",
+            Error::UnknownReason { .. } => "unknown_reason",
+            Error::OnHold { .. } => "account_on_hold",
+            Error::NotTransactional => "not_transactional",
+            Error::Send() => "send",
+            Error::Audit { .. } => "audit",
+            Error::Wal(_) => "wal",
+            Error::Snapshot(_) => "snapshot",
+            Error::Invariant { violation, .. } => violation.code(),
+        }
+    }
+
+    fn client(&self) -> Option<u16> {
+        match self {
+            Error::Transaction { client, .. }
+            | Error::UnknownClient { client, .. }
+            | Error::ClientNotAllowed { client, .. }
+            | Error::ForeignTransaction { client, .. }
+            | Error::UnknownReason { client, .. }
+            | Error::OnHold { client, .. }
+            | Error::Invariant { client, .. } => Some(*client),
+            Error::NotTransactional
+            | Error::Send()
+            | Error::Audit { .. }
+            | Error::Wal(_)
+            | Error::Snapshot(_) => None,
+        }
+    }
+
+    fn tx(&self) -> Option<u32> {
+        match self {
+            Error::Transaction { tx, .. }
+            | Error::UnknownClient { tx, .. }
+            | Error::ClientNotAllowed { tx, .. }
+            | Error::ForeignTransaction { tx, .. }
+            | Error::UnknownReason { tx, .. }
+            | Error::OnHold { tx, .. }
+            | Error::Invariant { tx, .. } => Some(*tx),
+            Error::NotTransactional
+            | Error::Send()
+            | Error::Audit { .. }
+            | Error::Wal(_)
+            | Error::Snapshot(_) => None,
+        }
+    }
+}
+
+/**
+ * Inconsistencies found by the invariant checks, which indicate a bug in the processor.
+ */
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum Violation {
+    #[error("held funds {held} differ from the disputed amount {disputed}")]
+    Held { held: i64, disputed: i64 },
+    #[error("total {total} differs from available ({available}) plus held funds ({held})")]
+    Total {
+        total: i64,
+        available: i64,
+        held: i64,
+    },
+    #[error("reported state {reported:?} differs from the account state {actual:?}")]
+    View {
+        reported: Option<State>,
+        actual: State,
+    },
+}
+
+impl Violation {
+    fn code(&self) -> &'static str {
+        match self {
+            Violation::Held { .. } => "invariant_held",
+            Violation::Total { .. } => "invariant_total",
+            Violation::View { .. } => "invariant_view",
+        }
+    }
+}
+
+/**
+ * Anomalies worth a closer look which don't prevent the transaction from being processed.
+ */
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum Warning {
+    #[error("Large deposit of {amount} for client {client} in transaction {tx}.")]
+    LargeDeposit { client: u16, tx: u32, amount: i64 },
+    #[error("Total funds of client {client} exceed {total} after transaction {tx}.")]
+    LargeTotal { client: u16, tx: u32, total: i64 },
+    #[error("Client {client} has opened {disputes} disputes (latest: transaction {tx}).")]
+    ManyDisputes { client: u16, tx: u32, disputes: u32 },
+    #[error(
+        "Available funds of client {client} went negative ({available}) by the dispute of \
+         transaction {tx}, the account is on hold for review."
+    )]
+    NegativeBalance {
+        client: u16,
+        tx: u32,
+        available: i64,
+    },
+    #[error(
+        "Late transaction {tx} of client {client} was folded into its history, {replayed} later \
+         messages were replayed and {changed} of them changed their outcome."
+    )]
+    Backfilled {
+        client: u16,
+        tx: u32,
+        replayed: usize,
+        changed: usize,
+    },
+    #[error("Withdrawal of {amount} for client {client} in transaction {tx} awaits approval.")]
+    PendingApproval { client: u16, tx: u32, amount: i64 },
+    #[error(
+        "Transaction {tx} of client {client} was processed {lag} ms after its timestamp, beyond \
+         the SLA of {sla} ms."
+    )]
+    SlaExceeded {
+        client: u16,
+        tx: u32,
+        lag: u64,
+        sla: u64,
+    },
+    #[error("Authorization {tx} of client {client} over {amount} expired.")]
+    AuthorizationExpired { client: u16, tx: u32, amount: i64 },
+    #[error(
+        "Available funds of client {client} fell below {threshold} ({available}) after \
+         transaction {tx}."
+    )]
+    LowBalance {
+        client: u16,
+        tx: u32,
+        available: i64,
+        threshold: i64,
+    },
+}
+
+impl log::Event for Warning {
+    fn code(&self) -> &'static str {
+        match self {
+            Warning::LargeDeposit { .. } => "large_deposit",
+            Warning::LargeTotal { .. } => "large_total",
+            Warning::ManyDisputes { .. } => "many_disputes",
+            Warning::NegativeBalance { .. } => "negative_balance_hold",
+            Warning::Backfilled { .. } => "backfilled",
+            Warning::PendingApproval { .. } => "pending_approval",
+            Warning::SlaExceeded { .. } => "sla_exceeded",
+            Warning::AuthorizationExpired { .. } => "authorization_expired",
+            Warning::LowBalance { .. } => "low_balance",
+        }
+    }
+
+    fn client(&self) -> Option<u16> {
+        match self {
+            Warning::LargeDeposit { client, .. }
+            | Warning::LargeTotal { client, .. }
+            | Warning::ManyDisputes { client, .. }
+            | Warning::NegativeBalance { client, .. }
+            | Warning::Backfilled { client, .. }
+            | Warning::PendingApproval { client, .. }
+            | Warning::SlaExceeded { client, .. }
+            | Warning::AuthorizationExpired { client, .. }
+            | Warning::LowBalance { client, .. } => Some(*client),
+        }
+    }
+
+    fn tx(&self) -> Option<u32> {
+        match self {
+            Warning::LargeDeposit { tx, .. }
+            | Warning::LargeTotal { tx, .. }
+            | Warning::ManyDisputes { tx, .. }
+            | Warning::NegativeBalance { tx, .. }
+            | Warning::Backfilled { tx, .. }
+            | Warning::PendingApproval { tx, .. }
+            | Warning::SlaExceeded { tx, .. }
+            | Warning::AuthorizationExpired { tx, .. }
+            | Warning::LowBalance { tx, .. } => Some(*tx),
+        }
+    }
+}
+
+/**
+ * Messages recovered from a write-ahead log along with their origin.
+ */
+pub type Recovered = Vec<(Message, Option<Origin>)>;
+
+/**
+ * Where a message was read from, so the errors it causes can be traced back to the input.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Origin {
+    /// Name of the input, e.g. the file path.
+    pub source: Arc<str>,
+    pub line: u64,
+    /// Byte offset of the record in the input.
+    pub offset: u64,
+    /// Free-form reference of the record, e.g. the partner's id of the transaction.
+    pub reference: Option<Arc<str>>,
+    /// Time of the transaction as given by the input, e.g. in Unix epoch milliseconds.
+    pub timestamp: Option<u64>,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.source, self.line)
+    }
 }
 
+/**
+ * Everything reported by the processor on its notification channel.
+ */
 #[derive(Debug)]
+pub enum Notification {
+    /// An error with the origin of the message causing it, if known.
+    Error(Error, Option<Origin>),
+    Warning(Warning, Option<Origin>),
+}
+
+/**
+ * A set of client ids, e.g. `1-100,250`.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Clients {
+    bits: Vec<u64>,
+}
+
+impl Clients {
+    /**
+     * Parses a list of client ids and inclusive ranges separated by commas or whitespace.
+     */
+    pub fn parse(s: &str) -> Result<Clients, String> {
+        let mut clients = Clients {
+            bits: vec![0; (usize::from(u16::MAX) + 1) / 64],
+        };
+        let id = |s: &str| {
+            s.trim()
+                .parse::<u16>()
+                .map_err(|err| format!("invalid client id `{s}`: {err}"))
+        };
+        for item in s
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|item| !item.is_empty())
+        {
+            let (first, last) = match item.split_once('-') {
+                Some((first, last)) => (id(first)?, id(last)?),
+                None => (id(item)?, id(item)?),
+            };
+            for client in first..=last {
+                clients.bits[usize::from(client) / 64] |= 1 << (client % 64);
+            }
+        }
+        Ok(clients)
+    }
+
+    pub fn contains(&self, client: u16) -> bool {
+        self.bits[usize::from(client) / 64] & (1 << (client % 64)) != 0
+    }
+}
+
+/**
+ * A reason code of a dispute, resolve or chargeback, like the card networks' `10.4` or `4837`.
+ * Codes are stored inline, so messages carrying them stay cheap to copy around.
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Reason {
+    len: u8,
+    code: [u8; Reason::MAX_LEN],
+}
+
+impl Reason {
+    pub const MAX_LEN: usize = 8;
+
+    /**
+     * Parses a code of up to eight ASCII letters, digits, dots and dashes.
+     */
+    pub fn parse(s: &str) -> Result<Reason, String> {
+        let valid = |b: &u8| b.is_ascii_alphanumeric() || *b == b'.' || *b == b'-';
+        if s.is_empty() || s.len() > Self::MAX_LEN || !s.bytes().all(|b| valid(&b)) {
+            return Err(format!(
+                "invalid reason code `{s}`, expected up to {} letters, digits, dots and dashes",
+                Self::MAX_LEN
+            ));
+        }
+        let mut code = [0; Self::MAX_LEN];
+        code[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(Self {
+            len: s.len() as u8,
+            code,
+        })
+    }
+
+    /**
+     * Parses a code list file with one code per line, optionally followed by a description.
+     * Empty lines and lines starting with `#` are skipped.
+     */
+    pub fn parse_file(s: &str) -> Result<Vec<Reason>, String> {
+        s.lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .filter(|code| !code.starts_with('#'))
+            .map(Self::parse)
+            .collect()
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only ASCII gets parsed
+        std::str::from_utf8(&self.code[..usize::from(self.len)]).unwrap_or_default()
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+/**
+ * Thresholds above which warnings get emitted. Unset thresholds are not checked.
+ */
+#[derive(Debug, Default, Clone)]
+pub struct Config {
+    /// Warn about single deposits above this amount.
+    pub large_deposit: Option<i64>,
+    /// Warn when the total funds of an account rise above this amount.
+    pub large_total: Option<i64>,
+    /// Warn when a client opens more than this number of disputes.
+    pub max_disputes: Option<u32>,
+    /// Write a snapshot after this many transactional messages.
+    pub snapshot_every: Option<u64>,
+    /// Check the invariants of the affected account after every transactional message.
+    pub check_invariants: bool,
+    /// Mark charged back transactions as reversed, which excludes them from further disputes.
+    pub reverse_chargebacks: bool,
+    /// Reject transactions of clients not in this set.
+    pub clients: Option<Clients>,
+    /// Put accounts on hold whose available funds go negative by a dispute. Withdrawals of accounts
+    /// on hold are rejected until the hold is released.
+    pub hold_negative: bool,
+    /// Fold transactions with a timestamp older than the latest one of their account into the
+    /// account's history and replay the later messages, instead of applying them as new activity.
+    pub backfill: bool,
+    /// Remove accounts from memory which are empty, not on hold and had no transactional message
+    /// among the last this many ones. Their transaction log is dropped, so their old transactions
+    /// can't be disputed anymore and their ids can be used again.
+    pub evict_idle: Option<u64>,
+    /// Reject disputes, resolves and chargebacks with reason codes not in this list.
+    pub reason_codes: Option<Vec<Reason>>,
+    /// Reject deposits and withdrawals with amounts out of these bounds.
+    pub limits: account::Limits,
+    /// Withdrawals above this amount only earmark the funds until an `approve` message debits
+    /// them or a `reject` message releases them.
+    pub approve_above: Option<i64>,
+    /// Warn about transactions processed too long after their timestamp.
+    pub sla: Sla,
+    /// Authorizations expire this many milliseconds after their timestamp unless captured before.
+    /// Without a TTL, or without a timestamp, they stay open until captured.
+    pub authorization_ttl: Option<u64>,
+    /// Warn when the available funds of an account fall below its threshold.
+    pub low_balance: LowBalance,
+}
+
+/**
+ * Thresholds of the available funds, e.g. for margin calls. Accounts without a threshold are not
+ * checked.
+ */
+#[derive(Debug, Default, Clone)]
+pub struct LowBalance {
+    /// Threshold of all clients without one of their own.
+    pub threshold: Option<i64>,
+    /// Threshold per client.
+    pub clients: BTreeMap<u16, i64>,
+}
+
+impl LowBalance {
+    pub fn limit(&self, client: u16) -> Option<i64> {
+        self.clients.get(&client).copied().or(self.threshold)
+    }
+
+    /**
+     * Parses the threshold of a client, e.g. `7=100.00`.
+     */
+    pub fn parse_client(s: &str) -> Result<(u16, i64), String> {
+        s.split_once('=')
+            .and_then(|(client, threshold)| {
+                Some((
+                    client.trim().parse().ok()?,
+                    amount::parse(threshold.trim()).ok()?,
+                ))
+            })
+            .ok_or_else(|| format!("expected `<client>=<amount>`, got `{s}`"))
+    }
+}
+
+/**
+ * Maximum lags between the timestamp of a transaction, in Unix epoch milliseconds, and its
+ * processing. Unset lags are not checked.
+ */
+#[derive(Debug, Default, Clone)]
+pub struct Sla {
+    /// Milliseconds allowed for all clients without one of their own.
+    pub lag: Option<u64>,
+    /// Milliseconds allowed per client.
+    pub clients: BTreeMap<u16, u64>,
+}
+
+impl Sla {
+    pub fn limit(&self, client: u16) -> Option<u64> {
+        self.clients.get(&client).copied().or(self.lag)
+    }
+
+    /**
+     * Parses the SLA of a client, e.g. `7=60000`.
+     */
+    pub fn parse_client(s: &str) -> Result<(u16, u64), String> {
+        s.split_once('=')
+            .and_then(|(client, lag)| Some((client.trim().parse().ok()?, lag.trim().parse().ok()?)))
+            .ok_or_else(|| format!("expected `<client>=<milliseconds>`, got `{s}`"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisputeStatus {
+    Opened,
+    Resolved,
+    ChargedBack,
+}
+
+/**
+ * Published on the dispute event channel whenever the dispute state of a transaction changes.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisputeEvent {
+    pub client: u16,
+    pub tx: u32,
+    /// The amount of the disputed transaction.
+    pub amount: i64,
+    pub status: DisputeStatus,
+    /// The reason code of the message, or else the one the dispute was opened with.
+    pub reason: Option<Reason>,
+    /// Where the message changing the dispute state was read from, if known.
+    pub origin: Option<Origin>,
+}
+
+/**
+ * An open dispute, one part of the held funds of an account.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenDispute {
+    pub client: u16,
+    pub tx: u32,
+    /// The amount of the disputed transaction.
+    pub amount: i64,
+    /// Number of transactional messages handled since the dispute was opened, unknown for
+    /// disputes restored from a snapshot.
+    pub age: Option<u64>,
+    pub reason: Option<Reason>,
+}
+
+impl fmt::Display for DisputeEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match self.status {
+            DisputeStatus::Opened => "opened",
+            DisputeStatus::Resolved => "resolved",
+            DisputeStatus::ChargedBack => "charged back",
+        };
+        write!(
+            f,
+            "Dispute of transaction {} for client {} {status} (amount: {}",
+            self.tx, self.client, self.amount
+        )?;
+        match self.reason {
+            Some(reason) => write!(f, ", reason: {reason})."),
+            None => write!(f, ")."),
+        }
+    }
+}
+
+impl log::Event for DisputeEvent {
+    fn code(&self) -> &'static str {
+        match self.status {
+            DisputeStatus::Opened => "dispute_opened",
+            DisputeStatus::Resolved => "dispute_resolved",
+            DisputeStatus::ChargedBack => "dispute_charged_back",
+        }
+    }
+
+    fn client(&self) -> Option<u16> {
+        Some(self.client)
+    }
+
+    fn tx(&self) -> Option<u32> {
+        Some(self.tx)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct State {
     pub client: u16,
     pub available: i64,
     pub held: i64,
     pub total: i64,
     pub locked: bool,
+    /// Funds earmarked by withdrawals awaiting approval, still part of the available funds.
+    pub pending: i64,
+}
+
+impl State {
+    fn new(client: u16, account: &Account) -> State {
+        Self {
+            client,
+            available: account.available(),
+            held: account.held(),
+            total: account.total(),
+            locked: account.locked(),
+            pending: account.pending(),
+        }
+    }
+}
+
+const CHUNK_SIZE: usize = 256;
+
+type Chunk = [Option<State>; CHUNK_SIZE];
+
+/**
+ * Copy-on-write view of all account states, ordered by client id.
+ *
+ * The states live in fixed chunks of client ids which are shared with any copy handed out by
+ * `GetState`. Handing out a copy only clones the chunk pointers, so the processor doesn't stall
+ * while the receiver iterates over the accounts. A shared chunk is copied on its next update.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct StateView {
+    chunks: Vec<Option<Arc<Chunk>>>,
+}
+
+impl StateView {
+    pub(crate) fn set(&mut self, state: State) {
+        if self.chunks.is_empty() {
+            self.chunks = vec![None; (usize::from(u16::MAX) + 1) / CHUNK_SIZE];
+        }
+        let client = usize::from(state.client);
+        let chunk =
+            self.chunks[client / CHUNK_SIZE].get_or_insert_with(|| Arc::new([None; CHUNK_SIZE]));
+        Arc::make_mut(chunk)[client % CHUNK_SIZE] = Some(state);
+    }
+
+    pub fn get(&self, client: u16) -> Option<&State> {
+        let client = usize::from(client);
+        self.chunks.get(client / CHUNK_SIZE)?.as_ref()?[client % CHUNK_SIZE].as_ref()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &State> {
+        self.chunks
+            .iter()
+            .flatten()
+            .flat_map(|chunk| chunk.iter().flatten())
+    }
+}
+
+/**
+ * Counters of the transactional messages handled since startup.
+ */
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// Accepted messages by message type.
+    pub processed: BTreeMap<&'static str, u64>,
+    /// Rejected messages by message type.
+    pub rejected: BTreeMap<&'static str, u64>,
+    /// Rejected messages by error code.
+    pub errors: BTreeMap<&'static str, u64>,
+    /// Idle accounts removed from memory, see `Config::evict_idle`.
+    pub evicted: u64,
+    /// Authorizations which expired before they were captured.
+    pub expired: u64,
+    /// Lags of the transactions with a timestamp.
+    pub lags: Lags,
+}
+
+// Upper bounds of the lag buckets in milliseconds, the last bucket takes all longer lags.
+const LAG_BOUNDS: [(u64, &str); 6] = [
+    (1_000, "1s"),
+    (10_000, "10s"),
+    (60_000, "1m"),
+    (600_000, "10m"),
+    (3_600_000, "1h"),
+    (86_400_000, "1d"),
+];
+
+/**
+ * Distribution of the lags between the timestamps of transactions and their processing, in
+ * milliseconds.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Lags {
+    pub count: u64,
+    pub sum: u64,
+    pub max: u64,
+    /// Number of lags up to each bound of `LAG_BOUNDS` and above the last one.
+    pub buckets: [u64; LAG_BOUNDS.len() + 1],
+    /// Number of lags beyond the SLA of their client.
+    pub exceeded: u64,
+}
+
+impl Lags {
+    fn record(&mut self, lag: u64) {
+        self.count += 1;
+        self.sum = self.sum.saturating_add(lag);
+        self.max = self.max.max(lag);
+        let bucket = LAG_BOUNDS.partition_point(|(bound, _)| *bound < lag);
+        self.buckets[bucket] += 1;
+    }
+}
+
+impl Stats {
+    fn count(&mut self, kind: &'static str, res: &Result<(), Error>) {
+        match res {
+            Ok(()) => *self.processed.entry(kind).or_default() += 1,
+            Err(err) => {
+                *self.rejected.entry(kind).or_default() += 1;
+                *self.errors.entry(err.code()).or_default() += 1;
+            }
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (label, counts) in [
+            ("processed", &self.processed),
+            ("rejected", &self.rejected),
+            ("error", &self.errors),
+        ] {
+            for (key, count) in counts {
+                writeln!(f, "{label} {key}: {count}")?;
+            }
+        }
+        if self.evicted > 0 {
+            writeln!(f, "evicted accounts: {}", self.evicted)?;
+        }
+        if self.expired > 0 {
+            writeln!(f, "expired authorizations: {}", self.expired)?;
+        }
+        let lags = &self.lags;
+        if lags.count > 0 {
+            writeln!(
+                f,
+                "lag count: {}, mean: {} ms, max: {} ms",
+                lags.count,
+                lags.sum / lags.count,
+                lags.max
+            )?;
+            for ((_, label), count) in LAG_BOUNDS.iter().zip(&lags.buckets) {
+                writeln!(f, "lag up to {label}: {count}")?;
+            }
+            let (_, last) = LAG_BOUNDS[LAG_BOUNDS.len() - 1];
+            writeln!(f, "lag over {last}: {}", lags.buckets[LAG_BOUNDS.len()])?;
+            writeln!(f, "lag beyond sla: {}", lags.exceeded)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -28,97 +766,2702 @@ pub enum Message {
         client: u16,
         tx: u32,
         amount: i64,
+        /// The asset bought with the amount, see the `position` module.
+        trade: Option<Trade>,
     },
     Withdrawal {
         client: u16,
         tx: u32,
         amount: i64,
+        /// The asset sold for the amount.
+        trade: Option<Trade>,
     },
     Dispute {
         client: u16,
         tx: u32,
+        reason: Option<Reason>,
     },
     Resolve {
         client: u16,
         tx: u32,
+        reason: Option<Reason>,
     },
     Chargeback {
         client: u16,
         tx: u32,
+        reason: Option<Reason>,
+    },
+    /// Debits a withdrawal awaiting approval.
+    Approve {
+        client: u16,
+        tx: u32,
+    },
+    /// Releases the funds earmarked by a withdrawal awaiting approval.
+    Reject {
+        client: u16,
+        tx: u32,
+    },
+    /// Earmarks funds like a card authorization, until captured or expired.
+    Authorize {
+        client: u16,
+        tx: u32,
+        amount: i64,
+    },
+    /// Debits an open authorization.
+    Capture {
+        client: u16,
+        tx: u32,
     },
     GetState {
-        tx: oneshot::Sender<Vec<State>>, // Return a stream instead?
+        tx: oneshot::Sender<StateView>,
+    },
+    GetStats {
+        reply: oneshot::Sender<Stats>,
+    },
+    /// The open disputes of all accounts, ordered by client and transaction.
+    GetDisputes {
+        tx: oneshot::Sender<Vec<OpenDispute>>,
+    },
+    /// The positions of all clients, ordered by client and asset.
+    GetPositions {
+        tx: oneshot::Sender<Vec<(u16, Asset, Position)>>,
+    },
+    SubscribeDisputes {
+        tx: oneshot::Sender<broadcast::Receiver<DisputeEvent>>,
+    },
+    /// Writes a snapshot if a snapshot path is configured and replies once done.
+    SaveSnapshot {
+        tx: oneshot::Sender<()>,
+    },
+    /// Applies a transactional message to a copy of its account and replies with the state the
+    /// account would have, or the error the message would be rejected with. Nothing is committed.
+    Simulate {
+        inner: Box<Message>,
+        reply: oneshot::Sender<Result<State, Error>>,
     },
+    /// Messages handled in order, which saves a channel send per message.
+    Batch(Batch),
 }
 
-struct Processor {
-    accounts: BTreeMap<u16, Account>,
-}
+impl Message {
+    /**
+     * The message type of transactional messages. Queries and admin messages have no type.
+     */
+    pub fn kind(&self) -> Option<&'static str> {
+        match self {
+            Message::Deposit { .. } => Some("deposit"),
+            Message::Withdrawal { .. } => Some("withdrawal"),
+            Message::Dispute { .. } => Some("dispute"),
+            Message::Resolve { .. } => Some("resolve"),
+            Message::Chargeback { .. } => Some("chargeback"),
+            Message::Approve { .. } => Some("approve"),
+            Message::Reject { .. } => Some("reject"),
+            Message::Authorize { .. } => Some("authorize"),
+            Message::Capture { .. } => Some("capture"),
+            Message::GetState { .. }
+            | Message::GetStats { .. }
+            | Message::GetDisputes { .. }
+            | Message::GetPositions { .. }
+            | Message::SubscribeDisputes { .. }
+            | Message::SaveSnapshot { .. }
+            | Message::Simulate { .. }
+            | Message::Batch(_) => None,
+        }
+    }
 
-impl Processor {
-    fn new() -> Processor {
-        Self {
-            accounts: BTreeMap::new(),
+    pub fn target(&self) -> Option<(u16, u32)> {
+        match self {
+            Message::Deposit { client, tx, .. }
+            | Message::Withdrawal { client, tx, .. }
+            | Message::Dispute { client, tx, .. }
+            | Message::Resolve { client, tx, .. }
+            | Message::Chargeback { client, tx, .. }
+            | Message::Approve { client, tx }
+            | Message::Reject { client, tx }
+            | Message::Authorize { client, tx, .. }
+            | Message::Capture { client, tx } => Some((*client, *tx)),
+            _ => None,
         }
     }
 
-    fn tx<F>(&mut self, client: u16, create: bool, mut f: F) -> Result<(), Error>
-    where
-        F: FnMut(&mut Account) -> Result<(), account::Error>,
-    {
-        let account = match self.accounts.entry(client) {
-            Entry::Occupied(entry) => entry.into_mut(),
-            Entry::Vacant(entry) => {
-                if create {
-                    entry.insert(Account::new())
-                } else {
-                    Err(Error::UnknownClient(client))?
-                }
+    /**
+     * Checks the amount of a deposit or withdrawal against the limits before it is sent to the
+     * processor, which would reject it the same way.
+     */
+    pub fn check_limits(&self, limits: &account::Limits) -> Result<(), Error> {
+        let res = match self {
+            Message::Deposit { amount, .. } => limits.deposit.check(*amount),
+            Message::Withdrawal { amount, .. } | Message::Authorize { amount, .. } => {
+                limits.withdrawal.check(*amount)
             }
+            _ => Ok(()),
         };
-        f(account).map_err(|err| Error::Transaction { client, err })
+        let (client, tx) = self.target().unwrap_or_default();
+        res.map_err(|err| Error::Transaction { client, tx, err })
     }
 
-    async fn handle(&mut self, msg: Message, tx_err: &mpsc::Sender<Error>) {
-        use Message::*;
+    /**
+     * A copy of a transactional message. Queries can't be copied.
+     */
+    pub fn copy(&self) -> Option<Message> {
+        match *self {
+            Message::Deposit {
+                client,
+                tx,
+                amount,
+                trade,
+            } => Some(Message::Deposit {
+                client,
+                tx,
+                amount,
+                trade,
+            }),
+            Message::Withdrawal {
+                client,
+                tx,
+                amount,
+                trade,
+            } => Some(Message::Withdrawal {
+                client,
+                tx,
+                amount,
+                trade,
+            }),
+            Message::Dispute { client, tx, reason } => {
+                Some(Message::Dispute { client, tx, reason })
+            }
+            Message::Resolve { client, tx, reason } => {
+                Some(Message::Resolve { client, tx, reason })
+            }
+            Message::Chargeback { client, tx, reason } => {
+                Some(Message::Chargeback { client, tx, reason })
+            }
+            Message::Approve { client, tx } => Some(Message::Approve { client, tx }),
+            Message::Reject { client, tx } => Some(Message::Reject { client, tx }),
+            Message::Authorize { client, tx, amount } => {
+                Some(Message::Authorize { client, tx, amount })
+            }
+            Message::Capture { client, tx } => Some(Message::Capture { client, tx }),
+            _ => None,
+        }
+    }
 
-        let res = match msg {
-            Deposit { client, tx, amount } => self.tx(client, true, |a| a.deposit(tx, amount)),
-            Withdrawal { client, tx, amount } => self.tx(client, false, |a| a.withdraw(tx, amount)),
-            Dispute { client, tx } => self.tx(client, false, |a| a.dispute(tx)),
-            Resolve { client, tx } => self.tx(client, false, |a| a.resolve(tx)),
-            Chargeback { client, tx } => self.tx(client, false, |a| a.chargeback(tx)),
-            GetState { tx } => tx.send(self.state()).map_err(|_| Error::Send()),
-        };
-        if let Err(err) = res {
-            let _ = tx_err.send(err).await;
+    pub fn trade(&self) -> Option<Trade> {
+        match self {
+            Message::Deposit { trade, .. } | Message::Withdrawal { trade, .. } => *trade,
+            _ => None,
         }
     }
 
-    fn state(&self) -> Vec<State> {
-        self.accounts
-            .iter()
-            .map(|(client, account)| State {
-                client: *client,
-                available: account.available,
-                held: account.held,
-                total: account.total(),
-                locked: account.locked,
-            })
-            .collect()
+    pub fn reason(&self) -> Option<Reason> {
+        match self {
+            Message::Dispute { reason, .. }
+            | Message::Resolve { reason, .. }
+            | Message::Chargeback { reason, .. } => *reason,
+            _ => None,
+        }
     }
 }
 
-pub async fn run() -> (mpsc::Sender<Message>, mpsc::Receiver<Error>) {
-    let (tx_msg, mut rx_msg) = mpsc::channel(100);
-    let (tx_err, rx_err) = mpsc::channel(100);
+/**
+ * External storage used by the processor.
+ */
+#[derive(Default)]
+pub struct Storage {
+    /// Accepted state-changing messages get appended to the audit log.
+    pub audit: Option<audit::Log>,
+    /// Older transaction log entries of all accounts get spilled to this store.
+    pub spill: Option<Arc<spill::Store>>,
+    /// State-changing messages get appended to the write-ahead log before they are applied.
+    pub wal: Option<wal::Log>,
+    /// Messages recovered from the write-ahead log, which are replayed before any new message.
+    pub recovered: Recovered,
+    /// Snapshots get written to this path. Writing one resets the write-ahead log.
+    pub snapshot: Option<PathBuf>,
+    /// The state to start from, loaded from an earlier snapshot.
+    pub restored: Option<snapshot::Contents>,
+    /// Handled batches are returned to this pool for reuse by the producers.
+    pub batches: BatchPool,
+}
 
-    tokio::spawn(async move {
-        let mut processor = Processor::new();
-        while let Some(msg) = rx_msg.recv().await {
-            processor.handle(msg, &tx_err).await;
+/**
+ * The messages of a `Batch` message, optionally with their origins.
+ */
+#[derive(Debug, Default)]
+pub struct Batch {
+    pub msgs: Vec<Message>,
+    /// Origins of the messages at the same positions. Empty if unknown.
+    pub origins: Vec<Origin>,
+}
+
+impl Batch {
+    pub fn len(&self) -> usize {
+        self.msgs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.msgs.is_empty()
+    }
+
+    pub fn push(&mut self, msg: Message, origin: Origin) {
+        self.msgs.push(msg);
+        self.origins.push(origin);
+    }
+
+    fn clear(&mut self) {
+        self.msgs.clear();
+        self.origins.clear();
+    }
+}
+
+impl From<Vec<Message>> for Batch {
+    fn from(msgs: Vec<Message>) -> Batch {
+        Batch {
+            msgs,
+            origins: Vec::new(),
         }
-    });
+    }
+}
+
+/**
+ * Recycles the buffers of `Batch` messages, so steady-state producers don't allocate per batch.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct BatchPool(Arc<Mutex<Vec<Batch>>>);
+
+impl BatchPool {
+    /**
+     * An empty batch with room for `BATCH_SIZE` messages.
+     */
+    pub fn take(&self) -> Batch {
+        self.0.lock().unwrap().pop().unwrap_or_else(|| Batch {
+            msgs: Vec::with_capacity(BATCH_SIZE),
+            origins: Vec::new(),
+        })
+    }
+
+    fn put(&self, mut batch: Batch) {
+        batch.clear();
+        let mut pool = self.0.lock().unwrap();
+        // At most every slot of the channel can hold a batch.
+        if pool.len() < CHANNEL_SIZE {
+            pool.push(batch);
+        }
+    }
+}
+
+// A message in the history of an account, with its outcome when it was applied last.
+struct Historic {
+    timestamp: u64,
+    msg: Message,
+    accepted: bool,
+}
+
+struct Processor {
+    config: Config,
+    storage: Storage,
+    /// The limits of the configuration, shared by all accounts.
+    limits: Option<Arc<account::Limits>>,
+    accounts: HashMap<u16, Account>,
+    /// The client of every logged transaction, to tell foreign transactions from unknown ones.
+    /// Stays in memory even with a spill store, so it grows with every transaction id.
+    owners: HashMap<u32, u16>,
+    view: StateView,
+    stats: Stats,
+    /// Number of disputes opened per client.
+    disputes: BTreeMap<u16, u32>,
+    /// Reason codes of the open disputes which were opened with one.
+    reasons: HashMap<(u16, u32), Reason>,
+    /// When the open disputes were opened, as the number of transactional messages handled by then.
+    opened: HashMap<(u16, u32), u64>,
+    /// Transactional messages of every account in timestamp order, see `Config::backfill`.
+    history: HashMap<u16, Vec<Historic>>,
+    /// Set while the history of an account is replayed, which publishes no dispute events.
+    replaying: bool,
+    /// Clients on hold for review, see `Config::hold_negative`.
+    holds: BTreeSet<u16>,
+    /// Clients whose transactions are booked on the account of a primary client.
+    links: links::Links,
+    /// Positions of the clients by asset, see the `position` module.
+    positions: BTreeMap<(u16, Asset), Position>,
+    /// Number of the last transactional message of every account, see `Config::evict_idle`.
+    active: HashMap<u16, u64>,
+    /// Clients whose empty accounts were evicted. They stay in the report and in snapshots.
+    evicted: BTreeSet<u16>,
+    /// Number of transactional messages handled.
+    handled: u64,
+    /// Account metadata of the restored snapshot, only carried along into the next one.
+    metadata: BTreeMap<u16, Metadata>,
+    /// Warnings raised while handling the current message.
+    warnings: Vec<Warning>,
+    dispute_events: broadcast::Sender<DisputeEvent>,
+    /// Origin of the message being handled.
+    origin: Option<Origin>,
+    /// Number of transactional messages handled since the last snapshot.
+    since_snapshot: u64,
+    /// Expiry times of the open authorizations with their client and transaction, in the order
+    /// they expire. Entries of captured authorizations are skipped when they come up.
+    expiries: BTreeSet<(u64, u16, u32)>,
+    /// The current time in Unix epoch milliseconds, to measure lags.
+    now: fn() -> u64,
+}
+
+fn epoch_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+/**
+ * The configuration an account books a transactional message with. `apply` books messages on the
+ * account of their client and `simulate` on a fork of it, both through `book`.
+ */
+#[derive(Clone, Copy)]
+struct Terms {
+    approve_above: Option<i64>,
+    reverse_chargebacks: bool,
+    /// Expiry time of an authorization booked now.
+    expires: u64,
+}
+
+impl Terms {
+    // Withdrawals above the limit wait for an approval.
+    fn approval(self, amount: i64) -> bool {
+        self.approve_above.is_some_and(|limit| amount > limit)
+    }
+
+    fn book(self, msg: &Message, account: &mut Account) -> Result<(), account::Error> {
+        use Message::*;
 
-    (tx_msg, rx_err)
+        match *msg {
+            Deposit { tx, amount, .. } => account.deposit(tx, amount),
+            Withdrawal { tx, amount, .. } if self.approval(amount) => account.request(tx, amount),
+            Withdrawal { tx, amount, .. } => account.withdraw(tx, amount),
+            Approve { tx, .. } => account.approve(tx),
+            Reject { tx, .. } => account.reject(tx),
+            Authorize { tx, amount, .. } => account.authorize(tx, amount, self.expires),
+            Capture { tx, .. } => account.capture(tx),
+            Dispute { tx, .. } => account.dispute(tx),
+            Resolve { tx, .. } => account.resolve(tx),
+            Chargeback { tx, .. } => account.chargeback(tx, self.reverse_chargebacks),
+            // not transactional
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Processor {
+    fn new(config: Config, storage: Storage) -> Processor {
+        Self {
+            limits: (!config.limits.is_empty()).then(|| Arc::new(config.limits)),
+            config,
+            storage,
+            accounts: HashMap::new(),
+            owners: HashMap::new(),
+            view: StateView::default(),
+            stats: Stats::default(),
+            disputes: BTreeMap::new(),
+            reasons: HashMap::new(),
+            opened: HashMap::new(),
+            history: HashMap::new(),
+            replaying: false,
+            holds: BTreeSet::new(),
+            links: links::Links::default(),
+            positions: BTreeMap::new(),
+            active: HashMap::new(),
+            evicted: BTreeSet::new(),
+            handled: 0,
+            metadata: BTreeMap::new(),
+            warnings: Vec::new(),
+            dispute_events: broadcast::channel(100).0,
+            origin: None,
+            since_snapshot: 0,
+            expiries: BTreeSet::new(),
+            now: epoch_millis,
+        }
+    }
+
+    /**
+     * Rejects clients which aren't allowed, and clients without an account unless the message
+     * creates one.
+     */
+    fn chk_client(&self, client: u16, tx: u32, create: bool) -> Result<(), Error> {
+        match &self.config.clients {
+            Some(clients) if !clients.contains(client) => {
+                Err(Error::ClientNotAllowed { client, tx })
+            }
+            _ if create
+                || self.accounts.contains_key(&client)
+                || self.evicted.contains(&client) =>
+            {
+                Ok(())
+            }
+            _ => Err(Error::UnknownClient { client, tx }),
+        }
+    }
+
+    fn tx<F>(&mut self, client: u16, tx: u32, create: bool, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(&mut Account) -> Result<(), account::Error>,
+    {
+        self.chk_client(client, tx, create)?;
+        let account = match self.accounts.entry(client) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                // evicted accounts were empty, they start over with an empty log
+                self.evicted.remove(&client);
+                let account = match &self.storage.spill {
+                    Some(store) => Account::with_store(store.clone()),
+                    None => Account::new(),
+                };
+                entry.insert(match &self.limits {
+                    Some(limits) => account.limited(limits.clone()),
+                    None => account,
+                })
+            }
+        };
+        let before = account.total();
+        let available = account.available();
+        let res = f(account);
+        // also covers accounts just created by a rejected transaction
+        self.view.set(State::new(client, account));
+        res.map_err(|err| Error::Transaction { client, tx, err })?;
+        match self.config.large_total {
+            Some(limit) if before <= limit && account.total() > limit => {
+                self.warnings.push(Warning::LargeTotal {
+                    client,
+                    tx,
+                    total: limit,
+                })
+            }
+            _ => (),
+        }
+        // only crossing the threshold warns, not every transaction below it
+        match self.config.low_balance.limit(client) {
+            Some(threshold) if available >= threshold && account.available() < threshold => {
+                self.warnings.push(Warning::LowBalance {
+                    client,
+                    tx,
+                    available: account.available(),
+                    threshold,
+                })
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // The amount of the transaction a dispute, resolve, chargeback, approval, rejection or capture
+    // refers to.
+    fn disputed(&self, msg: &Message) -> Option<i64> {
+        match msg {
+            Message::Dispute { client, tx, .. }
+            | Message::Resolve { client, tx, .. }
+            | Message::Chargeback { client, tx, .. } => {
+                self.accounts.get(client).and_then(|a| a.amount(*tx))
+            }
+            Message::Approve { client, tx } | Message::Reject { client, tx } => self
+                .accounts
+                .get(client)
+                .and_then(|a| a.pending_amount(*tx)),
+            Message::Capture { client, tx } => self
+                .accounts
+                .get(client)
+                .and_then(|a| a.authorization(*tx))
+                .map(|(amount, _)| amount),
+            _ => None,
+        }
+    }
+
+    /**
+     * The message with the client replaced by its primary client, if it is linked.
+     */
+    fn relink(&self, msg: &Message) -> Option<Message> {
+        let (client, _) = msg.target()?;
+        let primary = self.links.primary(client)?;
+        let mut linked = msg.copy()?;
+        match &mut linked {
+            Message::Deposit { client, .. }
+            | Message::Withdrawal { client, .. }
+            | Message::Dispute { client, .. }
+            | Message::Resolve { client, .. }
+            | Message::Chargeback { client, .. }
+            | Message::Approve { client, .. }
+            | Message::Reject { client, .. }
+            | Message::Authorize { client, .. }
+            | Message::Capture { client, .. } => *client = primary,
+            _ => (),
+        }
+        Some(linked)
+    }
+
+    fn own(&mut self, client: u16, tx: u32) {
+        // Transaction ids are only unique per account, the first client keeps the id.
+        self.owners.entry(tx).or_insert(client);
+    }
+
+    /**
+     * Rejects references to a transaction which the client doesn't have but another client does.
+     */
+    fn chk_owner(&self, client: u16, tx: u32) -> Result<(), Error> {
+        match self.owners.get(&tx) {
+            Some(owner)
+                if *owner != client
+                    && self
+                        .accounts
+                        .get(&client)
+                        .and_then(|a| a.amount(tx))
+                        .is_none() =>
+            {
+                Err(Error::ForeignTransaction {
+                    client,
+                    tx,
+                    owner: *owner,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn chk_reason(&self, client: u16, tx: u32, reason: Option<Reason>) -> Result<(), Error> {
+        match (reason, &self.config.reason_codes) {
+            (Some(reason), Some(codes)) if !codes.contains(&reason) => {
+                Err(Error::UnknownReason { client, tx, reason })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn publish(&self, client: u16, tx: u32, status: DisputeStatus, reason: Option<Reason>) {
+        if self.replaying {
+            return;
+        }
+        if let Some(amount) = self.accounts.get(&client).and_then(|a| a.amount(tx)) {
+            // Sending only fails without subscribers.
+            let _ = self.dispute_events.send(DisputeEvent {
+                client,
+                tx,
+                amount,
+                status,
+                reason,
+                origin: self.origin.clone(),
+            });
+        }
+    }
+
+    /**
+     * Publishes the end of a dispute, with the reason code of the dispute if the message has none.
+     */
+    fn close(&mut self, client: u16, tx: u32, status: DisputeStatus, reason: Option<Reason>) {
+        self.opened.remove(&(client, tx));
+        let opened = self.reasons.remove(&(client, tx));
+        self.publish(client, tx, status, reason.or(opened));
+    }
+
+    /**
+     * Publishes an accepted dispute and counts it.
+     */
+    fn open(&mut self, client: u16, tx: u32, reason: Option<Reason>) {
+        match reason {
+            Some(reason) => self.reasons.insert((client, tx), reason),
+            None => self.reasons.remove(&(client, tx)),
+        };
+        // the message itself isn't counted yet
+        self.opened.insert((client, tx), self.handled + 1);
+        self.publish(client, tx, DisputeStatus::Opened, reason);
+        let disputes = self.disputes.entry(client).or_default();
+        *disputes += 1;
+        match self.config.max_disputes {
+            Some(limit) if *disputes == limit + 1 => self.warnings.push(Warning::ManyDisputes {
+                client,
+                tx,
+                disputes: *disputes,
+            }),
+            _ => (),
+        }
+        let available = self.accounts.get(&client).map_or(0, Account::available);
+        if self.config.hold_negative && available < 0 && self.holds.insert(client) {
+            self.warnings.push(Warning::NegativeBalance {
+                client,
+                tx,
+                available,
+            });
+        }
+    }
+
+    // Books the asset bought or sold by an accepted deposit or withdrawal.
+    fn trade(&mut self, client: u16, amount: i64, trade: Option<Trade>, buy: bool) {
+        if let Some(trade) = trade {
+            let position = self.positions.entry((client, trade.asset)).or_default();
+            if buy {
+                position.buy(amount, &trade);
+            } else {
+                position.sell(amount, &trade);
+            }
+        }
+    }
+
+    fn open_disputes(&self) -> Vec<OpenDispute> {
+        let mut disputes = Vec::new();
+        for (client, account) in &self.accounts {
+            for tx in account.disputes() {
+                disputes.push(OpenDispute {
+                    client: *client,
+                    tx,
+                    amount: account.amount(tx).unwrap_or_default(),
+                    age: self
+                        .opened
+                        .get(&(*client, tx))
+                        .map(|opened| self.handled - opened),
+                    reason: self.reasons.get(&(*client, tx)).copied(),
+                });
+            }
+        }
+        disputes.sort_unstable_by_key(|dispute| (dispute.client, dispute.tx));
+        disputes
+    }
+
+    /**
+     * Checks the invariants of an account, including its entry in the state view.
+     */
+    fn verify(&self, client: u16, tx: u32) -> Result<(), Error> {
+        let account = match self.accounts.get(&client) {
+            Some(account) => account,
+            None => return Ok(()),
+        };
+        let violation = |violation| Error::Invariant {
+            client,
+            tx,
+            violation,
+        };
+        let disputed = account
+            .disputed()
+            .map_err(|err| Error::Transaction { client, tx, err })?;
+        if account.held() != disputed {
+            return Err(violation(Violation::Held {
+                held: account.held(),
+                disputed,
+            }));
+        }
+        let actual = State::new(client, account);
+        let reported = self.view.get(client).copied();
+        match reported {
+            Some(state) if state.total != state.available + state.held => {
+                Err(violation(Violation::Total {
+                    total: state.total,
+                    available: state.available,
+                    held: state.held,
+                }))
+            }
+            Some(state) if state == actual => Ok(()),
+            _ => Err(violation(Violation::View { reported, actual })),
+        }
+    }
+
+    /**
+     * The state of the account after a transactional message, applied to a copy of the account
+     * with the same checks as `apply`. Messages of linked clients are applied to the copy of the
+     * primary's account, which the state belongs to. Expiry times and warnings aren't simulated.
+     */
+    fn simulate(&self, msg: &Message) -> Result<State, Error> {
+        let (member, _) = msg.target().ok_or(Error::NotTransactional)?;
+        match self.relink(msg) {
+            Some(linked) => self.fork(&linked).map_err(|err| err.for_client(member)),
+            None => self.fork(msg),
+        }
+    }
+
+    fn fork(&self, msg: &Message) -> Result<State, Error> {
+        let (client, tx) = msg.target().ok_or(Error::NotTransactional)?;
+        let err = |err| Error::Transaction { client, tx, err };
+        self.admit(msg)?;
+        self.chk_client(client, tx, matches!(msg, Message::Deposit { .. }))?;
+        let mut account = match self.accounts.get(&client) {
+            Some(account) => account.fork(tx).map_err(err)?,
+            None => match &self.limits {
+                Some(limits) => Account::new().limited(limits.clone()),
+                None => Account::new(),
+            },
+        };
+        self.terms().book(msg, &mut account).map_err(err)?;
+        Ok(State::new(client, &account))
+    }
+
+    /**
+     * Rejects messages to clients on hold, about transactions of other clients or with unknown
+     * reason codes, before their account is touched.
+     */
+    fn admit(&self, msg: &Message) -> Result<(), Error> {
+        use Message::*;
+
+        match *msg {
+            Withdrawal { client, tx, .. }
+            | Approve { client, tx }
+            | Authorize { client, tx, .. }
+            | Capture { client, tx }
+                if self.holds.contains(&client) =>
+            {
+                Err(Error::OnHold { client, tx })
+            }
+            Dispute { client, tx, reason }
+            | Resolve { client, tx, reason }
+            | Chargeback { client, tx, reason } => {
+                self.chk_owner(client, tx)?;
+                self.chk_reason(client, tx, reason)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn terms(&self) -> Terms {
+        // event time, so replaying the write-ahead log expires the same authorizations
+        let timestamp = self.origin.as_ref().and_then(|origin| origin.timestamp);
+        Terms {
+            approve_above: self.config.approve_above,
+            reverse_chargebacks: self.config.reverse_chargebacks,
+            expires: match (self.config.authorization_ttl, timestamp) {
+                (Some(ttl), Some(timestamp)) => timestamp.saturating_add(ttl),
+                _ => u64::MAX,
+            },
+        }
+    }
+
+    fn apply(&mut self, msg: Message) -> Result<(), Error> {
+        use Message::*;
+
+        let (client, tx) = match msg.target() {
+            Some(target) => target,
+            None => return self.respond(msg),
+        };
+        self.admit(&msg)?;
+        let terms = self.terms();
+        let authorization = match msg {
+            Capture { .. } => self.accounts.get(&client).and_then(|a| a.authorization(tx)),
+            _ => None,
+        };
+        let create = matches!(msg, Deposit { .. });
+        self.tx(client, tx, create, |a| terms.book(&msg, a))?;
+        match msg {
+            Deposit { amount, trade, .. } => {
+                self.own(client, tx);
+                self.trade(client, amount, trade, true);
+                match self.config.large_deposit {
+                    Some(limit) if amount > limit => {
+                        self.warnings
+                            .push(Warning::LargeDeposit { client, tx, amount });
+                    }
+                    _ => (),
+                }
+            }
+            Withdrawal { amount, trade, .. } => {
+                self.own(client, tx);
+                // the sale is booked with the request, approvals carry no trade
+                self.trade(client, amount, trade, false);
+                if terms.approval(amount) {
+                    self.warnings
+                        .push(Warning::PendingApproval { client, tx, amount });
+                }
+            }
+            Authorize { .. } => {
+                self.own(client, tx);
+                self.expiries.insert((terms.expires, client, tx));
+            }
+            Capture { .. } => {
+                if let Some((_, expires)) = authorization {
+                    self.expiries.remove(&(expires, client, tx));
+                }
+            }
+            Dispute { reason, .. } => self.open(client, tx, reason),
+            Resolve { reason, .. } => self.close(client, tx, DisputeStatus::Resolved, reason),
+            Chargeback { reason, .. } => self.close(client, tx, DisputeStatus::ChargedBack, reason),
+            // approvals and rejections only change the account
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // Answers queries and carries out admin messages.
+    fn respond(&mut self, msg: Message) -> Result<(), Error> {
+        use Message::*;
+
+        match msg {
+            GetState { tx } => tx.send(self.view.clone()).map_err(|_| Error::Send()),
+            GetStats { reply } => reply.send(self.stats.clone()).map_err(|_| Error::Send()),
+            GetDisputes { tx } => tx.send(self.open_disputes()).map_err(|_| Error::Send()),
+            GetPositions { tx } => tx
+                .send(
+                    self.positions
+                        .iter()
+                        .map(|((client, asset), position)| (*client, *asset, *position))
+                        .collect(),
+                )
+                .map_err(|_| Error::Send()),
+            SubscribeDisputes { tx } => tx
+                .send(self.dispute_events.subscribe())
+                .map_err(|_| Error::Send()),
+            SaveSnapshot { tx } => {
+                let res = self.save();
+                let _ = tx.send(());
+                res
+            }
+            Simulate { inner, reply } => {
+                reply.send(self.simulate(&inner)).map_err(|_| Error::Send())
+            }
+            // Unpacked by the receive loop.
+            Batch(_) => Ok(()),
+            // Booked by `apply`, which only passes the other messages on.
+            Deposit { .. }
+            | Withdrawal { .. }
+            | Dispute { .. }
+            | Resolve { .. }
+            | Chargeback { .. }
+            | Approve { .. }
+            | Reject { .. }
+            | Authorize { .. }
+            | Capture { .. } => Ok(()),
+        }
+    }
+
+    /**
+     * Applies a message in timestamp order. Messages without a timestamp count as the latest ones
+     * of their account. A late message is inserted into the history of its account, which is then
+     * rebuilt by replaying the whole history.
+     */
+    fn backfill(&mut self, msg: Message) -> Result<(), Error> {
+        let (client, tx, copy) = match (msg.target(), msg.copy()) {
+            (Some((client, tx)), Some(copy)) => (client, tx, copy),
+            _ => return self.apply(msg),
+        };
+        // Accounts restored from a snapshot have no history to replay.
+        if !self.history.contains_key(&client) && self.accounts.contains_key(&client) {
+            return self.apply(msg);
+        }
+        let history = self.history.entry(client).or_default();
+        let latest = history.last().map(|historic| historic.timestamp);
+        let timestamp = self
+            .origin
+            .as_ref()
+            .and_then(|origin| origin.timestamp)
+            .or(latest)
+            .unwrap_or_default();
+        if latest.is_none_or(|latest| timestamp >= latest) {
+            let res = self.apply(msg);
+            self.history.entry(client).or_default().push(Historic {
+                timestamp,
+                msg: copy,
+                accepted: res.is_ok(),
+            });
+            return res;
+        }
+
+        // Ties keep the arrival order, the late message goes after all messages of its time.
+        let pos = history.partition_point(|historic| historic.timestamp <= timestamp);
+        history.insert(
+            pos,
+            Historic {
+                timestamp,
+                msg: copy,
+                accepted: false,
+            },
+        );
+        let mut history = self.history.remove(&client).unwrap_or_default();
+        // Warnings and dispute counts were already raised when the messages arrived.
+        let warnings = self.warnings.len();
+        let disputes = self.disputes.get(&client).copied();
+        self.accounts.remove(&client);
+        self.positions.retain(|(c, _), _| *c != client);
+        self.replaying = true;
+        let mut res = Ok(());
+        let mut changed = 0;
+        for (i, historic) in history.iter_mut().enumerate() {
+            let replayed = historic.msg.copy().map_or(Ok(()), |msg| self.apply(msg));
+            if i == pos {
+                historic.accepted = replayed.is_ok();
+                res = replayed;
+            } else if historic.accepted != replayed.is_ok() {
+                historic.accepted = replayed.is_ok();
+                changed += 1;
+            }
+        }
+        self.replaying = false;
+        self.warnings.truncate(warnings);
+        match disputes {
+            Some(count) => self.disputes.insert(client, count),
+            None => self.disputes.remove(&client),
+        };
+        let replayed = history.len() - pos - 1;
+        self.history.insert(client, history);
+        self.warnings.push(Warning::Backfilled {
+            client,
+            tx,
+            replayed,
+            changed,
+        });
+        res
+    }
+
+    async fn handle(
+        &mut self,
+        msg: Message,
+        origin: Option<Origin>,
+        tx_notify: &mpsc::Sender<Notification>,
+    ) {
+        if let Some(timestamp) = origin.as_ref().and_then(|origin| origin.timestamp) {
+            self.expire(timestamp, tx_notify).await;
+        }
+        // Messages of linked clients are booked on the primary's account but keep their client in
+        // the records and errors.
+        let linked = self.relink(&msg);
+        let member = linked.as_ref().and(msg.target()).map(|(client, _)| client);
+        self.origin = origin;
+        let record = match (&self.storage.audit, &self.storage.wal) {
+            (None, None) => None,
+            _ => audit::record(
+                &msg,
+                self.disputed(linked.as_ref().unwrap_or(&msg)),
+                self.origin.as_ref(),
+            ),
+        };
+        let msg = linked.unwrap_or(msg);
+        let kind = msg.kind();
+        let msg_target = msg.target();
+        let client = msg_target.map(|(client, _)| client);
+        let target = msg_target.filter(|_| self.config.check_invariants);
+        let res = match (&mut self.storage.wal, &record) {
+            (Some(wal), Some(record)) => wal.append(record).map_err(Error::Wal),
+            _ => Ok(()),
+        };
+        let res = res.and_then(|()| {
+            if self.config.backfill {
+                self.backfill(msg)
+            } else {
+                self.apply(msg)
+            }
+        });
+        let res = match member {
+            Some(member) => res.map_err(|err| err.for_client(member)),
+            None => res,
+        };
+        let timestamp = self.origin.as_ref().and_then(|origin| origin.timestamp);
+        if let (Some((client, tx)), Some(timestamp)) = (msg_target, timestamp) {
+            self.lag(member.unwrap_or(client), tx, timestamp);
+        }
+        if let Some(kind) = kind {
+            self.stats.count(kind, &res);
+            self.since_snapshot += 1;
+            self.handled += 1;
+        }
+        if let Some(client) = client {
+            self.active.insert(client, self.handled);
+        }
+        match self.config.evict_idle {
+            Some(idle) if kind.is_some() && self.handled.is_multiple_of(idle.max(1)) => {
+                self.evict(idle)
+            }
+            _ => (),
+        }
+        let res = match (res, &mut self.storage.audit, record) {
+            (Ok(()), Some(log), Some(record)) => {
+                // The audit log must not get ahead of the write-ahead log, or a crash within the
+                // batch leaves audited transactions which recovery doesn't replay.
+                let flushed = match &mut self.storage.wal {
+                    Some(wal) => wal.flush().map_err(Error::Wal),
+                    None => Ok(()),
+                };
+                flushed.and_then(|()| {
+                    log.append(&record)
+                        .map_err(|err| Error::Audit { record, err })
+                })
+            }
+            (res, ..) => res,
+        };
+        if let Err(err) = res {
+            let _ = tx_notify
+                .send(Notification::Error(err, self.origin.clone()))
+                .await;
+        }
+        // rejected messages are checked as well, they must leave the account untouched
+        if let Some(Err(err)) = target.map(|(client, tx)| self.verify(client, tx)) {
+            let _ = tx_notify
+                .send(Notification::Error(err, self.origin.clone()))
+                .await;
+        }
+        for warning in self.warnings.drain(..) {
+            let _ = tx_notify
+                .send(Notification::Warning(warning, self.origin.clone()))
+                .await;
+        }
+    }
+
+    /**
+     * Releases the authorizations whose expiry time is before the timestamp of the message about
+     * to be handled. Runs before every message with a timestamp, so the processor needs no timer
+     * task of its own and expires the same authorizations when the messages are replayed.
+     */
+    async fn expire(&mut self, now: u64, tx_notify: &mpsc::Sender<Notification>) {
+        while let Some(&(expires, client, tx)) = self.expiries.first() {
+            if expires > now {
+                break;
+            }
+            self.expiries.pop_first();
+            let amount = self
+                .accounts
+                .get_mut(&client)
+                .and_then(|a| a.expire(tx, expires));
+            if let Some(amount) = amount {
+                self.stats.expired += 1;
+                let warning = Warning::AuthorizationExpired { client, tx, amount };
+                let _ = tx_notify.send(Notification::Warning(warning, None)).await;
+            }
+        }
+    }
+
+    // Records the lag of a transaction and warns if it is beyond the SLA of the client.
+    fn lag(&mut self, client: u16, tx: u32, timestamp: u64) {
+        let lag = (self.now)().saturating_sub(timestamp);
+        self.stats.lags.record(lag);
+        match self.config.sla.limit(client) {
+            Some(sla) if lag > sla => {
+                self.stats.lags.exceeded += 1;
+                self.warnings.push(Warning::SlaExceeded {
+                    client,
+                    tx,
+                    lag,
+                    sla,
+                });
+            }
+            _ => (),
+        }
+    }
+
+    /**
+     * Removes the empty accounts without a transactional message among the last `idle` ones. Runs
+     * every `idle` messages, so an account stays in memory for at most twice as many.
+     */
+    fn evict(&mut self, idle: u64) {
+        let handled = self.handled;
+        let idle = |client: &u16| {
+            self.active
+                .get(client)
+                .is_none_or(|active| handled - active >= idle)
+        };
+        let evict: Vec<u16> = self
+            .accounts
+            .iter()
+            .filter(|(client, account)| {
+                account.is_empty() && !self.holds.contains(client) && idle(client)
+            })
+            .map(|(client, _)| *client)
+            .collect();
+        for client in evict {
+            if let Some(account) = self.accounts.remove(&client) {
+                // Without the account the ids of its transactions have no owner anymore.
+                for (tx, _) in account
+                    .to_parts()
+                    .map(|parts| parts.log)
+                    .unwrap_or_default()
+                {
+                    if self.owners.get(&tx) == Some(&client) {
+                        self.owners.remove(&tx);
+                    }
+                }
+            }
+            self.active.remove(&client);
+            self.history.remove(&client);
+            self.evicted.insert(client);
+            self.stats.evicted += 1;
+        }
+    }
+
+    /**
+     * Ends a batch of write-ahead log entries.
+     */
+    async fn commit(&mut self, tx_notify: &mpsc::Sender<Notification>) {
+        if let Some(Err(err)) = self.storage.wal.as_mut().map(wal::Log::commit) {
+            let _ = tx_notify
+                .send(Notification::Error(Error::Wal(err), None))
+                .await;
+        }
+    }
+
+    /**
+     * Writes a snapshot if one is due.
+     */
+    async fn checkpoint(&mut self, tx_notify: &mpsc::Sender<Notification>) {
+        match self.config.snapshot_every {
+            Some(every) if self.since_snapshot >= every => {
+                if let Err(err) = self.save() {
+                    let _ = tx_notify.send(Notification::Error(err, None)).await;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn save(&mut self) -> Result<(), Error> {
+        let path = match &self.storage.snapshot {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut contents = snapshot::Contents {
+            accounts: Vec::with_capacity(self.accounts.len()),
+            disputes: self.disputes.clone(),
+            reasons: self
+                .reasons
+                .iter()
+                .map(|(key, reason)| (*key, reason.to_string()))
+                .collect(),
+            holds: self.holds.clone(),
+            links: self.links.clone(),
+            metadata: self.metadata.clone(),
+            positions: self
+                .positions
+                .iter()
+                .map(|((client, asset), position)| ((*client, asset.to_string()), *position))
+                .collect(),
+        };
+        for (client, account) in &self.accounts {
+            let parts = account
+                .to_parts()
+                .map_err(|err| Error::Snapshot(snapshot::Error::Account(err)))?;
+            contents.accounts.push((*client, parts));
+        }
+        contents.accounts.extend(
+            self.evicted
+                .iter()
+                .map(|client| (*client, account::Parts::default())),
+        );
+        contents
+            .accounts
+            .sort_unstable_by_key(|(client, _)| *client);
+        snapshot::write(path, &contents).map_err(Error::Snapshot)?;
+        self.since_snapshot = 0;
+        // everything in the write-ahead log is part of the snapshot now
+        match &mut self.storage.wal {
+            Some(wal) => wal.reset().map_err(Error::Wal),
+            None => Ok(()),
+        }
+    }
+
+    /**
+     * Loads the accounts of the snapshot to start from.
+     */
+    fn restore(&mut self) -> Result<(), Error> {
+        let contents = match self.storage.restored.take() {
+            Some(contents) => contents,
+            None => return Ok(()),
+        };
+        for (client, parts) in contents.accounts {
+            for (tx, _) in &parts.log {
+                self.own(client, *tx);
+            }
+            for (tx, _, expires) in &parts.authorized {
+                self.own(client, *tx);
+                self.expiries.insert((*expires, client, *tx));
+            }
+            let mut account = Account::from_parts(parts, self.storage.spill.clone())
+                .map_err(|err| Error::Snapshot(snapshot::Error::Account(err)))?;
+            if let Some(limits) = &self.limits {
+                account = account.limited(limits.clone());
+            }
+            self.view.set(State::new(client, &account));
+            self.accounts.insert(client, account);
+        }
+        self.disputes = contents.disputes;
+        self.holds = contents.holds;
+        self.links = contents.links;
+        self.metadata = contents.metadata;
+        for (key, reason) in contents.reasons {
+            let reason = Reason::parse(&reason)
+                .map_err(|_| Error::Snapshot(snapshot::Error::Reason(reason)))?;
+            self.reasons.insert(key, reason);
+        }
+        for ((client, asset), position) in contents.positions {
+            let asset =
+                Asset::parse(&asset).map_err(|_| Error::Snapshot(snapshot::Error::Asset(asset)))?;
+            self.positions.insert((client, asset), position);
+        }
+        Ok(())
+    }
+
+    /**
+     * Replays the messages recovered from the write-ahead log.
+     */
+    async fn recover(&mut self) {
+        // The messages are already logged and their notifications were sent before the crash.
+        let audit = self.storage.audit.take();
+        let wal = self.storage.wal.take();
+        let (tx_ignore, _) = mpsc::channel(1);
+        for (msg, origin) in mem::take(&mut self.storage.recovered) {
+            self.handle(msg, origin, &tx_ignore).await;
+        }
+        self.storage.audit = audit;
+        self.storage.wal = wal;
+    }
+}
+
+/**
+ * Spawns the processor task.
+ */
+pub async fn run(
+    config: Config,
+    storage: Storage,
+) -> (mpsc::Sender<Message>, mpsc::Receiver<Notification>) {
+    let (tx_msg, mut rx_msg) = mpsc::channel(CHANNEL_SIZE);
+    let (tx_notify, rx_notify) = mpsc::channel(CHANNEL_SIZE);
+
+    tokio::spawn(async move {
+        let mut processor = Processor::new(config, storage);
+        if let Err(err) = processor.restore() {
+            let _ = tx_notify.send(Notification::Error(err, None)).await;
+        }
+        processor.recover().await;
+        while let Some(msg) = rx_msg.recv().await {
+            match msg {
+                Message::Batch(mut batch) => {
+                    let mut origins = batch.origins.drain(..);
+                    for msg in batch.msgs.drain(..) {
+                        processor.handle(msg, origins.next(), &tx_notify).await;
+                    }
+                    drop(origins);
+                    processor.storage.batches.put(batch);
+                }
+                msg => processor.handle(msg, None, &tx_notify).await,
+            }
+            processor.commit(&tx_notify).await;
+            processor.checkpoint(&tx_notify).await;
+        }
+    });
+
+    (tx_msg, rx_notify)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stats() {
+        let (tx_msg, mut rx_notify) = run(Config::default(), Storage::default()).await;
+        for msg in [
+            Message::Deposit {
+                client: 1,
+                tx: 1,
+                amount: 5,
+                trade: None,
+            },
+            Message::Deposit {
+                client: 1,
+                tx: 1,
+                amount: 5,
+                trade: None,
+            },
+            Message::Withdrawal {
+                client: 1,
+                tx: 2,
+                amount: 10,
+                trade: None,
+            },
+            Message::Dispute {
+                client: 2,
+                tx: 3,
+                reason: None,
+            },
+            Message::Dispute {
+                client: 1,
+                tx: 1,
+                reason: None,
+            },
+        ] {
+            tx_msg.send(msg).await.unwrap();
+        }
+        let (reply, rx) = oneshot::channel();
+        tx_msg.send(Message::GetStats { reply }).await.unwrap();
+        let stats = rx.await.unwrap();
+
+        assert_eq!(
+            stats,
+            Stats {
+                processed: [("deposit", 1), ("dispute", 1)].into_iter().collect(),
+                rejected: [("deposit", 1), ("dispute", 1), ("withdrawal", 1)]
+                    .into_iter()
+                    .collect(),
+                errors: [
+                    ("insufficient_funds", 1),
+                    ("transaction_already_exists", 1),
+                    ("unknown_client", 1)
+                ]
+                .into_iter()
+                .collect(),
+                evicted: 0,
+                expired: 0,
+                lags: Lags::default(),
+            }
+        );
+        for _ in 0..3 {
+            assert!(matches!(
+                rx_notify.recv().await,
+                Some(Notification::Error(..))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn batch() {
+        let (tx_msg, _rx_notify) = run(Config::default(), Storage::default()).await;
+        let (tx, rx) = oneshot::channel();
+        tx_msg
+            .send(Message::Batch(
+                vec![
+                    Message::Deposit {
+                        client: 1,
+                        tx: 1,
+                        amount: 5,
+                        trade: None,
+                    },
+                    Message::Withdrawal {
+                        client: 1,
+                        tx: 2,
+                        amount: 2,
+                        trade: None,
+                    },
+                    Message::GetState { tx },
+                ]
+                .into(),
+            ))
+            .await
+            .unwrap();
+        let state: Vec<_> = rx.await.unwrap().iter().copied().collect();
+        assert_eq!(state.len(), 1);
+        assert_eq!(state[0].available, 3);
+    }
+
+    #[test]
+    fn state_view() {
+        let state = |client, available| State {
+            client,
+            available,
+            held: 0,
+            total: available,
+            locked: false,
+            pending: 0,
+        };
+        let mut live = StateView::default();
+        live.set(state(300, 1));
+        live.set(state(2, 1));
+        let snapshot = live.clone();
+        live.set(state(2, 5));
+        live.set(state(1, 1));
+
+        let clients = |s: &StateView| {
+            s.iter()
+                .map(|s| (s.client, s.available))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(clients(&snapshot), [(2, 1), (300, 1)]);
+        assert_eq!(clients(&live), [(1, 1), (2, 5), (300, 1)]);
+    }
+
+    #[tokio::test]
+    async fn foreign_transaction() {
+        let mut processor = Processor::new(Config::default(), Storage::default());
+        let (tx_notify, mut rx_notify) = mpsc::channel(CHANNEL_SIZE);
+        for msg in [
+            Message::Deposit {
+                client: 1,
+                tx: 1,
+                amount: 10,
+                trade: None,
+            },
+            Message::Deposit {
+                client: 2,
+                tx: 2,
+                amount: 10,
+                trade: None,
+            },
+            // same id as the first one but a different client
+            Message::Deposit {
+                client: 2,
+                tx: 1,
+                amount: 5,
+                trade: None,
+            },
+            Message::Dispute {
+                client: 1,
+                tx: 2,
+                reason: None,
+            },
+            Message::Resolve {
+                client: 3,
+                tx: 2,
+                reason: None,
+            },
+            Message::Dispute {
+                client: 2,
+                tx: 1,
+                reason: None,
+            },
+            Message::Dispute {
+                client: 1,
+                tx: 3,
+                reason: None,
+            },
+        ] {
+            processor.handle(msg, None, &tx_notify).await;
+        }
+        let mut errors = Vec::new();
+        while let Ok(Notification::Error(err, _)) = rx_notify.try_recv() {
+            errors.push(err);
+        }
+        assert!(matches!(
+            errors[..],
+            [
+                Error::ForeignTransaction {
+                    client: 1,
+                    tx: 2,
+                    owner: 2
+                },
+                Error::ForeignTransaction {
+                    client: 3,
+                    tx: 2,
+                    owner: 2
+                },
+                Error::Transaction {
+                    client: 1,
+                    tx: 3,
+                    ..
+                },
+            ]
+        ));
+        assert_eq!(processor.accounts[&2].held(), 5);
+    }
+
+    #[tokio::test]
+    async fn dispute_race() {
+        let (tx_msg, mut rx_notify) = run(Config::default(), Storage::default()).await;
+        for msg in [
+            Message::Deposit {
+                client: 1,
+                tx: 1,
+                amount: 5,
+                trade: None,
+            },
+            Message::Dispute {
+                client: 1,
+                tx: 1,
+                reason: None,
+            },
+        ] {
+            tx_msg.send(msg).await.unwrap();
+        }
+        // two sources closing the same dispute
+        let sources = [
+            Message::Resolve {
+                client: 1,
+                tx: 1,
+                reason: None,
+            },
+            Message::Chargeback {
+                client: 1,
+                tx: 1,
+                reason: None,
+            },
+        ]
+        .map(|msg| {
+            let tx_msg = tx_msg.clone();
+            tokio::spawn(async move { tx_msg.send(msg).await.unwrap() })
+        });
+        for source in sources {
+            source.await.unwrap();
+        }
+        let (tx, rx) = oneshot::channel();
+        tx_msg.send(Message::GetState { tx }).await.unwrap();
+        let state = *rx.await.unwrap().get(1).unwrap();
+
+        let err = match rx_notify.recv().await {
+            Some(Notification::Error(Error::Transaction { err, .. }, _)) => err,
+            other => panic!("unexpected notification {other:?}"),
+        };
+        // whichever arrived first wins
+        match err {
+            account::Error::DisputeAlreadyResolved(1) => {
+                assert_eq!((state.available, state.locked), (5, false))
+            }
+            account::Error::DisputeAlreadyChargedBack(1) => {
+                assert_eq!((state.total, state.locked), (0, true))
+            }
+            err => panic!("unexpected error {err:?}"),
+        }
+    }
+
+    #[test]
+    fn clients() {
+        let clients = Clients::parse("1-3, 7\n65535").unwrap();
+        assert_eq!(
+            (0..10).filter(|c| clients.contains(*c)).collect::<Vec<_>>(),
+            [1, 2, 3, 7]
+        );
+        assert!(clients.contains(u16::MAX));
+        assert!(Clients::parse("1-x").is_err());
+        assert!(Clients::parse("65536").is_err());
+    }
+
+    #[tokio::test]
+    async fn client_not_allowed() {
+        let config = Config {
+            clients: Some(Clients::parse("1").unwrap()),
+            ..Config::default()
+        };
+        let mut processor = Processor::new(config, Storage::default());
+        let (tx_notify, mut rx_notify) = mpsc::channel(CHANNEL_SIZE);
+        for client in [1, 11] {
+            let msg = Message::Deposit {
+                client,
+                tx: u32::from(client),
+                amount: 10,
+                trade: None,
+            };
+            processor.handle(msg, None, &tx_notify).await;
+        }
+        assert!(matches!(
+            rx_notify.try_recv(),
+            Ok(Notification::Error(
+                Error::ClientNotAllowed { client: 11, tx: 11 },
+                None
+            ))
+        ));
+        assert!(rx_notify.try_recv().is_err());
+        assert_eq!(processor.accounts.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn invariants() {
+        let config = Config {
+            check_invariants: true,
+            ..Config::default()
+        };
+        let mut processor = Processor::new(config, Storage::default());
+        let (tx_notify, mut rx_notify) = mpsc::channel(CHANNEL_SIZE);
+        for msg in [
+            Message::Deposit {
+                client: 1,
+                tx: 1,
+                amount: 10,
+                trade: None,
+            },
+            Message::Withdrawal {
+                client: 1,
+                tx: 2,
+                amount: 3,
+                trade: None,
+            },
+            Message::Dispute {
+                client: 1,
+                tx: 2,
+                reason: None,
+            },
+            Message::Dispute {
+                client: 1,
+                tx: 1,
+                reason: None,
+            },
+            Message::Resolve {
+                client: 1,
+                tx: 2,
+                reason: None,
+            },
+            Message::Chargeback {
+                client: 1,
+                tx: 1,
+                reason: None,
+            },
+            Message::Deposit {
+                client: 1,
+                tx: 3,
+                amount: 1,
+                trade: None,
+            },
+        ] {
+            processor.handle(msg, None, &tx_notify).await;
+        }
+        // only the deposit to the locked account gets rejected
+        assert!(matches!(
+            rx_notify.try_recv(),
+            Ok(Notification::Error(Error::Transaction { tx: 3, .. }, _))
+        ));
+        assert!(rx_notify.try_recv().is_err());
+
+        let account = processor.accounts.get_mut(&1).unwrap();
+        let available = account.available();
+        account.tamper(available, 1);
+        assert!(matches!(
+            processor.verify(1, 4),
+            Err(Error::Invariant {
+                client: 1,
+                tx: 4,
+                violation: Violation::Held {
+                    held: 1,
+                    disputed: 0
+                }
+            })
+        ));
+        let account = processor.accounts.get_mut(&1).unwrap();
+        account.tamper(available + 1, 0);
+        assert!(matches!(
+            processor.verify(1, 4),
+            Err(Error::Invariant {
+                violation: Violation::View { .. },
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn recover() {
+        let storage = Storage {
+            recovered: vec![
+                (
+                    Message::Deposit {
+                        client: 1,
+                        tx: 1,
+                        amount: 5,
+                        trade: None,
+                    },
+                    None,
+                ),
+                (
+                    Message::Withdrawal {
+                        client: 1,
+                        tx: 2,
+                        amount: 10,
+                        trade: None,
+                    },
+                    None,
+                ),
+            ],
+            ..Storage::default()
+        };
+        let (tx_msg, mut rx_notify) = run(Config::default(), storage).await;
+        let (reply, rx) = oneshot::channel();
+        tx_msg.send(Message::GetStats { reply }).await.unwrap();
+        let stats = rx.await.unwrap();
+        assert_eq!(stats.processed.get("deposit"), Some(&1));
+        assert_eq!(stats.rejected.get("withdrawal"), Some(&1));
+        // the rejection was already reported by the crashed run
+        drop(tx_msg);
+        assert!(rx_notify.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn audit_after_wal() {
+        let dir = std::env::temp_dir();
+        let wal = dir.join(format!("trapez-{}-audit-after.wal", std::process::id()));
+        let audit = dir.join(format!("trapez-{}-audit-after.audit", std::process::id()));
+        let _ = std::fs::remove_file(&wal);
+        let _ = std::fs::remove_file(&audit);
+        let storage = Storage {
+            wal: Some(wal::Log::open(&wal, wal::Fsync::Never).unwrap().0),
+            audit: Some(audit::Log::open(&audit).unwrap()),
+            ..Storage::default()
+        };
+        let mut processor = Processor::new(Config::default(), storage);
+        let (tx_notify, _rx_notify) = mpsc::channel(CHANNEL_SIZE);
+        let msg = Message::Deposit {
+            client: 1,
+            tx: 1,
+            amount: 10_000,
+            trade: None,
+        };
+        processor.handle(msg, None, &tx_notify).await;
+
+        // no batch was committed, yet the audited entry is in the write-ahead log
+        assert!(std::fs::read_to_string(&audit)
+            .unwrap()
+            .contains("deposit,1,1,1.0000"));
+        assert!(std::fs::read_to_string(&wal)
+            .unwrap()
+            .contains("deposit,1,1,1.0000"));
+        std::fs::remove_file(&wal).unwrap();
+        std::fs::remove_file(&audit).unwrap();
+    }
+
+    #[tokio::test]
+    async fn snapshot() {
+        let path = std::env::temp_dir().join(format!("trapez-{}-p.snapshot", std::process::id()));
+        let storage = Storage {
+            snapshot: Some(path.clone()),
+            ..Storage::default()
+        };
+        let (tx_msg, _rx_notify) = run(Config::default(), storage).await;
+        let (tx, rx) = oneshot::channel();
+        tx_msg
+            .send(Message::Batch(
+                vec![
+                    Message::Deposit {
+                        client: 1,
+                        tx: 1,
+                        amount: 5,
+                        trade: None,
+                    },
+                    Message::Dispute {
+                        client: 1,
+                        tx: 1,
+                        reason: None,
+                    },
+                    Message::SaveSnapshot { tx },
+                ]
+                .into(),
+            ))
+            .await
+            .unwrap();
+        rx.await.unwrap();
+
+        let storage = Storage {
+            restored: Some(snapshot::read(&path).unwrap()),
+            ..Storage::default()
+        };
+        let (tx_msg, _rx_notify) = run(Config::default(), storage).await;
+        let (tx, rx) = oneshot::channel();
+        tx_msg
+            .send(Message::Batch(
+                vec![
+                    Message::Resolve {
+                        client: 1,
+                        tx: 1,
+                        reason: None,
+                    },
+                    Message::GetState { tx },
+                ]
+                .into(),
+            ))
+            .await
+            .unwrap();
+        let state: Vec<_> = rx.await.unwrap().iter().copied().collect();
+        assert_eq!(state.len(), 1);
+        assert_eq!((state[0].available, state[0].held), (5, 0));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn snapshot_diff() {
+        let dir = std::env::temp_dir();
+        let first = dir.join(format!("trapez-{}-d1.snapshot", std::process::id()));
+        let second = dir.join(format!("trapez-{}-d2.snapshot", std::process::id()));
+        let config = Config {
+            reverse_chargebacks: true,
+            ..Config::default()
+        };
+        let storage = Storage {
+            snapshot: Some(first.clone()),
+            ..Storage::default()
+        };
+        let (tx_msg, _rx_notify) = run(config.clone(), storage).await;
+        let (tx, rx) = oneshot::channel();
+        tx_msg
+            .send(Message::Batch(
+                vec![
+                    Message::Deposit {
+                        client: 1,
+                        tx: 1,
+                        amount: 5,
+                        trade: None,
+                    },
+                    Message::Dispute {
+                        client: 1,
+                        tx: 1,
+                        reason: None,
+                    },
+                    Message::Deposit {
+                        client: 2,
+                        tx: 2,
+                        amount: 7,
+                        trade: None,
+                    },
+                    Message::Withdrawal {
+                        client: 2,
+                        tx: 3,
+                        amount: 3,
+                        trade: None,
+                    },
+                    Message::Dispute {
+                        client: 2,
+                        tx: 2,
+                        reason: None,
+                    },
+                    Message::Chargeback {
+                        client: 2,
+                        tx: 2,
+                        reason: None,
+                    },
+                    Message::SaveSnapshot { tx },
+                ]
+                .into(),
+            ))
+            .await
+            .unwrap();
+        rx.await.unwrap();
+
+        // a restored processor saves the same state it was restored from
+        let storage = Storage {
+            snapshot: Some(second.clone()),
+            restored: Some(snapshot::read(&first).unwrap()),
+            ..Storage::default()
+        };
+        let (tx_msg, _rx_notify) = run(config, storage).await;
+        let (tx, rx) = oneshot::channel();
+        tx_msg
+            .send(Message::Batch(vec![Message::SaveSnapshot { tx }].into()))
+            .await
+            .unwrap();
+        rx.await.unwrap();
+        let saved = snapshot::read(&first).unwrap();
+        assert_eq!(saved.accounts.len(), 2);
+        assert_eq!(
+            snapshot::diff(&saved, &snapshot::read(&second).unwrap()),
+            []
+        );
+
+        let (tx, rx) = oneshot::channel();
+        tx_msg
+            .send(Message::Batch(
+                vec![
+                    Message::Resolve {
+                        client: 1,
+                        tx: 1,
+                        reason: None,
+                    },
+                    Message::SaveSnapshot { tx },
+                ]
+                .into(),
+            ))
+            .await
+            .unwrap();
+        rx.await.unwrap();
+        assert_eq!(
+            snapshot::diff(&saved, &snapshot::read(&second).unwrap()),
+            [
+                snapshot::Difference::Amount {
+                    client: 1,
+                    field: "available",
+                    left: 0,
+                    right: 5
+                },
+                snapshot::Difference::Amount {
+                    client: 1,
+                    field: "held",
+                    left: 5,
+                    right: 0
+                },
+                snapshot::Difference::Dispute {
+                    client: 1,
+                    tx: 1,
+                    only_in: snapshot::Side::Left
+                },
+            ]
+        );
+        std::fs::remove_file(&first).unwrap();
+        std::fs::remove_file(&second).unwrap();
+    }
+
+    #[test]
+    fn batch_pool() {
+        let pool = BatchPool::default();
+        let mut batch = pool.take();
+        batch.push(
+            Message::Dispute {
+                client: 1,
+                tx: 1,
+                reason: None,
+            },
+            Origin {
+                source: "input".into(),
+                line: 2,
+                offset: 22,
+                reference: None,
+                timestamp: None,
+            },
+        );
+        let ptr = batch.msgs.as_ptr();
+        pool.put(batch);
+        let batch = pool.take();
+        assert!(batch.is_empty() && batch.origins.is_empty());
+        assert_eq!(batch.msgs.as_ptr(), ptr);
+    }
+
+    #[tokio::test]
+    async fn dispute_events() {
+        let (tx_msg, _rx_notify) = run(Config::default(), Storage::default()).await;
+        let (tx, rx) = oneshot::channel();
+        tx_msg
+            .send(Message::SubscribeDisputes { tx })
+            .await
+            .unwrap();
+        let mut rx_events = rx.await.unwrap();
+
+        for msg in [
+            Message::Deposit {
+                client: 1,
+                tx: 1,
+                amount: 5,
+                trade: None,
+            },
+            Message::Dispute {
+                client: 1,
+                tx: 1,
+                reason: None,
+            },
+            Message::Resolve {
+                client: 1,
+                tx: 1,
+                reason: None,
+            },
+            Message::Dispute {
+                client: 1,
+                tx: 2,
+                reason: None,
+            },
+            Message::Dispute {
+                client: 1,
+                tx: 1,
+                reason: Reason::parse("10.4").ok(),
+            },
+            Message::Chargeback {
+                client: 1,
+                tx: 1,
+                reason: None,
+            },
+        ] {
+            tx_msg.send(msg).await.unwrap();
+        }
+
+        // the chargeback carries the reason code the dispute was opened with
+        let reason = Reason::parse("10.4").ok();
+        for (status, reason) in [
+            (DisputeStatus::Opened, None),
+            (DisputeStatus::Resolved, None),
+            (DisputeStatus::Opened, reason),
+            (DisputeStatus::ChargedBack, reason),
+        ] {
+            assert_eq!(
+                rx_events.recv().await.unwrap(),
+                DisputeEvent {
+                    client: 1,
+                    tx: 1,
+                    amount: 5,
+                    status,
+                    reason,
+                    origin: None
+                }
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn negative_balance_hold() {
+        let config = Config {
+            hold_negative: true,
+            ..Config::default()
+        };
+        let mut processor = Processor::new(config, Storage::default());
+        let (tx_notify, mut rx_notify) = mpsc::channel(CHANNEL_SIZE);
+        for msg in [
+            Message::Deposit {
+                client: 1,
+                tx: 1,
+                amount: 5,
+                trade: None,
+            },
+            Message::Withdrawal {
+                client: 1,
+                tx: 2,
+                amount: 4,
+                trade: None,
+            },
+            Message::Dispute {
+                client: 1,
+                tx: 1,
+                reason: None,
+            },
+            Message::Withdrawal {
+                client: 1,
+                tx: 3,
+                amount: 1,
+                trade: None,
+            },
+        ] {
+            processor.handle(msg, None, &tx_notify).await;
+        }
+        assert!(matches!(
+            rx_notify.try_recv(),
+            Ok(Notification::Warning(
+                Warning::NegativeBalance {
+                    client: 1,
+                    tx: 1,
+                    available: -4
+                },
+                None
+            ))
+        ));
+        assert!(matches!(
+            rx_notify.try_recv(),
+            Ok(Notification::Error(
+                Error::OnHold { client: 1, tx: 3 },
+                None
+            ))
+        ));
+        assert!(rx_notify.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn backfill() {
+        let config = Config {
+            backfill: true,
+            ..Config::default()
+        };
+        let mut processor = Processor::new(config, Storage::default());
+        let (tx_notify, mut rx_notify) = mpsc::channel(CHANNEL_SIZE);
+        let at = |timestamp| {
+            Some(Origin {
+                source: "input".into(),
+                line: 0,
+                offset: 0,
+                reference: None,
+                timestamp: Some(timestamp),
+            })
+        };
+        for (msg, timestamp) in [
+            (
+                Message::Deposit {
+                    client: 1,
+                    tx: 1,
+                    amount: 5,
+                    trade: None,
+                },
+                10,
+            ),
+            (
+                Message::Withdrawal {
+                    client: 1,
+                    tx: 2,
+                    amount: 8,
+                    trade: None,
+                },
+                30,
+            ),
+            // arrives late, but makes the withdrawal succeed
+            (
+                Message::Deposit {
+                    client: 1,
+                    tx: 3,
+                    amount: 4,
+                    trade: None,
+                },
+                20,
+            ),
+        ] {
+            processor.handle(msg, at(timestamp), &tx_notify).await;
+        }
+        assert!(matches!(
+            rx_notify.try_recv(),
+            Ok(Notification::Error(
+                Error::Transaction {
+                    client: 1,
+                    tx: 2,
+                    err: account::Error::InsufficientFunds { .. }
+                },
+                _
+            ))
+        ));
+        assert!(matches!(
+            rx_notify.try_recv(),
+            Ok(Notification::Warning(
+                Warning::Backfilled {
+                    client: 1,
+                    tx: 3,
+                    replayed: 1,
+                    changed: 1
+                },
+                _
+            ))
+        ));
+        assert!(rx_notify.try_recv().is_err());
+        assert_eq!(processor.accounts[&1].available(), 1);
+    }
+
+    #[tokio::test]
+    async fn evict_idle() {
+        let config = Config {
+            evict_idle: Some(2),
+            ..Config::default()
+        };
+        let mut processor = Processor::new(config, Storage::default());
+        let (tx_notify, mut rx_notify) = mpsc::channel(CHANNEL_SIZE);
+        let deposit = |client, tx| Message::Deposit {
+            client,
+            tx,
+            amount: 5,
+            trade: None,
+        };
+        for msg in [
+            deposit(1, 1),
+            Message::Withdrawal {
+                client: 1,
+                tx: 2,
+                amount: 5,
+                trade: None,
+            },
+            deposit(2, 3),
+            deposit(2, 4),
+        ] {
+            processor.handle(msg, None, &tx_notify).await;
+        }
+        assert!(rx_notify.try_recv().is_err());
+        assert_eq!(processor.stats.evicted, 1);
+        assert!(!processor.accounts.contains_key(&1));
+        // the evicted account stays in the report
+        assert_eq!(processor.view.get(1).map(|state| state.total), Some(0));
+
+        // it comes back empty, without its old transactions
+        for msg in [
+            Message::Dispute {
+                client: 1,
+                tx: 1,
+                reason: None,
+            },
+            deposit(1, 1),
+        ] {
+            processor.handle(msg, None, &tx_notify).await;
+        }
+        assert!(matches!(
+            rx_notify.try_recv(),
+            Ok(Notification::Error(Error::Transaction { client: 1, .. }, _))
+        ));
+        assert!(rx_notify.try_recv().is_err());
+        assert_eq!(processor.accounts[&1].available(), 5);
+    }
+
+    #[tokio::test]
+    async fn reason_codes() {
+        assert_eq!(Reason::parse("10.4").unwrap().as_str(), "10.4");
+        assert!(Reason::parse("").is_err());
+        assert!(Reason::parse("123456789").is_err());
+        assert!(Reason::parse("10 4").is_err());
+        assert_eq!(
+            Reason::parse_file("# Visa\n10.4 Other fraud\n\n13.1\n").unwrap(),
+            [
+                Reason::parse("10.4").unwrap(),
+                Reason::parse("13.1").unwrap()
+            ]
+        );
+
+        let config = Config {
+            reason_codes: Some(Reason::parse_file("10.4").unwrap()),
+            ..Config::default()
+        };
+        let mut processor = Processor::new(config, Storage::default());
+        let (tx_notify, mut rx_notify) = mpsc::channel(CHANNEL_SIZE);
+        for msg in [
+            Message::Deposit {
+                client: 1,
+                tx: 1,
+                amount: 5,
+                trade: None,
+            },
+            Message::Dispute {
+                client: 1,
+                tx: 1,
+                reason: Reason::parse("4837").ok(),
+            },
+            Message::Dispute {
+                client: 1,
+                tx: 1,
+                reason: Reason::parse("10.4").ok(),
+            },
+        ] {
+            processor.handle(msg, None, &tx_notify).await;
+        }
+        assert!(matches!(
+            rx_notify.try_recv(),
+            Ok(Notification::Error(
+                Error::UnknownReason { client: 1, tx: 1, reason },
+                None
+            )) if reason.as_str() == "4837"
+        ));
+        assert!(rx_notify.try_recv().is_err());
+        assert_eq!(processor.reasons[&(1, 1)].as_str(), "10.4");
+
+        processor
+            .handle(
+                Message::Resolve {
+                    client: 1,
+                    tx: 1,
+                    reason: None,
+                },
+                None,
+                &tx_notify,
+            )
+            .await;
+        assert!(processor.reasons.is_empty());
+    }
+
+    #[tokio::test]
+    async fn links() {
+        let mut processor = Processor::new(Config::default(), Storage::default());
+        let (tx_notify, mut rx_notify) = mpsc::channel(CHANNEL_SIZE);
+        // as set by the `link` command on a snapshot
+        processor.links.link(2, 1, |client| client == 1).unwrap();
+        for msg in [
+            Message::Deposit {
+                client: 1,
+                tx: 1,
+                amount: 10,
+                trade: None,
+            },
+            Message::Deposit {
+                client: 2,
+                tx: 2,
+                amount: 5,
+                trade: None,
+            },
+            Message::Withdrawal {
+                client: 2,
+                tx: 3,
+                amount: 20,
+                trade: None,
+            },
+            Message::Dispute {
+                client: 1,
+                tx: 2,
+                reason: None,
+            },
+        ] {
+            processor.handle(msg, None, &tx_notify).await;
+        }
+        // errors keep the client of the message
+        assert!(matches!(
+            rx_notify.try_recv(),
+            Ok(Notification::Error(
+                Error::Transaction {
+                    client: 2,
+                    tx: 3,
+                    err: account::Error::InsufficientFunds { .. }
+                },
+                None
+            ))
+        ));
+        assert!(rx_notify.try_recv().is_err());
+        let state = State::new(1, &processor.accounts[&1]);
+        assert_eq!((state.available, state.held), (10, 5));
+        assert!(!processor.accounts.contains_key(&2));
+
+        processor.links.unlink(2).unwrap();
+        let msg = Message::Deposit {
+            client: 2,
+            tx: 4,
+            amount: 3,
+            trade: None,
+        };
+        processor.handle(msg, None, &tx_notify).await;
+        assert!(rx_notify.try_recv().is_err());
+        assert_eq!(processor.accounts[&2].total(), 3);
+        assert_eq!(processor.accounts[&1].total(), 15);
+    }
+
+    #[tokio::test]
+    async fn sla() {
+        let config = Config {
+            sla: Sla {
+                lag: Some(60_000),
+                clients: [(2, 500)].into(),
+            },
+            ..Config::default()
+        };
+        let mut processor = Processor::new(config, Storage::default());
+        processor.now = || 100_000;
+        let (tx_notify, mut rx_notify) = mpsc::channel(CHANNEL_SIZE);
+        let origin = |timestamp| Origin {
+            source: "test".into(),
+            line: 0,
+            offset: 0,
+            reference: None,
+            timestamp,
+        };
+        for (client, tx, timestamp) in [
+            (1, 1, Some(99_500)),
+            (1, 2, Some(20_000)),
+            (2, 3, Some(99_000)),
+            (2, 4, None),
+        ] {
+            let msg = Message::Deposit {
+                client,
+                tx,
+                amount: 1,
+                trade: None,
+            };
+            processor
+                .handle(msg, Some(origin(timestamp)), &tx_notify)
+                .await;
+        }
+        for (client, tx, lag, sla) in [(1, 2, 80_000, 60_000), (2, 3, 1_000, 500)] {
+            assert!(matches!(
+                rx_notify.try_recv(),
+                Ok(Notification::Warning(warning, Some(_)))
+                    if warning == Warning::SlaExceeded { client, tx, lag, sla }
+            ));
+        }
+        assert!(rx_notify.try_recv().is_err());
+        assert_eq!(
+            processor.stats.lags,
+            Lags {
+                count: 3,
+                sum: 81_500,
+                max: 80_000,
+                buckets: [2, 0, 0, 1, 0, 0, 0],
+                exceeded: 2,
+            }
+        );
+        assert!(processor
+            .stats
+            .to_string()
+            .contains("lag count: 3, mean: 27166 ms, max: 80000 ms\nlag up to 1s: 2\n"));
+    }
+
+    #[tokio::test]
+    async fn approvals() {
+        let config = Config {
+            approve_above: Some(5),
+            ..Config::default()
+        };
+        let mut processor = Processor::new(config, Storage::default());
+        let (tx_notify, mut rx_notify) = mpsc::channel(CHANNEL_SIZE);
+        for msg in [
+            Message::Deposit {
+                client: 1,
+                tx: 1,
+                amount: 10,
+                trade: None,
+            },
+            Message::Withdrawal {
+                client: 1,
+                tx: 2,
+                amount: 8,
+                trade: None,
+            },
+            Message::Withdrawal {
+                client: 1,
+                tx: 3,
+                amount: 3,
+                trade: None,
+            },
+        ] {
+            processor.handle(msg, None, &tx_notify).await;
+        }
+        assert!(matches!(
+            rx_notify.try_recv(),
+            Ok(Notification::Warning(
+                Warning::PendingApproval {
+                    client: 1,
+                    tx: 2,
+                    amount: 8
+                },
+                None
+            ))
+        ));
+        // the earmarked funds can't be withdrawn
+        assert!(matches!(
+            rx_notify.try_recv(),
+            Ok(Notification::Error(
+                Error::Transaction {
+                    client: 1,
+                    tx: 3,
+                    err: account::Error::InsufficientFunds { .. }
+                },
+                None
+            ))
+        ));
+        assert_eq!(State::new(1, &processor.accounts[&1]).pending, 8);
+
+        for msg in [
+            Message::Approve { client: 1, tx: 2 },
+            Message::Reject { client: 1, tx: 2 },
+        ] {
+            processor.handle(msg, None, &tx_notify).await;
+        }
+        assert!(matches!(
+            rx_notify.try_recv(),
+            Ok(Notification::Error(
+                Error::Transaction {
+                    client: 1,
+                    tx: 2,
+                    err: account::Error::NotPending(2)
+                },
+                None
+            ))
+        ));
+        assert!(rx_notify.try_recv().is_err());
+        let state = State::new(1, &processor.accounts[&1]);
+        assert_eq!((state.available, state.pending), (2, 0));
+    }
+
+    #[tokio::test]
+    async fn authorizations() {
+        let config = Config {
+            authorization_ttl: Some(500),
+            ..Config::default()
+        };
+        let mut processor = Processor::new(config, Storage::default());
+        let (tx_notify, mut rx_notify) = mpsc::channel(CHANNEL_SIZE);
+        let origin = |timestamp| Origin {
+            source: "test".into(),
+            line: 0,
+            offset: 0,
+            reference: None,
+            timestamp: Some(timestamp),
+        };
+        for (msg, timestamp) in [
+            (
+                Message::Deposit {
+                    client: 1,
+                    tx: 1,
+                    amount: 10,
+                    trade: None,
+                },
+                Some(1_000),
+            ),
+            (
+                Message::Authorize {
+                    client: 1,
+                    tx: 2,
+                    amount: 4,
+                },
+                Some(1_000),
+            ),
+            (
+                Message::Authorize {
+                    client: 1,
+                    tx: 3,
+                    amount: 5,
+                },
+                Some(1_000),
+            ),
+            // without a timestamp it doesn't expire
+            (
+                Message::Authorize {
+                    client: 1,
+                    tx: 4,
+                    amount: 1,
+                },
+                None,
+            ),
+            (Message::Capture { client: 1, tx: 2 }, Some(1_200)),
+            // messages without a timestamp don't expire anything
+            (
+                Message::Deposit {
+                    client: 1,
+                    tx: 5,
+                    amount: 0,
+                    trade: None,
+                },
+                None,
+            ),
+        ] {
+            processor
+                .handle(msg, timestamp.map(origin), &tx_notify)
+                .await;
+        }
+        assert!(rx_notify.try_recv().is_err());
+        let account = &processor.accounts[&1];
+        assert_eq!((account.available(), account.authorized()), (6, 6));
+
+        // expired by the time of the capture
+        processor
+            .handle(
+                Message::Capture { client: 1, tx: 3 },
+                Some(origin(1_500)),
+                &tx_notify,
+            )
+            .await;
+        assert!(matches!(
+            rx_notify.try_recv(),
+            Ok(Notification::Warning(
+                Warning::AuthorizationExpired {
+                    client: 1,
+                    tx: 3,
+                    amount: 5
+                },
+                None
+            ))
+        ));
+        assert!(matches!(
+            rx_notify.try_recv(),
+            Ok(Notification::Error(
+                Error::Transaction {
+                    client: 1,
+                    tx: 3,
+                    err: account::Error::NotAuthorized(3)
+                },
+                Some(_)
+            ))
+        ));
+        let account = &processor.accounts[&1];
+        assert_eq!((account.available(), account.authorized()), (6, 1));
+        assert_eq!(processor.stats.expired, 1);
+        assert_eq!(processor.expiries.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn simulate() {
+        let mut processor = Processor::new(Config::default(), Storage::default());
+        let (tx_notify, mut rx_notify) = mpsc::channel(CHANNEL_SIZE);
+        let msg = Message::Deposit {
+            client: 1,
+            tx: 1,
+            amount: 10,
+            trade: None,
+        };
+        processor.handle(msg, None, &tx_notify).await;
+        processor.links.link(2, 1, |client| client == 1).unwrap();
+        let (reply, mut rx) = oneshot::channel();
+        let msg = Message::Simulate {
+            inner: Box::new(Message::Withdrawal {
+                client: 2,
+                tx: 2,
+                amount: 4,
+                trade: None,
+            }),
+            reply,
+        };
+        processor.handle(msg, None, &tx_notify).await;
+        let state = rx.try_recv().unwrap().unwrap();
+        assert_eq!((state.client, state.available, state.total), (1, 6, 6));
+
+        let state = processor
+            .simulate(&Message::Dispute {
+                client: 1,
+                tx: 1,
+                reason: None,
+            })
+            .unwrap();
+        assert_eq!((state.available, state.held), (0, 10));
+        assert!(matches!(
+            processor.simulate(&Message::Withdrawal {
+                client: 2,
+                tx: 2,
+                amount: 40,
+                trade: None,
+            }),
+            Err(Error::Transaction {
+                client: 2,
+                tx: 2,
+                err: account::Error::InsufficientFunds { .. }
+            })
+        ));
+        assert!(matches!(
+            processor.simulate(&Message::Capture { client: 3, tx: 3 }),
+            Err(Error::UnknownClient { client: 3, tx: 3 })
+        ));
+        let (reply, _) = oneshot::channel();
+        assert!(matches!(
+            processor.simulate(&Message::GetStats { reply }),
+            Err(Error::NotTransactional)
+        ));
+
+        // nothing was committed
+        assert!(rx_notify.try_recv().is_err());
+        let account = &processor.accounts[&1];
+        assert_eq!((account.available(), account.held()), (10, 0));
+        assert_eq!(account.amount(2), None);
+        assert_eq!(processor.view.get(1).map(|state| state.available), Some(10));
+        assert_eq!(processor.stats.processed.get("withdrawal"), None);
+        assert!(!processor.accounts.contains_key(&3));
+    }
+
+    #[tokio::test]
+    async fn warnings() {
+        let (tx_msg, mut rx_notify) = run(
+            Config {
+                large_deposit: Some(10),
+                large_total: Some(20),
+                max_disputes: Some(1),
+                low_balance: LowBalance {
+                    threshold: Some(100),
+                    clients: [(1, 15)].into(),
+                },
+                ..Config::default()
+            },
+            Storage::default(),
+        )
+        .await;
+        for msg in [
+            Message::Deposit {
+                client: 1,
+                tx: 1,
+                amount: 10,
+                trade: None,
+            },
+            Message::Deposit {
+                client: 1,
+                tx: 2,
+                amount: 11,
+                trade: None,
+            },
+            Message::Deposit {
+                client: 1,
+                tx: 3,
+                amount: 1,
+                trade: None,
+            },
+            Message::Dispute {
+                client: 1,
+                tx: 1,
+                reason: None,
+            },
+            Message::Dispute {
+                client: 1,
+                tx: 2,
+                reason: None,
+            },
+            Message::Dispute {
+                client: 1,
+                tx: 3,
+                reason: None,
+            },
+        ] {
+            tx_msg.send(msg).await.unwrap();
+        }
+        drop(tx_msg);
+
+        let mut warnings = Vec::new();
+        while let Some(notification) = rx_notify.recv().await {
+            if let Notification::Warning(warning, _) = notification {
+                warnings.push(warning);
+            }
+        }
+        assert_eq!(
+            warnings,
+            vec![
+                Warning::LargeTotal {
+                    client: 1,
+                    tx: 2,
+                    total: 20
+                },
+                Warning::LargeDeposit {
+                    client: 1,
+                    tx: 2,
+                    amount: 11
+                },
+                Warning::LowBalance {
+                    client: 1,
+                    tx: 1,
+                    available: 12,
+                    threshold: 15
+                },
+                Warning::ManyDisputes {
+                    client: 1,
+                    tx: 2,
+                    disputes: 2
+                },
+            ]
+        );
+    }
 }
@@ -0,0 +1,287 @@
+/**
+ * Reconciliation of the final state against a control file of expected balances.
+ *
+ * The control file uses the columns of the report (`client,available,held,total,locked`). Only
+ * `client` is required, so a file with independently calculated totals only checks the totals.
+ */
+use std::{borrow::Cow, collections::BTreeSet, fmt, io::Read};
+
+use crate::{amount, log, processor};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Reconciliation file error: `{0}`.")]
+    Csv(#[from] csv::Error),
+    #[error("Invalid field `{field}` in line {line} of the reconciliation file: `{reason}`.")]
+    Parse {
+        line: u64,
+        field: &'static str,
+        reason: Cow<'static, str>,
+    },
+}
+
+/**
+ * Expected values of an account. Unset values aren't checked.
+ */
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Expected {
+    pub client: u16,
+    pub available: Option<i64>,
+    pub held: Option<i64>,
+    pub total: Option<i64>,
+    pub locked: Option<bool>,
+}
+
+pub fn read<R: Read>(reader: R) -> Result<Vec<Expected>, Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| headers.iter().position(|header| header == name);
+    let columns = [
+        column("available"),
+        column("held"),
+        column("total"),
+        column("locked"),
+    ];
+    let client = column("client");
+
+    let mut expected = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        let line = record.position().map_or(0, |pos| pos.line());
+        let err = |field, reason: String| Error::Parse {
+            line,
+            field,
+            reason: reason.into(),
+        };
+        let get =
+            |column: Option<usize>| column.and_then(|c| record.get(c)).filter(|v| !v.is_empty());
+        let amount = |field, column| {
+            get(column)
+                .map(|v| amount::parse(v).map_err(|e| err(field, e.to_string())))
+                .transpose()
+        };
+        let [available, held, total, locked] = columns;
+        expected.push(Expected {
+            client: get(client)
+                .ok_or_else(|| err("client", "missing field".to_string()))?
+                .parse()
+                .map_err(|e: std::num::ParseIntError| err("client", e.to_string()))?,
+            available: amount("available", available)?,
+            held: amount("held", held)?,
+            total: amount("total", total)?,
+            locked: get(locked)
+                .map(|v| {
+                    v.parse()
+                        .map_err(|e: std::str::ParseBoolError| err("locked", e.to_string()))
+                })
+                .transpose()?,
+        });
+    }
+    Ok(expected)
+}
+
+/**
+ * A difference between the expected and the actual state.
+ */
+#[derive(Debug, PartialEq, Eq)]
+pub enum Mismatch {
+    /// An expected account which doesn't exist.
+    Missing { client: u16 },
+    /// An account which isn't in the control file.
+    Unexpected { client: u16 },
+    Amount {
+        client: u16,
+        field: &'static str,
+        expected: i64,
+        actual: i64,
+    },
+    Locked {
+        client: u16,
+        expected: bool,
+        actual: bool,
+    },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mismatch::Missing { client } => write!(f, "Expected client {client} doesn't exist."),
+            Mismatch::Unexpected { client } => {
+                write!(f, "Client {client} is missing in the reconciliation file.")
+            }
+            Mismatch::Amount {
+                client,
+                field,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Client {client}: {field} is {}, expected {} (difference: {}).",
+                amount::Decimal(*actual),
+                amount::Decimal(*expected),
+                amount::Decimal(actual - expected)
+            ),
+            Mismatch::Locked {
+                client,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Client {client}: locked is {actual}, expected {expected}."
+            ),
+        }
+    }
+}
+
+impl log::Event for Mismatch {
+    fn code(&self) -> &'static str {
+        match self {
+            Mismatch::Missing { .. } => "reconcile_missing",
+            Mismatch::Unexpected { .. } => "reconcile_unexpected",
+            Mismatch::Amount { .. } => "reconcile_amount",
+            Mismatch::Locked { .. } => "reconcile_locked",
+        }
+    }
+
+    fn client(&self) -> Option<u16> {
+        match self {
+            Mismatch::Missing { client }
+            | Mismatch::Unexpected { client }
+            | Mismatch::Amount { client, .. }
+            | Mismatch::Locked { client, .. } => Some(*client),
+        }
+    }
+}
+
+/**
+ * Compares the state to the expected values. Amounts may differ by up to `tolerance`.
+ */
+pub fn compare(
+    expected: &[Expected],
+    state: &processor::StateView,
+    tolerance: i64,
+) -> Vec<Mismatch> {
+    let mut mismatches = Vec::new();
+    for e in expected {
+        let actual = match state.get(e.client) {
+            Some(actual) => actual,
+            None => {
+                mismatches.push(Mismatch::Missing { client: e.client });
+                continue;
+            }
+        };
+        for (field, expected, actual) in [
+            ("available", e.available, actual.available),
+            ("held", e.held, actual.held),
+            ("total", e.total, actual.total),
+        ] {
+            match expected {
+                Some(expected) if (actual - expected).abs() > tolerance => {
+                    mismatches.push(Mismatch::Amount {
+                        client: e.client,
+                        field,
+                        expected,
+                        actual,
+                    })
+                }
+                _ => (),
+            }
+        }
+        match e.locked {
+            Some(expected) if expected != actual.locked => mismatches.push(Mismatch::Locked {
+                client: e.client,
+                expected,
+                actual: actual.locked,
+            }),
+            _ => (),
+        }
+    }
+    let clients: BTreeSet<_> = expected.iter().map(|e| e.client).collect();
+    for s in state.iter() {
+        if !clients.contains(&s.client) {
+            mismatches.push(Mismatch::Unexpected { client: s.client });
+        }
+    }
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconcile() {
+        let expected =
+            read("client,total,locked\n1,10.0,false\n2,5.0,\n3,1.0,true\n4,0,false\n".as_bytes())
+                .unwrap();
+        assert_eq!(
+            expected[0],
+            Expected {
+                client: 1,
+                total: Some(100000),
+                locked: Some(false),
+                ..Expected::default()
+            }
+        );
+
+        let mut state = processor::StateView::default();
+        for (client, total, locked) in [(1, 100001, false), (2, 49000, false), (3, 10000, false)] {
+            state.set(processor::State {
+                client,
+                available: total,
+                held: 0,
+                total,
+                locked,
+                pending: 0,
+            });
+        }
+        state.set(processor::State {
+            client: 5,
+            available: 0,
+            held: 0,
+            total: 0,
+            locked: false,
+            pending: 0,
+        });
+        assert_eq!(
+            compare(&expected, &state, 1),
+            [
+                Mismatch::Amount {
+                    client: 2,
+                    field: "total",
+                    expected: 50000,
+                    actual: 49000
+                },
+                Mismatch::Locked {
+                    client: 3,
+                    expected: true,
+                    actual: false
+                },
+                Mismatch::Missing { client: 4 },
+                Mismatch::Unexpected { client: 5 },
+            ]
+        );
+        assert_eq!(compare(&expected, &state, 0).len(), 5);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(
+            read("client,total\n1,x\n".as_bytes()),
+            Err(Error::Parse {
+                line: 2,
+                field: "total",
+                ..
+            })
+        ));
+        assert!(matches!(
+            read("total\n1\n".as_bytes()),
+            Err(Error::Parse {
+                field: "client",
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,234 @@
+/**
+ * Reordering of timestamped messages between the CSV reader and the processor.
+ *
+ * Feeds merged from several sources arrive slightly out of order, e.g. a dispute before the
+ * deposit it refers to. Every message is held back until the input has reached a timestamp
+ * `window` past its own, and the held messages are released in timestamp order. Messages without
+ * a timestamp count as the latest ones so far, so an input without timestamps passes unchanged.
+ * Messages older than one already released are too late to be reordered and pass right away.
+ */
+use std::{cmp::Reverse, collections::BinaryHeap, fmt};
+
+use crate::{
+    log,
+    processor::{Batch, Message, Origin},
+};
+
+/**
+ * Counts of the reordered and late messages, logged at the end of the input.
+ */
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Messages with an earlier timestamp than a message before them in the input.
+    pub reordered: u64,
+    /// Messages older than the window, applied in input order.
+    pub late: u64,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Reordered {} messages by timestamp, {} arrived too late for the window.",
+            self.reordered, self.late
+        )
+    }
+}
+
+impl log::Event for Report {
+    fn code(&self) -> &'static str {
+        "reorder"
+    }
+}
+
+// A held back message, ordered by timestamp and then by input order.
+struct Held {
+    timestamp: u64,
+    seq: u64,
+    msg: Message,
+    origin: Origin,
+}
+
+impl Held {
+    fn key(&self) -> (u64, u64) {
+        (self.timestamp, self.seq)
+    }
+}
+
+impl PartialEq for Held {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Held {}
+
+impl PartialOrd for Held {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Held {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+pub struct Reorder {
+    /// How far the input has to be past a message before it is released, in the unit of the
+    /// timestamps.
+    window: u64,
+    held: BinaryHeap<Reverse<Held>>,
+    /// The latest timestamp read so far.
+    latest: u64,
+    /// The timestamp of the last released message.
+    released: u64,
+    seq: u64,
+    report: Report,
+}
+
+impl Reorder {
+    pub fn new(window: u64) -> Reorder {
+        Self {
+            window,
+            held: BinaryHeap::new(),
+            latest: 0,
+            released: 0,
+            seq: 0,
+            report: Report::default(),
+        }
+    }
+
+    /**
+     * Holds back the messages of a batch before it's sent and adds the due ones in timestamp order.
+     * The batch may end up empty.
+     */
+    pub fn apply(&mut self, batch: &mut Batch) {
+        let msgs = std::mem::take(&mut batch.msgs);
+        let origins = std::mem::take(&mut batch.origins);
+        for (msg, origin) in msgs.into_iter().zip(origins) {
+            let timestamp = match origin.timestamp {
+                Some(timestamp) if timestamp < self.released => {
+                    self.report.late += 1;
+                    batch.push(msg, origin);
+                    continue;
+                }
+                Some(timestamp) => timestamp,
+                None => self.latest,
+            };
+            if timestamp < self.latest {
+                self.report.reordered += 1;
+            }
+            self.latest = self.latest.max(timestamp);
+            self.held.push(Reverse(Held {
+                timestamp,
+                seq: self.seq,
+                msg,
+                origin,
+            }));
+            self.seq += 1;
+        }
+        let due = self.latest.saturating_sub(self.window);
+        while self
+            .held
+            .peek()
+            .is_some_and(|Reverse(held)| held.timestamp <= due)
+        {
+            if let Some(Reverse(held)) = self.held.pop() {
+                self.released = held.timestamp;
+                batch.push(held.msg, held.origin);
+            }
+        }
+    }
+
+    /**
+     * Adds all messages still held back to the last batch and returns the counts.
+     */
+    pub fn finish(mut self, batch: &mut Batch) -> Report {
+        while let Some(Reverse(held)) = self.held.pop() {
+            batch.push(held.msg, held.origin);
+        }
+        self.report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli;
+
+    #[test]
+    fn reorder() {
+        let mut reorder = Reorder::new(10);
+        let mut batch = Batch::from(Vec::new());
+        let mut sent = Vec::new();
+        let mut send = |reorder: &mut Reorder, txs: &[(u32, Option<u64>)], sent: &mut Vec<u32>| {
+            for (tx, timestamp) in txs {
+                batch.push(
+                    Message::Dispute {
+                        client: 1,
+                        tx: *tx,
+                        reason: None,
+                    },
+                    Origin {
+                        source: "-".into(),
+                        line: u64::from(*tx),
+                        offset: 0,
+                        reference: None,
+                        timestamp: *timestamp,
+                    },
+                );
+            }
+            reorder.apply(&mut batch);
+            sent.extend(batch.msgs.drain(..).map(|msg| msg.target().unwrap().1));
+            batch.origins.clear();
+        };
+
+        send(&mut reorder, &[(1, Some(100)), (2, Some(95))], &mut sent);
+        assert!(sent.is_empty());
+        send(
+            &mut reorder,
+            &[(3, None), (4, Some(108)), (5, Some(110))],
+            &mut sent,
+        );
+        assert_eq!(sent, [2, 1, 3]);
+        // older than the released messages
+        send(&mut reorder, &[(6, Some(99))], &mut sent);
+        assert_eq!(sent, [2, 1, 3, 6]);
+
+        let report = reorder.finish(&mut batch);
+        sent.extend(batch.msgs.iter().map(|msg| msg.target().unwrap().1));
+        assert_eq!(sent, [2, 1, 3, 6, 4, 5]);
+        assert_eq!(
+            report,
+            Report {
+                reordered: 1,
+                late: 1
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn dispute_before_deposit() {
+        let input = "type,client,tx,amount,timestamp\n\
+                     dispute,1,1,,1005\n\
+                     deposit,1,1,10,1000\n";
+        for (window, expected) in [
+            (None, "1,10.0000,0.0000,10.0000,false\n"),
+            (Some(10), "1,0.0000,10.0000,10.0000,false\n"),
+        ] {
+            let options = cli::Options {
+                logger: log::Logger::capture(log::Format::Text),
+                reorder_window: window,
+                ..cli::Options::default()
+            };
+            let mut buf = Vec::new();
+            cli::run(input.as_bytes(), &mut buf, options).await.unwrap();
+            assert_eq!(
+                String::from_utf8(buf).unwrap(),
+                format!("client,available,held,total,locked\n{expected}")
+            );
+        }
+    }
+}
@@ -0,0 +1,184 @@
+/**
+ * Replay of a write-ahead log up to a given message, to inspect the state an account was in right
+ * before it.
+ *
+ * The log is replayed through a processor with the configuration of the run writing it, like
+ * `ledger::verify` does, and stops before the first message with the given transaction id or before the given line.
+ * The state of the client of that message is what the processor saw when it handled the message.
+ */
+use std::{fmt, fs::File, path::Path, sync::Arc};
+
+use tokio::sync::oneshot;
+
+use crate::{
+    amount, cli,
+    processor::{self, Message},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error: `{0}`.")]
+    Io(#[from] std::io::Error),
+    #[error("Could not communicate with the processor.")]
+    Processor,
+    #[error("No message with transaction {0} in the log.")]
+    UnknownTx(u32),
+    #[error("The log has no line {0}.")]
+    UnknownLine(u64),
+    #[error("Line {line} of the log holds no valid transaction. {err}")]
+    Invalid { line: u64, err: cli::Error },
+}
+
+/**
+ * The message to stop at.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Point {
+    Tx(u32),
+    Line(u64),
+}
+
+/**
+ * The state of the client of a message right before the message was handled.
+ */
+#[derive(Debug)]
+pub struct Report {
+    /// Number of messages replayed before the message.
+    pub messages: u64,
+    pub line: u64,
+    pub message: Message,
+    /// The state of the client only, empty if the client had no account yet.
+    pub state: processor::StateView,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (client, tx) = self.message.target().unwrap_or_default();
+        write!(
+            f,
+            "State before line {} ({} of client {client}, transaction {tx}",
+            self.line,
+            self.message.kind().unwrap_or_default(),
+        )?;
+        if let Message::Deposit { amount, .. } | Message::Withdrawal { amount, .. } = self.message {
+            write!(f, ", amount {}", amount::Decimal(amount))?;
+        }
+        write!(f, ") after {} messages", self.messages)?;
+        match self.state.get(client) {
+            Some(_) => write!(f, ":"),
+            None => write!(f, ": client {client} has no account yet."),
+        }
+    }
+}
+
+/**
+ * Replays the write-ahead log at the given path with the given configuration up to the given point.
+ */
+pub async fn state_at<P: AsRef<Path>>(
+    path: P,
+    config: processor::Config,
+    point: Point,
+) -> Result<Report, Error> {
+    let (tx_msg, _) = processor::run(config, processor::Storage::default()).await;
+
+    let source: Arc<str> = path.as_ref().to_string_lossy().into();
+    let mut msgs = cli::read_csv(File::open(path)?);
+    let mut messages = 0;
+    let mut batch = processor::Batch::default();
+    let (line, message) = loop {
+        let res = match msgs.next() {
+            Some(res) => res,
+            None => {
+                return Err(match point {
+                    Point::Tx(tx) => Error::UnknownTx(tx),
+                    Point::Line(line) => Error::UnknownLine(line),
+                })
+            }
+        };
+        // the processor expires authorizations by the timestamps of the origins
+        let origin = msgs.origin(&source);
+        let line = origin.line;
+        match (res, point) {
+            (Err(err), Point::Line(target)) if line == target => {
+                return Err(Error::Invalid { line, err });
+            }
+            (Ok(msg), Point::Line(target)) if line == target => break (line, msg),
+            (Ok(msg), Point::Tx(target)) if msg.target().map(|(_, tx)| tx) == Some(target) => {
+                break (line, msg)
+            }
+            // like on recovery, invalid lines are skipped
+            (Err(_), _) => continue,
+            (Ok(msg), _) => {
+                messages += 1;
+                batch.push(msg, origin);
+            }
+        }
+        if batch.len() == processor::BATCH_SIZE {
+            let full = std::mem::take(&mut batch);
+            tx_msg
+                .send(Message::Batch(full))
+                .await
+                .map_err(|_| Error::Processor)?;
+        }
+    };
+    tx_msg
+        .send(Message::Batch(batch))
+        .await
+        .map_err(|_| Error::Processor)?;
+    let (tx, rx) = oneshot::channel();
+    tx_msg
+        .send(Message::GetState { tx })
+        .await
+        .map_err(|_| Error::Processor)?;
+    let all = rx.await.map_err(|_| Error::Processor)?;
+
+    let mut state = processor::StateView::default();
+    if let Some(s) = message.target().and_then(|(client, _)| all.get(client)) {
+        state.set(*s);
+    }
+    Ok(Report {
+        messages,
+        line,
+        message,
+        state,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn state_at() {
+        // the input format is the log format
+        let path = "data/cases/sample/in.csv";
+        let report = super::state_at(path, processor::Config::default(), Point::Tx(5))
+            .await
+            .unwrap();
+        assert_eq!(report.line, 7);
+        assert!(matches!(
+            report.message,
+            Message::Withdrawal { client: 2, .. }
+        ));
+        let state = report.state.get(2).unwrap();
+        assert_eq!((state.available, state.held), (20000, 0));
+        assert_eq!(report.state.iter().count(), 1);
+
+        let report = super::state_at(path, processor::Config::default(), Point::Line(7))
+            .await
+            .unwrap();
+        assert!(matches!(report.message, Message::Withdrawal { tx: 5, .. }));
+        assert!(report.to_string().starts_with(
+            "State before line 7 (withdrawal of client 2, transaction 5, amount 3.0000)"
+        ));
+
+        assert!(matches!(
+            super::state_at(path, processor::Config::default(), Point::Line(5)).await,
+            Err(Error::Invalid { line: 5, .. })
+        ));
+        assert!(matches!(
+            super::state_at(path, processor::Config::default(), Point::Tx(99)).await,
+            Err(Error::UnknownTx(99))
+        ));
+    }
+}
@@ -0,0 +1,295 @@
+/**
+ * JSON Schemas (draft 2020-12) of the data exchanged with trapez, so partners can validate their
+ * feeds against the definitions the parser actually uses.
+ *
+ * - `input`: a record of the input file, with the CSV fields as JSON values.
+ * - `message`: a transactional message as the processor receives it, with amounts in 1/10000th
+ *   units.
+ * - `state`: a row of the report.
+ *
+ * The schemas are built from the same constants as the parser where possible, e.g. the transaction
+ * type names, so they can't drift apart.
+ */
+use crate::{cli, json::Value};
+
+/**
+ * The documents `trapez schema` can print.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Kind {
+    Input,
+    Message,
+    State,
+}
+
+const DIALECT: &str = "https://json-schema.org/draft/2020-12/schema";
+// Decimal amounts as accepted by `amount::parse`, additional decimal places get truncated.
+const AMOUNT: &str = r"^[+-]?([0-9]+(\.[0-9]*)?|\.[0-9]+)$";
+const REASON: &str = r"^[A-Za-z0-9.-]{1,8}$";
+const ASSET: &str = r"^[A-Za-z0-9.-]{1,12}$";
+// Amounts as written in the report.
+const REPORT_AMOUNT: &str = r"^-?[0-9]+\.[0-9]{4}$";
+
+pub fn schema(kind: Kind) -> Value {
+    match kind {
+        Kind::Input => input(),
+        Kind::Message => message(),
+        Kind::State => state(),
+    }
+}
+
+fn object(fields: Vec<(&str, Value)>) -> Value {
+    Value::Object(
+        fields
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect(),
+    )
+}
+
+fn string(s: &str) -> Value {
+    Value::String(s.to_string())
+}
+
+fn strings(strings: &[&str]) -> Value {
+    Value::Array(strings.iter().map(|s| string(s)).collect())
+}
+
+fn integer(max: i64) -> Value {
+    object(vec![
+        ("type", string("integer")),
+        ("minimum", Value::Number(0)),
+        ("maximum", Value::Number(max)),
+    ])
+}
+
+fn document(title: &str, properties: Vec<(&str, Value)>, required: &[&str]) -> Value {
+    object(vec![
+        ("$schema", string(DIALECT)),
+        ("title", string(title)),
+        ("type", string("object")),
+        ("properties", object(properties)),
+        ("required", strings(required)),
+    ])
+}
+
+// JSON Schema patterns have no case-insensitive flag, so every letter gets a class of both cases.
+fn case_insensitive(names: impl Iterator<Item = &'static str>) -> String {
+    let alternatives: Vec<String> = names
+        .map(|name| {
+            name.chars()
+                .map(|c| format!("[{}{}]", c.to_ascii_lowercase(), c.to_ascii_uppercase()))
+                .collect()
+        })
+        .collect();
+    format!("^({})$", alternatives.join("|"))
+}
+
+fn input() -> Value {
+    document(
+        "trapez input record",
+        vec![
+            (
+                "type",
+                object(vec![
+                    ("type", string("string")),
+                    (
+                        "pattern",
+                        Value::String(case_insensitive(cli::type_names().chain([cli::TRAILER]))),
+                    ),
+                    (
+                        "description",
+                        string("Transaction type, case-insensitive. Aliases configured with --alias are accepted as well. Trailer rows close a block of rows with its row count in tx and the sum of its amounts in amount."),
+                    ),
+                ]),
+            ),
+            ("client", integer(u16::MAX.into())),
+            ("tx", integer(u32::MAX.into())),
+            (
+                "amount",
+                object(vec![
+                    ("type", strings(&["string", "null"])),
+                    ("pattern", string(AMOUNT)),
+                    (
+                        "description",
+                        string("Decimal amount, required for deposits, withdrawals and authorizations. Digits beyond the fourth decimal place are truncated."),
+                    ),
+                ]),
+            ),
+            (
+                "reference",
+                object(vec![
+                    ("type", strings(&["string", "null"])),
+                    (
+                        "description",
+                        string("Free-form reference like the partner's id of the transaction, kept in the logs. The column may also be named memo."),
+                    ),
+                ]),
+            ),
+            (
+                "reason",
+                object(vec![
+                    ("type", strings(&["string", "null"])),
+                    ("pattern", string(REASON)),
+                    (
+                        "description",
+                        string("Reason code of a dispute, resolve or chargeback, checked against --reason-codes if given."),
+                    ),
+                ]),
+            ),
+            (
+                "timestamp",
+                object(vec![
+                    ("type", strings(&["integer", "null"])),
+                    ("minimum", Value::Number(0)),
+                    (
+                        "description",
+                        string("Time of the transaction, e.g. in seconds since the epoch. With --backfill, late transactions are folded into the history of their account by it."),
+                    ),
+                ]),
+            ),
+            (
+                "asset",
+                object(vec![
+                    ("type", strings(&["string", "null"])),
+                    ("pattern", string(ASSET)),
+                    (
+                        "description",
+                        string("Asset bought by a deposit or sold by a withdrawal, given together with price."),
+                    ),
+                ]),
+            ),
+            (
+                "price",
+                object(vec![
+                    ("type", strings(&["string", "null"])),
+                    ("pattern", string(AMOUNT)),
+                    (
+                        "description",
+                        string("Positive unit price of the asset. The amount is the cash value of the trade."),
+                    ),
+                ]),
+            ),
+        ],
+        &["type", "client", "tx"],
+    )
+}
+
+fn message() -> Value {
+    let variant = |name: &str, amount: bool| {
+        let mut properties = vec![
+            ("type", object(vec![("const", string(name))])),
+            ("client", integer(u16::MAX.into())),
+            ("tx", integer(u32::MAX.into())),
+        ];
+        let mut required = vec!["type", "client", "tx"];
+        if amount {
+            properties.push((
+                "amount",
+                object(vec![
+                    ("type", string("integer")),
+                    ("description", string("Amount in 1/10000th units.")),
+                ]),
+            ));
+            required.push("amount");
+        } else {
+            properties.push((
+                "reason",
+                object(vec![
+                    ("type", strings(&["string", "null"])),
+                    ("pattern", string(REASON)),
+                ]),
+            ));
+        }
+        object(vec![
+            ("type", string("object")),
+            ("properties", object(properties)),
+            ("required", strings(&required)),
+            ("additionalProperties", Value::Bool(false)),
+        ])
+    };
+    object(vec![
+        ("$schema", string(DIALECT)),
+        ("title", string("trapez message")),
+        (
+            "oneOf",
+            Value::Array(
+                cli::type_names()
+                    .map(|name| {
+                        variant(name, matches!(name, "deposit" | "withdrawal" | "authorize"))
+                    })
+                    .collect(),
+            ),
+        ),
+    ])
+}
+
+fn state() -> Value {
+    let amount = || {
+        object(vec![
+            ("type", string("string")),
+            ("pattern", string(REPORT_AMOUNT)),
+        ])
+    };
+    document(
+        "trapez account state",
+        vec![
+            ("client", integer(u16::MAX.into())),
+            ("available", amount()),
+            ("held", amount()),
+            ("total", amount()),
+            ("locked", object(vec![("type", string("boolean"))])),
+            (
+                "pending",
+                object(vec![
+                    ("type", string("string")),
+                    ("pattern", string(REPORT_AMOUNT)),
+                    (
+                        "description",
+                        string("Funds earmarked by withdrawals awaiting approval, only reported with --approve-withdrawals-above."),
+                    ),
+                ]),
+            ),
+        ],
+        &["client", "available", "held", "total", "locked"],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{json, processor};
+
+    #[test]
+    fn documents() {
+        for kind in [Kind::Input, Kind::Message, Kind::State] {
+            let schema = schema(kind);
+            assert_eq!(json::parse(&schema.to_string()).unwrap(), schema);
+        }
+        assert_eq!(
+            message()
+                .get("oneOf")
+                .and_then(Value::as_array)
+                .map(<[_]>::len),
+            Some(9)
+        );
+        assert_eq!(case_insensitive(["ab"].into_iter()), "^([aA][bB])$");
+    }
+
+    #[test]
+    fn report_columns() {
+        // the state schema has to describe exactly the columns of the report
+        let mut report = Vec::new();
+        cli::write_report(&mut report, processor::StateView::default().iter()).unwrap();
+        let header = String::from_utf8(report).unwrap();
+        let state = state();
+        let required: Vec<_> = state
+            .get("required")
+            .and_then(Value::as_array)
+            .unwrap()
+            .iter()
+            .filter_map(Value::as_str)
+            .collect();
+        assert_eq!(header.trim_end(), required.join(","));
+    }
+}
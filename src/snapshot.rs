@@ -0,0 +1,1262 @@
+/**
+ * Snapshot files of the complete processor state.
+ *
+ * A snapshot holds all accounts including their transaction logs and open disputes, so a later
+ * run can continue where an earlier one stopped. All integers are little endian.
+ *
+ * The header consists of a magic number, the format version, the schema of the body as text and
+ * the SHA-256 digest of the schema. The schema makes files self-describing and lets a build reject
+ * a body layout it doesn't know even if the version number matches.
+ */
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsString,
+    fmt::{self, Write as _},
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use crate::{
+    account, amount,
+    json::{self, Value},
+    links::Links,
+    log,
+    metadata::Metadata,
+    position::Position,
+    sha256,
+};
+
+const MAGIC: &[u8; 8] = b"TRAPEZSN";
+const VERSION: u32 = 1;
+const SCHEMA: &str = "accounts:u32[client:u16 available:i64 held:i64 locked:u8 \
+                      log:u64[tx:u32 amount:i64] disputes:u32[tx:u32] reversed:u32[tx:u32] \
+                      closed:u8[tx:u32 charged_back:u8]] \
+                      dispute_counts:u32[client:u16 count:u32] \
+                      reasons:u32[client:u16 tx:u32 reason:u8[u8]] \
+                      metadata:u32[client:u16 values:u32[key:u16[u8] value:u16[u8]] \
+                      tags:u32[tag:u16[u8]]] \
+                      holds:u32[client:u16] \
+                      pending:u32[client:u16 tx:u32 amount:i64] \
+                      links:u32[client:u16 primary:u16] \
+                      positions:u32[client:u16 asset:u8[u8] units:i64 cost:i64 realized:i64] \
+                      authorized:u32[client:u16 tx:u32 amount:i64 expires:u64]";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Snapshot IO error: `{0}`.")]
+    Io(#[from] io::Error),
+    #[error("Not a snapshot file.")]
+    Magic,
+    #[error("Unsupported snapshot version {0} (supported: {VERSION}).")]
+    Version(u32),
+    #[error("Unknown snapshot schema `{schema}` for version {version}.")]
+    Schema { version: u32, schema: String },
+    #[error("Corrupt snapshot header.")]
+    Header,
+    #[error("Snapshot account error: `{0}`.")]
+    Account(account::Error),
+    #[error("Invalid reason code `{0}` in snapshot.")]
+    Reason(String),
+    #[error("Invalid asset `{0}` in snapshot.")]
+    Asset(String),
+    #[error("Invalid metadata of client {0} in snapshot.")]
+    Metadata(u16),
+    #[error("{0}")]
+    Json(#[from] json::Error),
+    #[error("Missing or invalid field `{0}` in state dump.")]
+    Field(&'static str),
+    #[error("The states differ in {0} cases.")]
+    Differ(usize),
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Contents {
+    pub accounts: Vec<(u16, account::Parts)>,
+    /// Number of disputes opened per client.
+    pub disputes: BTreeMap<u16, u32>,
+    /// Reason codes of open disputes by client and transaction.
+    pub reasons: BTreeMap<(u16, u32), String>,
+    /// Metadata and tags of accounts set by admins.
+    pub metadata: BTreeMap<u16, Metadata>,
+    /// Clients on hold for review because of a negative balance.
+    pub holds: BTreeSet<u16>,
+    /// Clients sharing the account of a primary client.
+    pub links: Links,
+    /// Positions in traded assets by client and asset.
+    pub positions: BTreeMap<(u16, String), Position>,
+}
+
+/**
+ * Writes the snapshot to a temporary file first and moves it into place once it is complete, so
+ * an interrupted write never replaces the previous snapshot.
+ */
+pub fn write<P: AsRef<Path>>(path: P, contents: &Contents) -> Result<(), Error> {
+    let path = path.as_ref();
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut w = BufWriter::new(File::create(&tmp)?);
+    w.write_all(MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())?;
+    w.write_all(&(SCHEMA.len() as u32).to_le_bytes())?;
+    w.write_all(SCHEMA.as_bytes())?;
+    w.write_all(&sha256::digest(SCHEMA.as_bytes()))?;
+    encode(&mut w, contents)?;
+    w.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Contents, Error> {
+    let mut r = BufReader::new(File::open(path)?);
+    if bytes::<8>(&mut r)? != *MAGIC {
+        return Err(Error::Magic);
+    }
+    let version = u32::from_le_bytes(bytes(&mut r)?);
+    if version != VERSION {
+        return Err(Error::Version(version));
+    }
+    let schema = schema(&mut r)?;
+    if schema != SCHEMA {
+        return Err(Error::Schema { version, schema });
+    }
+    decode(&mut r)
+}
+
+/**
+ * Digests of the input files whose messages are part of a snapshot.
+ *
+ * Kept as one hex digest per line in `<snapshot>.inputs` next to the snapshot, so a run on top of
+ * a snapshot can skip an input which was already processed.
+ */
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Inputs(BTreeSet<String>);
+
+impl Inputs {
+    fn path(snapshot: &Path) -> OsString {
+        let mut path = snapshot.as_os_str().to_owned();
+        path.push(".inputs");
+        path
+    }
+
+    /**
+     * Reads the inputs of a snapshot. A missing file means none are known.
+     */
+    pub fn read<P: AsRef<Path>>(snapshot: P) -> io::Result<Inputs> {
+        match File::open(Self::path(snapshot.as_ref())) {
+            Ok(file) => BufReader::new(file).lines().collect(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Inputs::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn contains(&self, digest: &sha256::Digest) -> bool {
+        self.0.contains(&sha256::hex(digest))
+    }
+
+    pub fn insert(&mut self, digest: &sha256::Digest) {
+        self.0.insert(sha256::hex(digest));
+    }
+
+    /**
+     * Writes the inputs next to the snapshot, replacing the file atomically like `write`.
+     */
+    pub fn write<P: AsRef<Path>>(&self, snapshot: P) -> io::Result<()> {
+        let path = Self::path(snapshot.as_ref());
+        let mut tmp = path.clone();
+        tmp.push(".tmp");
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        for digest in &self.0 {
+            writeln!(w, "{digest}")?;
+        }
+        w.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+        fs::rename(&tmp, path)
+    }
+}
+
+impl FromIterator<String> for Inputs {
+    fn from_iter<I: IntoIterator<Item = String>>(iter: I) -> Self {
+        Self(iter.into_iter().filter(|line| !line.is_empty()).collect())
+    }
+}
+
+fn schema<R: Read>(r: &mut R) -> Result<String, Error> {
+    let len = u32::from_le_bytes(bytes(r)?);
+    let mut schema = Vec::new();
+    r.take(u64::from(len)).read_to_end(&mut schema)?;
+    if schema.len() != len as usize || bytes::<32>(r)? != sha256::digest(&schema) {
+        return Err(Error::Header);
+    }
+    String::from_utf8(schema).map_err(|_| Error::Header)
+}
+
+/**
+ * Renders the contents as human-readable JSON with amounts as decimal strings.
+ */
+pub fn to_json(contents: &Contents) -> String {
+    let mut json = String::from("{\n  \"accounts\": [");
+    for (i, (client, parts)) in contents.accounts.iter().enumerate() {
+        let sep = if i == 0 { "" } else { "," };
+        let _ = write!(
+            json,
+            "{sep}\n    {{\n      \"client\": {client},\n      \"available\": \"{}\",\n      \
+             \"held\": \"{}\",\n      \"locked\": {},\n      \"disputes_opened\": {},\n      \
+             \"log\": [",
+            amount::Decimal(parts.available),
+            amount::Decimal(parts.held),
+            parts.locked,
+            contents.disputes.get(client).copied().unwrap_or_default(),
+        );
+        for (j, (tx, amount)) in parts.log.iter().enumerate() {
+            let sep = if j == 0 { "" } else { "," };
+            let _ = write!(
+                json,
+                "{sep}\n        {{\"tx\": {tx}, \"amount\": \"{}\"}}",
+                amount::Decimal(*amount)
+            );
+        }
+        let disputes: Vec<_> = parts.disputes.iter().map(u32::to_string).collect();
+        let reversed: Vec<_> = parts.reversed.iter().map(u32::to_string).collect();
+        let _ = write!(
+            json,
+            "\n      ],\n      \"disputes\": [{}],\n      \"reversed\": [{}]",
+            disputes.join(", "),
+            reversed.join(", ")
+        );
+        let reasons: Vec<_> = contents
+            .reasons
+            .range((*client, 0)..=(*client, u32::MAX))
+            .map(|((_, tx), reason)| format!("{{\"tx\": {tx}, \"reason\": \"{reason}\"}}"))
+            .collect();
+        if !reasons.is_empty() {
+            let _ = write!(json, ",\n      \"reasons\": [{}]", reasons.join(", "));
+        }
+        if let Some(metadata) = contents.metadata.get(client).filter(|m| !m.is_empty()) {
+            let values: Vec<_> = metadata
+                .values
+                .iter()
+                .map(|(key, value)| format!("\"{key}\": \"{}\"", json::escape(value)))
+                .collect();
+            let tags: Vec<_> = metadata
+                .tags
+                .iter()
+                .map(|tag| format!("\"{tag}\""))
+                .collect();
+            let _ = write!(
+                json,
+                ",\n      \"metadata\": {{{}}},\n      \"tags\": [{}]",
+                values.join(", "),
+                tags.join(", ")
+            );
+        }
+        if contents.holds.contains(client) {
+            json.push_str(",\n      \"on_hold\": true");
+        }
+        let pending: Vec<_> = parts
+            .pending
+            .iter()
+            .map(|(tx, amount)| {
+                format!(
+                    "{{\"tx\": {tx}, \"amount\": \"{}\"}}",
+                    amount::Decimal(*amount)
+                )
+            })
+            .collect();
+        if !pending.is_empty() {
+            let _ = write!(json, ",\n      \"pending\": [{}]", pending.join(", "));
+        }
+        let authorized: Vec<_> = parts
+            .authorized
+            .iter()
+            .map(|(tx, amount, expires)| {
+                format!(
+                    "{{\"tx\": {tx}, \"amount\": \"{}\", \"expires\": {expires}}}",
+                    amount::Decimal(*amount)
+                )
+            })
+            .collect();
+        if !authorized.is_empty() {
+            let _ = write!(json, ",\n      \"authorized\": [{}]", authorized.join(", "));
+        }
+        let closed: Vec<_> = parts
+            .closed
+            .iter()
+            .map(|(tx, charged_back)| format!("{{\"tx\": {tx}, \"charged_back\": {charged_back}}}"))
+            .collect();
+        if !closed.is_empty() {
+            let _ = write!(json, ",\n      \"closed\": [{}]", closed.join(", "));
+        }
+        let members: Vec<_> = contents
+            .links
+            .members(*client)
+            .map(|c| c.to_string())
+            .collect();
+        if !members.is_empty() {
+            let _ = write!(json, ",\n      \"members\": [{}]", members.join(", "));
+        }
+        let positions: Vec<_> = contents
+            .positions
+            .iter()
+            .filter(|((c, _), _)| c == client)
+            .map(|((_, asset), position)| {
+                format!(
+                    "{{\"asset\": \"{asset}\", \"units\": \"{}\", \"cost\": \"{}\", \
+                     \"realized\": \"{}\"}}",
+                    amount::Decimal(position.units),
+                    amount::Decimal(position.cost),
+                    amount::Decimal(position.realized)
+                )
+            })
+            .collect();
+        if !positions.is_empty() {
+            let _ = write!(json, ",\n      \"positions\": [{}]", positions.join(", "));
+        }
+        json.push_str("\n    }");
+    }
+    json.push_str("\n  ]\n}\n");
+    json
+}
+
+/**
+ * Reads contents in the format written by `to_json`.
+ */
+pub fn from_json(s: &str) -> Result<Contents, Error> {
+    fn field<'a>(value: &'a Value, key: &'static str) -> Result<&'a Value, Error> {
+        value.get(key).ok_or(Error::Field(key))
+    }
+    fn int<T: TryFrom<i64>>(value: &Value, key: &'static str) -> Result<T, Error> {
+        field(value, key)?
+            .as_i64()
+            .and_then(|n| T::try_from(n).ok())
+            .ok_or(Error::Field(key))
+    }
+    fn decimal(value: &Value, key: &'static str) -> Result<i64, Error> {
+        field(value, key)?
+            .as_str()
+            .and_then(|s| amount::parse(s).ok())
+            .ok_or(Error::Field(key))
+    }
+    fn array<'a>(value: &'a Value, key: &'static str) -> Result<&'a [Value], Error> {
+        field(value, key)?.as_array().ok_or(Error::Field(key))
+    }
+
+    let root = json::parse(s)?;
+    let mut contents = Contents::default();
+    for account in array(&root, "accounts")? {
+        let client = int(account, "client")?;
+        let mut parts = account::Parts {
+            available: decimal(account, "available")?,
+            held: decimal(account, "held")?,
+            locked: field(account, "locked")?
+                .as_bool()
+                .ok_or(Error::Field("locked"))?,
+            ..account::Parts::default()
+        };
+        for entry in array(account, "log")? {
+            parts
+                .log
+                .push((int(entry, "tx")?, decimal(entry, "amount")?));
+        }
+        parts.log.sort_unstable_by_key(|(tx, _)| *tx);
+        for tx in array(account, "disputes")? {
+            parts.disputes.push(
+                tx.as_i64()
+                    .and_then(|tx| u32::try_from(tx).ok())
+                    .ok_or(Error::Field("disputes"))?,
+            );
+        }
+        for tx in array(account, "reversed")? {
+            parts.reversed.push(
+                tx.as_i64()
+                    .and_then(|tx| u32::try_from(tx).ok())
+                    .ok_or(Error::Field("reversed"))?,
+            );
+        }
+        // only written for accounts with reason codes
+        let reasons = match account.get("reasons") {
+            Some(_) => array(account, "reasons")?,
+            None => &[],
+        };
+        for entry in reasons {
+            let reason = field(entry, "reason")?
+                .as_str()
+                .ok_or(Error::Field("reason"))?;
+            contents
+                .reasons
+                .insert((client, int(entry, "tx")?), reason.to_string());
+        }
+        // only written for accounts with metadata
+        let mut metadata = Metadata::default();
+        match account.get("metadata") {
+            Some(Value::Object(values)) => {
+                for (key, value) in values {
+                    let value = value.as_str().ok_or(Error::Field("metadata"))?;
+                    metadata.values.insert(key.clone(), value.to_string());
+                }
+            }
+            Some(_) => return Err(Error::Field("metadata")),
+            None => (),
+        }
+        if account.get("tags").is_some() {
+            for tag in array(account, "tags")? {
+                let tag = tag.as_str().ok_or(Error::Field("tags"))?;
+                metadata.tags.insert(tag.to_string());
+            }
+        }
+        if !metadata.is_empty() {
+            contents.metadata.insert(client, metadata);
+        }
+        // only written for accounts on hold
+        if account.get("on_hold").is_some() {
+            let on_hold = field(account, "on_hold")?
+                .as_bool()
+                .ok_or(Error::Field("on_hold"))?;
+            if on_hold {
+                contents.holds.insert(client);
+            }
+        }
+        // only written for accounts with withdrawals pending approval
+        if account.get("pending").is_some() {
+            for entry in array(account, "pending")? {
+                parts
+                    .pending
+                    .push((int(entry, "tx")?, decimal(entry, "amount")?));
+            }
+            parts.pending.sort_unstable_by_key(|(tx, _)| *tx);
+        }
+        // only written for accounts with open authorizations
+        if account.get("authorized").is_some() {
+            for entry in array(account, "authorized")? {
+                parts.authorized.push((
+                    int(entry, "tx")?,
+                    decimal(entry, "amount")?,
+                    int(entry, "expires")?,
+                ));
+            }
+            parts.authorized.sort_unstable_by_key(|(tx, ..)| *tx);
+        }
+        // only written for accounts with closed disputes
+        if account.get("closed").is_some() {
+            for entry in array(account, "closed")? {
+                let charged_back = field(entry, "charged_back")?
+                    .as_bool()
+                    .ok_or(Error::Field("charged_back"))?;
+                parts.closed.push((int(entry, "tx")?, charged_back));
+            }
+        }
+        // only written for accounts with linked clients
+        if account.get("members").is_some() {
+            for member in array(account, "members")? {
+                let member = member
+                    .as_i64()
+                    .and_then(|member| u16::try_from(member).ok())
+                    .ok_or(Error::Field("members"))?;
+                contents.links.insert(member, client);
+            }
+        }
+        // only written for accounts with positions
+        if account.get("positions").is_some() {
+            for entry in array(account, "positions")? {
+                let asset = field(entry, "asset")?
+                    .as_str()
+                    .ok_or(Error::Field("asset"))?;
+                let position = Position {
+                    units: decimal(entry, "units")?,
+                    cost: decimal(entry, "cost")?,
+                    realized: decimal(entry, "realized")?,
+                };
+                contents
+                    .positions
+                    .insert((client, asset.to_string()), position);
+            }
+        }
+        let opened = match account.get("disputes_opened") {
+            Some(_) => int(account, "disputes_opened")?,
+            None => 0,
+        };
+        if opened > 0 {
+            contents.disputes.insert(client, opened);
+        }
+        contents.accounts.push((client, parts));
+    }
+    Ok(contents)
+}
+
+/**
+ * Reads either a snapshot or a JSON state dump, told apart by the magic number.
+ */
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Contents, Error> {
+    let data = fs::read(&path)?;
+    if data.starts_with(MAGIC) {
+        read(path)
+    } else {
+        from_json(&String::from_utf8_lossy(&data))
+    }
+}
+
+/**
+ * One side of a comparison.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Side::Left => write!(f, "left"),
+            Side::Right => write!(f, "right"),
+        }
+    }
+}
+
+/**
+ * A difference between two states as found by `diff`.
+ */
+#[derive(Debug, PartialEq, Eq)]
+pub enum Difference {
+    /// An account which only exists on one side.
+    Account { client: u16, only_in: Side },
+    Amount {
+        client: u16,
+        field: &'static str,
+        left: i64,
+        right: i64,
+    },
+    Locked {
+        client: u16,
+        left: bool,
+        right: bool,
+    },
+    /// A logged transaction which is missing on one side or has different amounts.
+    Transaction {
+        client: u16,
+        tx: u32,
+        left: Option<i64>,
+        right: Option<i64>,
+    },
+    /// A transaction which is disputed on one side only.
+    Dispute { client: u16, tx: u32, only_in: Side },
+    /// A transaction which is reversed on one side only.
+    Reversed { client: u16, tx: u32, only_in: Side },
+    /// Different numbers of opened disputes.
+    DisputeCount { client: u16, left: u32, right: u32 },
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let amount = |amount: &Option<i64>| match amount {
+            Some(amount) => amount::Decimal(*amount).to_string(),
+            None => "missing".to_string(),
+        };
+        match self {
+            Difference::Account { client, only_in } => {
+                write!(f, "Client {client} only exists on the {only_in} side.")
+            }
+            Difference::Amount {
+                client,
+                field,
+                left,
+                right,
+            } => write!(
+                f,
+                "Client {client}: {field} is {} on the left and {} on the right.",
+                amount::Decimal(*left),
+                amount::Decimal(*right)
+            ),
+            Difference::Locked {
+                client,
+                left,
+                right,
+            } => write!(
+                f,
+                "Client {client}: locked is {left} on the left and {right} on the right."
+            ),
+            Difference::Transaction {
+                client,
+                tx,
+                left,
+                right,
+            } => write!(
+                f,
+                "Client {client}: transaction {tx} is {} on the left and {} on the right.",
+                amount(left),
+                amount(right)
+            ),
+            Difference::Dispute {
+                client,
+                tx,
+                only_in,
+            } => write!(
+                f,
+                "Client {client}: transaction {tx} is only disputed on the {only_in} side."
+            ),
+            Difference::Reversed {
+                client,
+                tx,
+                only_in,
+            } => write!(
+                f,
+                "Client {client}: transaction {tx} is only reversed on the {only_in} side."
+            ),
+            Difference::DisputeCount {
+                client,
+                left,
+                right,
+            } => write!(
+                f,
+                "Client {client}: {left} disputes opened on the left and {right} on the right."
+            ),
+        }
+    }
+}
+
+impl log::Event for Difference {
+    fn code(&self) -> &'static str {
+        match self {
+            Difference::Account { .. } => "diff_account",
+            Difference::Amount { .. } => "diff_amount",
+            Difference::Locked { .. } => "diff_locked",
+            Difference::Transaction { .. } => "diff_transaction",
+            Difference::Dispute { .. } => "diff_dispute",
+            Difference::Reversed { .. } => "diff_reversed",
+            Difference::DisputeCount { .. } => "diff_dispute_count",
+        }
+    }
+
+    fn client(&self) -> Option<u16> {
+        match self {
+            Difference::Account { client, .. }
+            | Difference::Amount { client, .. }
+            | Difference::Locked { client, .. }
+            | Difference::Transaction { client, .. }
+            | Difference::Dispute { client, .. }
+            | Difference::Reversed { client, .. }
+            | Difference::DisputeCount { client, .. } => Some(*client),
+        }
+    }
+}
+
+/**
+ * Compares two states field by field, ordered by client. The order of accounts and of the entries
+ * within an account doesn't matter.
+ */
+pub fn diff(left: &Contents, right: &Contents) -> Vec<Difference> {
+    fn accounts(contents: &Contents) -> BTreeMap<u16, &account::Parts> {
+        contents.accounts.iter().map(|(c, p)| (*c, p)).collect()
+    }
+    // The transactions in only one of the lists and the side they're on.
+    fn only_in(left: &[u32], right: &[u32]) -> Vec<(u32, Side)> {
+        let (left, right): (BTreeSet<_>, BTreeSet<_>) = (
+            left.iter().copied().collect(),
+            right.iter().copied().collect(),
+        );
+        left.symmetric_difference(&right)
+            .map(|tx| {
+                let side = if left.contains(tx) {
+                    Side::Left
+                } else {
+                    Side::Right
+                };
+                (*tx, side)
+            })
+            .collect()
+    }
+
+    let (lefts, rights) = (accounts(left), accounts(right));
+    let clients: BTreeSet<_> = lefts.keys().chain(rights.keys()).copied().collect();
+    let mut differences = Vec::new();
+    for client in clients {
+        let (l, r) = match (lefts.get(&client), rights.get(&client)) {
+            (Some(l), Some(r)) => (l, r),
+            (Some(_), None) => {
+                differences.push(Difference::Account {
+                    client,
+                    only_in: Side::Left,
+                });
+                continue;
+            }
+            _ => {
+                differences.push(Difference::Account {
+                    client,
+                    only_in: Side::Right,
+                });
+                continue;
+            }
+        };
+        for (field, left, right) in [
+            ("available", l.available, r.available),
+            ("held", l.held, r.held),
+        ] {
+            if left != right {
+                differences.push(Difference::Amount {
+                    client,
+                    field,
+                    left,
+                    right,
+                });
+            }
+        }
+        if l.locked != r.locked {
+            differences.push(Difference::Locked {
+                client,
+                left: l.locked,
+                right: r.locked,
+            });
+        }
+
+        let log_l: BTreeMap<_, _> = l.log.iter().copied().collect();
+        let log_r: BTreeMap<_, _> = r.log.iter().copied().collect();
+        let txs: BTreeSet<_> = log_l.keys().chain(log_r.keys()).copied().collect();
+        for tx in txs {
+            let (left, right) = (log_l.get(&tx).copied(), log_r.get(&tx).copied());
+            if left != right {
+                differences.push(Difference::Transaction {
+                    client,
+                    tx,
+                    left,
+                    right,
+                });
+            }
+        }
+        for (tx, only_in) in only_in(&l.disputes, &r.disputes) {
+            differences.push(Difference::Dispute {
+                client,
+                tx,
+                only_in,
+            });
+        }
+        for (tx, only_in) in only_in(&l.reversed, &r.reversed) {
+            differences.push(Difference::Reversed {
+                client,
+                tx,
+                only_in,
+            });
+        }
+
+        let left = left.disputes.get(&client).copied().unwrap_or_default();
+        let right = right.disputes.get(&client).copied().unwrap_or_default();
+        if left != right {
+            differences.push(Difference::DisputeCount {
+                client,
+                left,
+                right,
+            });
+        }
+    }
+    differences
+}
+
+fn encode<W: Write>(w: &mut W, contents: &Contents) -> io::Result<()> {
+    w.write_all(&(contents.accounts.len() as u32).to_le_bytes())?;
+    for (client, parts) in &contents.accounts {
+        w.write_all(&client.to_le_bytes())?;
+        w.write_all(&parts.available.to_le_bytes())?;
+        w.write_all(&parts.held.to_le_bytes())?;
+        w.write_all(&[u8::from(parts.locked)])?;
+        w.write_all(&(parts.log.len() as u64).to_le_bytes())?;
+        for (tx, amount) in &parts.log {
+            w.write_all(&tx.to_le_bytes())?;
+            w.write_all(&amount.to_le_bytes())?;
+        }
+        w.write_all(&(parts.disputes.len() as u32).to_le_bytes())?;
+        for tx in &parts.disputes {
+            w.write_all(&tx.to_le_bytes())?;
+        }
+        w.write_all(&(parts.reversed.len() as u32).to_le_bytes())?;
+        for tx in &parts.reversed {
+            w.write_all(&tx.to_le_bytes())?;
+        }
+        // at most `account::CLOSED` entries
+        w.write_all(&[parts.closed.len() as u8])?;
+        for (tx, charged_back) in &parts.closed {
+            w.write_all(&tx.to_le_bytes())?;
+            w.write_all(&[u8::from(*charged_back)])?;
+        }
+    }
+    w.write_all(&(contents.disputes.len() as u32).to_le_bytes())?;
+    for (client, count) in &contents.disputes {
+        w.write_all(&client.to_le_bytes())?;
+        w.write_all(&count.to_le_bytes())?;
+    }
+    w.write_all(&(contents.reasons.len() as u32).to_le_bytes())?;
+    for ((client, tx), reason) in &contents.reasons {
+        w.write_all(&client.to_le_bytes())?;
+        w.write_all(&tx.to_le_bytes())?;
+        // reason codes are at most eight bytes long
+        w.write_all(&[reason.len() as u8])?;
+        w.write_all(reason.as_bytes())?;
+    }
+    w.write_all(&(contents.metadata.len() as u32).to_le_bytes())?;
+    for (client, metadata) in &contents.metadata {
+        w.write_all(&client.to_le_bytes())?;
+        w.write_all(&(metadata.values.len() as u32).to_le_bytes())?;
+        for (key, value) in &metadata.values {
+            encode_str(w, key)?;
+            encode_str(w, value)?;
+        }
+        w.write_all(&(metadata.tags.len() as u32).to_le_bytes())?;
+        for tag in &metadata.tags {
+            encode_str(w, tag)?;
+        }
+    }
+    w.write_all(&(contents.holds.len() as u32).to_le_bytes())?;
+    for client in &contents.holds {
+        w.write_all(&client.to_le_bytes())?;
+    }
+    let pending = contents
+        .accounts
+        .iter()
+        .flat_map(|(client, parts)| parts.pending.iter().map(move |entry| (client, entry)));
+    w.write_all(&(pending.clone().count() as u32).to_le_bytes())?;
+    for (client, (tx, amount)) in pending {
+        w.write_all(&client.to_le_bytes())?;
+        w.write_all(&tx.to_le_bytes())?;
+        w.write_all(&amount.to_le_bytes())?;
+    }
+    w.write_all(&(contents.links.iter().count() as u32).to_le_bytes())?;
+    for (client, primary) in contents.links.iter() {
+        w.write_all(&client.to_le_bytes())?;
+        w.write_all(&primary.to_le_bytes())?;
+    }
+    w.write_all(&(contents.positions.len() as u32).to_le_bytes())?;
+    for ((client, asset), position) in &contents.positions {
+        w.write_all(&client.to_le_bytes())?;
+        // asset codes are at most twelve bytes long
+        w.write_all(&[asset.len() as u8])?;
+        w.write_all(asset.as_bytes())?;
+        w.write_all(&position.units.to_le_bytes())?;
+        w.write_all(&position.cost.to_le_bytes())?;
+        w.write_all(&position.realized.to_le_bytes())?;
+    }
+    let authorized = contents
+        .accounts
+        .iter()
+        .flat_map(|(client, parts)| parts.authorized.iter().map(move |entry| (client, entry)));
+    w.write_all(&(authorized.clone().count() as u32).to_le_bytes())?;
+    for (client, (tx, amount, expires)) in authorized {
+        w.write_all(&client.to_le_bytes())?;
+        w.write_all(&tx.to_le_bytes())?;
+        w.write_all(&amount.to_le_bytes())?;
+        w.write_all(&expires.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+// Keys, values and tags are checked to be at most `u16::MAX` bytes long.
+fn encode_str<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    w.write_all(&(s.len() as u16).to_le_bytes())?;
+    w.write_all(s.as_bytes())
+}
+
+fn decode<R: Read>(r: &mut R) -> Result<Contents, Error> {
+    let mut contents = Contents::default();
+    // Lengths aren't used for preallocation, a corrupt file fails with an unexpected EOF instead.
+    for _ in 0..u32::from_le_bytes(bytes(r)?) {
+        let client = u16::from_le_bytes(bytes(r)?);
+        let mut parts = account::Parts {
+            available: i64::from_le_bytes(bytes(r)?),
+            held: i64::from_le_bytes(bytes(r)?),
+            locked: bytes::<1>(r)? != [0],
+            ..account::Parts::default()
+        };
+        for _ in 0..u64::from_le_bytes(bytes(r)?) {
+            parts
+                .log
+                .push((u32::from_le_bytes(bytes(r)?), i64::from_le_bytes(bytes(r)?)));
+        }
+        for _ in 0..u32::from_le_bytes(bytes(r)?) {
+            parts.disputes.push(u32::from_le_bytes(bytes(r)?));
+        }
+        for _ in 0..u32::from_le_bytes(bytes(r)?) {
+            parts.reversed.push(u32::from_le_bytes(bytes(r)?));
+        }
+        for _ in 0..bytes::<1>(r)?[0] {
+            let tx = u32::from_le_bytes(bytes(r)?);
+            parts.closed.push((tx, bytes::<1>(r)? != [0]));
+        }
+        contents.accounts.push((client, parts));
+    }
+    for _ in 0..u32::from_le_bytes(bytes(r)?) {
+        let client = u16::from_le_bytes(bytes(r)?);
+        contents
+            .disputes
+            .insert(client, u32::from_le_bytes(bytes(r)?));
+    }
+    for _ in 0..u32::from_le_bytes(bytes(r)?) {
+        let key = (u16::from_le_bytes(bytes(r)?), u32::from_le_bytes(bytes(r)?));
+        let mut reason = vec![0; usize::from(bytes::<1>(r)?[0])];
+        r.read_exact(&mut reason)?;
+        let reason = String::from_utf8(reason)
+            .map_err(|e| Error::Reason(String::from_utf8_lossy(e.as_bytes()).into_owned()))?;
+        contents.reasons.insert(key, reason);
+    }
+    for _ in 0..u32::from_le_bytes(bytes(r)?) {
+        let client = u16::from_le_bytes(bytes(r)?);
+        let mut metadata = Metadata::default();
+        for _ in 0..u32::from_le_bytes(bytes(r)?) {
+            let key = decode_str(r, client)?;
+            metadata.values.insert(key, decode_str(r, client)?);
+        }
+        for _ in 0..u32::from_le_bytes(bytes(r)?) {
+            metadata.tags.insert(decode_str(r, client)?);
+        }
+        contents.metadata.insert(client, metadata);
+    }
+    for _ in 0..u32::from_le_bytes(bytes(r)?) {
+        contents.holds.insert(u16::from_le_bytes(bytes(r)?));
+    }
+    let mut pending = BTreeMap::<u16, Vec<(u32, i64)>>::new();
+    for _ in 0..u32::from_le_bytes(bytes(r)?) {
+        let client = u16::from_le_bytes(bytes(r)?);
+        let entry = (u32::from_le_bytes(bytes(r)?), i64::from_le_bytes(bytes(r)?));
+        pending.entry(client).or_default().push(entry);
+    }
+    for (client, parts) in &mut contents.accounts {
+        parts.pending = pending.remove(client).unwrap_or_default();
+    }
+    for _ in 0..u32::from_le_bytes(bytes(r)?) {
+        let client = u16::from_le_bytes(bytes(r)?);
+        contents.links.insert(client, u16::from_le_bytes(bytes(r)?));
+    }
+    for _ in 0..u32::from_le_bytes(bytes(r)?) {
+        let client = u16::from_le_bytes(bytes(r)?);
+        let mut asset = vec![0; usize::from(bytes::<1>(r)?[0])];
+        r.read_exact(&mut asset)?;
+        let asset = String::from_utf8(asset)
+            .map_err(|e| Error::Asset(String::from_utf8_lossy(e.as_bytes()).into_owned()))?;
+        let position = Position {
+            units: i64::from_le_bytes(bytes(r)?),
+            cost: i64::from_le_bytes(bytes(r)?),
+            realized: i64::from_le_bytes(bytes(r)?),
+        };
+        contents.positions.insert((client, asset), position);
+    }
+    let mut authorized = BTreeMap::<u16, Vec<(u32, i64, u64)>>::new();
+    for _ in 0..u32::from_le_bytes(bytes(r)?) {
+        let client = u16::from_le_bytes(bytes(r)?);
+        let entry = (
+            u32::from_le_bytes(bytes(r)?),
+            i64::from_le_bytes(bytes(r)?),
+            u64::from_le_bytes(bytes(r)?),
+        );
+        authorized.entry(client).or_default().push(entry);
+    }
+    for (client, parts) in &mut contents.accounts {
+        parts.authorized = authorized.remove(client).unwrap_or_default();
+    }
+    Ok(contents)
+}
+
+fn decode_str<R: Read>(r: &mut R, client: u16) -> Result<String, Error> {
+    let mut s = vec![0; usize::from(u16::from_le_bytes(bytes(r)?))];
+    r.read_exact(&mut s)?;
+    String::from_utf8(s).map_err(|_| Error::Metadata(client))
+}
+
+fn bytes<const N: usize>(r: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inputs() {
+        let path = std::env::temp_dir().join(format!("trapez-{}-i.snapshot", std::process::id()));
+        let mut inputs = Inputs::read(&path).unwrap();
+        assert_eq!(inputs, Inputs::default());
+        let digest = sha256::digest(b"type,client,tx,amount\n");
+        inputs.insert(&digest);
+        inputs.write(&path).unwrap();
+
+        let inputs = Inputs::read(&path).unwrap();
+        assert!(inputs.contains(&digest));
+        assert!(!inputs.contains(&sha256::digest(b"")));
+        fs::remove_file(Inputs::path(&path)).unwrap();
+    }
+
+    #[test]
+    fn roundtrip() {
+        let path = std::env::temp_dir().join(format!("trapez-{}.snapshot", std::process::id()));
+        let contents = Contents {
+            accounts: vec![
+                (
+                    1,
+                    account::Parts {
+                        available: 5,
+                        held: 10,
+                        locked: false,
+                        log: vec![(1, 10), (2, 5)],
+                        disputes: vec![1],
+                        reversed: Vec::new(),
+                        pending: vec![(5, 3)],
+                        authorized: vec![(6, 2, 1_700_000_000_000)],
+                        closed: vec![(2, false)],
+                    },
+                ),
+                (
+                    7,
+                    account::Parts {
+                        locked: true,
+                        log: vec![(3, -1), (4, 2)],
+                        reversed: vec![4],
+                        ..account::Parts::default()
+                    },
+                ),
+            ],
+            disputes: [(1, 1), (7, 1)].into_iter().collect(),
+            reasons: [((1, 1), "10.4".to_string())].into_iter().collect(),
+            metadata: [(
+                7,
+                Metadata {
+                    values: [("tier".to_string(), "high".to_string())].into(),
+                    tags: ["vip".to_string()].into(),
+                },
+            )]
+            .into(),
+            holds: [7].into(),
+            links: {
+                let mut links = Links::default();
+                links.insert(3, 1);
+                links
+            },
+            positions: [(
+                (1, "AAPL".to_string()),
+                Position {
+                    units: 150_000,
+                    cost: 22_500_000,
+                    realized: -1_500_000,
+                },
+            )]
+            .into(),
+        };
+        write(&path, &contents).unwrap();
+        assert_eq!(read(&path).unwrap(), contents);
+
+        let data = fs::read(&path).unwrap();
+        let body = 16 + SCHEMA.len() + 32;
+        fs::write(&path, &data[..body + 20]).unwrap();
+        assert!(matches!(read(&path), Err(Error::Io(_))));
+        let mut data = data;
+        data[20] ^= 1;
+        fs::write(&path, &data).unwrap();
+        assert!(matches!(read(&path), Err(Error::Header)));
+        data[8] = 99;
+        fs::write(&path, &data).unwrap();
+        assert!(matches!(read(&path), Err(Error::Version(99))));
+        data[0] = b'x';
+        fs::write(&path, &data).unwrap();
+        assert!(matches!(read(&path), Err(Error::Magic)));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn json() {
+        let contents = Contents {
+            accounts: vec![
+                (
+                    1,
+                    account::Parts {
+                        available: 5,
+                        held: 10,
+                        locked: false,
+                        log: vec![(1, 10), (2, -5)],
+                        disputes: vec![1],
+                        reversed: Vec::new(),
+                        pending: Vec::new(),
+                        authorized: vec![(6, 2, 1_700_000_000_000)],
+                        closed: Vec::new(),
+                    },
+                ),
+                (
+                    7,
+                    account::Parts {
+                        locked: true,
+                        log: vec![(3, 4)],
+                        reversed: vec![3],
+                        closed: vec![(3, true)],
+                        ..account::Parts::default()
+                    },
+                ),
+            ],
+            disputes: [(1, 2)].into_iter().collect(),
+            reasons: [((1, 1), "4837".to_string())].into_iter().collect(),
+            metadata: [(
+                7,
+                Metadata {
+                    values: [("region".to_string(), "eu \"west\"".to_string())].into(),
+                    tags: ["vip".to_string(), "watch".to_string()].into(),
+                },
+            )]
+            .into(),
+            holds: [7].into(),
+            links: {
+                let mut links = Links::default();
+                links.insert(3, 1);
+                links
+            },
+            positions: [(
+                (1, "AAPL".to_string()),
+                Position {
+                    units: 150_000,
+                    cost: 22_500_000,
+                    realized: -1_500_000,
+                },
+            )]
+            .into(),
+        };
+        let json = to_json(&contents);
+        assert!(json.contains("{\"tx\": 2, \"amount\": \"-0.0005\"}"));
+        assert_eq!(from_json(&json).unwrap(), contents);
+        let incomplete = json.replace(",\n      \"reversed\": []", "");
+        assert!(matches!(
+            from_json(&incomplete),
+            Err(Error::Field("reversed"))
+        ));
+        assert_eq!(
+            from_json("{\"accounts\": []}").unwrap(),
+            Contents::default()
+        );
+        assert!(matches!(
+            from_json("{\"accounts\": [{\"client\": 1}]}"),
+            Err(Error::Field("available"))
+        ));
+        assert!(matches!(from_json("{"), Err(Error::Json(_))));
+    }
+
+    #[test]
+    fn differences() {
+        let left = Contents {
+            accounts: vec![
+                (
+                    1,
+                    account::Parts {
+                        available: 5,
+                        held: 10,
+                        log: vec![(1, 10), (2, 5)],
+                        disputes: vec![1],
+                        ..account::Parts::default()
+                    },
+                ),
+                (2, account::Parts::default()),
+            ],
+            disputes: [(1, 1)].into_iter().collect(),
+            reasons: BTreeMap::new(),
+            metadata: BTreeMap::new(),
+            holds: BTreeSet::new(),
+            links: Links::default(),
+            positions: BTreeMap::new(),
+        };
+        assert_eq!(diff(&left, &left), []);
+
+        let right = Contents {
+            accounts: vec![
+                (
+                    3,
+                    account::Parts {
+                        locked: true,
+                        ..account::Parts::default()
+                    },
+                ),
+                (
+                    1,
+                    account::Parts {
+                        available: 5,
+                        held: 10,
+                        locked: true,
+                        log: vec![(1, 10), (2, 6), (4, 1)],
+                        reversed: vec![1],
+                        ..account::Parts::default()
+                    },
+                ),
+            ],
+            disputes: [(1, 2)].into_iter().collect(),
+            reasons: BTreeMap::new(),
+            metadata: BTreeMap::new(),
+            holds: BTreeSet::new(),
+            links: Links::default(),
+            positions: BTreeMap::new(),
+        };
+        let differences = diff(&left, &right);
+        assert_eq!(
+            differences,
+            [
+                Difference::Locked {
+                    client: 1,
+                    left: false,
+                    right: true
+                },
+                Difference::Transaction {
+                    client: 1,
+                    tx: 2,
+                    left: Some(5),
+                    right: Some(6)
+                },
+                Difference::Transaction {
+                    client: 1,
+                    tx: 4,
+                    left: None,
+                    right: Some(1)
+                },
+                Difference::Dispute {
+                    client: 1,
+                    tx: 1,
+                    only_in: Side::Left
+                },
+                Difference::Reversed {
+                    client: 1,
+                    tx: 1,
+                    only_in: Side::Right
+                },
+                Difference::DisputeCount {
+                    client: 1,
+                    left: 1,
+                    right: 2
+                },
+                Difference::Account {
+                    client: 2,
+                    only_in: Side::Left
+                },
+                Difference::Account {
+                    client: 3,
+                    only_in: Side::Right
+                },
+            ]
+        );
+        assert_eq!(
+            differences[2].to_string(),
+            "Client 1: transaction 4 is missing on the left and 0.0001 on the right."
+        );
+    }
+
+    #[test]
+    fn header() {
+        let path = std::env::temp_dir().join(format!("trapez-{}-h.snapshot", std::process::id()));
+        let file = |version: u32, schema: &str| {
+            let mut file = MAGIC.to_vec();
+            file.extend_from_slice(&version.to_le_bytes());
+            file.extend_from_slice(&(schema.len() as u32).to_le_bytes());
+            file.extend_from_slice(schema.as_bytes());
+            file.extend_from_slice(&sha256::digest(schema.as_bytes()));
+            file
+        };
+
+        fs::write(&path, file(2, SCHEMA)).unwrap();
+        assert!(matches!(read(&path), Err(Error::Version(2))));
+        fs::write(&path, file(VERSION, "accounts:u32[client:u32]")).unwrap();
+        assert!(matches!(read(&path), Err(Error::Schema { version: 1, .. })));
+        let mut corrupt = file(VERSION, SCHEMA);
+        *corrupt.last_mut().unwrap() ^= 1;
+        fs::write(&path, corrupt).unwrap();
+        assert!(matches!(read(&path), Err(Error::Header)));
+        fs::write(&path, b"TRAPEZXX").unwrap();
+        assert!(matches!(read(&path), Err(Error::Magic)));
+        fs::remove_file(&path).unwrap();
+    }
+}
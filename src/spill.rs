@@ -0,0 +1,158 @@
+/**
+ * Disk storage for transaction log entries which don't fit the in-memory window of an account.
+ *
+ * All accounts share one spill file. Each spill appends a segment of entries sorted by transaction
+ * id, so lookups only need a binary search over the segments whose id range contains the
+ * transaction. The file is removed when the store is dropped.
+ */
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+// transaction id (u32) and amount (i64), little endian
+const ENTRY_SIZE: u64 = 12;
+
+pub struct Store {
+    file: Mutex<File>,
+    path: PathBuf,
+    /// The number of log entries per account which are kept in memory.
+    pub window: usize,
+}
+
+/**
+ * A sorted run of spilled log entries.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    offset: u64,
+    len: u64,
+    pub min: u32,
+    pub max: u32,
+}
+
+impl Store {
+    pub fn create<P: AsRef<Path>>(dir: P, window: usize) -> io::Result<Arc<Store>> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = dir.as_ref().join(format!(
+            "trapez-{}-{}.spill",
+            process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(Arc::new(Self {
+            file: Mutex::new(file),
+            path,
+            window,
+        }))
+    }
+
+    /**
+     * Appends the entries which have to be sorted by transaction id.
+     */
+    pub fn write(&self, txs: &[u32], amounts: &[i64]) -> io::Result<Segment> {
+        let mut buf = Vec::with_capacity(txs.len() * ENTRY_SIZE as usize);
+        for (tx, amount) in txs.iter().zip(amounts) {
+            buf.extend_from_slice(&tx.to_le_bytes());
+            buf.extend_from_slice(&amount.to_le_bytes());
+        }
+        let mut file = self.file.lock().unwrap();
+        let offset = file.seek(SeekFrom::End(0))?;
+        file.write_all(&buf)?;
+        Ok(Segment {
+            offset,
+            len: txs.len() as u64,
+            min: txs.first().copied().unwrap_or_default(),
+            max: txs.last().copied().unwrap_or_default(),
+        })
+    }
+
+    /**
+     * Looks up the amount of the transaction in the segment.
+     */
+    pub fn get(&self, segment: &Segment, tx: u32) -> io::Result<Option<i64>> {
+        if tx < segment.min || tx > segment.max {
+            return Ok(None);
+        }
+        let mut file = self.file.lock().unwrap();
+        let (mut lo, mut hi) = (0, segment.len);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let mut entry = [0; ENTRY_SIZE as usize];
+            file.seek(SeekFrom::Start(segment.offset + mid * ENTRY_SIZE))?;
+            file.read_exact(&mut entry)?;
+            let found = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]);
+            if found == tx {
+                let mut amount = [0; 8];
+                amount.copy_from_slice(&entry[4..]);
+                return Ok(Some(i64::from_le_bytes(amount)));
+            } else if found < tx {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        Ok(None)
+    }
+
+    /**
+     * Reads all entries of the segment.
+     */
+    pub fn read(&self, segment: &Segment) -> io::Result<Vec<(u32, i64)>> {
+        let mut buf = vec![0; (segment.len * ENTRY_SIZE) as usize];
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(segment.offset))?;
+        file.read_exact(&mut buf)?;
+        Ok(buf
+            .chunks_exact(ENTRY_SIZE as usize)
+            .map(|entry| {
+                let mut tx = [0; 4];
+                let mut amount = [0; 8];
+                tx.copy_from_slice(&entry[..4]);
+                amount.copy_from_slice(&entry[4..]);
+                (u32::from_le_bytes(tx), i64::from_le_bytes(amount))
+            })
+            .collect())
+    }
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segments() {
+        let store = Store::create(std::env::temp_dir(), 2).unwrap();
+        let path = store.path.clone();
+        let first = store.write(&[1, 3, 5], &[10, 30, -50]).unwrap();
+        let second = store.write(&[2, 4], &[20, 40]).unwrap();
+        assert_eq!((first.min, first.max), (1, 5));
+
+        assert_eq!(store.get(&first, 1).unwrap(), Some(10));
+        assert_eq!(store.get(&first, 5).unwrap(), Some(-50));
+        assert_eq!(store.get(&first, 2).unwrap(), None);
+        assert_eq!(store.get(&first, 6).unwrap(), None);
+        assert_eq!(store.get(&second, 4).unwrap(), Some(40));
+        assert_eq!(store.read(&second).unwrap(), [(2, 20), (4, 40)]);
+
+        assert!(path.exists());
+        drop(store);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,757 @@
+/**
+ * Model-based testing of accounts.
+ *
+ * Generates seeded sequences of operations, either valid ones (operations referring to existing
+ * transactions in a sensible order) or adversarial ones (colliding ids, negative amounts,
+ * operations out of order), and checks them against `Model`, a reference implementation which
+ * derives the balances from the transaction log instead of tracking them incrementally.
+ *
+ * `simulate` does the same for the processor with several concurrent producers. `stress` runs
+ * producers on several threads, which share clients, and `check_history` searches for an order of
+ * the recorded history the model agrees with.
+ */
+use std::{
+    collections::{btree_map::Entry, BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::oneshot;
+
+pub use crate::rng::Rng;
+use crate::{
+    account::{self, Account},
+    log::Event as _,
+    processor::{self, Batch, Message, Origin},
+};
+
+/**
+ * Random bytes, mostly from `alphabet` and occasionally any byte, so inputs get close enough to the
+ * expected format to reach deep into parsers.
+ */
+pub fn bytes(rng: &mut Rng, alphabet: &[u8], max_len: u64) -> Vec<u8> {
+    (0..rng.below(max_len + 1))
+        .map(|_| match rng.below(16) {
+            0 => rng.next_u64() as u8,
+            _ => alphabet[rng.below(alphabet.len() as u64) as usize],
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Deposit(u32, i64),
+    Withdraw(u32, i64),
+    Dispute(u32),
+    Resolve(u32),
+    Chargeback { tx: u32, reverse: bool },
+}
+
+impl Op {
+    pub fn apply(self, account: &mut Account) -> Result<(), account::Error> {
+        match self {
+            Op::Deposit(tx, amount) => account.deposit(tx, amount),
+            Op::Withdraw(tx, amount) => account.withdraw(tx, amount),
+            Op::Dispute(tx) => account.dispute(tx),
+            Op::Resolve(tx) => account.resolve(tx),
+            Op::Chargeback { tx, reverse } => account.chargeback(tx, reverse),
+        }
+    }
+
+    /**
+     * The same operation with the transaction id shifted by `offset`.
+     */
+    pub fn shift(self, offset: u32) -> Op {
+        match self {
+            Op::Deposit(tx, amount) => Op::Deposit(tx + offset, amount),
+            Op::Withdraw(tx, amount) => Op::Withdraw(tx + offset, amount),
+            Op::Dispute(tx) => Op::Dispute(tx + offset),
+            Op::Resolve(tx) => Op::Resolve(tx + offset),
+            Op::Chargeback { tx, reverse } => Op::Chargeback {
+                tx: tx + offset,
+                reverse,
+            },
+        }
+    }
+
+    pub fn message(self, client: u16) -> Message {
+        match self {
+            Op::Deposit(tx, amount) => Message::Deposit {
+                client,
+                tx,
+                amount,
+                trade: None,
+            },
+            Op::Withdraw(tx, amount) => Message::Withdrawal {
+                client,
+                tx,
+                amount,
+                trade: None,
+            },
+            Op::Dispute(tx) => Message::Dispute {
+                client,
+                tx,
+                reason: None,
+            },
+            Op::Resolve(tx) => Message::Resolve {
+                client,
+                tx,
+                reason: None,
+            },
+            Op::Chargeback { tx, .. } => Message::Chargeback {
+                client,
+                tx,
+                reason: None,
+            },
+        }
+    }
+}
+
+/**
+ * Generates `len` operations. Valid sequences only dispute known transactions and only resolve
+ * or charge back after a dispute, adversarial ones pick ids and amounts from small ranges.
+ */
+pub fn sequence(rng: &mut Rng, len: usize, adversarial: bool) -> Vec<Op> {
+    let mut ops = Vec::with_capacity(len);
+    let mut next_tx = 1;
+    let mut disputed = Vec::new();
+    for _ in 0..len {
+        let (tx, amount) = if adversarial {
+            (rng.below(8) as u32, rng.below(200) as i64 - 20)
+        } else {
+            (next_tx, rng.below(100_000) as i64 + 1)
+        };
+        let known = match next_tx {
+            1 => tx,
+            _ => rng.below(u64::from(next_tx - 1)) as u32 + 1,
+        };
+        let op = match rng.below(10) {
+            0..=3 => Op::Deposit(tx, amount),
+            4..=5 => Op::Withdraw(tx, amount),
+            6..=7 => Op::Dispute(if adversarial { tx } else { known }),
+            n => {
+                let tx = match (adversarial, disputed.is_empty()) {
+                    (true, _) | (false, true) => tx,
+                    (false, false) => {
+                        disputed.swap_remove(rng.below(disputed.len() as u64) as usize)
+                    }
+                };
+                match n {
+                    8 => Op::Resolve(tx),
+                    _ => Op::Chargeback {
+                        tx,
+                        reverse: rng.below(2) == 0,
+                    },
+                }
+            }
+        };
+        match op {
+            Op::Deposit(..) | Op::Withdraw(..) if !adversarial => next_tx += 1,
+            Op::Dispute(tx) => disputed.push(tx),
+            _ => (),
+        }
+        ops.push(op);
+    }
+    ops
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Status {
+    Settled,
+    Disputed,
+    Resolved,
+    ChargedBack,
+    Reversed,
+}
+
+/**
+ * Reference implementation of an account. Balances are sums over the log: held funds are the
+ * disputed amounts, available funds all other amounts which weren't charged back.
+ */
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct Model {
+    log: BTreeMap<u32, (i64, Status)>,
+    locked: bool,
+    /// The last `account::CLOSED` closed disputes, oldest first.
+    closed: Vec<u32>,
+}
+
+impl Model {
+    pub fn available(&self) -> i64 {
+        self.log
+            .values()
+            .filter(|(_, status)| matches!(status, Status::Settled | Status::Resolved))
+            .map(|(amount, _)| amount)
+            .sum()
+    }
+
+    pub fn held(&self) -> i64 {
+        self.log
+            .values()
+            .filter(|(_, status)| *status == Status::Disputed)
+            .map(|(amount, _)| amount)
+            .sum()
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    /**
+     * Applies an operation, returning the code of the error the engine must report if it's
+     * rejected.
+     */
+    pub fn apply(&mut self, op: Op) -> Result<(), &'static str> {
+        match op {
+            Op::Deposit(tx, amount) | Op::Withdraw(tx, amount) => {
+                let withdraw = matches!(op, Op::Withdraw(..));
+                if self.locked {
+                    Err("locked")
+                } else if amount < 0 {
+                    Err("negative_amount")
+                } else if withdraw && self.available() < amount {
+                    Err("insufficient_funds")
+                } else {
+                    let amount = if withdraw { -amount } else { amount };
+                    match self.log.entry(tx) {
+                        Entry::Occupied(_) => Err("transaction_already_exists"),
+                        Entry::Vacant(entry) => {
+                            entry.insert((amount, Status::Settled));
+                            Ok(())
+                        }
+                    }
+                }
+            }
+            Op::Dispute(tx) => match self.log.get(&tx).map(|(_, status)| *status) {
+                Some(Status::Reversed) => Err("transaction_reversed"),
+                _ if self.locked => Err("locked"),
+                None => Err("transaction_unknown"),
+                Some(Status::Disputed) => Err("transaction_already_disputed"),
+                Some(_) => {
+                    self.closed.retain(|closed| *closed != tx);
+                    self.set(tx, Status::Disputed)
+                }
+            },
+            Op::Resolve(tx) | Op::Chargeback { tx, .. } => {
+                let resolve = matches!(op, Op::Resolve(_));
+                let recent = self.closed.contains(&tx);
+                match self.log.get(&tx).map(|(_, status)| *status) {
+                    Some(Status::Reversed) => Err("transaction_reversed"),
+                    Some(Status::Resolved) if recent && !resolve => Err("dispute_already_resolved"),
+                    Some(Status::ChargedBack) if recent && resolve => {
+                        Err("dispute_already_charged_back")
+                    }
+                    _ if self.locked => Err("locked"),
+                    None => Err("transaction_unknown"),
+                    Some(Status::Disputed) => {
+                        if self.closed.len() == account::CLOSED {
+                            self.closed.remove(0);
+                        }
+                        self.closed.push(tx);
+                        match op {
+                            Op::Chargeback { reverse, .. } => {
+                                self.locked = true;
+                                let status = if reverse {
+                                    Status::Reversed
+                                } else {
+                                    Status::ChargedBack
+                                };
+                                self.set(tx, status)
+                            }
+                            _ => self.set(tx, Status::Resolved),
+                        }
+                    }
+                    Some(_) => Err("transaction_undisputed"),
+                }
+            }
+        }
+    }
+
+    fn set(&mut self, tx: u32, status: Status) -> Result<(), &'static str> {
+        if let Some(entry) = self.log.get_mut(&tx) {
+            entry.1 = status;
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Runs a sequence against a fresh account and the model, panicking with the seed and the
+ * position of the first divergence.
+ */
+pub fn check(seed: u64, ops: &[Op]) {
+    let mut account = Account::new();
+    let mut model = Model::default();
+    for (i, op) in ops.iter().enumerate() {
+        let actual = op.apply(&mut account).map_err(|err| err.code());
+        let expected = model.apply(*op);
+        let state = (account.available(), account.held(), account.locked());
+        let modeled = (model.available(), model.held(), model.locked());
+        assert!(
+            actual == expected && state == modeled,
+            "seed {seed}, op {i} ({op:?}): engine {actual:?} {state:?}, model {expected:?} {modeled:?}"
+        );
+    }
+}
+
+/**
+ * A simulated run of the processor, see `simulate`.
+ */
+pub struct Simulation {
+    /// The operations of all clients in the order the processor received them.
+    pub received: Vec<(u16, Op)>,
+    pub state: processor::StateView,
+    pub notifications: Vec<processor::Notification>,
+}
+
+/**
+ * Runs `producers` concurrent producers, each sending `len` operations of its own client, against
+ * `processor::run` on a single threaded runtime, so the seed alone determines the schedule. Between
+ * messages every producer yields a random number of times, which varies the interleaving. Faults
+ * get injected by the seed as well: state requests whose reply is never awaited, and with odd seeds
+ * a notification receiver which is gone from the start.
+ */
+pub fn simulate(seed: u64, producers: u16, len: usize) -> Simulation {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async move {
+        let (tx_msg, mut rx_notify) = processor::run(
+            processor::Config {
+                check_invariants: true,
+                ..processor::Config::default()
+            },
+            processor::Storage::default(),
+        )
+        .await;
+        let notifications = match seed % 2 {
+            0 => Some(tokio::spawn(async move {
+                let mut notifications = Vec::new();
+                while let Some(notification) = rx_notify.recv().await {
+                    notifications.push(notification);
+                }
+                notifications
+            })),
+            _ => {
+                drop(rx_notify);
+                None
+            }
+        };
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut handles = Vec::new();
+        for client in 1..=producers {
+            let mut rng = Rng::new(seed ^ u64::from(client) << 32);
+            let adversarial = rng.below(2) == 0;
+            let ops = sequence(&mut rng, len, adversarial);
+            let tx_msg = tx_msg.clone();
+            let received = received.clone();
+            handles.push(tokio::spawn(async move {
+                // separate transaction ids per client
+                let offset = u32::from(client) * 1_000_000;
+                for op in ops {
+                    for _ in 0..rng.below(4) {
+                        tokio::task::yield_now().await;
+                    }
+                    if rng.below(20) == 0 {
+                        let (tx, rx) = oneshot::channel();
+                        drop(rx);
+                        tx_msg.send(Message::GetState { tx }).await.unwrap();
+                    }
+                    tx_msg.send(op.shift(offset).message(client)).await.unwrap();
+                    // nothing else runs before the lock, so this is the order of the channel
+                    received.lock().unwrap().push((client, op));
+                }
+            }));
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        let (tx, rx) = oneshot::channel();
+        tx_msg.send(Message::GetState { tx }).await.unwrap();
+        let state = rx.await.unwrap();
+        drop(tx_msg);
+        let notifications = match notifications {
+            Some(handle) => handle.await.unwrap(),
+            None => Vec::new(),
+        };
+        let received = std::mem::take(&mut *received.lock().unwrap());
+        Simulation {
+            received,
+            state,
+            notifications,
+        }
+    })
+}
+
+/**
+ * Replays the received operations against a model per client and compares the outcome to the state
+ * reported by the processor.
+ */
+pub fn check_simulation(seed: u64, sim: &Simulation) {
+    let mut models: BTreeMap<u16, Model> = BTreeMap::new();
+    for (client, op) in &sim.received {
+        let op = match *op {
+            // the processor doesn't reverse chargebacks by default
+            Op::Chargeback { tx, .. } => Op::Chargeback { tx, reverse: false },
+            op => op,
+        };
+        let _ = models.entry(*client).or_default().apply(op);
+    }
+    for (client, model) in &models {
+        let modeled = (model.available(), model.held(), model.locked());
+        let actual = sim
+            .state
+            .get(*client)
+            .map_or((0, 0, false), |s| (s.available, s.held, s.locked));
+        assert_eq!(actual, modeled, "seed {seed}, client {client}");
+    }
+    for notification in &sim.notifications {
+        assert!(
+            !matches!(
+                notification,
+                processor::Notification::Error(processor::Error::Invariant { .. }, _)
+            ),
+            "seed {seed}: {notification:?}"
+        );
+    }
+}
+
+/**
+ * Settings of a stress run, see `stress`.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Stress {
+    pub seed: u64,
+    pub producers: u16,
+    pub clients: u16,
+    /// Number of operations of every producer per client, below 1000.
+    pub len: usize,
+}
+
+/**
+ * How the processor responded to an operation: the code of the error it reported, if any.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Accepted,
+    Rejected(&'static str),
+}
+
+/**
+ * An operation of a stress run, see `stress`.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Call {
+    pub client: u16,
+    pub op: Op,
+    pub outcome: Outcome,
+}
+
+/**
+ * The recorded history of a stress run.
+ */
+pub struct History {
+    pub reverse_chargebacks: bool,
+    /// The calls of every producer in the order it sent them.
+    pub producers: Vec<Vec<Call>>,
+    pub state: processor::StateView,
+    /// Invariant violations reported by the processor.
+    pub violations: Vec<processor::Error>,
+}
+
+/**
+ * Runs `producers` producers on a multi-threaded runtime with a worker per producer, all sending
+ * operations of the same clients in batches of a random size. Every message carries its producer
+ * and position as origin, so the errors reported by the processor can be attributed to the calls.
+ * Unlike with `simulate`, the interleaving is up to the scheduler, only the operations of every
+ * producer are generated from the seed.
+ */
+pub fn stress(config: Stress) -> History {
+    assert!(config.len < 1000 && config.clients < 1000);
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(usize::from(config.producers.max(1)))
+        .build()
+        .unwrap();
+    let reverse_chargebacks = config.seed % 2 == 1;
+    runtime.block_on(async move {
+        let (tx_msg, mut rx_notify) = processor::run(
+            processor::Config {
+                check_invariants: true,
+                reverse_chargebacks,
+                ..processor::Config::default()
+            },
+            processor::Storage::default(),
+        )
+        .await;
+        let notifications = tokio::spawn(async move {
+            let mut rejected = HashMap::new();
+            let mut violations = Vec::new();
+            while let Some(notification) = rx_notify.recv().await {
+                match notification {
+                    processor::Notification::Error(err @ processor::Error::Invariant { .. }, _) => {
+                        violations.push(err)
+                    }
+                    processor::Notification::Error(err, Some(origin)) => {
+                        let producer: usize = origin.source.parse().unwrap();
+                        rejected.insert((producer, origin.line), err.code());
+                    }
+                    _ => (),
+                }
+            }
+            (rejected, violations)
+        });
+
+        let mut handles = Vec::new();
+        for producer in 0..config.producers {
+            let mut rng = Rng::new(config.seed ^ u64::from(producer) << 32);
+            let mut pending: Vec<_> = (1..=config.clients)
+                .map(|client| {
+                    let adversarial = rng.below(2) == 0;
+                    let mut ops = sequence(&mut rng, config.len, adversarial);
+                    ops.reverse();
+                    (client, ops)
+                })
+                .collect();
+            // the operations of all clients, interleaved at random
+            let mut calls = Vec::new();
+            while !pending.is_empty() {
+                let i = rng.below(pending.len() as u64) as usize;
+                let client = pending[i].0;
+                match pending[i].1.pop() {
+                    // separate transaction ids per producer and client
+                    Some(op) => calls.push((
+                        client,
+                        op.shift(u32::from(producer) * 1_000_000 + u32::from(client) * 1000),
+                    )),
+                    None => {
+                        pending.swap_remove(i);
+                    }
+                }
+            }
+            let tx_msg = tx_msg.clone();
+            handles.push(tokio::spawn(async move {
+                let source: Arc<str> = producer.to_string().into();
+                let mut line = 0;
+                for chunk in calls.chunks(rng.below(8) as usize + 1) {
+                    let mut batch = Batch::from(Vec::new());
+                    for (client, op) in chunk {
+                        batch.push(
+                            op.message(*client),
+                            Origin {
+                                source: source.clone(),
+                                line,
+                                offset: 0,
+                                reference: None,
+                                timestamp: None,
+                            },
+                        );
+                        line += 1;
+                    }
+                    tx_msg.send(Message::Batch(batch)).await.unwrap();
+                }
+                calls
+            }));
+        }
+        let mut sent = Vec::new();
+        for handle in handles {
+            sent.push(handle.await.unwrap());
+        }
+
+        let (tx, rx) = oneshot::channel();
+        tx_msg.send(Message::GetState { tx }).await.unwrap();
+        let state = rx.await.unwrap();
+        drop(tx_msg);
+        let (rejected, violations) = notifications.await.unwrap();
+        let producers = sent
+            .into_iter()
+            .enumerate()
+            .map(|(producer, calls)| {
+                calls
+                    .into_iter()
+                    .enumerate()
+                    .map(|(line, (client, op))| Call {
+                        client,
+                        op,
+                        outcome: match rejected.get(&(producer, line as u64)) {
+                            Some(code) => Outcome::Rejected(code),
+                            None => Outcome::Accepted,
+                        },
+                    })
+                    .collect()
+            })
+            .collect();
+        History {
+            reverse_chargebacks,
+            producers,
+            state,
+            violations,
+        }
+    })
+}
+
+/**
+ * Checks that the history is linearizable per client: for every client there must be an order of
+ * its calls which keeps the order of every producer, in which the model responds to each call like
+ * the processor did and ends up in the final state of the processor. Returns a description of the
+ * first client without such an order.
+ */
+pub fn check_history(history: &History) -> Result<(), String> {
+    if let Some(err) = history.violations.first() {
+        return Err(err.to_string());
+    }
+    let mut clients: BTreeMap<u16, Vec<Vec<Call>>> = BTreeMap::new();
+    for (producer, calls) in history.producers.iter().enumerate() {
+        for call in calls {
+            let lanes = clients
+                .entry(call.client)
+                .or_insert_with(|| vec![Vec::new(); history.producers.len()]);
+            let op = match call.op {
+                Op::Chargeback { tx, .. } => Op::Chargeback {
+                    tx,
+                    reverse: history.reverse_chargebacks,
+                },
+                op => op,
+            };
+            lanes[producer].push(Call { op, ..*call });
+        }
+    }
+    for (client, lanes) in &clients {
+        let state = history
+            .state
+            .get(*client)
+            .map_or((0, 0, false), |s| (s.available, s.held, s.locked));
+        let mut search = Search {
+            lanes,
+            state,
+            positions: vec![0; lanes.len()],
+            failed: HashSet::new(),
+        };
+        if !search.run(&Shadow::default()) {
+            return Err(format!(
+                "client {client}: no order of the calls leads to {state:?}"
+            ));
+        }
+    }
+    Ok(())
+}
+
+// The model of an account of the processor, which only exists after a first deposit.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+struct Shadow {
+    opened: bool,
+    model: Model,
+}
+
+impl Shadow {
+    fn apply(&mut self, op: Op) -> Outcome {
+        if let Op::Deposit(..) = op {
+            self.opened = true;
+        }
+        if !self.opened {
+            return Outcome::Rejected("unknown_client");
+        }
+        match self.model.apply(op) {
+            Ok(()) => Outcome::Accepted,
+            Err(code) => Outcome::Rejected(code),
+        }
+    }
+}
+
+// Depth-first search for a linearization of the calls of one client.
+struct Search<'a> {
+    /// The calls of every producer.
+    lanes: &'a [Vec<Call>],
+    /// The final state to reach.
+    state: (i64, i64, bool),
+    /// The number of calls taken from every lane.
+    positions: Vec<usize>,
+    /// Positions and models which don't lead to a linearization.
+    failed: HashSet<(Vec<usize>, Shadow)>,
+}
+
+impl Search<'_> {
+    fn run(&mut self, shadow: &Shadow) -> bool {
+        if self
+            .positions
+            .iter()
+            .zip(self.lanes)
+            .all(|(i, lane)| *i == lane.len())
+        {
+            let model = &shadow.model;
+            return (model.available(), model.held(), model.locked()) == self.state;
+        }
+        if self
+            .failed
+            .contains(&(self.positions.clone(), shadow.clone()))
+        {
+            return false;
+        }
+        for lane in 0..self.lanes.len() {
+            let call = match self.lanes[lane].get(self.positions[lane]) {
+                Some(call) => call,
+                None => continue,
+            };
+            let mut next = shadow.clone();
+            if next.apply(call.op) != call.outcome {
+                continue;
+            }
+            self.positions[lane] += 1;
+            let found = self.run(&next);
+            self.positions[lane] -= 1;
+            if found {
+                return true;
+            }
+        }
+        self.failed.insert((self.positions.clone(), shadow.clone()));
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn model() {
+        for seed in 0..500 {
+            let mut rng = Rng::new(seed);
+            let adversarial = seed % 2 == 1;
+            let ops = sequence(&mut rng, 200, adversarial);
+            check(seed, &ops);
+        }
+    }
+
+    #[test]
+    fn simulation() {
+        for seed in 0..100 {
+            let sim = simulate(seed, 4, 100);
+            check_simulation(seed, &sim);
+            let again = simulate(seed, 4, 100);
+            assert_eq!(sim.received, again.received, "seed {seed}");
+        }
+        // different seeds schedule differently
+        assert_ne!(simulate(0, 4, 100).received, simulate(2, 4, 100).received);
+    }
+
+    #[test]
+    fn linearizable() {
+        for seed in 0..20 {
+            let mut history = stress(Stress {
+                seed,
+                producers: 3,
+                clients: 3,
+                len: 40,
+            });
+            check_history(&history).unwrap_or_else(|err| panic!("seed {seed}: {err}"));
+
+            // a response the model can't produce in any order
+            let call = &mut history.producers[0][0];
+            call.outcome = match call.op {
+                Op::Deposit(..) | Op::Withdraw(..) => Outcome::Rejected("transaction_reversed"),
+                _ => Outcome::Rejected("insufficient_funds"),
+            };
+            assert!(check_history(&history).is_err(), "seed {seed}");
+        }
+    }
+}
@@ -0,0 +1,165 @@
+/**
+ * Throughput measurement of a single run.
+ */
+use std::{
+    fmt,
+    io::{self, Read},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/**
+ * Counts the bytes read from the inner reader.
+ */
+pub struct CountingReader<R> {
+    inner: R,
+    bytes: Arc<AtomicU64>,
+}
+
+impl<R> CountingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            bytes: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /**
+     * A handle to the number of bytes read so far which stays valid after the reader is consumed.
+     */
+    pub fn counter(&self) -> Arc<AtomicU64> {
+        self.bytes.clone()
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+pub struct Meter {
+    start: Instant,
+    bytes: Arc<AtomicU64>,
+    rows: u64,
+    peak_depth: usize,
+}
+
+impl Meter {
+    pub fn start(bytes: Arc<AtomicU64>) -> Meter {
+        Self {
+            start: Instant::now(),
+            bytes,
+            rows: 0,
+            peak_depth: 0,
+        }
+    }
+
+    pub fn row(&mut self) {
+        self.rows += 1;
+    }
+
+    pub fn depth(&mut self, depth: usize) {
+        self.peak_depth = self.peak_depth.max(depth);
+    }
+
+    pub fn finish(self) -> Throughput {
+        Throughput {
+            duration: self.start.elapsed(),
+            rows: self.rows,
+            bytes: self.bytes.load(Ordering::Relaxed),
+            peak_depth: self.peak_depth,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Throughput {
+    pub duration: Duration,
+    /// Number of input rows including rejected ones.
+    pub rows: u64,
+    pub bytes: u64,
+    /// The maximum number of message batches waiting in the processor channel.
+    pub peak_depth: usize,
+}
+
+impl Throughput {
+    fn per_sec(&self, n: u64) -> u64 {
+        let secs = self.duration.as_secs_f64();
+        if secs > 0.0 {
+            (n as f64 / secs) as u64
+        } else {
+            0
+        }
+    }
+
+    pub fn json(&self) -> String {
+        format!(
+            "{{\"duration_ms\":{},\"rows\":{},\"rows_per_sec\":{},\"bytes\":{},\"bytes_per_sec\":{},\
+             \"peak_channel_depth\":{}}}",
+            self.duration.as_millis(),
+            self.rows,
+            self.per_sec(self.rows),
+            self.bytes,
+            self.per_sec(self.bytes),
+            self.peak_depth
+        )
+    }
+}
+
+impl fmt::Display for Throughput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Processed {} rows ({} bytes) in {:?}: {} rows/s, {} bytes/s, peak channel depth {}.",
+            self.rows,
+            self.bytes,
+            self.duration,
+            self.per_sec(self.rows),
+            self.per_sec(self.bytes),
+            self.peak_depth
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counting_reader() {
+        let mut reader = CountingReader::new("hello world".as_bytes());
+        let bytes = reader.counter();
+        let mut buf = [0; 4];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(bytes.load(Ordering::Relaxed), 4);
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(bytes.load(Ordering::Relaxed), 11);
+    }
+
+    #[test]
+    fn report() {
+        let throughput = Throughput {
+            duration: Duration::from_millis(500),
+            rows: 100,
+            bytes: 2000,
+            peak_depth: 7,
+        };
+        assert_eq!(
+            throughput.to_string(),
+            "Processed 100 rows (2000 bytes) in 500ms: 200 rows/s, 4000 bytes/s, peak channel \
+             depth 7."
+        );
+        assert_eq!(
+            throughput.json(),
+            "{\"duration_ms\":500,\"rows\":100,\"rows_per_sec\":200,\"bytes\":2000,\
+             \"bytes_per_sec\":4000,\"peak_channel_depth\":7}"
+        );
+    }
+}
@@ -0,0 +1,238 @@
+/**
+ * Write-ahead log of state-changing messages for crash recovery.
+ *
+ * The log is a CSV file in the input format, so recovery simply feeds it through the CSV parser.
+ * Every state-changing message is appended before the processor applies it. After a crash the
+ * processor replays the log before handling new input, which rebuilds the exact same state since
+ * message handling is deterministic. A torn last line of an interrupted write is cut off on open.
+ */
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use crate::{cli, processor};
+
+const HEADER: &[u8] = b"type,client,tx,amount,reference,reason,timestamp,asset,price\n";
+
+/**
+ * When appended entries are forced to disk.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Fsync {
+    /// After every entry.
+    Always,
+    /// After every batch of messages received by the processor.
+    Batch,
+    /// Never, entries are only handed to the OS at the end of every batch. Killing the process
+    /// loses the entries of the current batch, a system crash may lose more.
+    Never,
+}
+
+/**
+ * The number of entries in the log at the given path a run would recover, zero without a log.
+ */
+pub fn entries<P: AsRef<Path>>(path: P) -> io::Result<usize> {
+    let mut content = match std::fs::read(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    // a torn last line is cut off on open
+    let complete = content
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map_or(0, |pos| pos + 1);
+    content.truncate(complete);
+    Ok(cli::read_csv(&content[..]).flatten().count())
+}
+
+pub struct Log {
+    writer: BufWriter<File>,
+    fsync: Fsync,
+}
+
+impl Log {
+    /**
+     * Opens the log at the given path for appending and returns the messages it already holds,
+     * with their origins in the log.
+     */
+    pub fn open<P: AsRef<Path>>(path: P, fsync: Fsync) -> io::Result<(Log, processor::Recovered)> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        let complete = content
+            .iter()
+            .rposition(|b| *b == b'\n')
+            .map_or(0, |pos| pos + 1);
+        content.truncate(complete);
+        if !content.is_empty() && !content.starts_with(HEADER) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a write-ahead log of this version, unknown header",
+            ));
+        }
+        let source: Arc<str> = path.to_string_lossy().into();
+        let mut messages = Vec::new();
+        let mut msgs = cli::read_csv(&content[..]);
+        while let Some(res) = msgs.next() {
+            if let Ok(msg) = res {
+                messages.push((msg, Some(msgs.origin(&source))));
+            }
+        }
+
+        file.set_len(complete as u64)?;
+        if content.is_empty() {
+            file.write_all(HEADER)?;
+        }
+        file.seek(SeekFrom::End(0))?;
+        let mut log = Self {
+            writer: BufWriter::new(file),
+            fsync,
+        };
+        log.commit()?;
+        Ok((log, messages))
+    }
+
+    /**
+     * Appends the record of a message (see `audit::record`).
+     */
+    pub fn append(&mut self, record: &str) -> io::Result<()> {
+        writeln!(self.writer, "{record}")?;
+        if self.fsync == Fsync::Always {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Hands the appended entries to the OS without forcing them to disk, so they survive the
+     * process getting killed.
+     */
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /**
+     * Ends a batch of entries.
+     */
+    pub fn commit(&mut self) -> io::Result<()> {
+        match self.fsync {
+            Fsync::Always | Fsync::Batch => self.sync(),
+            Fsync::Never => self.writer.flush(),
+        }
+    }
+
+    /**
+     * Removes all entries, e.g. once they are covered by a snapshot.
+     */
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        let file = self.writer.get_mut();
+        file.set_len(HEADER.len() as u64)?;
+        file.seek(SeekFrom::End(0))?;
+        self.commit()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recover() {
+        let path = std::env::temp_dir().join(format!("trapez-wal-{}.csv", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let (mut log, messages) = Log::open(&path, Fsync::Never).unwrap();
+        assert!(messages.is_empty());
+        log.append("deposit,1,1,1.0000").unwrap();
+        log.append("dispute,1,1,").unwrap();
+        log.commit().unwrap();
+        drop(log);
+
+        // simulate a torn write
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"deposit,1,2,12").unwrap();
+        drop(file);
+        assert_eq!(entries(&path).unwrap(), 2);
+
+        let (mut log, messages) = Log::open(&path, Fsync::Batch).unwrap();
+        assert!(matches!(
+            messages[..],
+            [
+                (
+                    processor::Message::Deposit {
+                        client: 1,
+                        tx: 1,
+                        amount: 10000,
+                        trade: None,
+                    },
+                    _
+                ),
+                (
+                    processor::Message::Dispute {
+                        client: 1,
+                        tx: 1,
+                        reason: None
+                    },
+                    _
+                )
+            ]
+        ));
+        log.append("resolve,1,1,").unwrap();
+        log.commit().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "type,client,tx,amount,reference,reason,timestamp,asset,price\ndeposit,1,1,1.0000\ndispute,1,1,\nresolve,1,1,\n"
+        );
+        log.reset().unwrap();
+        log.append("deposit,2,3,1").unwrap();
+        log.commit().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "type,client,tx,amount,reference,reason,timestamp,asset,price\ndeposit,2,3,1\n"
+        );
+        drop(log);
+
+        // the optional fields are recovered
+        let (mut log, messages) = Log::open(&path, Fsync::Never).unwrap();
+        assert_eq!(messages.len(), 1);
+        log.append("dispute,2,3,1.0000,,10.4,17").unwrap();
+        log.commit().unwrap();
+        drop(log);
+        let (mut log, messages) = Log::open(&path, Fsync::Never).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].0.reason().unwrap().as_str(), "10.4");
+        assert_eq!(messages[1].1.as_ref().and_then(|o| o.timestamp), Some(17));
+        log.reset().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "type,client,tx,amount,reference,reason,timestamp,asset,price\n"
+        );
+        drop(log);
+        assert_eq!(entries(&path).unwrap(), 0);
+
+        // other files aren't mistaken for a log
+        std::fs::write(&path, "type,client,tx,amount\ndeposit,1,1,1\n").unwrap();
+        assert_eq!(
+            Log::open(&path, Fsync::Never).err().map(|err| err.kind()),
+            Some(io::ErrorKind::InvalidData)
+        );
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(entries(&path).unwrap(), 0);
+    }
+}
@@ -0,0 +1,503 @@
+/**
+ * Webhook notifications about chargebacks, account locks, large disputes and low balances.
+ *
+ * The hooks are derived from the dispute event stream and the low balance warnings of the
+ * processor and posted as JSON to a configured `http://` URL. Delivery runs on its own thread, so
+ * slow endpoints and retries never hold up the processor. A failed delivery is retried with
+ * exponentially growing delays, and given up on after the configured number of attempts.
+ */
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Write},
+    net::TcpStream,
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    amount,
+    json::Value,
+    log,
+    processor::{DisputeEvent, DisputeStatus, Reason, Warning},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Invalid webhook URL `{0}`, expected `http://host[:port][/path]`.")]
+    Url(String),
+    #[error("Invalid webhook header `{0}`, expected `Name: value`.")]
+    Header(String),
+    #[error("Webhook request failed: `{0}`.")]
+    Io(#[from] io::Error),
+    #[error("Webhook endpoint responded with status {0}.")]
+    Status(u16),
+    #[error("Invalid response from the webhook endpoint.")]
+    Response,
+}
+
+/**
+ * The endpoint of a webhook, parsed from an `http://` URL.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Url {
+    pub fn parse(s: &str) -> Result<Url, Error> {
+        let err = || Error::Url(s.to_string());
+        let rest = s.strip_prefix("http://").ok_or_else(err)?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| err())?),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(err());
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+/**
+ * Parses an additional request header like `Authorization: Bearer <token>`.
+ */
+pub fn parse_header(s: &str) -> Result<String, Error> {
+    match s.split_once(':') {
+        Some((name, _)) if !name.trim().is_empty() && !s.contains(['\r', '\n']) => {
+            Ok(s.to_string())
+        }
+        _ => Err(Error::Header(s.to_string())),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub url: Url,
+    /// Additional header sent with every request, e.g. for authentication.
+    pub header: Option<String>,
+    /// Number of delivery attempts per hook.
+    pub attempts: u32,
+    /// Delay before the first retry, doubled for every further one.
+    pub backoff: Duration,
+    /// Post a hook for disputes of transactions with an absolute amount above this one.
+    pub dispute_threshold: Option<i64>,
+}
+
+/**
+ * A notification posted to the webhook.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Hook {
+    Chargeback {
+        client: u16,
+        tx: u32,
+        amount: i64,
+        reason: Option<Reason>,
+    },
+    Locked {
+        client: u16,
+        tx: u32,
+    },
+    LargeDispute {
+        client: u16,
+        tx: u32,
+        amount: i64,
+        reason: Option<Reason>,
+    },
+    LowBalance {
+        client: u16,
+        tx: u32,
+        available: i64,
+        threshold: i64,
+    },
+}
+
+impl Hook {
+    /**
+     * The hooks a dispute event triggers. Chargebacks always lock the account.
+     */
+    pub fn from_event(event: &DisputeEvent, dispute_threshold: Option<i64>) -> Vec<Hook> {
+        let DisputeEvent {
+            client,
+            tx,
+            amount,
+            reason,
+            ..
+        } = *event;
+        match event.status {
+            DisputeStatus::ChargedBack => vec![
+                Hook::Chargeback {
+                    client,
+                    tx,
+                    amount,
+                    reason,
+                },
+                Hook::Locked { client, tx },
+            ],
+            DisputeStatus::Opened
+                if dispute_threshold.is_some_and(|limit| amount.abs() > limit) =>
+            {
+                vec![Hook::LargeDispute {
+                    client,
+                    tx,
+                    amount,
+                    reason,
+                }]
+            }
+            DisputeStatus::Opened | DisputeStatus::Resolved => Vec::new(),
+        }
+    }
+
+    /**
+     * The hook a warning triggers, only low balances have one.
+     */
+    pub fn from_warning(warning: &Warning) -> Option<Hook> {
+        match *warning {
+            Warning::LowBalance {
+                client,
+                tx,
+                available,
+                threshold,
+            } => Some(Hook::LowBalance {
+                client,
+                tx,
+                available,
+                threshold,
+            }),
+            _ => None,
+        }
+    }
+
+    fn event(&self) -> &'static str {
+        match self {
+            Hook::Chargeback { .. } => "chargeback",
+            Hook::Locked { .. } => "account_locked",
+            Hook::LargeDispute { .. } => "large_dispute",
+            Hook::LowBalance { .. } => "low_balance",
+        }
+    }
+
+    /**
+     * The JSON body of the request.
+     */
+    pub fn body(&self) -> String {
+        let (client, tx, amount, reason) = match *self {
+            Hook::Chargeback {
+                client,
+                tx,
+                amount,
+                reason,
+            }
+            | Hook::LargeDispute {
+                client,
+                tx,
+                amount,
+                reason,
+            } => (client, tx, Some(amount), reason),
+            Hook::LowBalance {
+                client,
+                tx,
+                available,
+                ..
+            } => (client, tx, Some(available), None),
+            Hook::Locked { client, tx } => (client, tx, None, None),
+        };
+        let mut fields = vec![
+            ("event".to_string(), Value::String(self.event().to_string())),
+            ("client".to_string(), Value::Number(client.into())),
+            ("tx".to_string(), Value::Number(tx.into())),
+        ];
+        if let Some(amount) = amount {
+            fields.push(("amount".to_string(), Value::String(amount::format(amount))));
+        }
+        if let Some(reason) = reason {
+            fields.push(("reason".to_string(), Value::String(reason.to_string())));
+        }
+        if let Hook::LowBalance { threshold, .. } = self {
+            fields.push((
+                "threshold".to_string(),
+                Value::String(amount::format(*threshold)),
+            ));
+        }
+        format!("{:#}", Value::Object(fields))
+    }
+}
+
+/**
+ * A hook which couldn't be delivered.
+ */
+#[derive(Debug)]
+pub struct Failed {
+    pub hook: Hook,
+    pub attempts: u32,
+    pub err: Error,
+}
+
+impl fmt::Display for Failed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Gave up delivering the {} webhook for client {} after {} attempts: {}",
+            self.hook.event(),
+            log::Event::client(self).unwrap_or_default(),
+            self.attempts,
+            self.err
+        )
+    }
+}
+
+impl log::Event for Failed {
+    fn code(&self) -> &'static str {
+        "webhook_failed"
+    }
+
+    fn client(&self) -> Option<u16> {
+        match self.hook {
+            Hook::Chargeback { client, .. }
+            | Hook::Locked { client, .. }
+            | Hook::LargeDispute { client, .. }
+            | Hook::LowBalance { client, .. } => Some(client),
+        }
+    }
+
+    fn tx(&self) -> Option<u32> {
+        match self.hook {
+            Hook::Chargeback { tx, .. }
+            | Hook::Locked { tx, .. }
+            | Hook::LargeDispute { tx, .. }
+            | Hook::LowBalance { tx, .. } => Some(tx),
+        }
+    }
+}
+
+/**
+ * Delivers hooks in the order they're sent on a background thread.
+ */
+pub struct Sender {
+    tx: mpsc::Sender<Hook>,
+    thread: thread::JoinHandle<Vec<Failed>>,
+}
+
+impl Sender {
+    pub fn start(config: Config) -> Sender {
+        let (tx, rx) = mpsc::channel::<Hook>();
+        let thread = thread::spawn(move || {
+            let mut failed = Vec::new();
+            for hook in rx {
+                if let Err(err) = deliver(&config, &hook) {
+                    failed.push(Failed {
+                        hook,
+                        attempts: config.attempts.max(1),
+                        err,
+                    });
+                }
+            }
+            failed
+        });
+        Self { tx, thread }
+    }
+
+    pub fn send(&self, hook: Hook) {
+        // the thread only ends once the sender is gone
+        let _ = self.tx.send(hook);
+    }
+
+    /**
+     * Another handle to send hooks from a different task. All handles have to be dropped before
+     * `finish` can return.
+     */
+    pub fn handle(&self) -> mpsc::Sender<Hook> {
+        self.tx.clone()
+    }
+
+    /**
+     * Waits until all hooks are delivered or given up on, and returns the failed ones.
+     */
+    pub fn finish(self) -> Vec<Failed> {
+        drop(self.tx);
+        self.thread.join().unwrap_or_default()
+    }
+}
+
+fn deliver(config: &Config, hook: &Hook) -> Result<(), Error> {
+    let body = hook.body();
+    let mut delay = config.backoff;
+    let mut attempt = 1;
+    loop {
+        match post(config, &body) {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt >= config.attempts => return Err(err),
+            Err(_) => {
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+fn post(config: &Config, body: &str) -> Result<(), Error> {
+    let Url { host, port, path } = &config.url;
+    let mut stream = TcpStream::connect((host.as_str(), *port))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let header = match &config.header {
+        Some(header) => format!("{header}\r\n"),
+        None => String::new(),
+    };
+    write!(
+        stream,
+        "POST {path} HTTP/1.1\r\nHost: {host}:{port}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n{header}\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()?;
+
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status)?;
+    let code: u16 = status
+        .split(' ')
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or(Error::Response)?;
+    match code {
+        200..=299 => Ok(()),
+        code => Err(Error::Status(code)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, net::TcpListener};
+
+    use super::*;
+
+    #[test]
+    fn config() {
+        assert_eq!(
+            Url::parse("http://localhost:8080/hooks/trapez").unwrap(),
+            Url {
+                host: "localhost".into(),
+                port: 8080,
+                path: "/hooks/trapez".into()
+            }
+        );
+        assert_eq!(Url::parse("http://example.com").unwrap().path, "/");
+        assert_eq!(Url::parse("http://example.com").unwrap().port, 80);
+        assert!(Url::parse("https://example.com").is_err());
+        assert!(Url::parse("http://:80/").is_err());
+        assert!(parse_header("Authorization: Bearer x").is_ok());
+        assert!(parse_header("Authorization").is_err());
+        assert!(parse_header("X: a\r\nY: b").is_err());
+    }
+
+    #[test]
+    fn hooks() {
+        let event = |status| DisputeEvent {
+            client: 2,
+            tx: 7,
+            amount: 150_000,
+            status,
+            reason: Reason::parse("4837").ok(),
+            origin: None,
+        };
+        assert_eq!(
+            Hook::from_event(&event(DisputeStatus::Opened), Some(100_000)),
+            [Hook::LargeDispute {
+                client: 2,
+                tx: 7,
+                amount: 150_000,
+                reason: Reason::parse("4837").ok(),
+            }]
+        );
+        assert_eq!(Hook::from_event(&event(DisputeStatus::Opened), None), []);
+        let hooks = Hook::from_event(&event(DisputeStatus::ChargedBack), None);
+        assert_eq!(hooks.len(), 2);
+        assert_eq!(
+            hooks[0].body(),
+            r#"{"event": "chargeback", "client": 2, "tx": 7, "amount": "15.0000", "reason": "4837"}"#
+        );
+        assert_eq!(
+            hooks[1].body(),
+            r#"{"event": "account_locked", "client": 2, "tx": 7}"#
+        );
+
+        let warning = Warning::LowBalance {
+            client: 3,
+            tx: 9,
+            available: 5_000,
+            threshold: 10_000,
+        };
+        assert_eq!(
+            Hook::from_warning(&warning).unwrap().body(),
+            r#"{"event": "low_balance", "client": 3, "tx": 9, "amount": "0.5000", "threshold": "1.0000"}"#
+        );
+        let warning = Warning::LargeDeposit {
+            client: 3,
+            tx: 9,
+            amount: 1,
+        };
+        assert_eq!(Hook::from_warning(&warning), None);
+    }
+
+    // Accepts requests and answers them with the given statuses, returning the requests.
+    fn endpoint(statuses: Vec<u16>) -> (Url, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/hook", listener.local_addr().unwrap())).unwrap();
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                // the body ends with the closing brace of the JSON object
+                while !request.ends_with(b"}") {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                write!(stream, "HTTP/1.1 {status} X\r\nContent-Length: 0\r\n\r\n").unwrap();
+                requests.push(String::from_utf8(request).unwrap());
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn delivery() {
+        let (url, endpoint) = endpoint(vec![500, 200, 503, 503]);
+        let sender = Sender::start(Config {
+            url,
+            header: Some("Authorization: Bearer secret".into()),
+            attempts: 2,
+            backoff: Duration::from_millis(1),
+            dispute_threshold: None,
+        });
+        sender.send(Hook::Locked { client: 1, tx: 1 });
+        sender.send(Hook::Locked { client: 2, tx: 2 });
+        let failed = sender.finish();
+        let requests = endpoint.join().unwrap();
+
+        // the first hook got through on the retry, the second one not at all
+        assert_eq!(requests.len(), 4);
+        assert!(requests[0].starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(requests[0].contains("\r\nAuthorization: Bearer secret\r\n"));
+        assert!(requests[1].ends_with(r#"{"event": "account_locked", "client": 1, "tx": 1}"#));
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].hook, Hook::Locked { client: 2, tx: 2 });
+        assert!(matches!(failed[0].err, Error::Status(503)));
+    }
+}